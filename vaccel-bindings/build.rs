@@ -5,6 +5,12 @@ extern crate bindgen;
 use std::{env, path::PathBuf};
 
 fn main() {
+    // The stub backend needs neither libvaccel nor its headers
+    if env::var_os("CARGO_FEATURE_STUB").is_some() {
+        println!("cargo:rustc-env=VACCEL_VERSION=stub");
+        return;
+    }
+
     let lib = pkg_config::Config::new()
         .probe("vaccel")
         .expect("Could not find vaccel");
@@ -19,6 +25,9 @@ fn main() {
     // Tell cargo to tell rustc to link to libvaccel.
     println!("cargo:rustc-link-lib=vaccel");
 
+    // Expose the vaccel version to the crate
+    println!("cargo:rustc-env=VACCEL_VERSION={}", lib.version);
+
     // Tell cargo to invalidate the built crate whenever the wrapper changes
     println!("cargo:rerun-if-changed=wrapper.h");
 
@@ -2,7 +2,7 @@
 
 use crate::{ffi, Error, Handle, Result};
 use std::{
-    ffi::{c_char, c_uint, CString},
+    ffi::{c_char, c_uint, CStr, CString},
     ptr::{self, NonNull},
 };
 
@@ -89,6 +89,28 @@ impl Config {
 
         unsafe { Self::from_ptr_owned(ptr) }
     }
+
+    /// Returns the plugins of the config, i.e. the libraries vAccel loads
+    /// on bootstrap.
+    pub fn plugins(&self) -> Result<Vec<String>> {
+        let inner = unsafe { self.inner.as_ref() };
+
+        if inner.plugins.is_null() {
+            return Ok(Vec::new());
+        }
+
+        match unsafe { CStr::from_ptr(inner.plugins).to_str() } {
+            Ok(p) => Ok(p
+                .split(':')
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_string())
+                .collect()),
+            Err(e) => Err(Error::ConversionFailed(format!(
+                "Could not convert `plugins` to `String` [{}]",
+                e
+            ))),
+        }
+    }
 }
 
 impl_component_drop!(Config, vaccel_config_delete, inner, owned);
@@ -2,4 +2,17 @@
 
 #![allow(clippy::all)]
 
+#[cfg(not(feature = "stub"))]
 include!(concat!(env!("OUT_DIR"), "/ffi.rs"));
+
+/// An in-process replacement of the libvaccel API.
+///
+/// Objects follow the libvaccel lifecycle and ownership rules, but no plugins
+/// are loaded and operations are deterministic fakes: genop echoes its read
+/// args, model runs return copies of their inputs and image classification
+/// returns fixed tags.
+#[cfg(feature = "stub")]
+mod stub;
+
+#[cfg(feature = "stub")]
+pub use stub::*;
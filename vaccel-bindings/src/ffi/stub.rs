@@ -0,0 +1,1461 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use libc::{c_char, c_int, c_void};
+use std::{
+    collections::BTreeSet,
+    ffi::CStr,
+    fs,
+    path::Path,
+    ptr,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Condvar, Mutex, MutexGuard,
+    },
+};
+
+pub type vaccel_id_t = i64;
+
+pub const VACCEL_OK: u32 = 0;
+pub const VACCEL_EIO: u32 = libc::EIO as u32;
+pub const VACCEL_ENOMEM: u32 = libc::ENOMEM as u32;
+pub const VACCEL_EINVAL: u32 = libc::EINVAL as u32;
+pub const VACCEL_EBACKEND: u32 = 1001;
+
+pub const VACCEL_RESOURCE_LIB: u32 = 0;
+pub const VACCEL_RESOURCE_DATA: u32 = 1;
+pub const VACCEL_RESOURCE_MODEL: u32 = 2;
+
+pub const VACCEL_BLOB_FILE: u32 = 0;
+pub const VACCEL_BLOB_BUFFER: u32 = 1;
+pub const VACCEL_BLOB_MAPPED: u32 = 2;
+
+pub const VACCEL_ARG_RAW: u32 = 0;
+pub const VACCEL_ARG_INT8: u32 = 1;
+pub const VACCEL_ARG_INT8_ARRAY: u32 = 2;
+pub const VACCEL_ARG_INT16: u32 = 3;
+pub const VACCEL_ARG_INT16_ARRAY: u32 = 4;
+pub const VACCEL_ARG_INT32: u32 = 5;
+pub const VACCEL_ARG_INT32_ARRAY: u32 = 6;
+pub const VACCEL_ARG_INT64: u32 = 7;
+pub const VACCEL_ARG_INT64_ARRAY: u32 = 8;
+pub const VACCEL_ARG_UINT8: u32 = 9;
+pub const VACCEL_ARG_UINT8_ARRAY: u32 = 10;
+pub const VACCEL_ARG_UINT16: u32 = 11;
+pub const VACCEL_ARG_UINT16_ARRAY: u32 = 12;
+pub const VACCEL_ARG_UINT32: u32 = 13;
+pub const VACCEL_ARG_UINT32_ARRAY: u32 = 14;
+pub const VACCEL_ARG_UINT64: u32 = 15;
+pub const VACCEL_ARG_UINT64_ARRAY: u32 = 16;
+pub const VACCEL_ARG_FLOAT32: u32 = 17;
+pub const VACCEL_ARG_FLOAT32_ARRAY: u32 = 18;
+pub const VACCEL_ARG_FLOAT64: u32 = 19;
+pub const VACCEL_ARG_FLOAT64_ARRAY: u32 = 20;
+pub const VACCEL_ARG_BOOL: u32 = 21;
+pub const VACCEL_ARG_BOOL_ARRAY: u32 = 22;
+pub const VACCEL_ARG_CHAR: u32 = 23;
+pub const VACCEL_ARG_CHAR_ARRAY: u32 = 24;
+pub const VACCEL_ARG_UCHAR: u32 = 25;
+pub const VACCEL_ARG_UCHAR_ARRAY: u32 = 26;
+pub const VACCEL_ARG_STRING: u32 = 27;
+pub const VACCEL_ARG_BUFFER: u32 = 28;
+pub const VACCEL_ARG_CUSTOM: u32 = 29;
+
+pub const VACCEL_TF_FLOAT: u32 = 1;
+pub const VACCEL_TF_DOUBLE: u32 = 2;
+pub const VACCEL_TF_INT32: u32 = 3;
+pub const VACCEL_TF_UINT8: u32 = 4;
+pub const VACCEL_TF_INT16: u32 = 5;
+pub const VACCEL_TF_INT8: u32 = 6;
+pub const VACCEL_TF_STRING: u32 = 7;
+pub const VACCEL_TF_COMPLEX64: u32 = 8;
+pub const VACCEL_TF_INT64: u32 = 9;
+pub const VACCEL_TF_BOOL: u32 = 10;
+pub const VACCEL_TF_QINT8: u32 = 11;
+pub const VACCEL_TF_QUINT8: u32 = 12;
+pub const VACCEL_TF_QINT32: u32 = 13;
+pub const VACCEL_TF_BFLOAT16: u32 = 14;
+pub const VACCEL_TF_QINT16: u32 = 15;
+pub const VACCEL_TF_QUINT16: u32 = 16;
+pub const VACCEL_TF_UINT16: u32 = 17;
+pub const VACCEL_TF_COMPLEX128: u32 = 18;
+pub const VACCEL_TF_HALF: u32 = 19;
+pub const VACCEL_TF_RESOURCE: u32 = 20;
+pub const VACCEL_TF_VARIANT: u32 = 21;
+pub const VACCEL_TF_UINT32: u32 = 22;
+pub const VACCEL_TF_UINT64: u32 = 23;
+
+pub const VACCEL_TFLITE_NOTYPE: u32 = 1;
+pub const VACCEL_TFLITE_FLOAT32: u32 = 2;
+pub const VACCEL_TFLITE_INT32: u32 = 3;
+pub const VACCEL_TFLITE_UINT8: u32 = 4;
+pub const VACCEL_TFLITE_INT64: u32 = 5;
+pub const VACCEL_TFLITE_STRING: u32 = 6;
+pub const VACCEL_TFLITE_BOOL: u32 = 7;
+pub const VACCEL_TFLITE_INT16: u32 = 8;
+pub const VACCEL_TFLITE_COMPLEX64: u32 = 9;
+pub const VACCEL_TFLITE_INT8: u32 = 10;
+pub const VACCEL_TFLITE_FLOAT16: u32 = 11;
+pub const VACCEL_TFLITE_FLOAT64: u32 = 12;
+pub const VACCEL_TFLITE_COMPLEX128: u32 = 13;
+pub const VACCEL_TFLITE_UINT64: u32 = 14;
+pub const VACCEL_TFLITE_RESOURCE: u32 = 15;
+pub const VACCEL_TFLITE_VARIANT: u32 = 16;
+pub const VACCEL_TFLITE_UINT32: u32 = 17;
+pub const VACCEL_TFLITE_UINT16: u32 = 18;
+pub const VACCEL_TFLITE_INT4: u32 = 19;
+
+pub const VACCEL_TORCH_BYTE: u32 = 1;
+pub const VACCEL_TORCH_CHAR: u32 = 2;
+pub const VACCEL_TORCH_SHORT: u32 = 3;
+pub const VACCEL_TORCH_INT: u32 = 4;
+pub const VACCEL_TORCH_LONG: u32 = 5;
+pub const VACCEL_TORCH_HALF: u32 = 6;
+pub const VACCEL_TORCH_FLOAT: u32 = 7;
+
+/// The tags written by the stub image classification.
+pub const STUB_IMAGE_TAGS: &str = "This is a stub classification tag";
+
+const OK: c_int = VACCEL_OK as c_int;
+const EINVAL: c_int = VACCEL_EINVAL as c_int;
+const ENOMEM: c_int = VACCEL_ENOMEM as c_int;
+const EIO: c_int = VACCEL_EIO as c_int;
+
+/// The global state of the stub.
+struct State {
+    initialized: bool,
+    profiling: bool,
+    sessions: BTreeSet<vaccel_id_t>,
+    /// Registered (session, resource) pairs.
+    registrations: BTreeSet<(vaccel_id_t, vaccel_id_t)>,
+    /// Loaded (session, model resource) pairs.
+    loaded: BTreeSet<(vaccel_id_t, vaccel_id_t)>,
+    /// (session, model resource) pairs explicitly unloaded.
+    unloaded: BTreeSet<(vaccel_id_t, vaccel_id_t)>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    initialized: false,
+    profiling: false,
+    sessions: BTreeSet::new(),
+    registrations: BTreeSet::new(),
+    loaded: BTreeSet::new(),
+    unloaded: BTreeSet::new(),
+});
+
+/// Sessions whose model runs are held until released.
+static HELD_RUNS: Mutex<BTreeSet<vaccel_id_t>> = Mutex::new(BTreeSet::new());
+static HELD_RUNS_CHANGED: Condvar = Condvar::new();
+
+static NEXT_SESSION_ID: AtomicI64 = AtomicI64::new(1);
+static NEXT_RESOURCE_ID: AtomicI64 = AtomicI64::new(1);
+static NEXT_BLOB_FILE: AtomicI64 = AtomicI64::new(1);
+
+fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+unsafe fn box_into<T>(ptr: *mut *mut T, value: T) -> c_int {
+    match ptr.as_mut() {
+        Some(p) => {
+            *p = Box::into_raw(Box::new(value));
+            OK
+        }
+        None => EINVAL,
+    }
+}
+
+/// Copies `size` bytes to a new `malloc()`ed buffer.
+unsafe fn copy_buf(data: *const c_void, size: usize) -> *mut c_void {
+    let buf = libc::malloc(size.max(1));
+    if !buf.is_null() && !data.is_null() {
+        ptr::copy_nonoverlapping(data as *const u8, buf as *mut u8, size);
+    }
+    buf
+}
+
+unsafe fn copy_array<T: Copy>(data: *const T, len: usize) -> *mut T {
+    copy_buf(data as *const c_void, len * std::mem::size_of::<T>()) as *mut T
+}
+
+unsafe fn strdup(s: *const c_char) -> *mut c_char {
+    match s.is_null() {
+        true => ptr::null_mut(),
+        false => libc::strdup(s),
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    match s.is_null() {
+        true => None,
+        false => CStr::from_ptr(s).to_str().ok(),
+    }
+}
+
+fn new_cstring(s: &str) -> *mut c_char {
+    match std::ffi::CString::new(s) {
+        Ok(s) => unsafe { libc::strdup(s.as_ptr()) },
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Stub of the `struct vaccel_config` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_config {
+    pub plugins: *mut c_char,
+    pub log_level: u32,
+    pub log_file: *mut c_char,
+    pub profiling_enabled: bool,
+    pub version_ignore: bool,
+}
+
+pub unsafe fn vaccel_config_new(
+    config: *mut *mut vaccel_config,
+    plugins: *const c_char,
+    log_level: u32,
+    log_file: *const c_char,
+    profiling_enabled: bool,
+    version_ignore: bool,
+) -> c_int {
+    box_into(
+        config,
+        vaccel_config {
+            plugins: strdup(plugins),
+            log_level,
+            log_file: strdup(log_file),
+            profiling_enabled,
+            version_ignore,
+        },
+    )
+}
+
+pub unsafe fn vaccel_config_from_env(config: *mut *mut vaccel_config) -> c_int {
+    let var = |name| std::env::var(name).ok();
+    let plugins = var("VACCEL_PLUGINS").map_or(ptr::null_mut(), |p| new_cstring(&p));
+    let log_file = var("VACCEL_LOG_FILE").map_or(ptr::null_mut(), |f| new_cstring(&f));
+    box_into(
+        config,
+        vaccel_config {
+            plugins,
+            log_level: var("VACCEL_LOG_LEVEL")
+                .and_then(|l| l.parse().ok())
+                .unwrap_or(1),
+            log_file,
+            profiling_enabled: var("VACCEL_PROF_ENABLED").is_some_and(|p| p == "enabled"),
+            version_ignore: var("VACCEL_VERSION_IGNORE").is_some_and(|v| v == "1"),
+        },
+    )
+}
+
+pub unsafe fn vaccel_config_delete(config: *mut vaccel_config) -> c_int {
+    if config.is_null() {
+        return EINVAL;
+    }
+    let config = Box::from_raw(config);
+    libc::free(config.plugins as *mut c_void);
+    libc::free(config.log_file as *mut c_void);
+    OK
+}
+
+pub unsafe fn vaccel_bootstrap_with_config(config: *mut vaccel_config) -> c_int {
+    let config = match config.as_ref() {
+        Some(c) => c,
+        None => return EINVAL,
+    };
+    let mut state = state();
+    state.initialized = true;
+    state.profiling = config.profiling_enabled;
+    OK
+}
+
+pub unsafe fn vaccel_bootstrap() -> c_int {
+    let mut config = ptr::null_mut();
+    let ret = vaccel_config_from_env(&mut config);
+    if ret != OK {
+        return ret;
+    }
+    let ret = vaccel_bootstrap_with_config(config);
+    vaccel_config_delete(config);
+    ret
+}
+
+pub unsafe fn vaccel_cleanup() -> c_int {
+    let mut state = state();
+    state.initialized = false;
+    state.profiling = false;
+    OK
+}
+
+pub unsafe fn vaccel_is_initialized() -> bool {
+    state().initialized
+}
+
+pub unsafe fn vaccel_prof_enabled() -> bool {
+    state().profiling
+}
+
+/// Stub of the `struct vaccel_prof_sample` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_prof_sample {
+    pub start: u64,
+    pub time: u64,
+}
+
+/// Stub of the `struct vaccel_prof_region` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_prof_region {
+    pub name: *mut c_char,
+    pub name_owned: bool,
+    pub samples: *mut vaccel_prof_sample,
+    pub nr_entries: usize,
+    pub size: usize,
+}
+
+/// Stub of the `struct vaccel_session` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_session {
+    pub id: vaccel_id_t,
+    pub remote_id: vaccel_id_t,
+    pub hint: u32,
+}
+
+pub unsafe fn vaccel_session_new(sess: *mut *mut vaccel_session, flags: u32) -> c_int {
+    let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+    let ret = box_into(
+        sess,
+        vaccel_session {
+            id,
+            remote_id: 0,
+            hint: flags,
+        },
+    );
+    if ret == OK {
+        state().sessions.insert(id);
+    }
+    ret
+}
+
+/// Releases a session, unregistering its resources.
+pub unsafe fn vaccel_session_delete(sess: *mut vaccel_session) -> c_int {
+    if sess.is_null() {
+        return EINVAL;
+    }
+    let sess = Box::from_raw(sess);
+
+    let mut state = state();
+    state.sessions.remove(&sess.id);
+    state.registrations.retain(|&(s, _)| s != sess.id);
+    state.loaded.retain(|&(s, _)| s != sess.id);
+    OK
+}
+
+pub unsafe fn vaccel_session_has_resource(
+    sess: *const vaccel_session,
+    res: *const vaccel_resource,
+) -> bool {
+    match (sess.as_ref(), res.as_ref()) {
+        (Some(s), Some(r)) => state().registrations.contains(&(s.id, r.id)),
+        _ => false,
+    }
+}
+
+/// Returns the ids of a live session and of a resource registered with it.
+unsafe fn registered(
+    sess: *const vaccel_session,
+    res: *const vaccel_resource,
+) -> Option<(vaccel_id_t, vaccel_id_t)> {
+    let ids = (sess.as_ref()?.id, res.as_ref()?.id);
+    state().registrations.contains(&ids).then_some(ids)
+}
+
+unsafe fn valid_session(sess: *const vaccel_session) -> bool {
+    sess.as_ref()
+        .is_some_and(|s| state().sessions.contains(&s.id))
+}
+
+/// Stub of the `struct vaccel_blob` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_blob {
+    pub type_: u32,
+    pub name: *mut c_char,
+    pub path: *mut c_char,
+    pub path_owned: bool,
+    pub data: *mut u8,
+    pub data_owned: bool,
+    pub size: usize,
+}
+
+/// Creates a file blob; the file is not required to exist.
+pub unsafe fn vaccel_blob_new(blob: *mut *mut vaccel_blob, path: *const c_char) -> c_int {
+    let path_str = match to_str(path) {
+        Some(p) if !p.is_empty() => p,
+        _ => return EINVAL,
+    };
+    let name = Path::new(path_str)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(path_str);
+
+    box_into(
+        blob,
+        vaccel_blob {
+            type_: VACCEL_BLOB_FILE,
+            name: new_cstring(name),
+            path: strdup(path),
+            path_owned: false,
+            data: ptr::null_mut(),
+            data_owned: false,
+            size: fs::metadata(path_str).map_or(0, |m| m.len() as usize),
+        },
+    )
+}
+
+/// Creates a blob from a buffer, which is copied if `own` is set.
+///
+/// If `dir` is set, the data is also written to a file under it, which is
+/// removed along with the blob.
+pub unsafe fn vaccel_blob_from_buf(
+    blob: *mut *mut vaccel_blob,
+    buf: *const u8,
+    size: usize,
+    own: bool,
+    name: *const c_char,
+    dir: *const c_char,
+    randomize: bool,
+) -> c_int {
+    let name_str = match to_str(name) {
+        Some(n) if !n.is_empty() => n,
+        _ => return EINVAL,
+    };
+    if buf.is_null() && size > 0 {
+        return EINVAL;
+    }
+
+    let mut path = ptr::null_mut();
+    if let Some(dir) = to_str(dir) {
+        let file_name = match randomize {
+            true => format!(
+                "{}.{}",
+                name_str,
+                NEXT_BLOB_FILE.fetch_add(1, Ordering::Relaxed)
+            ),
+            false => name_str.to_string(),
+        };
+        let file_path = Path::new(dir).join(file_name);
+        let data = match buf.is_null() {
+            true => &[][..],
+            false => std::slice::from_raw_parts(buf, size),
+        };
+        if fs::write(&file_path, data).is_err() {
+            return EIO;
+        }
+        path = new_cstring(&file_path.to_string_lossy());
+    }
+
+    let data = match own {
+        true => copy_buf(buf as *const c_void, size) as *mut u8,
+        false => buf as *mut u8,
+    };
+    if data.is_null() && size > 0 {
+        return ENOMEM;
+    }
+
+    box_into(
+        blob,
+        vaccel_blob {
+            type_: match path.is_null() {
+                true => VACCEL_BLOB_BUFFER,
+                false => VACCEL_BLOB_FILE,
+            },
+            name: new_cstring(name_str),
+            path,
+            path_owned: !path.is_null(),
+            data,
+            data_owned: own,
+            size,
+        },
+    )
+}
+
+pub unsafe fn vaccel_blob_delete(blob: *mut vaccel_blob) -> c_int {
+    if blob.is_null() {
+        return EINVAL;
+    }
+    let blob = Box::from_raw(blob);
+
+    if blob.path_owned {
+        if let Some(path) = to_str(blob.path) {
+            let _ = fs::remove_file(path);
+        }
+    }
+    if blob.data_owned {
+        libc::free(blob.data as *mut c_void);
+    }
+    libc::free(blob.name as *mut c_void);
+    libc::free(blob.path as *mut c_void);
+    OK
+}
+
+/// Stub of the `struct vaccel_resource` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_resource {
+    pub id: vaccel_id_t,
+    pub remote_id: vaccel_id_t,
+    pub type_: u32,
+    pub blobs: *mut *mut vaccel_blob,
+    pub nr_blobs: usize,
+}
+
+unsafe fn resource_new(
+    res: *mut *mut vaccel_resource,
+    blobs: Vec<*mut vaccel_blob>,
+    type_: u32,
+) -> c_int {
+    if res.is_null() {
+        for blob in blobs {
+            vaccel_blob_delete(blob);
+        }
+        return EINVAL;
+    }
+
+    let nr_blobs = blobs.len();
+    box_into(
+        res,
+        vaccel_resource {
+            id: NEXT_RESOURCE_ID.fetch_add(1, Ordering::Relaxed),
+            remote_id: 0,
+            type_,
+            blobs: Box::into_raw(blobs.into_boxed_slice()) as *mut *mut vaccel_blob,
+            nr_blobs,
+        },
+    )
+}
+
+unsafe fn free_blobs(blobs: *mut *mut vaccel_blob, nr_blobs: usize) {
+    let blobs = Box::from_raw(ptr::slice_from_raw_parts_mut(blobs, nr_blobs));
+    for &blob in blobs.iter() {
+        vaccel_blob_delete(blob);
+    }
+}
+
+pub unsafe fn vaccel_resource_multi_new(
+    res: *mut *mut vaccel_resource,
+    paths: *mut *const c_char,
+    nr_paths: usize,
+    type_: u32,
+) -> c_int {
+    if paths.is_null() || nr_paths == 0 {
+        return EINVAL;
+    }
+
+    let mut blobs = Vec::with_capacity(nr_paths);
+    for &path in std::slice::from_raw_parts(paths, nr_paths) {
+        let mut blob = ptr::null_mut();
+        let ret = vaccel_blob_new(&mut blob, path);
+        if ret != OK {
+            for blob in blobs {
+                vaccel_blob_delete(blob);
+            }
+            return ret;
+        }
+        blobs.push(blob);
+    }
+
+    resource_new(res, blobs, type_)
+}
+
+/// Creates a resource referencing the blobs, which must outlive it.
+pub unsafe fn vaccel_resource_from_blobs(
+    res: *mut *mut vaccel_resource,
+    blobs: *mut *const vaccel_blob,
+    nr_blobs: usize,
+    type_: u32,
+) -> c_int {
+    if blobs.is_null() || nr_blobs == 0 {
+        return EINVAL;
+    }
+
+    let mut copies = Vec::with_capacity(nr_blobs);
+    for &blob in std::slice::from_raw_parts(blobs, nr_blobs) {
+        let blob = match blob.as_ref() {
+            Some(b) => b,
+            None => {
+                for copy in copies {
+                    vaccel_blob_delete(copy);
+                }
+                return EINVAL;
+            }
+        };
+        copies.push(Box::into_raw(Box::new(vaccel_blob {
+            name: strdup(blob.name),
+            path: strdup(blob.path),
+            path_owned: false,
+            data_owned: false,
+            ..*blob
+        })));
+    }
+
+    resource_new(res, copies, type_)
+}
+
+/// Creates a resource from a copy of a buffer, kept in memory.
+pub unsafe fn vaccel_resource_from_buf(
+    res: *mut *mut vaccel_resource,
+    buf: *mut c_void,
+    nr_bytes: usize,
+    type_: u32,
+    filename: *const c_char,
+    _mem_only: bool,
+) -> c_int {
+    let mut blob = ptr::null_mut();
+    let ret = vaccel_blob_from_buf(
+        &mut blob,
+        buf as *const u8,
+        nr_bytes,
+        true,
+        filename,
+        ptr::null(),
+        false,
+    );
+    if ret != OK {
+        return ret;
+    }
+
+    resource_new(res, vec![blob], type_)
+}
+
+pub unsafe fn vaccel_resource_register(
+    res: *mut vaccel_resource,
+    sess: *mut vaccel_session,
+) -> c_int {
+    let (res, sess) = match (res.as_ref(), sess.as_ref()) {
+        (Some(r), Some(s)) => (r, s),
+        _ => return EINVAL,
+    };
+
+    let mut state = state();
+    if !state.sessions.contains(&sess.id) {
+        return EINVAL;
+    }
+    state.registrations.insert((sess.id, res.id));
+    OK
+}
+
+pub unsafe fn vaccel_resource_unregister(
+    res: *mut vaccel_resource,
+    sess: *mut vaccel_session,
+) -> c_int {
+    let ids = match (sess.as_ref(), res.as_ref()) {
+        (Some(s), Some(r)) => (s.id, r.id),
+        _ => return EINVAL,
+    };
+
+    let mut state = state();
+    if !state.registrations.remove(&ids) {
+        return EINVAL;
+    }
+    state.loaded.remove(&ids);
+    OK
+}
+
+/// Returns the number of sessions the resource is registered with.
+pub unsafe fn vaccel_resource_refcount(res: *const vaccel_resource) -> c_int {
+    match res.as_ref() {
+        Some(r) => state()
+            .registrations
+            .iter()
+            .filter(|&&(_, id)| id == r.id)
+            .count() as c_int,
+        None => -EINVAL,
+    }
+}
+
+pub unsafe fn vaccel_resource_delete(res: *mut vaccel_resource) -> c_int {
+    if res.is_null() {
+        return EINVAL;
+    }
+    let res = Box::from_raw(res);
+
+    let mut state = state();
+    state.registrations.retain(|&(_, id)| id != res.id);
+    state.loaded.retain(|&(_, id)| id != res.id);
+    drop(state);
+
+    free_blobs(res.blobs, res.nr_blobs);
+    OK
+}
+
+/// Stub of the `struct vaccel_arg` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_arg {
+    pub size: usize,
+    pub buf: *mut c_void,
+    pub owned: bool,
+    pub type_: u32,
+    pub custom_type_id: u32,
+}
+
+/// Creates an arg holding a copy of `buf`.
+pub unsafe fn vaccel_arg_new(
+    arg: *mut *mut vaccel_arg,
+    buf: *const c_void,
+    size: usize,
+    type_: u32,
+    custom_type_id: u32,
+) -> c_int {
+    let copy = copy_buf(buf, size);
+    if copy.is_null() {
+        return ENOMEM;
+    }
+    let ret = vaccel_arg_from_buf(arg, copy, size, type_, custom_type_id);
+    match ret {
+        OK => (**arg).owned = true,
+        _ => libc::free(copy),
+    }
+    ret
+}
+
+/// Creates an arg referencing `buf`, which must outlive it.
+pub unsafe fn vaccel_arg_from_buf(
+    arg: *mut *mut vaccel_arg,
+    buf: *mut c_void,
+    size: usize,
+    type_: u32,
+    custom_type_id: u32,
+) -> c_int {
+    box_into(
+        arg,
+        vaccel_arg {
+            size,
+            buf,
+            owned: false,
+            type_,
+            custom_type_id,
+        },
+    )
+}
+
+pub unsafe fn vaccel_arg_delete(arg: *mut vaccel_arg) -> c_int {
+    if arg.is_null() {
+        return EINVAL;
+    }
+    let arg = Box::from_raw(arg);
+    if arg.owned {
+        libc::free(arg.buf);
+    }
+    OK
+}
+
+pub unsafe fn vaccel_noop(sess: *mut vaccel_session) -> c_int {
+    match valid_session(sess) {
+        true => OK,
+        false => EINVAL,
+    }
+}
+
+/// Copies each read arg to the write arg at the same position, truncating it
+/// to the size of the write arg.
+pub unsafe fn vaccel_genop(
+    sess: *mut vaccel_session,
+    read: *mut vaccel_arg,
+    nr_read: c_int,
+    write: *mut vaccel_arg,
+    nr_write: c_int,
+) -> c_int {
+    if !valid_session(sess) || nr_read < 0 || nr_write < 0 {
+        return EINVAL;
+    }
+    let read = match read.is_null() {
+        true => &[][..],
+        false => std::slice::from_raw_parts(read, nr_read as usize),
+    };
+    let write = match write.is_null() {
+        true => &mut [][..],
+        false => std::slice::from_raw_parts_mut(write, nr_write as usize),
+    };
+
+    for (r, w) in read.iter().zip(write.iter_mut()) {
+        if !r.buf.is_null() && !w.buf.is_null() {
+            ptr::copy_nonoverlapping(r.buf as *const u8, w.buf as *mut u8, r.size.min(w.size));
+        }
+    }
+    OK
+}
+
+/// Writes `STUB_IMAGE_TAGS` as the tags and a copy of the image as the
+/// output image.
+pub unsafe fn vaccel_image_classification(
+    sess: *mut vaccel_session,
+    img: *mut c_void,
+    out_text: *mut u8,
+    out_imgname: *mut u8,
+    len_img: usize,
+    len_out_text: usize,
+    len_out_imgname: usize,
+) -> c_int {
+    if !valid_session(sess) || img.is_null() {
+        return EINVAL;
+    }
+
+    if !out_text.is_null() && len_out_text > 0 {
+        let len = STUB_IMAGE_TAGS.len().min(len_out_text - 1);
+        ptr::copy_nonoverlapping(STUB_IMAGE_TAGS.as_ptr(), out_text, len);
+        *out_text.add(len) = 0;
+    }
+    if !out_imgname.is_null() {
+        ptr::copy_nonoverlapping(img as *const u8, out_imgname, len_img.min(len_out_imgname));
+    }
+    OK
+}
+
+/// Writes a copy of the image as the output image.
+pub unsafe fn vaccel_image_detection(
+    sess: *mut vaccel_session,
+    img: *mut c_void,
+    out_imgname: *mut u8,
+    len_img: usize,
+    len_out_imgname: usize,
+) -> c_int {
+    if !valid_session(sess) || img.is_null() || out_imgname.is_null() {
+        return EINVAL;
+    }
+    ptr::copy_nonoverlapping(img as *const u8, out_imgname, len_img.min(len_out_imgname));
+    OK
+}
+
+/// Writes a copy of the image as the output image.
+pub unsafe fn vaccel_image_segmentation(
+    sess: *mut vaccel_session,
+    img: *mut c_void,
+    out_imgname: *mut u8,
+    len_img: usize,
+    len_out_imgname: usize,
+) -> c_int {
+    vaccel_image_detection(sess, img, out_imgname, len_img, len_out_imgname)
+}
+
+/// A tensor the stub models can copy.
+trait StubTensor {
+    /// Returns an owned copy of the tensor.
+    unsafe fn duplicate(&self) -> *mut Self;
+}
+
+macro_rules! stub_tensor {
+    (
+        $tensor:ident,
+        $nr_dims:ty,
+        $dim:ty,
+        new: $new:ident,
+        allocate: $allocate:ident,
+        set_data: $set_data:ident,
+        delete: $delete:ident
+    ) => {
+        #[doc = concat!("Stub of the `struct ", stringify!($tensor), "` C object.")]
+        #[repr(C)]
+        #[derive(Debug, Copy, Clone)]
+        pub struct $tensor {
+            pub owned: bool,
+            pub nr_dims: $nr_dims,
+            pub dims: *mut $dim,
+            pub data: *mut c_void,
+            pub size: usize,
+            pub data_type: u32,
+        }
+
+        pub unsafe fn $new(
+            tensor: *mut *mut $tensor,
+            nr_dims: $nr_dims,
+            dims: *const $dim,
+            type_: u32,
+        ) -> c_int {
+            if nr_dims < 0 || (nr_dims > 0 && dims.is_null()) {
+                return EINVAL;
+            }
+            box_into(
+                tensor,
+                $tensor {
+                    owned: false,
+                    nr_dims,
+                    dims: copy_array(dims, nr_dims as usize),
+                    data: ptr::null_mut(),
+                    size: 0,
+                    data_type: type_,
+                },
+            )
+        }
+
+        /// Creates a tensor owning `total_size` bytes of zeroed data.
+        pub unsafe fn $allocate(
+            tensor: *mut *mut $tensor,
+            nr_dims: $nr_dims,
+            dims: *const $dim,
+            type_: u32,
+            total_size: usize,
+        ) -> c_int {
+            let ret = $new(tensor, nr_dims, dims, type_);
+            if ret != OK {
+                return ret;
+            }
+
+            let t = &mut **tensor;
+            t.data = libc::calloc(total_size.max(1), 1);
+            if t.data.is_null() {
+                $delete(t);
+                *tensor = ptr::null_mut();
+                return ENOMEM;
+            }
+            t.size = total_size;
+            t.owned = true;
+            OK
+        }
+
+        /// Sets data that is not owned by the tensor.
+        pub unsafe fn $set_data(tensor: *mut $tensor, data: *mut c_void, size: usize) -> c_int {
+            let t = match tensor.as_mut() {
+                Some(t) => t,
+                None => return EINVAL,
+            };
+            if t.owned {
+                libc::free(t.data);
+            }
+            t.data = data;
+            t.size = size;
+            t.owned = false;
+            OK
+        }
+
+        pub unsafe fn $delete(tensor: *mut $tensor) -> c_int {
+            if tensor.is_null() {
+                return EINVAL;
+            }
+            let t = Box::from_raw(tensor);
+            libc::free(t.dims as *mut c_void);
+            if t.owned {
+                libc::free(t.data);
+            }
+            OK
+        }
+
+        impl StubTensor for $tensor {
+            unsafe fn duplicate(&self) -> *mut Self {
+                let mut copy = ptr::null_mut();
+                if $allocate(
+                    &mut copy,
+                    self.nr_dims,
+                    self.dims,
+                    self.data_type,
+                    self.size,
+                ) != OK
+                {
+                    return ptr::null_mut();
+                }
+                if !self.data.is_null() {
+                    ptr::copy_nonoverlapping(
+                        self.data as *const u8,
+                        (*copy).data as *mut u8,
+                        self.size,
+                    );
+                }
+                copy
+            }
+        }
+    };
+}
+
+stub_tensor!(
+    vaccel_tf_tensor,
+    i32,
+    i64,
+    new: vaccel_tf_tensor_new,
+    allocate: vaccel_tf_tensor_allocate,
+    set_data: vaccel_tf_tensor_set_data,
+    delete: vaccel_tf_tensor_delete
+);
+
+stub_tensor!(
+    vaccel_tflite_tensor,
+    i32,
+    i32,
+    new: vaccel_tflite_tensor_new,
+    allocate: vaccel_tflite_tensor_allocate,
+    set_data: vaccel_tflite_tensor_set_data,
+    delete: vaccel_tflite_tensor_delete
+);
+
+stub_tensor!(
+    vaccel_torch_tensor,
+    i64,
+    i64,
+    new: vaccel_torch_tensor_new,
+    allocate: vaccel_torch_tensor_allocate,
+    set_data: vaccel_torch_tensor_set_data,
+    delete: vaccel_torch_tensor_delete
+);
+
+/// Runs an identity model: each output tensor is a copy of the input tensor
+/// at the same position.
+unsafe fn run_identity<T: StubTensor>(
+    sess: *const vaccel_session,
+    res: *const vaccel_resource,
+    in_tensors: *const *mut T,
+    nr_in: c_int,
+    out_tensors: *mut *mut T,
+    nr_out: c_int,
+) -> c_int {
+    if registered(sess, res).is_none()
+        || in_tensors.is_null()
+        || out_tensors.is_null()
+        || nr_out < 0
+        || nr_out > nr_in
+    {
+        return EINVAL;
+    }
+    wait_until_released((*sess).id);
+
+    let ins = std::slice::from_raw_parts(in_tensors, nr_out as usize);
+    let outs = std::slice::from_raw_parts_mut(out_tensors, nr_out as usize);
+    for (out, &t) in outs.iter_mut().zip(ins) {
+        *out = match t.as_ref() {
+            Some(t) => t.duplicate(),
+            None => return EINVAL,
+        };
+        if out.is_null() {
+            return ENOMEM;
+        }
+    }
+    OK
+}
+
+unsafe fn model_load(sess: *const vaccel_session, res: *const vaccel_resource) -> c_int {
+    match registered(sess, res) {
+        Some(ids) => {
+            state().loaded.insert(ids);
+            OK
+        }
+        None => EINVAL,
+    }
+}
+
+unsafe fn model_unload(sess: *const vaccel_session, res: *const vaccel_resource) -> c_int {
+    let ids = match registered(sess, res) {
+        Some(ids) => ids,
+        None => return EINVAL,
+    };
+
+    let mut state = state();
+    match state.loaded.remove(&ids) {
+        true => {
+            state.unloaded.insert(ids);
+            OK
+        }
+        false => EINVAL,
+    }
+}
+
+/// Returns whether the model resource `res_id` was explicitly unloaded from
+/// session `sess_id`, rather than dropped along with the session.
+///
+/// Not part of the vAccel API; lets tests check that models are released.
+pub fn vaccel_stub_model_unloaded(sess_id: vaccel_id_t, res_id: vaccel_id_t) -> bool {
+    state().unloaded.contains(&(sess_id, res_id))
+}
+
+/// Holds the model runs of session `sess_id` until called again with
+/// `hold` unset.
+///
+/// Not part of the vAccel API; lets tests check that runs in other sessions
+/// are not blocked.
+pub fn vaccel_stub_hold_runs(sess_id: vaccel_id_t, hold: bool) {
+    let mut held = HELD_RUNS.lock().unwrap_or_else(|e| e.into_inner());
+    match hold {
+        true => held.insert(sess_id),
+        false => held.remove(&sess_id),
+    };
+    HELD_RUNS_CHANGED.notify_all();
+}
+
+fn wait_until_released(sess_id: vaccel_id_t) {
+    let held = HELD_RUNS.lock().unwrap_or_else(|e| e.into_inner());
+    let _held = HELD_RUNS_CHANGED
+        .wait_while(held, |h| h.contains(&sess_id))
+        .unwrap_or_else(|e| e.into_inner());
+}
+
+/// Stub of the `struct vaccel_tf_buffer` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_tf_buffer {
+    pub data: *mut c_void,
+    pub size: usize,
+}
+
+pub unsafe fn vaccel_tf_buffer_new(
+    buffer: *mut *mut vaccel_tf_buffer,
+    data: *mut c_void,
+    size: usize,
+) -> c_int {
+    box_into(buffer, vaccel_tf_buffer { data, size })
+}
+
+pub unsafe fn vaccel_tf_buffer_take_data(
+    buffer: *mut vaccel_tf_buffer,
+    data: *mut *mut c_void,
+    size: *mut usize,
+) -> c_int {
+    match (buffer.as_mut(), data.as_mut(), size.as_mut()) {
+        (Some(b), Some(d), Some(s)) => {
+            *d = std::mem::replace(&mut b.data, ptr::null_mut());
+            *s = std::mem::take(&mut b.size);
+            OK
+        }
+        _ => EINVAL,
+    }
+}
+
+pub unsafe fn vaccel_tf_buffer_delete(buffer: *mut vaccel_tf_buffer) -> c_int {
+    if buffer.is_null() {
+        return EINVAL;
+    }
+    libc::free(Box::from_raw(buffer).data);
+    OK
+}
+
+/// Stub of the `struct vaccel_tf_node` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_tf_node {
+    pub name: *mut c_char,
+    pub id: c_int,
+}
+
+pub unsafe fn vaccel_tf_node_new(
+    node: *mut *mut vaccel_tf_node,
+    name: *const c_char,
+    id: c_int,
+) -> c_int {
+    if name.is_null() {
+        return EINVAL;
+    }
+    box_into(
+        node,
+        vaccel_tf_node {
+            name: strdup(name),
+            id,
+        },
+    )
+}
+
+pub unsafe fn vaccel_tf_node_delete(node: *mut vaccel_tf_node) -> c_int {
+    if node.is_null() {
+        return EINVAL;
+    }
+    libc::free(Box::from_raw(node).name as *mut c_void);
+    OK
+}
+
+/// Stub of the `struct vaccel_tf_status` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_tf_status {
+    pub code: u8,
+    pub message: *mut c_char,
+}
+
+pub unsafe fn vaccel_tf_status_new(
+    status: *mut *mut vaccel_tf_status,
+    code: u8,
+    message: *const c_char,
+) -> c_int {
+    box_into(
+        status,
+        vaccel_tf_status {
+            code,
+            message: strdup(message),
+        },
+    )
+}
+
+pub unsafe fn vaccel_tf_status_delete(status: *mut vaccel_tf_status) -> c_int {
+    if status.is_null() {
+        return EINVAL;
+    }
+    libc::free(Box::from_raw(status).message as *mut c_void);
+    OK
+}
+
+pub unsafe fn vaccel_tf_model_load(
+    sess: *mut vaccel_session,
+    model: *mut vaccel_resource,
+    _status: *mut vaccel_tf_status,
+) -> c_int {
+    model_load(sess, model)
+}
+
+pub unsafe fn vaccel_tf_model_unload(
+    sess: *mut vaccel_session,
+    model: *mut vaccel_resource,
+    _status: *mut vaccel_tf_status,
+) -> c_int {
+    model_unload(sess, model)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn vaccel_tf_model_run(
+    sess: *mut vaccel_session,
+    model: *const vaccel_resource,
+    _run_options: *const vaccel_tf_buffer,
+    _in_nodes: *const vaccel_tf_node,
+    in_tensors: *const *mut vaccel_tf_tensor,
+    nr_inputs: c_int,
+    _out_nodes: *const vaccel_tf_node,
+    out_tensors: *mut *mut vaccel_tf_tensor,
+    nr_outputs: c_int,
+    _status: *mut vaccel_tf_status,
+) -> c_int {
+    run_identity(sess, model, in_tensors, nr_inputs, out_tensors, nr_outputs)
+}
+
+pub unsafe fn vaccel_tflite_model_load(
+    sess: *mut vaccel_session,
+    model: *mut vaccel_resource,
+) -> c_int {
+    model_load(sess, model)
+}
+
+pub unsafe fn vaccel_tflite_model_unload(
+    sess: *mut vaccel_session,
+    model: *mut vaccel_resource,
+) -> c_int {
+    model_unload(sess, model)
+}
+
+pub unsafe fn vaccel_tflite_model_run(
+    sess: *mut vaccel_session,
+    model: *const vaccel_resource,
+    in_tensors: *const *mut vaccel_tflite_tensor,
+    nr_inputs: c_int,
+    out_tensors: *mut *mut vaccel_tflite_tensor,
+    nr_outputs: c_int,
+    status: *mut u8,
+) -> c_int {
+    if let Some(s) = status.as_mut() {
+        *s = 0;
+    }
+    run_identity(sess, model, in_tensors, nr_inputs, out_tensors, nr_outputs)
+}
+
+/// Stub of the `struct vaccel_torch_buffer` C object.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vaccel_torch_buffer {
+    pub data: *mut c_char,
+    pub size: usize,
+}
+
+pub unsafe fn vaccel_torch_buffer_new(
+    buffer: *mut *mut vaccel_torch_buffer,
+    data: *mut c_char,
+    size: usize,
+) -> c_int {
+    box_into(buffer, vaccel_torch_buffer { data, size })
+}
+
+pub unsafe fn vaccel_torch_buffer_take_data(
+    buffer: *mut vaccel_torch_buffer,
+    data: *mut *mut c_char,
+    size: *mut usize,
+) -> c_int {
+    match (buffer.as_mut(), data.as_mut(), size.as_mut()) {
+        (Some(b), Some(d), Some(s)) => {
+            *d = std::mem::replace(&mut b.data, ptr::null_mut());
+            *s = std::mem::take(&mut b.size);
+            OK
+        }
+        _ => EINVAL,
+    }
+}
+
+pub unsafe fn vaccel_torch_buffer_delete(buffer: *mut vaccel_torch_buffer) -> c_int {
+    if buffer.is_null() {
+        return EINVAL;
+    }
+    libc::free(Box::from_raw(buffer).data as *mut c_void);
+    OK
+}
+
+pub unsafe fn vaccel_torch_model_load(
+    sess: *mut vaccel_session,
+    model: *mut vaccel_resource,
+) -> c_int {
+    model_load(sess, model)
+}
+
+pub unsafe fn vaccel_torch_model_run(
+    sess: *mut vaccel_session,
+    model: *const vaccel_resource,
+    _run_options: *const vaccel_torch_buffer,
+    in_tensors: *const *mut vaccel_torch_tensor,
+    nr_read: c_int,
+    out_tensors: *mut *mut vaccel_torch_tensor,
+    nr_write: c_int,
+) -> c_int {
+    run_identity(sess, model, in_tensors, nr_read, out_tensors, nr_write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::STUB_IMAGE_TAGS;
+    use crate::{
+        ops::{
+            torch::{DataType, DynTensor, Tensor},
+            Model as _, Tensor as _,
+        },
+        Arg, ArgType, Blob, Config, Resource, ResourceType, Session,
+    };
+
+    #[test]
+    fn config_lists_its_plugins() {
+        let config = Config::new(
+            Some("libvaccel-noop.so:libvaccel-torch.so"),
+            1,
+            None,
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            config.plugins().unwrap(),
+            vec!["libvaccel-noop.so", "libvaccel-torch.so"]
+        );
+
+        let config = Config::new(None, 1, None, false, false).unwrap();
+        assert!(config.plugins().unwrap().is_empty());
+    }
+
+    #[test]
+    fn resource_lifecycle() {
+        let mut sess = Session::new().unwrap();
+        let blob = Blob::from_buf(vec![1, 2, 3], "blob", None, false).unwrap();
+        let mut res = Resource::from_blobs(vec![blob], ResourceType::Data).unwrap();
+
+        res.register(&mut sess).unwrap();
+        assert!(sess.has_resource(&mut res));
+        assert_eq!(res.refcount().unwrap(), 1);
+
+        let mut other = Session::new().unwrap();
+        res.register(&mut other).unwrap();
+        assert_eq!(res.refcount().unwrap(), 2);
+        drop(other);
+        assert_eq!(res.refcount().unwrap(), 1);
+
+        res.unregister(&mut sess).unwrap();
+        assert!(!sess.has_resource(&mut res));
+        assert!(res.unregister(&mut sess).is_err());
+        assert_eq!(res.blobs().unwrap()[0].data(), Some(&[1u8, 2, 3][..]));
+    }
+
+    #[test]
+    fn resource_blobs_are_read_through_pointers() {
+        // Resources created from paths read their blobs from the C resource,
+        // which holds an array of blob pointers
+        let dir = std::env::temp_dir();
+        let paths = ["first", "second"].map(|name| {
+            let path = dir.join(format!("vaccel-stub-{}-{}.bin", std::process::id(), name));
+            std::fs::write(&path, name).unwrap();
+            path
+        });
+        let mut res = Resource::new(
+            paths.iter().map(|p| p.to_str().unwrap()),
+            ResourceType::Data,
+        )
+        .unwrap();
+
+        let blobs = res.blobs().unwrap();
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(
+            blobs[0].name().unwrap(),
+            paths[0].file_name().unwrap().to_str().unwrap()
+        );
+        assert_eq!(blobs[1].size(), "second".len());
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn genop_echoes_read_args() {
+        let mut sess = Session::new().unwrap();
+        let mut read = [Arg::new(b"hello", ArgType::Raw, 0).unwrap()];
+        let mut write = [Arg::from_buf(vec![0; 5], ArgType::Raw, 0).unwrap()];
+
+        sess.genop(&mut read, &mut write).unwrap();
+        assert_eq!(write[0].buf(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn image_classification_returns_fixed_tags() {
+        let mut sess = Session::new().unwrap();
+        let (tags, _) = sess.image_classification(&[0u8; 16]).unwrap();
+        assert!(tags.starts_with(STUB_IMAGE_TAGS.as_bytes()));
+    }
+
+    #[test]
+    fn model_run_is_identity() {
+        let mut sess = Session::new().unwrap();
+        let mut model = crate::ops::torch::Model::load("/nonexistent/model.pt", &mut sess).unwrap();
+
+        let input = Tensor::<f32>::from_data(&[1, 3], vec![1.0, 2.0, 3.0]).unwrap();
+        let out = model.run(&[input]).unwrap();
+        assert_eq!(out[0].dims().unwrap(), &[1, 3]);
+        assert_eq!(out[0].data().unwrap(), Some(&[1.0f32, 2.0, 3.0][..]));
+
+        let input = DynTensor::new(&[2], DataType::Float).unwrap();
+        assert_eq!(
+            model.run(&[input]).unwrap()[0].as_bytes(),
+            Some(&[0u8; 8][..])
+        );
+        model.unload().unwrap();
+    }
+
+    #[test]
+    fn model_load_with_warmup() {
+        let mut sess = Session::new().unwrap();
+        let input = DynTensor::new(&[1, 4], DataType::Float).unwrap();
+        let (mut model, _) = crate::ops::torch::Model::load_with_warmup(
+            "/nonexistent/model.pt",
+            &mut sess,
+            &[input],
+            3,
+        )
+        .unwrap();
+        assert!(model.is_loaded());
+
+        let input = Tensor::<f32>::from_data(&[1], vec![1.0]).unwrap();
+        model.warmup(&[input], 2).unwrap();
+        model.unload().unwrap();
+        assert!(model
+            .warmup(&[DynTensor::new(&[1], DataType::Float).unwrap()], 1)
+            .is_err());
+    }
+
+    #[test]
+    fn warmup_stops_before_a_refused_run() {
+        let mut runs = 0;
+        let ret = crate::ops::warmup(
+            5,
+            || {
+                runs += 1;
+                Ok::<_, ()>(())
+            },
+            |i| if i < 2 { Ok(()) } else { Err(()) },
+        );
+        assert!(ret.is_err());
+        assert_eq!(runs, 2);
+    }
+}
@@ -29,7 +29,10 @@ pub use error::{Error, Result};
 pub use handle::Handle;
 pub use resource::{Resource, ResourceType};
 pub use session::Session;
-pub use vaccel::{bootstrap, bootstrap_with_config, cleanup, is_initialized, VaccelId};
+pub use vaccel::{
+    bootstrap, bootstrap_with_config, cleanup, guess_loaded_version, is_initialized, plugins,
+    VaccelId, VERSION,
+};
 
 /// Wrapper for `slice::from_raw_parts()` with null pointer checking.
 ///
@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::{Handle, Result, Session};
+use std::time::{Duration, Instant};
 
 #[macro_use]
 mod macros;
@@ -11,6 +12,25 @@ pub mod noop;
 pub mod tf;
 pub mod torch;
 
+/// Warms up a model by calling `run` `nr_runs` times, discarding the
+/// outputs, and returns the time it took.
+///
+/// `before_run` is called with the index of each run before it starts, e.g.
+/// to give up on the warm-up past a deadline. Stops at the first error of
+/// either.
+pub fn warmup<T, E>(
+    nr_runs: usize,
+    mut run: impl FnMut() -> std::result::Result<T, E>,
+    mut before_run: impl FnMut(usize) -> std::result::Result<(), E>,
+) -> std::result::Result<Duration, E> {
+    let start = Instant::now();
+    for i in 0..nr_runs {
+        before_run(i)?;
+        run()?;
+    }
+    Ok(start.elapsed())
+}
+
 pub trait Tensor {
     type Data;
     type DataType;
@@ -62,4 +82,34 @@ pub trait Model<'a> {
 
     /// Returns `true` if the model is loaded.
     fn is_loaded(&self) -> bool;
+
+    /// Runs inference on the model `nr_runs` times, discarding the outputs,
+    /// and returns the time it took.
+    fn warmup<T: Tensor + Handle<CType = Self::TensorHandle>>(
+        &mut self,
+        in_tensors: &[T],
+        nr_runs: usize,
+    ) -> Result<Duration> {
+        warmup(nr_runs, || self.run(in_tensors), |_| Ok(()))
+    }
+
+    /// Creates and loads a new model and warms it up with `nr_runs`
+    /// inferences on `in_tensors`.
+    ///
+    /// Returns the model and the time the warm-up took.
+    fn load_with_warmup<P, T>(
+        path: P,
+        session: &'a mut Session,
+        in_tensors: &[T],
+        nr_runs: usize,
+    ) -> Result<(Self, Duration)>
+    where
+        P: AsRef<str>,
+        T: Tensor + Handle<CType = Self::TensorHandle>,
+        Self: Sized,
+    {
+        let mut model = Self::load(path, session)?;
+        let elapsed = model.warmup(in_tensors, nr_runs)?;
+        Ok((model, elapsed))
+    }
 }
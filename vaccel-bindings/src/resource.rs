@@ -140,11 +140,11 @@ impl Resource {
                 return Err(Error::EmptyValue);
             }
 
-            let blob_ptrs =
-                unsafe { std::slice::from_raw_parts_mut(inner.blobs as *mut _, inner.nr_blobs) };
+            let blob_ptrs: &[*mut ffi::vaccel_blob] =
+                unsafe { std::slice::from_raw_parts(inner.blobs as *const _, inner.nr_blobs) };
             let blobs = blob_ptrs
-                .iter_mut()
-                .map(|p| unsafe { Blob::from_ptr(p) })
+                .iter()
+                .map(|&p| unsafe { Blob::from_ptr(p) })
                 .collect::<Result<Vec<Blob>>>()?;
 
             self.blobs = Some(blobs);
@@ -2,7 +2,18 @@
 
 use crate::{ffi, Config, Error, Handle, Result};
 use derive_more::Display;
-use std::num::NonZeroI64;
+use libc::{c_int, c_void};
+use std::{
+    ffi::{CStr, OsStr},
+    fs,
+    num::NonZeroI64,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The version of the vAccel library the bindings were built against.
+pub const VERSION: &str = env!("VACCEL_VERSION");
 
 /// Wrapper for the `vaccel_id_t` C object.
 #[derive(Debug, Display, Copy, Clone, Eq, PartialEq, Hash)]
@@ -51,18 +62,30 @@ impl From<VaccelId> for i64 {
     }
 }
 
+/// The plugins of the config vAccel was last bootstrapped with.
+static PLUGINS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
 /// Bootstraps the vAccel library using the provided config.
 pub fn bootstrap_with_config(config: &mut Config) -> Result<()> {
+    let plugins = config.plugins()?;
     match unsafe { ffi::vaccel_bootstrap_with_config(config.as_mut_ptr()) as u32 } {
-        ffi::VACCEL_OK => Ok(()),
+        ffi::VACCEL_OK => {
+            *PLUGINS.lock().unwrap() = plugins;
+            Ok(())
+        }
         err => Err(Error::Ffi(err)),
     }
 }
 
 /// Bootstraps the vAccel library.
 pub fn bootstrap() -> Result<()> {
+    // The config vAccel reads from the environment on bootstrap
+    let plugins = Config::from_env()?.plugins()?;
     match unsafe { ffi::vaccel_bootstrap() as u32 } {
-        ffi::VACCEL_OK => Ok(()),
+        ffi::VACCEL_OK => {
+            *PLUGINS.lock().unwrap() = plugins;
+            Ok(())
+        }
         err => Err(Error::Ffi(err)),
     }
 }
@@ -70,12 +93,66 @@ pub fn bootstrap() -> Result<()> {
 /// Performs cleanup for the vAccel library.
 pub fn cleanup() -> Result<()> {
     match unsafe { ffi::vaccel_cleanup() as u32 } {
-        ffi::VACCEL_OK => Ok(()),
+        ffi::VACCEL_OK => {
+            PLUGINS.lock().unwrap().clear();
+            Ok(())
+        }
         err => Err(Error::Ffi(err)),
     }
 }
 
+/// Returns the plugins vAccel is bootstrapped with, as listed in the
+/// libvaccel config it was bootstrapped with.
+pub fn plugins() -> Vec<String> {
+    PLUGINS.lock().unwrap().clone()
+}
+
 /// Returns `true` if the vAccel library is initialized.
 pub fn is_initialized() -> bool {
     unsafe { ffi::vaccel_is_initialized() }
 }
+
+/// Returns the paths of the shared objects loaded by the process.
+fn loaded_objects() -> Vec<PathBuf> {
+    unsafe extern "C" fn collect(
+        info: *mut libc::dl_phdr_info,
+        _size: usize,
+        data: *mut c_void,
+    ) -> c_int {
+        let objects = &mut *(data as *mut Vec<PathBuf>);
+        let name = (*info).dlpi_name;
+        if !name.is_null() {
+            let name = CStr::from_ptr(name).to_bytes();
+            if !name.is_empty() {
+                objects.push(PathBuf::from(OsStr::from_bytes(name)));
+            }
+        }
+        0
+    }
+
+    let mut objects: Vec<PathBuf> = Vec::new();
+    unsafe { libc::dl_iterate_phdr(Some(collect), &mut objects as *mut _ as *mut c_void) };
+    objects
+}
+
+fn file_name(path: &Path) -> Option<&str> {
+    path.file_name().and_then(|n| n.to_str())
+}
+
+/// Guesses the version of the vAccel library loaded by the process from the
+/// file name its soname resolves to (e.g. `libvaccel.so.0.8.0`).
+///
+/// This is a heuristic, as libvaccel does not report its version at runtime:
+/// it is `None` if the library is linked statically or installed without a
+/// versioned file name, and wrong if the file name does not match the actual
+/// version.
+pub fn guess_loaded_version() -> Option<String> {
+    loaded_objects()
+        .iter()
+        .filter(|p| file_name(p).is_some_and(|n| n.starts_with("libvaccel.so")))
+        .find_map(|p| {
+            let path = fs::canonicalize(p).ok()?;
+            let version = file_name(&path)?.strip_prefix("libvaccel.so.")?;
+            version.contains('.').then(|| version.to_string())
+        })
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent_service::{AgentService, Result, Shared},
+    audit::AuditIds,
+    session::AgentSession,
+};
+use log::info;
+use protobuf::{EnumOrUnknown, MessageFull};
+use std::{
+    os::unix::io::RawFd,
+    sync::TryLockError,
+    time::{Instant, UNIX_EPOCH},
+};
+use vaccel::{Resource, VaccelId};
+use vaccel_rpc_proto::{
+    admin::{
+        Blob as AdminBlob, DestroySessionRequest, ListResourcesResponse, ListSessionsResponse,
+        RemovePersistentRequest, Resource as AdminResource, Session as AdminSession,
+    },
+    empty::Empty,
+};
+
+impl AgentService {
+    /// Serves an admin `rpc` request of the connection with socket `fd`.
+    pub(crate) fn serve_admin<Req, Resp>(
+        &self,
+        fd: RawFd,
+        rpc: &'static str,
+        req: Req,
+        f: impl FnOnce(Req) -> Result<Resp>,
+    ) -> Result<Resp>
+    where
+        Req: MessageFull,
+        Resp: MessageFull,
+    {
+        let start = Instant::now();
+        let received = req.compute_size();
+        let ids = AuditIds::of(&req);
+
+        let ret = f(req);
+        self.audit(fd, None, rpc, ids, received, &ret, start.elapsed());
+
+        ret
+    }
+
+    pub(crate) fn do_list_sessions(&self) -> Result<ListSessionsResponse> {
+        // Clone the entries out first, so that the map is not locked while
+        // the sessions are
+        let entries: Vec<(VaccelId, Shared<AgentSession>)> = self
+            .sessions
+            .iter()
+            .map(|e| (*e.key(), e.value().clone()))
+            .collect();
+
+        let sessions = entries
+            .into_iter()
+            .map(|(id, sess)| {
+                // Do not wait on sessions serving a request, which may take
+                // arbitrarily long
+                let sess = match sess.try_lock() {
+                    Ok(sess) => sess,
+                    Err(TryLockError::WouldBlock) => {
+                        return AdminSession {
+                            id: id.into(),
+                            busy: true,
+                            ..Default::default()
+                        }
+                    }
+                    Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+                };
+                AdminSession {
+                    id: id.into(),
+                    flags: sess.flags(),
+                    created_at: sess
+                        .created_at
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0),
+                    resource_ids: sess.resources.iter().map(|&id| id.into()).collect(),
+                    peer: sess.peer.clone(),
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        let mut resp = ListSessionsResponse::new();
+        resp.sessions = sessions;
+
+        Ok(resp)
+    }
+
+    pub(crate) fn do_list_resources(&self) -> Result<ListResourcesResponse> {
+        let entries: Vec<(VaccelId, Shared<Resource>)> = self
+            .resources
+            .iter()
+            .map(|e| (*e.key(), e.value().clone()))
+            .collect();
+
+        let resources = entries
+            .into_iter()
+            .map(|(id, res)| {
+                let mut res = res.lock().unwrap();
+                let resource_type = EnumOrUnknown::from_i32(u32::from(res.type_()) as i32);
+                let refcount = res.refcount()?;
+                let blobs = res
+                    .blobs()?
+                    .iter()
+                    .map(|blob| {
+                        Ok(AdminBlob {
+                            name: blob.name()?,
+                            size: blob.size() as u64,
+                            ..Default::default()
+                        })
+                    })
+                    .collect::<Result<Vec<AdminBlob>>>()?;
+                let (name, client) = self
+                    .persistent
+                    .get(&id)
+                    .map(|p| (p.name.clone(), p.client.clone().unwrap_or_default()))
+                    .unwrap_or_default();
+
+                Ok(AdminResource {
+                    id: id.into(),
+                    resource_type,
+                    refcount,
+                    blobs,
+                    name,
+                    client,
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<Vec<AdminResource>>>()?;
+
+        let mut resp = ListResourcesResponse::new();
+        resp.resources = resources;
+
+        Ok(resp)
+    }
+
+    pub(crate) fn do_force_destroy_session(&self, req: DestroySessionRequest) -> Result<Empty> {
+        self.destroy_session(req.session_id.try_into()?)?;
+
+        info!("Force destroyed session {}", req.session_id);
+        Ok(Empty::new())
+    }
+
+    pub(crate) fn do_remove_persistent_resource(
+        &self,
+        req: RemovePersistentRequest,
+    ) -> Result<Empty> {
+        let client = Some(req.client).filter(|c| !c.is_empty());
+        self.remove_persistent(&client, &req.name)?;
+
+        Ok(Empty::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bootstrap, request_context};
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+    use vaccel_rpc_proto::session::CreateRequest;
+
+    #[test]
+    fn busy_sessions_are_listed_without_waiting() {
+        bootstrap();
+        let service = AgentService::new();
+        let (client, _server) = UnixStream::pair().unwrap();
+        let ctx = request_context(client.as_raw_fd());
+
+        let idle = service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap()
+            .session_id;
+        let busy = service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap()
+            .session_id;
+
+        let sess = service
+            .sessions
+            .get(&VaccelId::try_from(busy).unwrap())
+            .unwrap()
+            .clone();
+        let _guard = sess.lock().unwrap();
+
+        let sessions = service.do_list_sessions().unwrap().sessions;
+        let listed = |id| sessions.iter().find(|s| s.id == id).unwrap();
+        assert!(!listed(idle).busy);
+        assert!(listed(busy).busy);
+    }
+}
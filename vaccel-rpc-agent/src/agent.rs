@@ -1,9 +1,24 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{AgentService, Error, Result};
+use crate::{
+    agent_service::AgentServiceError,
+    audit::AuditLog,
+    capture::Capture,
+    config::{AuditConfig, CacheConfig, CaptureConfig, ModelConfig, StorageConfig, TlsConfig},
+    connection::ConnectionReaper,
+    metrics::MetricsServer,
+    storage::Storage,
+    tls::{self, SocketDir, TlsListener},
+    AgentService, Config, Error, Limits, Result,
+};
+use log::{debug, info, warn};
+#[cfg(not(feature = "async"))]
+use std::thread;
 use std::{
+    collections::HashMap,
     net::ToSocketAddrs,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 #[cfg(feature = "async")]
 use ttrpc::asynchronous::Server;
@@ -11,35 +26,105 @@ use ttrpc::asynchronous::Server;
 use ttrpc::sync::Server;
 use vaccel::Config as VaccelConfig;
 #[cfg(feature = "async")]
-use vaccel_rpc_proto::asynchronous::agent_ttrpc::create_agent_service;
+use vaccel_rpc_proto::asynchronous::{
+    admin_service_ttrpc::create_admin_service, agent_ttrpc::create_agent_service,
+};
 #[cfg(not(feature = "async"))]
-use vaccel_rpc_proto::sync::agent_ttrpc::create_agent_service;
+use vaccel_rpc_proto::sync::{
+    admin_service_ttrpc::create_admin_service, agent_ttrpc::create_agent_service,
+};
 
 #[derive(Default)]
 pub struct Agent {
-    pub server_address: String,
+    pub server_addresses: Vec<String>,
+    pub admin_address: Option<String>,
+    pub metrics_address: Option<String>,
     vaccel_config: Arc<Mutex<Option<VaccelConfig>>>,
-    server: Option<Server>,
+    servers: Vec<Server>,
+    admin_servers: Vec<Server>,
+    service: Arc<AgentService>,
+    reaper: Option<ConnectionReaper>,
+    metrics_server: Option<MetricsServer>,
+    tls: TlsConfig,
+    tls_listeners: Vec<TlsListener>,
+    socket_dir: Option<SocketDir>,
+    models: Vec<ModelConfig>,
 }
 
 impl Agent {
+    const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
     pub fn new(server_address: &str) -> Self {
+        Self::with_addresses(&[server_address])
+    }
+
+    /// Creates a new `Agent` listening on all the given addresses.
+    pub fn with_addresses(server_addresses: &[&str]) -> Self {
         Self {
-            server_address: server_address.to_string(),
+            server_addresses: server_addresses.iter().map(|a| a.to_string()).collect(),
             ..Default::default()
         }
     }
 
+    /// Returns the first server address.
+    #[deprecated(note = "use the `server_addresses` field")]
+    pub fn server_address(&self) -> &str {
+        self.server_addresses
+            .first()
+            .map(|a| a.as_str())
+            .unwrap_or_default()
+    }
+
+    /// Creates a new `Agent` from a `Config`.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut agent = Self::with_addresses(&config.server_addresses());
+        if let Some(admin_address) = &config.server.admin_address {
+            agent.set_admin_address(admin_address)?;
+        }
+        agent.metrics_address = config.metrics.address.clone();
+        agent.set_limits(config.limits.clone());
+        agent.set_auth_tokens(config.auth.tokens.clone());
+        agent.set_audit_config(&config.audit)?;
+        agent.set_capture_config(&config.capture)?;
+        agent.set_cache_config(&config.cache);
+        agent.set_storage_config(&config.storage)?;
+        if let Some(vaccel_config) = &config.vaccel {
+            agent.set_vaccel_config(vaccel_config.clone().try_into()?)?;
+        }
+        agent.tls = config.tls.clone();
+        agent.models = config.models.clone();
+
+        Ok(agent)
+    }
+
     pub fn set_server_address(&mut self, address: &str) -> Result<&mut Self> {
-        if self.server.is_some() {
+        if self.is_running() {
+            return Err(Error::AlreadyRunning);
+        }
+        self.server_addresses = vec![address.to_string()];
+        Ok(self)
+    }
+
+    /// Adds an address for the agent to listen on.
+    pub fn add_server_address(&mut self, address: &str) -> Result<&mut Self> {
+        if self.is_running() {
+            return Err(Error::AlreadyRunning);
+        }
+        self.server_addresses.push(address.to_string());
+        Ok(self)
+    }
+
+    pub fn set_admin_address(&mut self, address: &str) -> Result<&mut Self> {
+        if self.is_running() {
             return Err(Error::AlreadyRunning);
         }
-        self.server_address = address.to_string();
+        check_admin_address(address)?;
+        self.admin_address = Some(address.to_string());
         Ok(self)
     }
 
     pub fn set_vaccel_config(&self, config: VaccelConfig) -> Result<&Self> {
-        if self.server.is_some() {
+        if self.is_running() {
             return Err(Error::AlreadyRunning);
         }
         let config_ref = self.vaccel_config.clone();
@@ -48,96 +133,393 @@ impl Agent {
         Ok(self)
     }
 
+    pub fn set_tls_config(&mut self, tls: TlsConfig) -> Result<&mut Self> {
+        if self.is_running() {
+            return Err(Error::AlreadyRunning);
+        }
+        self.tls = tls;
+        Ok(self)
+    }
+
+    /// Sets the client tokens accepted by the agent, keyed by name.
+    pub fn set_auth_tokens(&self, tokens: HashMap<String, String>) -> &Self {
+        self.service.set_tokens(tokens);
+        self
+    }
+
+    /// Opens the audit log described by `audit`, closing any previous one.
+    pub fn set_audit_config(&self, audit: &AuditConfig) -> Result<&Self> {
+        self.service.set_audit_log(open_audit_log(audit)?);
+        Ok(self)
+    }
+
+    /// Opens the capture file described by `capture`, closing any previous
+    /// one.
+    pub fn set_capture_config(&self, capture: &CaptureConfig) -> Result<&Self> {
+        self.service.set_capture(open_capture(capture)?);
+        Ok(self)
+    }
+
+    /// Sets the limits of the blob cache.
+    pub fn set_cache_config(&self, cache: &CacheConfig) -> &Self {
+        self.service.set_cache_config(cache);
+        self
+    }
+
+    /// Opens the storage directory described by `storage`, removing any blob
+    /// files left over by previous runs.
+    pub fn set_storage_config(&self, storage: &StorageConfig) -> Result<&Self> {
+        if self.is_running() {
+            return Err(Error::AlreadyRunning);
+        }
+        let storage = Storage::open(storage).map_err(|e| {
+            Error::Config(format!(
+                "Could not open storage directory {}: {}",
+                storage.dir.as_ref().unwrap().display(),
+                e
+            ))
+        })?;
+        self.service.set_storage(storage);
+        Ok(self)
+    }
+
+    pub fn set_limits(&self, limits: Limits) -> &Self {
+        self.service.set_limits(limits);
+        self
+    }
+
+    /// Applies a reloaded `Config` to the running agent.
+    ///
+    /// The audit log, the capture file and the vAccel config are opened and
+    /// converted first, so that a config failing to do so is not applied at
+    /// all. Limits, auth tokens, the audit log, the capture file and the
+    /// cache limits are then updated, and vAccel is re-bootstrapped with the
+    /// new vAccel config and preloaded models. If sessions are active, the
+    /// re-bootstrap is deferred to a later reload, as told by the returned
+    /// `Reloaded`. Listen addresses and TLS settings and the storage
+    /// directory are not reloaded.
+    pub fn reload(&mut self, config: &Config) -> Result<Reloaded> {
+        let audit_log = open_audit_log(&config.audit)?;
+        let capture = open_capture(&config.capture)?;
+        let mut vaccel_config: Option<VaccelConfig> = match &config.vaccel {
+            Some(c) => Some(c.clone().try_into()?),
+            None => None,
+        };
+
+        self.set_limits(config.limits.clone());
+        self.set_auth_tokens(config.auth.tokens.clone());
+        self.service.set_audit_log(audit_log);
+        self.service.set_capture(capture);
+        self.set_cache_config(&config.cache);
+
+        let reloaded = self
+            .service
+            .reload_vaccel(vaccel_config.as_mut(), &config.models);
+        // Kept even if vAccel was not re-bootstrapped, as they are the
+        // config of the agent from now on
+        *self.vaccel_config.lock().unwrap() = vaccel_config;
+        self.models = config.models.clone();
+
+        match reloaded {
+            Ok(()) => Ok(Reloaded::All),
+            Err(AgentServiceError::Unavailable(reason)) => Ok(Reloaded::VaccelDeferred(reason)),
+            Err(e) => Err(Error::Other(format!(
+                "Applied the config except for vAccel, which could not be reloaded: {}",
+                e
+            ))),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        !self.servers.is_empty()
+    }
+
     #[cfg(not(feature = "async"))]
     pub fn start(&mut self) -> Result<()> {
-        if self.server.is_none() {
+        if !self.is_running() {
             self.vaccel_init()?;
+            self.models_init()?;
+            self.storage_init()?;
             self.server_init()?;
         }
 
-        self.server.as_mut().unwrap().start().map_err(|e| e.into())
+        for server in self.servers.iter_mut().chain(self.admin_servers.iter_mut()) {
+            server.start()?;
+        }
+        self.reaper_init();
+        self.metrics_init()?;
+
+        Ok(())
     }
 
     #[cfg(feature = "async")]
     pub async fn start(&mut self) -> Result<()> {
-        if self.server.is_none() {
+        if !self.is_running() {
             self.vaccel_init()?;
+            self.models_init()?;
+            self.storage_init()?;
             self.server_init()?;
         }
 
-        self.server
-            .as_mut()
-            .unwrap()
-            .start()
-            .await
-            .map_err(|e| e.into())
+        for server in self.servers.iter_mut().chain(self.admin_servers.iter_mut()) {
+            server.start().await?;
+        }
+        self.reaper_init();
+        self.metrics_init()?;
+
+        Ok(())
     }
 
     #[cfg(not(feature = "async"))]
     pub fn stop(&mut self) -> Result<()> {
-        match self.server.take() {
-            Some(server) => {
-                let server = server.stop_listen();
-                self.server = Some(server);
-                Ok(())
-            }
-            None => Err(Error::NotRunning),
+        if !self.is_running() {
+            return Err(Error::NotRunning);
         }
+
+        self.servers = self.servers.drain(..).map(|s| s.stop_listen()).collect();
+        self.admin_servers = self
+            .admin_servers
+            .drain(..)
+            .map(|s| s.stop_listen())
+            .collect();
+        Ok(())
     }
 
     #[cfg(feature = "async")]
     pub async fn stop(&mut self) -> Result<()> {
-        match self.server.as_mut() {
-            Some(server) => {
-                server.stop_listen().await;
-                Ok(())
-            }
-            None => Err(Error::NotRunning),
+        if !self.is_running() {
+            return Err(Error::NotRunning);
         }
+
+        for server in self.servers.iter_mut().chain(self.admin_servers.iter_mut()) {
+            server.stop_listen().await;
+        }
+        Ok(())
     }
 
     #[cfg(not(feature = "async"))]
     pub fn shutdown(&mut self) -> Result<()> {
-        match self.server.take() {
-            Some(server) => {
-                server.shutdown();
-                Ok(())
-            }
-            None => Err(Error::NotRunning),
+        if let Some(reaper) = self.reaper.take() {
+            reaper.stop();
+        }
+
+        if let Some(metrics_server) = self.metrics_server.take() {
+            metrics_server.stop();
+        }
+
+        if !self.is_running() {
+            return Err(Error::NotRunning);
+        }
+
+        for server in self.servers.drain(..).chain(self.admin_servers.drain(..)) {
+            server.shutdown();
         }
+        self.tls_shutdown();
+        Ok(())
     }
 
     #[cfg(feature = "async")]
     pub async fn shutdown(&mut self) -> Result<()> {
-        match self.server.take() {
-            Some(mut server) => server.shutdown().await.map_err(|e| e.into()),
-            None => Err(Error::NotRunning),
+        if let Some(reaper) = self.reaper.take() {
+            reaper.stop();
         }
+
+        if let Some(metrics_server) = self.metrics_server.take() {
+            metrics_server.stop();
+        }
+
+        if !self.is_running() {
+            return Err(Error::NotRunning);
+        }
+
+        let servers: Vec<Server> = self.servers.drain(..).collect();
+        let admin_servers: Vec<Server> = self.admin_servers.drain(..).collect();
+        for mut server in servers.into_iter().chain(admin_servers) {
+            server.shutdown().await?;
+        }
+        self.tls_shutdown();
+        Ok(())
+    }
+
+    /// Shuts down the agent gracefully.
+    ///
+    /// The agent stops accepting connections and requests, waits up to
+    /// `timeout` for in-flight requests to complete and releases all sessions
+    /// and resources before shutting down.
+    #[cfg(not(feature = "async"))]
+    pub fn drain(&mut self, timeout: Duration) -> Result<()> {
+        self.stop()?;
+        self.service.begin_drain();
+
+        let deadline = Instant::now() + timeout;
+        while self.service.in_flight() > 0 && Instant::now() < deadline {
+            thread::sleep(Self::DRAIN_POLL_INTERVAL);
+        }
+        self.release_all();
+
+        self.shutdown()
+    }
+
+    /// Shuts down the agent gracefully.
+    ///
+    /// The agent stops accepting connections and requests, waits up to
+    /// `timeout` for in-flight requests to complete and releases all sessions
+    /// and resources before shutting down.
+    #[cfg(feature = "async")]
+    pub async fn drain(&mut self, timeout: Duration) -> Result<()> {
+        self.stop().await?;
+        self.service.begin_drain();
+
+        let deadline = Instant::now() + timeout;
+        while self.service.in_flight() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Self::DRAIN_POLL_INTERVAL).await;
+        }
+        self.release_all();
+
+        self.shutdown().await
+    }
+
+    fn release_all(&self) {
+        let in_flight = self.service.in_flight();
+        if in_flight > 0 {
+            warn!(
+                "Drain timeout expired with {} request(s) in flight",
+                in_flight
+            );
+        }
+        info!("Releasing all sessions and resources");
+        self.service.release_all();
     }
 
     fn server_init(&mut self) -> Result<()> {
-        if self.server.is_some() {
+        if self.is_running() {
             return Err(Error::AlreadyRunning);
         }
 
-        if self.server_address.is_empty() {
+        if self.server_addresses.is_empty() {
             return Err(Error::InvalidArgument(
-                "Server address cannot be empty".to_string(),
+                "No server address provided".to_string(),
             ));
         }
 
-        let aservice = create_agent_service(Arc::new(AgentService::new()));
-        let resolved_uri = resolve_uri(&self.server_address)?;
+        match self.bind_all() {
+            Ok((servers, admin_servers)) => {
+                self.servers = servers;
+                self.admin_servers = admin_servers;
+                Ok(())
+            }
+            Err(e) => {
+                self.tls_shutdown();
+                Err(e)
+            }
+        }
+    }
+
+    fn bind_all(&mut self) -> Result<(Vec<Server>, Vec<Server>)> {
+        // A ttrpc server can only bind a single address so create one per
+        // resolved address, all sharing the same service
+        let service = self.service.clone();
+        let mut servers = Vec::new();
+        for address in self.server_addresses.clone() {
+            if address.is_empty() {
+                return Err(Error::InvalidArgument(
+                    "Server address cannot be empty".to_string(),
+                ));
+            }
+            servers.extend(self.bind(&address, |s| {
+                s.register_service(create_agent_service(service.clone()))
+            })?);
+        }
+
+        let mut admin_servers = Vec::new();
+        if let Some(admin_address) = self.admin_address.clone() {
+            check_admin_address(&admin_address)?;
+            admin_servers = self.bind(&admin_address, |s| {
+                s.register_service(create_admin_service(service.clone()))
+            })?;
+        }
+
+        Ok((servers, admin_servers))
+    }
+
+    /// Creates a server for each address `uri` resolves to, registering the
+    /// services with `register`.
+    fn bind<F>(&mut self, uri: &str, register: F) -> Result<Vec<Server>>
+    where
+        F: Fn(Server) -> Server,
+    {
+        let mut servers = Vec::new();
+        for resolved_uri in resolve_uri(uri)? {
+            let bind_uri = match resolved_uri.strip_prefix("tls://") {
+                Some(address) => self.tls_listen(address)?,
+                None => resolved_uri,
+            };
+            debug!("Binding {}", bind_uri);
+            servers.push(register(Server::new().bind(&bind_uri)?));
+        }
+        Ok(servers)
+    }
+
+    /// Starts a TLS listener on `address`, returning the uri of the unix
+    /// socket it forwards connections to.
+    fn tls_listen(&mut self, address: &str) -> Result<String> {
+        let config = tls::server_config(&self.tls)?;
+        let address = address
+            .parse()
+            .map_err(|_| Error::InvalidArgument(format!("Invalid TLS address {}", address)))?;
 
-        let server: Server = Server::new()
-            .bind(&resolved_uri)?
-            .register_service(aservice);
+        if self.socket_dir.is_none() {
+            self.socket_dir = Some(SocketDir::new()?);
+        }
+        let socket = self
+            .socket_dir
+            .as_ref()
+            .unwrap()
+            .socket(self.tls_listeners.len());
 
-        self.server = Some(server);
+        let listener = TlsListener::start(address, socket.clone(), config)?;
+        self.tls_listeners.push(listener);
 
+        Ok(format!("unix://{}", socket.display()))
+    }
+
+    fn tls_shutdown(&mut self) {
+        for listener in self.tls_listeners.drain(..) {
+            listener.stop();
+        }
+        self.socket_dir = None;
+    }
+
+    fn reaper_init(&mut self) {
+        if self.reaper.is_none() {
+            self.reaper = Some(ConnectionReaper::start(self.service.clone()));
+        }
+    }
+
+    fn metrics_init(&mut self) -> Result<()> {
+        if self.metrics_server.is_none() {
+            if let Some(address) = &self.metrics_address {
+                self.metrics_server = Some(MetricsServer::start(address, self.service.clone())?);
+            }
+        }
         Ok(())
     }
 
+    fn models_init(&mut self) -> Result<()> {
+        for model in &self.models {
+            self.service.preload_model(model).map_err(|e| {
+                Error::Other(format!("Could not preload model `{}`: {}", model.name, e))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn storage_init(&mut self) -> Result<()> {
+        self.service
+            .load_persistent()
+            .map_err(|e| Error::Other(format!("Could not load persistent resources: {}", e)))
+    }
+
     fn vaccel_init(&mut self) -> Result<()> {
         let mut vaccel_config = self.vaccel_config.lock().unwrap();
         match vaccel_config.as_mut() {
@@ -154,7 +536,51 @@ impl Agent {
     }
 }
 
-fn resolve_uri(uri: &str) -> Result<String> {
+/// Resolves a server address uri to all the addresses it corresponds to.
+///
+/// For TCP and TLS this is every IPv4 and IPv6 address the host resolves to.
+/// What a config reload applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reloaded {
+    /// The whole config was applied
+    All,
+    /// All but the vAccel config and the preloaded models were applied, as
+    /// vAccel could not be re-bootstrapped for the given reason
+    VaccelDeferred(String),
+}
+
+fn open_audit_log(audit: &AuditConfig) -> Result<Option<AuditLog>> {
+    AuditLog::open(audit).map_err(|e| {
+        Error::Config(format!(
+            "Could not open audit log {}: {}",
+            audit.path.as_ref().unwrap().display(),
+            e
+        ))
+    })
+}
+
+fn open_capture(capture: &CaptureConfig) -> Result<Option<Capture>> {
+    Capture::open(capture).map_err(|e| {
+        Error::Config(format!(
+            "Could not open capture file {}: {}",
+            capture.path.as_ref().unwrap().display(),
+            e
+        ))
+    })
+}
+
+/// Checks that the admin server address is local to the host, as the admin
+/// service is not authenticated.
+pub(crate) fn check_admin_address(uri: &str) -> Result<()> {
+    match uri.split_once("://") {
+        Some((scheme, _)) if ["unix", "vsock"].contains(&scheme.to_lowercase().as_str()) => Ok(()),
+        _ => Err(Error::InvalidArgument(
+            "The admin server address must be a unix or vsock address".into(),
+        )),
+    }
+}
+
+pub(crate) fn resolve_uri(uri: &str) -> Result<Vec<String>> {
     let parts: Vec<&str> = uri.split("://").collect();
     if parts.len() != 2 {
         return Err(Error::InvalidArgument("Invalid server address uri".into()));
@@ -162,17 +588,68 @@ fn resolve_uri(uri: &str) -> Result<String> {
 
     let scheme = parts[0].to_lowercase();
     match scheme.as_str() {
-        "vsock" | "unix" => Ok(uri.to_string()),
-        "tcp" => {
+        "vsock" | "unix" => Ok(vec![uri.to_string()]),
+        "tcp" | "tls" => {
             let address = parts[1].to_lowercase();
-            let mut resolved = address.to_socket_addrs()?;
-            let resolved_address = match resolved.next() {
-                Some(a) => a.to_string(),
-                None => return Err(Error::Other("Could not resolve TCP server address".into())),
-            };
+            let mut resolved = Vec::new();
+            for a in address.to_socket_addrs()? {
+                let a = format!("{}://{}", scheme, a);
+                if !resolved.contains(&a) {
+                    resolved.push(a);
+                }
+            }
+            if resolved.is_empty() {
+                return Err(Error::Other("Could not resolve TCP server address".into()));
+            }
 
-            Ok(format!("{}://{}", scheme, &resolved_address))
+            Ok(resolved)
         }
         _ => Err(Error::Unsupported("Unsupported protocol".into())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bootstrap, request_context};
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+    use vaccel_rpc_proto::session::CreateRequest;
+
+    #[test]
+    fn reload_applies_nothing_of_an_invalid_config() {
+        let mut agent = Agent::default();
+        let mut config = Config::default();
+        config.limits.max_sessions = Some(1);
+        config.audit.path = Some("/dev/null/audit.log".into());
+
+        agent.reload(&config).unwrap_err();
+        assert_eq!(agent.service.limits.read().unwrap().max_sessions, None);
+    }
+
+    #[test]
+    fn reload_defers_vaccel_while_sessions_are_active() {
+        bootstrap();
+
+        let mut agent = Agent::default();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+        agent
+            .service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap();
+
+        let mut config = Config::default();
+        config.limits.max_sessions = Some(4);
+        config.models = vec![ModelConfig {
+            name: "resnet".to_string(),
+            paths: vec!["/models/resnet.pt".to_string()],
+            batching: None,
+        }];
+
+        let reloaded = agent.reload(&config).unwrap();
+        assert!(matches!(reloaded, Reloaded::VaccelDeferred(_)));
+        assert_eq!(agent.service.limits.read().unwrap().max_sessions, Some(4));
+        assert_eq!(agent.models.len(), 1);
+        assert!(agent.service.preloaded.is_empty());
+    }
+}
@@ -1,16 +1,43 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::{
+    audit::{AuditIds, AuditLog},
+    batch::Batcher,
+    cache::{ResourceCache, Scope},
+    capture::Capture,
+    config::BatchingConfig,
+    connection::{Connection, ConnectionId},
+    limits::Limits,
+    metrics::Metrics,
+    migration::{instance_id, ImportKey, ImportedSession},
+    resource::SyncState,
+    session::AgentSession,
+    storage::{Persistent, Storage},
+    upload::Upload,
+};
 use dashmap::DashMap;
-use protobuf::Message;
-use std::sync::Arc;
+use log::{info, warn};
+use protobuf::{Message, MessageFull};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Deref,
+    os::unix::io::RawFd,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
 use thiserror::Error as ThisError;
-use vaccel::{self, profiling::ProfilerManager, Resource, Session, VaccelId};
+use vaccel::{self, profiling::ProfilerManager, Resource, VaccelId};
 use vaccel_rpc_proto::{
     profiling::{Request, Response},
-    vaccel::Error as ProtoError,
+    tflite, torch,
+    vaccel::{Error as ProtoError, Status as ProtoStatus},
 };
 
-#[derive(ThisError, Debug)]
+#[derive(ThisError, Debug, Clone)]
 pub enum AgentServiceError {
     #[error("Invalid argument: {0}")]
     InvalidArgument(String),
@@ -18,28 +45,64 @@ pub enum AgentServiceError {
     #[error("Object not found: {0}")]
     NotFound(String),
 
+    #[error("Object already exists: {0}")]
+    AlreadyExists(String),
+
+    #[error("Failed precondition: {0}")]
+    FailedPrecondition(String),
+
     #[error("Internal error: {0}")]
     Internal(String),
 
+    #[error("Resource exhausted: {0}")]
+    ResourceExhausted(String),
+
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("Deadline exceeded: {0}")]
+    DeadlineExceeded(String),
+
     #[error("Vaccel error: {0}")]
     Vaccel(#[from] vaccel::Error),
 }
 
+impl AgentServiceError {
+    /// Returns the ttrpc status code of the error.
+    pub(crate) fn code(&self) -> ttrpc::Code {
+        match self {
+            AgentServiceError::InvalidArgument(_) => ttrpc::Code::INVALID_ARGUMENT,
+            AgentServiceError::NotFound(_) => ttrpc::Code::NOT_FOUND,
+            AgentServiceError::AlreadyExists(_) => ttrpc::Code::ALREADY_EXISTS,
+            AgentServiceError::FailedPrecondition(_) => ttrpc::Code::FAILED_PRECONDITION,
+            AgentServiceError::Internal(_) => ttrpc::Code::INTERNAL,
+            AgentServiceError::ResourceExhausted(_) => ttrpc::Code::RESOURCE_EXHAUSTED,
+            AgentServiceError::Unauthenticated(_) => ttrpc::Code::UNAUTHENTICATED,
+            AgentServiceError::Unavailable(_) => ttrpc::Code::UNAVAILABLE,
+            AgentServiceError::DeadlineExceeded(_) => ttrpc::Code::DEADLINE_EXCEEDED,
+            AgentServiceError::Vaccel(_) => ttrpc::Code::INTERNAL,
+        }
+    }
+}
+
 impl From<AgentServiceError> for ttrpc::Error {
     fn from(e: AgentServiceError) -> Self {
+        let code = e.code();
         match e {
-            AgentServiceError::InvalidArgument(s) => {
-                ttrpc::error::get_rpc_status(ttrpc::Code::INVALID_ARGUMENT, s.clone())
-            }
-            AgentServiceError::NotFound(s) => {
-                ttrpc::error::get_rpc_status(ttrpc::Code::NOT_FOUND, s.clone())
-            }
-            AgentServiceError::Internal(s) => {
-                ttrpc::error::get_rpc_status(ttrpc::Code::INTERNAL, s.clone())
-            }
+            AgentServiceError::InvalidArgument(s)
+            | AgentServiceError::NotFound(s)
+            | AgentServiceError::AlreadyExists(s)
+            | AgentServiceError::FailedPrecondition(s)
+            | AgentServiceError::Internal(s)
+            | AgentServiceError::ResourceExhausted(s)
+            | AgentServiceError::Unauthenticated(s)
+            | AgentServiceError::Unavailable(s)
+            | AgentServiceError::DeadlineExceeded(s) => ttrpc::error::get_rpc_status(code, s),
             AgentServiceError::Vaccel(e) => {
-                let mut ttrpc_status =
-                    ttrpc::error::get_status(ttrpc::Code::INTERNAL, e.to_string());
+                let mut ttrpc_status = ttrpc::error::get_status(code, e.to_string());
                 let proto_error = ProtoError::from(e);
 
                 let details = proto_error.write_to_bytes().unwrap();
@@ -67,36 +130,303 @@ impl<T> IntoTtrpcResult<T> for Result<T> {
     }
 }
 
+/// The parts of a sync or async ttrpc context needed to serve a request.
+#[derive(Debug)]
+pub(crate) struct RequestMeta<'a> {
+    pub(crate) fd: RawFd,
+    pub(crate) metadata: &'a HashMap<String, Vec<String>>,
+    pub(crate) timeout_nano: i64,
+}
+
+/// What is known of a request being served, recorded along with its outcome
+/// by `AgentService::record()`.
+#[derive(Debug, Default)]
+pub(crate) struct Served {
+    /// The token name of the client, once authenticated
+    pub(crate) client: Option<String>,
+    /// The bytes received for the request
+    pub(crate) received: u64,
+    ids: AuditIds,
+    /// The captured request, with the rpc it is replayed as
+    captured: Option<(&'static str, Vec<u8>)>,
+}
+
+/// Per-request information extracted from the ttrpc context.
 #[derive(Clone, Debug)]
-pub struct AgentService {
-    pub(crate) sessions: Arc<DashMap<VaccelId, Box<Session>>>,
-    pub(crate) resources: Arc<DashMap<VaccelId, Box<Resource>>>,
+pub(crate) struct RequestContext {
+    pub(crate) connection: ConnectionId,
+    /// The name of the token the request was authenticated with
+    pub(crate) client: Option<String>,
+    /// The time after which the client no longer waits for a response
+    pub(crate) deadline: Option<Instant>,
+}
+
+impl RequestContext {
+    pub(crate) fn new(meta: &RequestMeta, client: Option<String>) -> Result<Self> {
+        let connection = ConnectionId::from_fd(meta.fd).map_err(|e| {
+            AgentServiceError::Internal(format!("Could not identify connection: {}", e))
+        })?;
+        let deadline = (meta.timeout_nano > 0)
+            .then(|| Instant::now() + Duration::from_nanos(meta.timeout_nano as u64));
+
+        Ok(RequestContext {
+            connection,
+            client,
+            deadline,
+        })
+    }
+
+    /// Fails if the deadline of the request has passed.
+    pub(crate) fn check_deadline(&self) -> Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(
+                AgentServiceError::DeadlineExceeded("Request deadline has passed".to_string()),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An entry of the agent sessions or resources map.
+///
+/// Entries are cloned out of their map before being locked, so that an
+/// operation only blocks the entries it uses and never holds a map lock while
+/// waiting on an entry. When both are needed, the session is locked before the
+/// resource.
+pub(crate) type Shared<T> = Arc<Mutex<T>>;
+
+/// Returns `true` if `entry` is still the entry of `id` in `map`, i.e. it was
+/// not removed while waiting for its lock.
+pub(crate) fn is_current<T>(
+    map: &DashMap<VaccelId, Shared<T>>,
+    id: &VaccelId,
+    entry: &Shared<T>,
+) -> bool {
+    map.get(id).is_some_and(|e| Arc::ptr_eq(e.value(), entry))
+}
+
+/// The state of the agent, shared by the handlers of all connections.
+#[derive(Debug)]
+pub struct Inner {
+    pub(crate) sessions: DashMap<VaccelId, Shared<AgentSession>>,
+    pub(crate) resources: DashMap<VaccelId, Shared<Resource>>,
+    pub(crate) connections: DashMap<ConnectionId, Connection>,
+    pub(crate) limits: RwLock<Limits>,
+    pub(crate) preloaded: DashMap<VaccelId, String>,
+    pub(crate) metrics: Metrics,
+    pub(crate) tokens: RwLock<HashMap<String, String>>,
+    pub(crate) draining: AtomicBool,
+    pub(crate) audit_log: RwLock<Option<AuditLog>>,
+    pub(crate) capture: RwLock<Option<Capture>>,
+    pub(crate) vaccel_lock: RwLock<()>,
+    pub(crate) cache: Mutex<ResourceCache>,
+    pub(crate) storage: RwLock<Option<Storage>>,
+    pub(crate) persistent: DashMap<VaccelId, Persistent>,
+    /// The clients of the resources created by registering them, which only
+    /// they can use. Persistent resources belong to the client of their
+    /// entry, while preloaded ones are shared by all clients.
+    pub(crate) resource_clients: DashMap<VaccelId, Scope>,
+    pub(crate) blob_dirs: DashMap<VaccelId, (PathBuf, u64)>,
+    pub(crate) uploads: DashMap<i64, (ConnectionId, Upload)>,
+    pub(crate) next_upload_id: AtomicI64,
+    pub(crate) sync_state: DashMap<VaccelId, SyncState>,
+    pub(crate) next_generation: AtomicU64,
+    /// Bumped after every operation, as operations may write to the blobs
+    /// of the resources registered with their session
+    pub(crate) blob_writes: AtomicU64,
+    /// Identifies the agent instance, as the source of the sessions it
+    /// exports
+    pub(crate) instance_id: String,
+    /// Sessions imported from other agents, by the instance id of the
+    /// exporting agent and their old id
+    pub(crate) imported: DashMap<ImportKey, ImportedSession>,
+    /// The batching settings of the preloaded models with batching enabled
+    pub(crate) batching: DashMap<VaccelId, BatchingConfig>,
+    pub(crate) torch_batches: Batcher<torch::Tensor, ()>,
+    pub(crate) tflite_batches: Batcher<tflite::Tensor, ProtoStatus>,
     pub(crate) profiler_manager: ProfilerManager,
 }
 
-unsafe impl Sync for AgentService {}
-unsafe impl Send for AgentService {}
+unsafe impl Sync for Inner {}
+unsafe impl Send for Inner {}
+
+/// The agent service, a cheaply cloneable handle to the agent state.
+#[derive(Clone, Debug)]
+pub struct AgentService(Arc<Inner>);
+
+impl Deref for AgentService {
+    type Target = Inner;
+
+    fn deref(&self) -> &Inner {
+        &self.0
+    }
+}
 
 impl AgentService {
     pub const TIMERS_PREFIX: &'static str = "vaccel-rpc-agent";
 
     pub(crate) fn new() -> Self {
-        AgentService {
-            sessions: Arc::new(DashMap::new()),
-            resources: Arc::new(DashMap::new()),
+        AgentService(Arc::new(Inner {
+            sessions: DashMap::new(),
+            resources: DashMap::new(),
+            connections: DashMap::new(),
+            limits: RwLock::new(Limits::default()),
+            preloaded: DashMap::new(),
+            metrics: Metrics::default(),
+            tokens: RwLock::new(HashMap::new()),
+            draining: AtomicBool::new(false),
+            audit_log: RwLock::new(None),
+            capture: RwLock::new(None),
+            vaccel_lock: RwLock::new(()),
+            cache: Mutex::new(ResourceCache::new()),
+            storage: RwLock::new(None),
+            persistent: DashMap::new(),
+            resource_clients: DashMap::new(),
+            blob_dirs: DashMap::new(),
+            uploads: DashMap::new(),
+            next_upload_id: AtomicI64::new(0),
+            sync_state: DashMap::new(),
+            next_generation: AtomicU64::new(0),
+            blob_writes: AtomicU64::new(0),
+            instance_id: instance_id(),
+            imported: DashMap::new(),
+            batching: DashMap::new(),
+            torch_batches: Batcher::default(),
+            tflite_batches: Batcher::default(),
             profiler_manager: ProfilerManager::new(Self::TIMERS_PREFIX),
+        }))
+    }
+
+    /// Serves an `rpc` request, skipping it if its deadline has already
+    /// passed.
+    pub(crate) fn serve<'a, Req, Resp>(
+        &self,
+        meta: impl Into<RequestMeta<'a>>,
+        rpc: &'static str,
+        req: Req,
+        f: impl FnOnce(&RequestContext, Req) -> Result<Resp>,
+    ) -> Result<Resp>
+    where
+        Req: MessageFull,
+        Resp: MessageFull,
+    {
+        let start = Instant::now();
+        let meta = meta.into();
+        let mut served = Served {
+            received: req.compute_size(),
+            ..Default::default()
+        };
+        self.received(&mut served, rpc, &req);
+
+        let ret = self.request_context(&meta).and_then(|ctx| {
+            served.client = ctx.client.clone();
+            let _in_flight = self.begin_request(&ctx)?;
+            ctx.check_deadline()?;
+            f(&ctx, req)
+        });
+
+        self.record(meta.fd, rpc, served, &ret, start);
+        ret
+    }
+
+    /// Notes the request of `served`, captured as a request of `rpc`.
+    pub(crate) fn received<Req: MessageFull>(
+        &self,
+        served: &mut Served,
+        rpc: &'static str,
+        req: &Req,
+    ) {
+        if self.is_audited() {
+            served.ids = AuditIds::of(req);
+        }
+        served.captured = self.capture_request(req).map(|bytes| (rpc, bytes));
+    }
+
+    /// Records a request served as `rpc` since `start` to the metrics, the
+    /// audit log and the capture file.
+    pub(crate) fn record<Resp: MessageFull>(
+        &self,
+        fd: RawFd,
+        rpc: &'static str,
+        served: Served,
+        ret: &Result<Resp>,
+        start: Instant,
+    ) {
+        let elapsed = start.elapsed();
+        let sent = ret.as_ref().map(|r| r.compute_size()).unwrap_or(0);
+        self.metrics.observe(
+            rpc,
+            elapsed,
+            served.received,
+            sent,
+            ret.as_ref().err().map(|e| e.code()),
+        );
+        self.audit(
+            fd,
+            served.client.as_deref(),
+            rpc,
+            served.ids,
+            served.received,
+            ret,
+            elapsed,
+        );
+        if let Some((rpc, request)) = served.captured {
+            self.capture(fd, rpc, request, ret, elapsed);
         }
     }
 
-    pub(crate) fn do_get_profiler(&self, req: Request) -> Result<Response> {
+    pub(crate) fn do_get_profiler(&self, ctx: &RequestContext, req: Request) -> Result<Response> {
+        let sess_id = req.session_id.try_into()?;
+        // Only the profiler of a session of another client is refused, as
+        // the profiler of an unknown session is empty
+        if self.session(sess_id).is_ok() {
+            self.client_session(ctx, sess_id)?;
+        }
+
         let mut resp = Response::new();
         resp.profiler = self
             .profiler_manager
-            .get(req.session_id.try_into()?)
+            .get(sess_id)
             .map(|p| p.clone().into())
             .into();
         Ok(resp)
     }
+
+    /// Releases the sessions, and any resources left unused by them, of
+    /// client connections that have been closed.
+    pub(crate) fn reap_connections(&self) {
+        let closed: Vec<ConnectionId> = self
+            .connections
+            .iter()
+            .filter(|c| !c.key().is_alive())
+            .map(|c| *c.key())
+            .collect();
+
+        for conn_id in closed {
+            self.uploads.retain(|_, u| u.0 != conn_id);
+            let sessions = match self.connections.remove(&conn_id) {
+                Some((_, conn)) => conn.sessions,
+                None => HashSet::new(),
+            };
+
+            info!(
+                "Connection {} closed; releasing {} session(s)",
+                conn_id,
+                sessions.len()
+            );
+            for sess_id in sessions {
+                if let Err(e) = self.release_session(sess_id) {
+                    warn!("Could not release session {}: {}", sess_id, e);
+                }
+            }
+        }
+    }
+}
+
+impl Default for AgentService {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AsRef<ProfilerManager> for AgentService {
@@ -104,3 +434,296 @@ impl AsRef<ProfilerManager> for AgentService {
         &self.profiler_manager
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::ModelConfig,
+        testing::{
+            bootstrap, create_session, destroy_session, register, register_blob, request_context,
+            torch_run, unregister,
+        },
+    };
+    use std::{
+        os::unix::{io::AsRawFd, net::UnixStream},
+        sync::mpsc::{self, RecvTimeoutError},
+        thread,
+    };
+    use vaccel_rpc_proto::{
+        image,
+        resource::{RegisterRequest, SyncRequest, UnregisterRequest},
+        session::DestroyRequest,
+        tflite,
+    };
+
+    const WORKERS: usize = 8;
+    const ITERATIONS: usize = 200;
+    const STRESS_TIMEOUT: Duration = Duration::from_secs(120);
+
+    /// Runs workers that concurrently register, use and unregister both a
+    /// shared resource and private ones, while recreating their sessions and
+    /// listing the agent state. Another session runs the shared model while
+    /// it is concurrently unregistered from it.
+    fn stress(service: &AgentService, ctx: &RequestContext) {
+        let anchor = create_session(service, ctx);
+        let shared = register(service, ctx, anchor, 0);
+        let racer = create_session(service, ctx);
+
+        thread::scope(|s| {
+            for _ in 0..WORKERS {
+                s.spawn(|| {
+                    let mut sess = create_session(service, ctx);
+                    for i in 0..ITERATIONS {
+                        register(service, ctx, sess, shared);
+                        service
+                            .do_image_classification(
+                                ctx,
+                                image::Request {
+                                    session_id: sess,
+                                    image: vec![0u8; 16],
+                                    ..Default::default()
+                                },
+                            )
+                            .unwrap();
+                        torch_run(service, ctx, sess, shared).unwrap();
+                        unregister(service, ctx, sess, shared);
+
+                        let private = register(service, ctx, sess, 0);
+                        if i % 10 == 0 {
+                            // Leave the resource to be released with the session
+                            destroy_session(service, ctx, sess);
+                            sess = create_session(service, ctx);
+                        } else {
+                            unregister(service, ctx, sess, private);
+                        }
+                    }
+                    destroy_session(service, ctx, sess);
+                });
+            }
+
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    service.do_list_sessions().unwrap();
+                    service.do_list_resources().unwrap();
+                }
+            });
+
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    register(service, ctx, racer, shared);
+                    unregister(service, ctx, racer, shared);
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    // Fails cleanly whenever the model is not registered
+                    match torch_run(service, ctx, racer, shared) {
+                        Ok(_) | Err(AgentServiceError::Vaccel(_)) => (),
+                        Err(e) => panic!("Unexpected run error: {}", e),
+                    }
+                }
+            });
+        });
+
+        destroy_session(service, ctx, racer);
+        destroy_session(service, ctx, anchor);
+    }
+
+    #[test]
+    fn concurrent_sessions_and_resources() {
+        bootstrap();
+
+        let service = Arc::new(AgentService::new());
+        let (client, _server) = UnixStream::pair().unwrap();
+        let fd = client.as_raw_fd();
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let svc = service.clone();
+        thread::spawn(move || {
+            let ctx = request_context(fd);
+            stress(&svc, &ctx);
+            done_tx.send(()).unwrap();
+        });
+
+        match done_rx.recv_timeout(STRESS_TIMEOUT) {
+            Ok(()) => (),
+            Err(RecvTimeoutError::Timeout) => panic!("Stress run deadlocked"),
+            Err(RecvTimeoutError::Disconnected) => panic!("Stress run failed"),
+        }
+
+        assert!(service.sessions.is_empty());
+        assert!(service.resources.is_empty());
+    }
+
+    #[test]
+    fn unrelated_sessions_run_in_parallel() {
+        bootstrap();
+
+        let service = Arc::new(AgentService::new());
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+        let (held, free) = (
+            create_session(&service, &ctx),
+            create_session(&service, &ctx),
+        );
+        let (held_model, free_model) = (
+            register_blob(&service, &ctx, held, 0, Some(vec![1u8; 64])),
+            register_blob(&service, &ctx, free, 0, Some(vec![2u8; 64])),
+        );
+
+        vaccel::ffi::vaccel_stub_hold_runs(held, true);
+        let (svc, held_ctx) = (service.clone(), ctx.clone());
+        let blocked =
+            thread::spawn(move || torch_run(&svc, &held_ctx, held, held_model).map(|_| ()));
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let (svc, free_ctx) = (service.clone(), ctx.clone());
+        thread::spawn(move || {
+            let _ = done_tx.send(torch_run(&svc, &free_ctx, free, free_model).map(|_| ()));
+        });
+        let free_run = done_rx.recv_timeout(Duration::from_secs(10));
+        let held_finished = blocked.is_finished();
+
+        vaccel::ffi::vaccel_stub_hold_runs(held, false);
+        blocked.join().unwrap().unwrap();
+
+        free_run
+            .expect("Run was blocked by another session")
+            .unwrap();
+        assert!(!held_finished);
+
+        destroy_session(&service, &ctx, held);
+        destroy_session(&service, &ctx, free);
+    }
+
+    #[test]
+    fn closed_connection_releases_its_state() {
+        bootstrap();
+
+        let service = AgentService::new();
+        let (client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+
+        let sess = create_session(&service, &ctx);
+        let resource = register(&service, &ctx, sess, 0);
+        service
+            .do_tflite_model_load(
+                &ctx,
+                tflite::ModelLoadRequest {
+                    session_id: sess,
+                    model_id: resource,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        // A live connection is left alone
+        service.reap_connections();
+        assert!(service
+            .sessions
+            .contains_key(&VaccelId::try_from(sess).unwrap()));
+
+        drop(client);
+        service.reap_connections();
+
+        assert!(service.connections.is_empty());
+        assert!(service.sessions.is_empty());
+        assert!(service.resources.is_empty());
+        assert!(vaccel::ffi::vaccel_stub_model_unloaded(sess, resource));
+    }
+
+    #[test]
+    fn preloaded_models_are_registered_by_name() {
+        bootstrap();
+
+        let service = AgentService::new();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+        let model = service
+            .preload_model(&ModelConfig {
+                name: "resnet".to_string(),
+                paths: vec!["/models/resnet.pt".to_string()],
+                batching: None,
+            })
+            .unwrap();
+
+        let sess = create_session(&service, &ctx);
+        let register = |name: &str| {
+            service.do_register_resource(
+                &ctx,
+                RegisterRequest {
+                    name: name.to_string(),
+                    session_id: sess,
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(register("resnet").unwrap().resource_id, i64::from(model));
+        assert_eq!(
+            register("unknown").unwrap_err().code(),
+            ttrpc::Code::NOT_FOUND
+        );
+
+        // Preloaded models outlive the sessions they are registered with
+        destroy_session(&service, &ctx, sess);
+        assert!(service.resources.contains_key(&model));
+    }
+
+    #[test]
+    fn sessions_and_resources_are_private_to_their_client() {
+        bootstrap();
+        let service = AgentService::new();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let mut alice = request_context(server.as_raw_fd());
+        alice.client = Some("alice".to_string());
+        let mut bob = alice.clone();
+        bob.client = Some("bob".to_string());
+
+        let sess = create_session(&service, &alice);
+        let res = register(&service, &alice, sess, 0);
+        let other = create_session(&service, &bob);
+
+        let not_found = |e: AgentServiceError| e.code() == ttrpc::Code::NOT_FOUND;
+        let destroy = DestroyRequest {
+            session_id: sess,
+            ..Default::default()
+        };
+        assert!(not_found(
+            service.do_destroy_session(&bob, destroy).unwrap_err()
+        ));
+        let unregister_req = UnregisterRequest {
+            resource_id: res,
+            session_id: sess,
+            ..Default::default()
+        };
+        assert!(not_found(
+            service
+                .do_unregister_resource(&bob, unregister_req)
+                .unwrap_err()
+        ));
+        let sync = SyncRequest {
+            resource_id: res,
+            ..Default::default()
+        };
+        assert!(not_found(service.do_sync_resource(&bob, sync).unwrap_err()));
+        let register_req = RegisterRequest {
+            resource_id: res,
+            session_id: other,
+            ..Default::default()
+        };
+        assert!(not_found(
+            service
+                .do_register_resource(&bob, register_req)
+                .unwrap_err()
+        ));
+        assert!(not_found(torch_run(&service, &bob, sess, res).unwrap_err()));
+        assert!(not_found(
+            torch_run(&service, &bob, other, res).unwrap_err()
+        ));
+
+        unregister(&service, &alice, sess, res);
+        destroy_session(&service, &alice, sess);
+        destroy_session(&service, &bob, other);
+    }
+}
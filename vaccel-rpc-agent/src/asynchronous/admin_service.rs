@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{agent_service::IntoTtrpcResult, AgentService};
+use async_trait::async_trait;
+use vaccel_rpc_proto::{
+    admin::{
+        DestroySessionRequest, ExportSessionRequest, ExportSessionResponse, ImportSessionRequest,
+        ImportSessionResponse, ListResourcesResponse, ListSessionsResponse,
+        RemovePersistentRequest,
+    },
+    asynchronous::admin_service_ttrpc,
+    empty::Empty,
+};
+
+#[async_trait]
+impl admin_service_ttrpc::AdminService for AgentService {
+    async fn list_sessions(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: Empty,
+    ) -> ttrpc::Result<ListSessionsResponse> {
+        let fd = ctx.fd;
+        self.blocking(move |s| s.serve_admin(fd, "ListSessions", req, |_| s.do_list_sessions()))
+            .await
+            .into_ttrpc()
+    }
+
+    async fn list_resources(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: Empty,
+    ) -> ttrpc::Result<ListResourcesResponse> {
+        let fd = ctx.fd;
+        self.blocking(move |s| s.serve_admin(fd, "ListResources", req, |_| s.do_list_resources()))
+            .await
+            .into_ttrpc()
+    }
+
+    async fn force_destroy_session(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: DestroySessionRequest,
+    ) -> ttrpc::Result<Empty> {
+        let fd = ctx.fd;
+        self.blocking(move |s| {
+            s.serve_admin(fd, "ForceDestroySession", req, |req| {
+                s.do_force_destroy_session(req)
+            })
+        })
+        .await
+        .into_ttrpc()
+    }
+
+    async fn export_session(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: ExportSessionRequest,
+    ) -> ttrpc::Result<ExportSessionResponse> {
+        let fd = ctx.fd;
+        self.blocking(move |s| {
+            s.serve_admin(fd, "ExportSession", req, |req| s.do_export_session(req))
+        })
+        .await
+        .into_ttrpc()
+    }
+
+    async fn import_session(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: ImportSessionRequest,
+    ) -> ttrpc::Result<ImportSessionResponse> {
+        let fd = ctx.fd;
+        self.blocking(move |s| {
+            s.serve_admin(fd, "ImportSession", req, |req| s.do_import_session(fd, req))
+        })
+        .await
+        .into_ttrpc()
+    }
+
+    async fn remove_persistent_resource(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: RemovePersistentRequest,
+    ) -> ttrpc::Result<Empty> {
+        let fd = ctx.fd;
+        self.blocking(move |s| {
+            s.serve_admin(fd, "RemovePersistentResource", req, |req| {
+                s.do_remove_persistent_resource(req)
+            })
+        })
+        .await
+        .into_ttrpc()
+    }
+}
@@ -1,9 +1,15 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{agent_service::IntoTtrpcResult, AgentService};
+use crate::{
+    agent_service::{AgentServiceError, IntoTtrpcResult, RequestMeta, Result, Served},
+    asynchronous::blocking::BlockingContext,
+    upload::Upload,
+    AgentService,
+};
 use async_trait::async_trait;
 use log::debug;
-use std::default::Default;
+use protobuf::Message;
+use std::{default::Default, time::Instant};
 #[allow(unused_imports)]
 use vaccel_rpc_proto::tf::{
     ModelLoadRequest as TFModelLoadRequest, ModelLoadResponse as TFModelLoadResponse,
@@ -15,200 +21,481 @@ use vaccel_rpc_proto::{
     empty::Empty,
     genop::{Arg, Request as GenopRequest, Response as GenopResponse},
     image::{Request as ImageRequest, Response as ImageResponse},
+    info::{Request as InfoRequest, Response as InfoResponse},
     profiling::{Request as ProfilingRequest, Response as ProfilingResponse},
-    resource::{RegisterRequest, RegisterResponse, SyncRequest, SyncResponse, UnregisterRequest},
-    session::{CreateRequest, CreateResponse, DestroyRequest, UpdateRequest},
+    resource::{
+        LookupBlobsRequest, LookupBlobsResponse, RegisterChunk, RegisterChunkResponse,
+        RegisterRequest, RegisterResponse, SyncRequest, SyncResponse, UnregisterRequest,
+    },
+    session::{
+        CreateRequest, CreateResponse, DestroyRequest, ResolveIdsRequest, ResolveIdsResponse,
+        UpdateRequest,
+    },
     tflite::{
-        ModelLoadRequest as TFLiteModelLoadRequest, ModelRunRequest as TFLiteModelRunRequest,
-        ModelRunResponse as TFLiteModelRunResponse, ModelUnloadRequest as TFLiteModelUnloadRequest,
+        ModelLoadRequest as TFLiteModelLoadRequest, ModelLoadResponse as TFLiteModelLoadResponse,
+        ModelRunRequest as TFLiteModelRunRequest, ModelRunResponse as TFLiteModelRunResponse,
+        ModelUnloadRequest as TFLiteModelUnloadRequest,
     },
     torch::{
-        ModelLoadRequest as TorchModelLoadRequest, ModelRunRequest as TorchModelRunRequest,
-        ModelRunResponse as TorchModelRunResponse,
+        ModelLoadRequest as TorchModelLoadRequest, ModelLoadResponse as TorchModelLoadResponse,
+        ModelRunRequest as TorchModelRunRequest, ModelRunResponse as TorchModelRunResponse,
     },
 };
 //use tracing::{info, instrument, Instrument};
 
+/// The full name of the agent ttrpc service.
+pub(crate) const SERVICE: &str = "vaccel.asynchronous.agent.AgentService";
+
+impl<'a> From<&'a ::ttrpc::asynchronous::TtrpcContext> for RequestMeta<'a> {
+    fn from(ctx: &'a ::ttrpc::asynchronous::TtrpcContext) -> Self {
+        RequestMeta {
+            fd: ctx.fd,
+            metadata: &ctx.metadata,
+            timeout_nano: ctx.timeout_nano,
+        }
+    }
+}
+
 #[async_trait]
 impl agent_ttrpc::AgentService for AgentService {
     async fn create_session(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: CreateRequest,
     ) -> ttrpc::Result<CreateResponse> {
-        self.do_create_session(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "CreateSession", req, |ctx, req| {
+                s.do_create_session(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn update_session(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: UpdateRequest,
     ) -> ttrpc::Result<Empty> {
-        self.do_update_session(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "UpdateSession", req, |ctx, req| {
+                s.do_update_session(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn destroy_session(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: DestroyRequest,
     ) -> ttrpc::Result<Empty> {
-        self.do_destroy_session(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "DestroySession", req, |ctx, req| {
+                s.do_destroy_session(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
+    }
+
+    async fn resolve_ids(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: ResolveIdsRequest,
+    ) -> ttrpc::Result<ResolveIdsResponse> {
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "ResolveIds", req, |ctx, req| {
+                s.do_resolve_ids(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn register_resource(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: RegisterRequest,
     ) -> ttrpc::Result<RegisterResponse> {
-        self.do_register_resource(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "RegisterResource", req, |ctx, req| {
+                s.do_register_resource(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
+    }
+
+    async fn register_resource_chunk(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: RegisterChunk,
+    ) -> ttrpc::Result<RegisterChunkResponse> {
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "RegisterResourceChunk", req, |ctx, req| {
+                s.do_register_chunk(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
+    }
+
+    async fn register_resource_stream(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        r: ::ttrpc::asynchronous::ServerStreamReceiver<RegisterChunk>,
+    ) -> ttrpc::Result<RegisterResponse> {
+        let start = Instant::now();
+        let mut served = Served::default();
+        let ret = self.serve_register_stream(ctx, r, &mut served).await;
+        self.record(ctx.fd, "RegisterResourceStream", served, &ret, start);
+        ret.into_ttrpc()
     }
 
     async fn unregister_resource(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: UnregisterRequest,
     ) -> ttrpc::Result<Empty> {
-        self.do_unregister_resource(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "UnregisterResource", req, |ctx, req| {
+                s.do_unregister_resource(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn sync_resource(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: SyncRequest,
     ) -> ttrpc::Result<SyncResponse> {
-        self.do_sync_resource(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "SyncResource", req, |ctx, req| {
+                s.do_sync_resource(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
+    }
+
+    async fn lookup_blobs(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: LookupBlobsRequest,
+    ) -> ttrpc::Result<LookupBlobsResponse> {
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "LookupBlobs", req, |ctx, req| {
+                s.do_lookup_blobs(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn genop(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: GenopRequest,
     ) -> ttrpc::Result<GenopResponse> {
-        self.do_genop(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| s.serve(&ctx, "Genop", req, |ctx, req| s.do_genop(ctx, req)))
+            .await
+            .into_ttrpc()
     }
 
     async fn genop_stream(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
-        mut r: ::ttrpc::asynchronous::ServerStreamReceiver<GenopRequest>,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        r: ::ttrpc::asynchronous::ServerStreamReceiver<GenopRequest>,
     ) -> ttrpc::Result<GenopResponse> {
-        let mut req = GenopRequest::default();
-        let mut r_arg = vec![Arg::new()];
-        let mut w_arg = vec![Arg::new()];
-        while let Some(mut data) = r.recv().await? {
-            req.session_id = data.session_id;
-            if data.read_args.len() == 1 && data.read_args[0].parts > 0 {
-                if data.read_args[0].part_no < data.read_args[0].parts {
-                    r_arg[0].buf.append(&mut data.read_args[0].buf);
-                } else {
-                    r_arg[0].buf.append(&mut data.read_args[0].buf);
-                    r_arg[0].size = data.read_args[0].size;
-                    r_arg[0].arg_type = data.read_args[0].arg_type;
-                    r_arg[0].custom_type_id = data.read_args[0].custom_type_id;
-                    req.read_args.append(&mut r_arg);
-                    r_arg = vec![Arg::new()];
-                }
-            } else if data.write_args.len() == 1 && data.write_args[0].parts > 0 {
-                if data.write_args[0].part_no < data.write_args[0].parts {
-                    w_arg[0].buf.append(&mut data.write_args[0].buf);
-                } else {
-                    w_arg[0].buf.append(&mut data.write_args[0].buf);
-                    w_arg[0].size = data.write_args[0].size;
-                    w_arg[0].arg_type = data.write_args[0].arg_type;
-                    w_arg[0].custom_type_id = data.write_args[0].custom_type_id;
-                    req.write_args.append(&mut w_arg);
-                    w_arg = vec![Arg::new()];
-                }
-            } else {
-                req.read_args.append(&mut data.read_args);
-                req.write_args.append(&mut data.write_args);
-            }
-        }
-
-        debug!("Genop is streaming");
-        self.do_genop(req).into_ttrpc()
+        let start = Instant::now();
+        let mut served = Served::default();
+        let ret = self.serve_genop_stream(ctx, r, &mut served).await;
+        self.record(ctx.fd, "GenopStream", served, &ret, start);
+        ret.into_ttrpc()
     }
 
     async fn get_profiler(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: ProfilingRequest,
     ) -> ttrpc::Result<ProfilingResponse> {
-        self.do_get_profiler(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "GetProfiler", req, |ctx, req| {
+                s.do_get_profiler(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
+    }
+
+    async fn get_agent_info(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        req: InfoRequest,
+    ) -> ttrpc::Result<InfoResponse> {
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "GetAgentInfo", req, |_, req| s.do_get_agent_info(req))
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn image_classification(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: ImageRequest,
     ) -> ttrpc::Result<ImageResponse> {
-        self.do_image_classification(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "ImageClassification", req, |ctx, req| {
+                s.do_image_classification(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     #[cfg(target_pointer_width = "64")]
     async fn tensorflow_model_load(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: TFModelLoadRequest,
     ) -> ttrpc::Result<TFModelLoadResponse> {
-        self.do_tensorflow_model_load(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "TensorflowModelLoad", req, |ctx, req| {
+                s.do_tensorflow_model_load(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     #[cfg(target_pointer_width = "64")]
     async fn tensorflow_model_unload(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: TFModelUnloadRequest,
     ) -> ttrpc::Result<TFModelUnloadResponse> {
-        self.do_tensorflow_model_unload(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "TensorflowModelUnload", req, |ctx, req| {
+                s.do_tensorflow_model_unload(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     #[cfg(target_pointer_width = "64")]
     async fn tensorflow_model_run(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: TFModelRunRequest,
     ) -> ttrpc::Result<TFModelRunResponse> {
-        self.do_tensorflow_model_run(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "TensorflowModelRun", req, |ctx, req| {
+                s.do_tensorflow_model_run(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn tensorflow_lite_model_load(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: TFLiteModelLoadRequest,
-    ) -> ttrpc::Result<Empty> {
-        self.do_tflite_model_load(req).into_ttrpc()
+    ) -> ttrpc::Result<TFLiteModelLoadResponse> {
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "TensorflowLiteModelLoad", req, |ctx, req| {
+                s.do_tflite_model_load(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn tensorflow_lite_model_unload(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: TFLiteModelUnloadRequest,
     ) -> ttrpc::Result<Empty> {
-        self.do_tflite_model_unload(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "TensorflowLiteModelUnload", req, |ctx, req| {
+                s.do_tflite_model_unload(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn tensorflow_lite_model_run(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: TFLiteModelRunRequest,
     ) -> ttrpc::Result<TFLiteModelRunResponse> {
-        self.do_tflite_model_run(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "TensorflowLiteModelRun", req, |ctx, req| {
+                s.do_tflite_model_run(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn torch_model_load(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: TorchModelLoadRequest,
-    ) -> ttrpc::Result<Empty> {
-        self.do_torch_model_load(req).into_ttrpc()
+    ) -> ttrpc::Result<TorchModelLoadResponse> {
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "TorchModelLoad", req, |ctx, req| {
+                s.do_torch_model_load(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
     }
 
     async fn torch_model_run(
         &self,
-        _ctx: &::ttrpc::asynchronous::TtrpcContext,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
         req: TorchModelRunRequest,
     ) -> ttrpc::Result<TorchModelRunResponse> {
-        self.do_torch_model_run(req).into_ttrpc()
+        let ctx = BlockingContext::from(ctx);
+        self.blocking(move |s| {
+            s.serve(&ctx, "TorchModelRun", req, |ctx, req| {
+                s.do_torch_model_run(ctx, req)
+            })
+        })
+        .await
+        .into_ttrpc()
+    }
+}
+
+fn recv_error(e: ttrpc::Error) -> AgentServiceError {
+    AgentServiceError::Internal(format!("Could not receive request: {}", e))
+}
+
+// Client-streaming requests are served like unary ones once received, and
+// captured as the equivalent unary request so that they can be replayed
+impl AgentService {
+    async fn serve_register_stream(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        mut r: ::ttrpc::asynchronous::ServerStreamReceiver<RegisterChunk>,
+        served: &mut Served,
+    ) -> Result<RegisterResponse> {
+        let req_ctx = self.request_context(&ctx.into())?;
+        served.client = req_ctx.client.clone();
+        let _in_flight = self.begin_request(&req_ctx)?;
+
+        let conn_id = req_ctx.connection;
+        self.begin_upload(conn_id)?;
+        let mut upload: Option<Upload> = None;
+        let mut ret = Ok(());
+        loop {
+            let chunk = match r.recv().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    ret = Err(recv_error(e));
+                    break;
+                }
+            };
+            served.received += chunk.compute_size();
+            ret = upload
+                .get_or_insert_with(|| Upload::new(&chunk))
+                .add(chunk, |bytes| self.charge_upload(conn_id, bytes));
+            if ret.is_err() {
+                break;
+            }
+        }
+        self.end_upload(conn_id, upload.as_ref().map_or(0, |u| u.bytes()));
+        ret?;
+
+        debug!("Resource is streaming");
+        let req = upload
+            .ok_or_else(|| AgentServiceError::InvalidArgument("Empty upload".to_string()))?
+            .finish()?;
+        self.received(served, "RegisterResource", &req);
+        req_ctx.check_deadline()?;
+
+        let req_ctx = req_ctx.clone();
+        self.blocking(move |s| s.do_register_resource(&req_ctx, req))
+            .await
+    }
+
+    async fn serve_genop_stream(
+        &self,
+        ctx: &::ttrpc::asynchronous::TtrpcContext,
+        mut r: ::ttrpc::asynchronous::ServerStreamReceiver<GenopRequest>,
+        served: &mut Served,
+    ) -> Result<GenopResponse> {
+        let req_ctx = self.request_context(&ctx.into())?;
+        served.client = req_ctx.client.clone();
+        let _in_flight = self.begin_request(&req_ctx)?;
+
+        let mut req = GenopRequest::default();
+        let mut r_arg = vec![Arg::new()];
+        let mut w_arg = vec![Arg::new()];
+        while let Some(mut data) = r.recv().await.map_err(recv_error)? {
+            served.received += data.compute_size();
+            req.session_id = data.session_id;
+            if data.read_args.len() == 1 && data.read_args[0].parts > 0 {
+                if data.read_args[0].part_no < data.read_args[0].parts {
+                    r_arg[0].buf.append(&mut data.read_args[0].buf);
+                } else {
+                    r_arg[0].buf.append(&mut data.read_args[0].buf);
+                    r_arg[0].size = data.read_args[0].size;
+                    r_arg[0].arg_type = data.read_args[0].arg_type;
+                    r_arg[0].custom_type_id = data.read_args[0].custom_type_id;
+                    req.read_args.append(&mut r_arg);
+                    r_arg = vec![Arg::new()];
+                }
+            } else if data.write_args.len() == 1 && data.write_args[0].parts > 0 {
+                if data.write_args[0].part_no < data.write_args[0].parts {
+                    w_arg[0].buf.append(&mut data.write_args[0].buf);
+                } else {
+                    w_arg[0].buf.append(&mut data.write_args[0].buf);
+                    w_arg[0].size = data.write_args[0].size;
+                    w_arg[0].arg_type = data.write_args[0].arg_type;
+                    w_arg[0].custom_type_id = data.write_args[0].custom_type_id;
+                    req.write_args.append(&mut w_arg);
+                    w_arg = vec![Arg::new()];
+                }
+            } else {
+                req.read_args.append(&mut data.read_args);
+                req.write_args.append(&mut data.write_args);
+            }
+        }
+
+        debug!("Genop is streaming");
+        self.received(served, "Genop", &req);
+        req_ctx.check_deadline()?;
+
+        self.blocking(move |s| s.do_genop(&req_ctx, req)).await
     }
 }
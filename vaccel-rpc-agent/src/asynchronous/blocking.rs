@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::agent_service::{AgentService, AgentServiceError, RequestMeta, Result};
+use std::{collections::HashMap, os::unix::io::RawFd, time::Instant};
+use ttrpc::asynchronous::TtrpcContext;
+
+/// The parts of a ttrpc context needed to serve a request on the blocking
+/// pool.
+#[derive(Debug)]
+pub(crate) struct BlockingContext {
+    fd: RawFd,
+    metadata: HashMap<String, Vec<String>>,
+    timeout_nano: i64,
+    queued_at: Instant,
+}
+
+impl From<&TtrpcContext> for BlockingContext {
+    fn from(ctx: &TtrpcContext) -> Self {
+        BlockingContext {
+            fd: ctx.fd,
+            metadata: ctx.metadata.clone(),
+            timeout_nano: ctx.timeout_nano,
+            queued_at: Instant::now(),
+        }
+    }
+}
+
+// The timeout is reduced by the time spent waiting for a blocking thread, so
+// that the request deadline is kept
+impl<'a> From<&'a BlockingContext> for RequestMeta<'a> {
+    fn from(ctx: &'a BlockingContext) -> Self {
+        let queued = ctx.queued_at.elapsed().as_nanos().min(i64::MAX as u128) as i64;
+        let timeout_nano = match ctx.timeout_nano {
+            t if t > 0 => t.saturating_sub(queued).max(1),
+            t => t,
+        };
+
+        RequestMeta {
+            fd: ctx.fd,
+            metadata: &ctx.metadata,
+            timeout_nano,
+        }
+    }
+}
+
+impl AgentService {
+    /// Runs `f` on the blocking pool of the runtime, so that vAccel calls do
+    /// not stall the requests of other connections.
+    pub(crate) async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&AgentService) -> Result<T> + Send + 'static,
+    {
+        let service = self.clone();
+        tokio::task::spawn_blocking(move || f(&service))
+            .await
+            .map_err(|e| AgentServiceError::Internal(format!("Blocking task failed: {}", e)))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    fn context(timeout_nano: i64) -> BlockingContext {
+        BlockingContext {
+            fd: -1,
+            metadata: HashMap::new(),
+            timeout_nano,
+            queued_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn queueing_time_is_taken_from_the_timeout() {
+        let timeout = Duration::from_secs(10).as_nanos() as i64;
+        let ctx = context(timeout);
+        thread::sleep(Duration::from_millis(20));
+
+        let meta = RequestMeta::from(&ctx);
+        assert!(meta.timeout_nano <= timeout - Duration::from_millis(20).as_nanos() as i64);
+        assert!(meta.timeout_nano > 0);
+    }
+
+    #[test]
+    fn expired_timeouts_are_kept_expired() {
+        let ctx = context(1000);
+        thread::sleep(Duration::from_millis(1));
+
+        // A zero timeout would mean no deadline
+        assert_eq!(RequestMeta::from(&ctx).timeout_nano, 1);
+    }
+
+    #[test]
+    fn no_timeout_is_kept() {
+        let ctx = context(0);
+        thread::sleep(Duration::from_millis(1));
+
+        assert_eq!(RequestMeta::from(&ctx).timeout_nano, 0);
+    }
+}
@@ -1,3 +1,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
+mod admin_service;
 mod agent_service;
+mod blocking;
+
+pub(crate) use agent_service::SERVICE;
@@ -0,0 +1,274 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent_service::{AgentService, Result},
+    config::AuditConfig,
+    connection::ConnectionId,
+};
+use log::warn;
+use protobuf::{reflect::ReflectValueRef, MessageDyn};
+use serde::Serialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::{fs::OpenOptionsExt, io::RawFd},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A record of a served request.
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    /// Milliseconds since the Unix epoch
+    timestamp_ms: u128,
+    peer: String,
+    client: Option<&'a str>,
+    rpc: &'a str,
+    session_id: Option<i64>,
+    resource_id: Option<i64>,
+    request_bytes: u64,
+    response_bytes: u64,
+    code: String,
+    duration_us: u128,
+}
+
+/// Returns the first non-zero `int64` field of `msg` named after one of
+/// `names`.
+fn id_field(msg: &dyn MessageDyn, names: &[&str]) -> Option<i64> {
+    let desc = msg.descriptor_dyn();
+    names
+        .iter()
+        .filter_map(|name| desc.field_by_name(name))
+        .find_map(|f| match f.get_singular_field_or_default(msg) {
+            ReflectValueRef::I64(id) if id != 0 => Some(id),
+            _ => None,
+        })
+}
+
+/// The session and resource ids a request refers to.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct AuditIds {
+    session_id: Option<i64>,
+    resource_id: Option<i64>,
+}
+
+impl AuditIds {
+    pub(crate) fn of(msg: &dyn MessageDyn) -> Self {
+        AuditIds {
+            session_id: id_field(msg, &["session_id"]),
+            resource_id: id_field(msg, &["resource_id", "model_id"]),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+/// A JSON-lines log of the requests served by the agent, rotated when it
+/// exceeds a size limit.
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: Mutex<AuditFile>,
+}
+
+fn open_append(path: &Path) -> io::Result<AuditFile> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+    let size = file.metadata()?.len();
+    Ok(AuditFile { file, size })
+}
+
+impl AuditLog {
+    pub(crate) const DEFAULT_MAX_BYTES: u64 = 100 * 1024 * 1024;
+    pub(crate) const DEFAULT_MAX_FILES: usize = 5;
+
+    pub(crate) fn open(config: &AuditConfig) -> io::Result<Option<Self>> {
+        let path = match &config.path {
+            Some(p) => p.clone(),
+            None => return Ok(None),
+        };
+
+        let file = open_append(&path)?;
+        Ok(Some(AuditLog {
+            path,
+            max_bytes: config.max_bytes.unwrap_or(Self::DEFAULT_MAX_BYTES),
+            max_files: config.max_files.unwrap_or(Self::DEFAULT_MAX_FILES),
+            file: Mutex::new(file),
+        }))
+    }
+
+    /// Renames `path` to `path.1`, shifting older files up to `max_files`.
+    fn rotate(&self, current: &mut AuditFile) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = rotated(n);
+                if from.exists() {
+                    fs::rename(&from, rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        *current = open_append(&self.path)?;
+        Ok(())
+    }
+
+    fn write(&self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut current = self.file.lock().unwrap();
+        if self.max_bytes > 0
+            && current.size > 0
+            && current.size + line.len() as u64 > self.max_bytes
+        {
+            self.rotate(&mut current)?;
+        }
+        current.file.write_all(&line)?;
+        current.size += line.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl AgentService {
+    pub(crate) fn set_audit_log(&self, audit_log: Option<AuditLog>) {
+        *self.audit_log.write().unwrap() = audit_log;
+    }
+
+    pub(crate) fn is_audited(&self) -> bool {
+        self.audit_log.read().unwrap().is_some()
+    }
+
+    /// Records a served request to the audit log, if one is set.
+    ///
+    /// Any ids missing from the request `ids` are taken from the response.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn audit<Resp: MessageDyn>(
+        &self,
+        fd: RawFd,
+        client: Option<&str>,
+        rpc: &str,
+        ids: AuditIds,
+        request_bytes: u64,
+        ret: &Result<Resp>,
+        duration: Duration,
+    ) {
+        let audit_log = self.audit_log.read().unwrap();
+        let audit_log = match audit_log.as_ref() {
+            Some(a) => a,
+            None => return,
+        };
+
+        let resp = ret.as_ref().ok().map(|r| r as &dyn MessageDyn);
+        let resp_ids = resp.map(AuditIds::of).unwrap_or_default();
+        let record = AuditRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            peer: ConnectionId::from_fd(fd)
+                .map(|c| c.peer())
+                .unwrap_or_default(),
+            client,
+            rpc,
+            session_id: ids.session_id.or(resp_ids.session_id),
+            resource_id: ids.resource_id.or(resp_ids.resource_id),
+            request_bytes,
+            response_bytes: resp.map(|r| r.compute_size_dyn()).unwrap_or(0),
+            code: match ret {
+                Ok(_) => format!("{:?}", ttrpc::Code::OK),
+                Err(e) => format!("{:?}", e.code()),
+            },
+            duration_us: duration.as_micros(),
+        };
+
+        if let Err(e) = audit_log.write(&record) {
+            warn!("Could not write audit record: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::fs::PermissionsExt, process};
+
+    fn record(rpc: &str) -> AuditRecord<'_> {
+        AuditRecord {
+            timestamp_ms: 0,
+            peer: String::new(),
+            client: None,
+            rpc,
+            session_id: None,
+            resource_id: None,
+            request_bytes: 0,
+            response_bytes: 0,
+            code: "OK".to_string(),
+            duration_us: 0,
+        }
+    }
+
+    #[test]
+    fn rotates_the_log() {
+        let dir = env::temp_dir().join(format!("vaccel-rpc-agent-audit-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+
+        let line_len = serde_json::to_vec(&record("rpc")).unwrap().len() as u64 + 1;
+        let log = AuditLog::open(&AuditConfig {
+            path: Some(path.clone()),
+            // Fits two records per file
+            max_bytes: Some(2 * line_len),
+            max_files: Some(2),
+        })
+        .unwrap()
+        .unwrap();
+
+        for _ in 0..7 {
+            log.write(&record("rpc")).unwrap();
+        }
+        let lines = |p: &Path| fs::read_to_string(p).unwrap().lines().count();
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+        let files = (
+            lines(&path),
+            lines(&rotated(1)),
+            lines(&rotated(2)),
+            rotated(3).exists(),
+        );
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        let modes = (mode(&path), mode(&rotated(1)));
+
+        // Without files to keep the log is restarted instead
+        let log = AuditLog::open(&AuditConfig {
+            path: Some(path.clone()),
+            max_bytes: Some(2 * line_len),
+            max_files: Some(0),
+        })
+        .unwrap()
+        .unwrap();
+        for _ in 0..2 {
+            log.write(&record("rpc")).unwrap();
+        }
+        let truncated = lines(&path);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, (1, 2, 2, false));
+        assert_eq!(modes, (0o600, 0o600));
+        assert_eq!(truncated, 1);
+    }
+}
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::agent_service::{AgentService, AgentServiceError, RequestContext, RequestMeta, Result};
+use log::warn;
+use std::collections::HashMap;
+use vaccel_rpc_proto::TOKEN_METADATA_KEY;
+
+/// Compares two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl AgentService {
+    /// Sets the accepted authentication tokens, mapping each token name to
+    /// its value. Authentication is disabled if no tokens are set.
+    pub(crate) fn set_tokens(&self, tokens: HashMap<String, String>) {
+        *self.tokens.write().unwrap() = tokens;
+    }
+
+    /// Returns the name of the token carried by the request metadata.
+    fn authenticate(&self, metadata: &HashMap<String, Vec<String>>) -> Result<Option<String>> {
+        let tokens = self.tokens.read().unwrap();
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+
+        let token = metadata
+            .get(TOKEN_METADATA_KEY)
+            .and_then(|v| v.first())
+            .ok_or_else(|| AgentServiceError::Unauthenticated("Missing token".to_string()))?;
+
+        tokens
+            .iter()
+            .find(|(_, t)| constant_time_eq(t.as_bytes(), token.as_bytes()))
+            .map(|(name, _)| Some(name.clone()))
+            .ok_or_else(|| AgentServiceError::Unauthenticated("Invalid token".to_string()))
+    }
+
+    /// Authenticates a request and creates its context.
+    pub(crate) fn request_context(&self, meta: &RequestMeta) -> Result<RequestContext> {
+        let client = self.authenticate(meta.metadata).inspect_err(|e| {
+            warn!("Rejected request on fd:{}: {}", meta.fd, e);
+        })?;
+
+        RequestContext::new(meta, client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(token: Option<&str>) -> HashMap<String, Vec<String>> {
+        token
+            .map(|t| HashMap::from([(TOKEN_METADATA_KEY.to_string(), vec![t.to_string()])]))
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn no_tokens_disables_authentication() {
+        let service = AgentService::new();
+        assert_eq!(service.authenticate(&metadata(None)).unwrap(), None);
+        assert_eq!(service.authenticate(&metadata(Some("any"))).unwrap(), None);
+    }
+
+    #[test]
+    fn authenticates_tokens_by_name() {
+        let service = AgentService::new();
+        service.set_tokens(HashMap::from([
+            ("alice".to_string(), "secret-a".to_string()),
+            ("bob".to_string(), "secret-b".to_string()),
+        ]));
+
+        let err = service.authenticate(&metadata(None)).unwrap_err();
+        assert!(matches!(err, AgentServiceError::Unauthenticated(m) if m == "Missing token"));
+
+        for token in ["secret", "secret-c", "secret-bb", ""] {
+            let err = service.authenticate(&metadata(Some(token))).unwrap_err();
+            assert!(matches!(err, AgentServiceError::Unauthenticated(m) if m == "Invalid token"));
+        }
+
+        assert_eq!(
+            service.authenticate(&metadata(Some("secret-a"))).unwrap(),
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            service.authenticate(&metadata(Some("secret-b"))).unwrap(),
+            Some("bob".to_string())
+        );
+    }
+}
@@ -0,0 +1,576 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent_service::{AgentService, AgentServiceError, Result, Shared},
+    config::BatchingConfig,
+    session::AgentSession,
+};
+use log::info;
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use vaccel::VaccelId;
+use vaccel_rpc_proto::{tflite, torch};
+
+/// A tensor that can be concatenated with others along its first dimension.
+pub(crate) trait BatchTensor: Sized {
+    /// Returns the size of the first dimension, if it is positive.
+    fn rows(&self) -> Option<u64>;
+
+    /// Returns the data type and all dimensions but the first.
+    fn signature(&self) -> Vec<i64>;
+
+    /// Fails if the data of the tensor does not hold exactly the elements of
+    /// its dimensions.
+    fn check(&self) -> Result<()>;
+
+    /// Concatenates non-empty `tensors`, checked with `check()`, along their
+    /// first dimension.
+    fn concat(tensors: Vec<Self>) -> Self;
+
+    /// Splits the tensor along its first dimension into parts of `rows`.
+    fn split(self, rows: &[u64]) -> Result<Vec<Self>>;
+}
+
+macro_rules! impl_batch_tensor {
+    ($tensor:ty, $dim:ty, $data_type:ty) => {
+        impl BatchTensor for $tensor {
+            fn rows(&self) -> Option<u64> {
+                self.dims
+                    .first()
+                    .and_then(|&d| u64::try_from(d).ok())
+                    .filter(|&d| d > 0)
+            }
+
+            fn signature(&self) -> Vec<i64> {
+                let dims = self.dims.iter().skip(1).map(|&d| d as i64);
+                std::iter::once(self.type_.value() as i64)
+                    .chain(dims)
+                    .collect()
+            }
+
+            fn check(&self) -> Result<()> {
+                let size = <$data_type>::from(self.type_.value() as u32)
+                    .try_size_of()
+                    .and_then(|elem_size| {
+                        self.dims.iter().try_fold(elem_size as u64, |size, &d| {
+                            u64::try_from(d).ok().and_then(|d| size.checked_mul(d))
+                        })
+                    });
+                if size != Some(self.data.len() as u64) {
+                    return Err(AgentServiceError::InvalidArgument(format!(
+                        "Tensor data of {} bytes does not match dimensions {:?}",
+                        self.data.len(),
+                        self.dims
+                    )));
+                }
+
+                Ok(())
+            }
+
+            fn concat(tensors: Vec<Self>) -> Self {
+                let mut tensors = tensors.into_iter();
+                let mut batched = tensors.next().unwrap();
+                for tensor in tensors {
+                    batched.dims[0] += tensor.dims[0];
+                    batched.data.extend(tensor.data);
+                }
+                batched
+            }
+
+            fn split(self, rows: &[u64]) -> Result<Vec<Self>> {
+                let total: u64 = rows.iter().sum();
+                if self.rows() != Some(total) || self.data.len() as u64 % total != 0 {
+                    return Err(AgentServiceError::Internal(format!(
+                        "Could not split batched output of dimensions {:?} into {} rows",
+                        self.dims, total
+                    )));
+                }
+
+                let row_len = self.data.len() / total as usize;
+                let mut data = self.data.as_slice();
+                Ok(rows
+                    .iter()
+                    .map(|&r| {
+                        let (part, rest) = data.split_at(r as usize * row_len);
+                        data = rest;
+                        let mut dims = self.dims.clone();
+                        dims[0] = r as $dim;
+                        Self {
+                            data: part.to_vec(),
+                            dims,
+                            type_: self.type_,
+                            ..Default::default()
+                        }
+                    })
+                    .collect())
+            }
+        }
+    };
+}
+
+impl_batch_tensor!(torch::Tensor, i64, vaccel::ops::torch::DataType);
+impl_batch_tensor!(tflite::Tensor, i32, vaccel::ops::tf::lite::DataType);
+
+/// Returns the batch size of a run, i.e. the first dimension shared by all
+/// its inputs.
+fn batch_rows<T: BatchTensor>(inputs: &[T]) -> Option<u64> {
+    let rows = inputs.first()?.rows()?;
+    inputs
+        .iter()
+        .all(|t| t.rows() == Some(rows))
+        .then_some(rows)
+}
+
+/// Identifies the runs that can be batched together.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct BatchKey {
+    model: VaccelId,
+    inputs: Vec<Vec<i64>>,
+    nr_out_tensors: u64,
+    run_options: Option<Vec<u8>>,
+}
+
+impl BatchKey {
+    fn new<T: BatchTensor>(
+        model: VaccelId,
+        inputs: &[T],
+        nr_out_tensors: u64,
+        run_options: Option<&Vec<u8>>,
+    ) -> Self {
+        BatchKey {
+            model,
+            inputs: inputs.iter().map(BatchTensor::signature).collect(),
+            nr_out_tensors,
+            run_options: run_options.cloned(),
+        }
+    }
+}
+
+/// The outputs of each run of a batch, taken by the runs, and their shared
+/// status.
+type BatchOutputs<T, S> = Result<(Vec<Option<Vec<T>>>, S)>;
+
+#[derive(Debug)]
+struct BatchState<T, S> {
+    /// The inputs of each run, taken when the batch runs
+    inputs: Vec<Vec<T>>,
+    rows: Vec<u64>,
+    closed: bool,
+    /// Set once the batch ran
+    outputs: Option<BatchOutputs<T, S>>,
+}
+
+/// A batch of runs, run by the thread of its first run.
+#[derive(Debug)]
+struct Batch<T, S> {
+    state: Mutex<BatchState<T, S>>,
+    changed: Condvar,
+}
+
+/// Fails the other runs of a batch if its first run panics before the batch
+/// ran, so that they do not wait for it forever.
+struct FailOnUnwind<'a, T, S>(&'a Batch<T, S>);
+
+impl<T, S> Drop for FailOnUnwind<'_, T, S> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.outputs.is_none() {
+            state.outputs = Some(Err(AgentServiceError::Internal(
+                "Batch failed to run".to_string(),
+            )));
+            self.0.changed.notify_all();
+        }
+    }
+}
+
+/// The open batches of a model operation, keyed by model and input
+/// signature.
+#[derive(Debug)]
+pub(crate) struct Batcher<T, S> {
+    open: Mutex<HashMap<BatchKey, Arc<Batch<T, S>>>>,
+}
+
+impl<T, S> Default for Batcher<T, S> {
+    fn default() -> Self {
+        Batcher {
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: BatchTensor, S: Clone> Batcher<T, S> {
+    /// Runs `inputs` as part of a batch of compatible runs.
+    ///
+    /// The first run of a batch waits up to the batching window for more runs,
+    /// or until the batch is full, and then runs the whole batch with its
+    /// `run`. The `run` of the other runs of the batch is not called, and they
+    /// wait for the batch up to their `deadline`.
+    pub(crate) fn run<F>(
+        &self,
+        key: BatchKey,
+        config: &BatchingConfig,
+        inputs: Vec<T>,
+        deadline: Option<Instant>,
+        run: F,
+    ) -> Result<(Vec<T>, S)>
+    where
+        F: FnOnce(Vec<T>) -> Result<(Vec<T>, S)>,
+    {
+        let rows = batch_rows(&inputs).ok_or_else(|| {
+            AgentServiceError::InvalidArgument("Inputs have no common batch size".to_string())
+        })?;
+        for tensor in &inputs {
+            tensor.check()?;
+        }
+
+        let (batch, index) = self.join(key.clone(), config, inputs, rows);
+        if index > 0 {
+            return Self::wait(&batch, index, deadline);
+        }
+        let _failed = FailOnUnwind(&batch);
+
+        let deadline = Instant::now() + Duration::from_millis(config.window_ms);
+        let mut state = batch.state.lock().unwrap();
+        while !state.closed {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = batch.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        drop(state);
+
+        let (inputs, rows) = self.close(&key, &batch);
+        info!(
+            "Running batch of {} run(s) with {} row(s) of model {}",
+            rows.len(),
+            rows.iter().sum::<u64>(),
+            key.model
+        );
+        let outputs = match inputs.len() {
+            1 => run(inputs.into_iter().next().unwrap()).map(|(out, s)| (vec![Some(out)], s)),
+            _ => Self::run_batched(inputs, &rows, run),
+        };
+
+        let mut state = batch.state.lock().unwrap();
+        state.outputs = Some(outputs);
+        batch.changed.notify_all();
+        Self::take_outputs(&mut state, 0)
+    }
+
+    /// Adds a run to the open batch of `key`, opening a new one if there is
+    /// none or it has no room left. Returns the batch and the index of the
+    /// run in it.
+    fn join(
+        &self,
+        key: BatchKey,
+        config: &BatchingConfig,
+        inputs: Vec<T>,
+        rows: u64,
+    ) -> (Arc<Batch<T, S>>, usize) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(batch) = open.get(&key).cloned() {
+            let mut state = batch.state.lock().unwrap();
+            if state.rows.iter().sum::<u64>() + rows <= config.max_batch_size {
+                state.inputs.push(inputs);
+                state.rows.push(rows);
+                let index = state.rows.len() - 1;
+                if state.rows.iter().sum::<u64>() == config.max_batch_size {
+                    open.remove(&key);
+                    state.closed = true;
+                    batch.changed.notify_all();
+                }
+                return (batch.clone(), index);
+            }
+
+            // Full, so run it right away
+            open.remove(&key);
+            state.closed = true;
+            batch.changed.notify_all();
+        }
+
+        let batch = Arc::new(Batch {
+            state: Mutex::new(BatchState {
+                inputs: vec![inputs],
+                rows: vec![rows],
+                closed: rows >= config.max_batch_size,
+                outputs: None,
+            }),
+            changed: Condvar::new(),
+        });
+        if rows < config.max_batch_size {
+            open.insert(key, batch.clone());
+        }
+        (batch, 0)
+    }
+
+    /// Stops runs from joining `batch` and returns the inputs and batch size
+    /// of its runs.
+    fn close(&self, key: &BatchKey, batch: &Arc<Batch<T, S>>) -> (Vec<Vec<T>>, Vec<u64>) {
+        let mut open = self.open.lock().unwrap();
+        if open.get(key).is_some_and(|b| Arc::ptr_eq(b, batch)) {
+            open.remove(key);
+        }
+
+        let mut state = batch.state.lock().unwrap();
+        state.closed = true;
+        (mem::take(&mut state.inputs), state.rows.clone())
+    }
+
+    /// Concatenates the inputs of the runs, runs them at once and splits the
+    /// outputs back to each run.
+    fn run_batched<F>(inputs: Vec<Vec<T>>, rows: &[u64], run: F) -> BatchOutputs<T, S>
+    where
+        F: FnOnce(Vec<T>) -> Result<(Vec<T>, S)>,
+    {
+        let mut per_input: Vec<Vec<T>> = Vec::new();
+        for run_inputs in inputs {
+            per_input.resize_with(run_inputs.len(), Vec::new);
+            for (i, tensor) in run_inputs.into_iter().enumerate() {
+                per_input[i].push(tensor);
+            }
+        }
+        let batched = per_input.into_iter().map(T::concat).collect();
+
+        let (outputs, status) = run(batched)?;
+
+        let mut per_run: Vec<Vec<T>> = rows.iter().map(|_| Vec::new()).collect();
+        for output in outputs {
+            for (i, part) in output.split(rows)?.into_iter().enumerate() {
+                per_run[i].push(part);
+            }
+        }
+
+        Ok((per_run.into_iter().map(Some).collect(), status))
+    }
+
+    /// Waits for `batch` to run, up to `deadline`, and returns the outputs of
+    /// run `index`.
+    fn wait(batch: &Batch<T, S>, index: usize, deadline: Option<Instant>) -> Result<(Vec<T>, S)> {
+        let mut state = batch.state.lock().unwrap();
+        while state.outputs.is_none() {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(AgentServiceError::DeadlineExceeded(
+                            "Request deadline passed waiting for the batch to run".to_string(),
+                        ));
+                    }
+                    batch.changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => batch.changed.wait(state).unwrap(),
+            };
+        }
+        Self::take_outputs(&mut state, index)
+    }
+
+    fn take_outputs(state: &mut BatchState<T, S>, index: usize) -> Result<(Vec<T>, S)> {
+        match state.outputs.as_mut().unwrap() {
+            Ok((outputs, status)) => Ok((outputs[index].take().unwrap(), status.clone())),
+            Err(e) => Err(e.clone()),
+        }
+    }
+}
+
+impl AgentService {
+    /// Returns the key and settings to batch a run of a model with, if the
+    /// model has batching enabled and the run can be batched.
+    ///
+    /// Batched runs are run with the session of the first run of the batch,
+    /// so the model must be registered with the session of each run.
+    pub(crate) fn batching<T: BatchTensor>(
+        &self,
+        sess: &Shared<AgentSession>,
+        model_id: VaccelId,
+        inputs: &[T],
+        nr_out_tensors: u64,
+        run_options: Option<&Vec<u8>>,
+    ) -> Result<Option<(BatchKey, BatchingConfig)>> {
+        let config = match self.batching.get(&model_id) {
+            Some(config) if batch_rows(inputs).is_some() => config.clone(),
+            _ => return Ok(None),
+        };
+
+        let sess = sess.lock().unwrap();
+        if !sess.resources.contains(&model_id) {
+            return Err(AgentServiceError::InvalidArgument(format!(
+                "Model {} is not registered with the session",
+                model_id
+            )));
+        }
+
+        let key = BatchKey::new(model_id, inputs, nr_out_tensors, run_options);
+        Ok(Some((key, config)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::EnumOrUnknown;
+    use std::thread;
+    use torch::{DataType, Tensor};
+
+    type TestBatcher = Batcher<Tensor, ()>;
+
+    /// Returns a tensor of `rows` rows of two floats, filled with `fill`.
+    fn tensor(rows: i64, fill: u8) -> Tensor {
+        Tensor {
+            data: vec![fill; rows as usize * 2 * 4],
+            dims: vec![rows, 2],
+            type_: EnumOrUnknown::new(DataType::FLOAT),
+            ..Default::default()
+        }
+    }
+
+    fn key() -> BatchKey {
+        BatchKey::new(VaccelId::try_from(1).unwrap(), &[tensor(1, 0)], 1, None)
+    }
+
+    fn config(window_ms: u64, max_batch_size: u64) -> BatchingConfig {
+        BatchingConfig {
+            window_ms,
+            max_batch_size,
+        }
+    }
+
+    #[test]
+    fn runs_join_the_open_batch_until_full() {
+        let batcher = TestBatcher::default();
+        let config = config(0, 4);
+
+        let (first, i) = batcher.join(key(), &config, vec![tensor(1, 0)], 1);
+        assert_eq!(i, 0);
+        let (second, j) = batcher.join(key(), &config, vec![tensor(2, 0)], 2);
+        assert_eq!(j, 1);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!first.state.lock().unwrap().closed);
+
+        // No room left, so the batch is closed and a new one opened
+        let (third, k) = batcher.join(key(), &config, vec![tensor(2, 0)], 2);
+        assert_eq!(k, 0);
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert!(first.state.lock().unwrap().closed);
+
+        // Filling a batch up closes it
+        let (fourth, l) = batcher.join(key(), &config, vec![tensor(2, 0)], 2);
+        assert_eq!(l, 1);
+        assert!(Arc::ptr_eq(&third, &fourth));
+        assert!(third.state.lock().unwrap().closed);
+        assert!(batcher.open.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn closing_takes_the_inputs_and_stops_joins() {
+        let batcher = TestBatcher::default();
+        let config = config(0, 8);
+
+        let (batch, _) = batcher.join(key(), &config, vec![tensor(1, 1)], 1);
+        batcher.join(key(), &config, vec![tensor(3, 2)], 3);
+
+        let (inputs, rows) = batcher.close(&key(), &batch);
+        assert_eq!(rows, vec![1, 3]);
+        assert_eq!(inputs, vec![vec![tensor(1, 1)], vec![tensor(3, 2)]]);
+        assert!(batch.state.lock().unwrap().closed);
+
+        let (next, i) = batcher.join(key(), &config, vec![tensor(1, 1)], 1);
+        assert_eq!(i, 0);
+        assert!(!Arc::ptr_eq(&batch, &next));
+    }
+
+    #[test]
+    fn concatenated_tensors_split_back() {
+        let parts = vec![tensor(1, 1), tensor(3, 2), tensor(2, 3)];
+        let batched = Tensor::concat(parts.clone());
+        assert_eq!(batched.dims, vec![6, 2]);
+        assert!(batched.check().is_ok());
+
+        assert_eq!(batched.split(&[1, 3, 2]).unwrap(), parts);
+    }
+
+    #[test]
+    fn mismatched_shapes_are_rejected() {
+        // Data not matching the dimensions
+        let mut short = tensor(2, 0);
+        short.data.pop();
+        assert!(short.check().is_err());
+        let mut negative = tensor(2, 0);
+        negative.dims[1] = -2;
+        assert!(negative.check().is_err());
+
+        // Outputs not matching the batch
+        assert!(tensor(3, 0).split(&[1, 1]).is_err());
+        let mut uneven = tensor(3, 0);
+        uneven.data.pop();
+        assert!(uneven.split(&[1, 2]).is_err());
+
+        let batcher = TestBatcher::default();
+        let run = |_: Vec<Tensor>| -> Result<(Vec<Tensor>, ())> { unreachable!() };
+        let ret = batcher.run(key(), &config(0, 8), vec![short], None, run);
+        assert!(matches!(ret, Err(AgentServiceError::InvalidArgument(_))));
+        let ret = batcher.run(
+            key(),
+            &config(0, 8),
+            vec![tensor(1, 0), tensor(2, 0)],
+            None,
+            run,
+        );
+        assert!(matches!(ret, Err(AgentServiceError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn runs_are_batched_and_split() {
+        let batcher = TestBatcher::default();
+        let config = config(10_000, 3);
+
+        thread::scope(|s| {
+            let first = s.spawn(|| {
+                batcher.run(key(), &config, vec![tensor(1, 1)], None, |inputs| {
+                    assert_eq!(inputs[0].dims, vec![3, 2]);
+                    Ok((inputs, ()))
+                })
+            });
+            while batcher.open.lock().unwrap().is_empty() {
+                thread::yield_now();
+            }
+
+            let ret = batcher.run(key(), &config, vec![tensor(2, 2)], None, |_| unreachable!());
+            assert_eq!(ret.unwrap().0, vec![tensor(2, 2)]);
+            assert_eq!(first.join().unwrap().unwrap().0, vec![tensor(1, 1)]);
+        });
+    }
+
+    #[test]
+    fn waiting_runs_are_bounded() {
+        let batcher = TestBatcher::default();
+        let config = config(10_000, 2);
+
+        // Up to the deadline of the request
+        let (batch, _) = batcher.join(key(), &config, vec![tensor(1, 0)], 1);
+        let (_, index) = batcher.join(key(), &config, vec![tensor(1, 0)], 1);
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let ret = TestBatcher::wait(&batch, index, Some(deadline));
+        assert!(matches!(ret, Err(AgentServiceError::DeadlineExceeded(_))));
+
+        // And fail if the first run panics
+        thread::scope(|s| {
+            let first = s.spawn(|| {
+                batcher.run(key(), &config, vec![tensor(1, 1)], None, |_| {
+                    panic!("run failed")
+                })
+            });
+            while batcher.open.lock().unwrap().is_empty() {
+                thread::yield_now();
+            }
+
+            let ret = batcher.run(key(), &config, vec![tensor(1, 2)], None, |_| unreachable!());
+            assert!(matches!(ret, Err(AgentServiceError::Internal(_))));
+            assert!(first.join().is_err());
+        });
+    }
+}
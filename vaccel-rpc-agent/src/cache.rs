@@ -0,0 +1,359 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent_service::{AgentService, AgentServiceError, RequestContext, Result},
+    config::CacheConfig,
+};
+use log::debug;
+use sha2::{Digest as _, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+use vaccel::VaccelId;
+use vaccel_rpc_proto::resource::{Blob as ProtoBlob, LookupBlobsRequest, LookupBlobsResponse};
+
+/// The SHA-256 digest of a blob.
+pub(crate) type Digest = [u8; 32];
+
+/// The name of the token a client authenticated with, or `None` when
+/// authentication is disabled.
+pub(crate) type Scope = Option<String>;
+
+/// Identifies a resource created from blobs by the client that created it,
+/// its type and the name and digest of each of its blobs.
+pub(crate) type ResourceKey = (Scope, u32, Vec<(String, Digest)>);
+
+/// The digest and data of uploaded blobs.
+pub(crate) type Uploads = Vec<(Digest, Vec<u8>)>;
+
+/// The blobs of a resource as resolved by `resolve_blobs`.
+#[derive(Debug, Default)]
+pub(crate) struct ResolvedBlobs {
+    pub(crate) digests: Vec<Digest>,
+    pub(crate) uploads: Uploads,
+    /// The digests of the blobs sent by digest that are not cached
+    pub(crate) missing: Vec<Vec<u8>>,
+}
+
+fn to_digest(bytes: &[u8]) -> Result<Digest> {
+    bytes.try_into().map_err(|_| {
+        AgentServiceError::InvalidArgument(format!(
+            "Invalid SHA-256 digest of {} bytes",
+            bytes.len()
+        ))
+    })
+}
+
+#[derive(Debug)]
+struct CachedBlob {
+    data: Arc<Vec<u8>>,
+    last_used: u64,
+    /// The clients that uploaded the data
+    owners: HashSet<Scope>,
+}
+
+/// A content-addressed cache of uploaded blob data, evicted in LRU order
+/// when it exceeds a size limit, along with an index of the live resources
+/// created from blobs. Caching is disabled while the size limit is 0, which
+/// is the default.
+///
+/// A digest is not a secret: a client that learns the digest of a blob
+/// uploaded by another client must not be able to use or probe for it. The
+/// data of a blob is stored once, but a client can only look up and use the
+/// blobs it has uploaded itself, and resources are only reused by the client
+/// that created them. When authentication is disabled, every client that can
+/// reach the agent is equally trusted and all of them share one scope.
+#[derive(Debug, Default)]
+pub(crate) struct ResourceCache {
+    blobs: HashMap<Digest, CachedBlob>,
+    size: usize,
+    max_bytes: usize,
+    clock: u64,
+    /// The cached blobs by the time they were last used
+    lru: BTreeMap<u64, Digest>,
+    resources: HashMap<ResourceKey, VaccelId>,
+    keys: HashMap<VaccelId, ResourceKey>,
+}
+
+impl ResourceCache {
+    pub(crate) fn new() -> Self {
+        ResourceCache::default()
+    }
+
+    pub(crate) fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        self.evict(0);
+        if !self.is_enabled() {
+            self.clear_resources();
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Marks a cached blob as the most recently used.
+    fn touch(&mut self, digest: &Digest) {
+        let now = self.tick();
+        if let Some(b) = self.blobs.get_mut(digest) {
+            self.lru.remove(&b.last_used);
+            b.last_used = now;
+            self.lru.insert(now, *digest);
+        }
+    }
+
+    pub(crate) fn contains(&self, scope: &Scope, digest: &Digest) -> bool {
+        self.blobs
+            .get(digest)
+            .is_some_and(|b| b.owners.contains(scope))
+    }
+
+    pub(crate) fn get(&mut self, scope: &Scope, digest: &Digest) -> Option<Arc<Vec<u8>>> {
+        if !self.contains(scope, digest) {
+            return None;
+        }
+        self.touch(digest);
+        self.blobs.get(digest).map(|b| b.data.clone())
+    }
+
+    /// Adds blob data uploaded by a client to the cache, evicting the least
+    /// recently used blobs to stay within the size limit. Blobs larger than
+    /// the limit are not cached.
+    pub(crate) fn insert(&mut self, scope: &Scope, digest: Digest, data: Vec<u8>) {
+        if data.len() > self.max_bytes {
+            return;
+        }
+        if let Some(b) = self.blobs.get_mut(&digest) {
+            b.owners.insert(scope.clone());
+            self.touch(&digest);
+            return;
+        }
+
+        self.evict(data.len());
+        let now = self.tick();
+        self.size += data.len();
+        self.blobs.insert(
+            digest,
+            CachedBlob {
+                data: Arc::new(data),
+                last_used: now,
+                owners: HashSet::from([scope.clone()]),
+            },
+        );
+        self.lru.insert(now, digest);
+    }
+
+    /// Evicts blobs until `incoming` more bytes fit in the cache.
+    fn evict(&mut self, incoming: usize) {
+        while self.size + incoming > self.max_bytes {
+            let Some((_, lru)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(b) = self.blobs.remove(&lru) {
+                debug!("Evicting cached blob of {} bytes", b.data.len());
+                self.size -= b.data.len();
+            }
+        }
+    }
+
+    pub(crate) fn resource(&self, key: &ResourceKey) -> Option<VaccelId> {
+        self.resources.get(key).copied()
+    }
+
+    pub(crate) fn add_resource(&mut self, key: ResourceKey, res_id: VaccelId) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.resources.insert(key.clone(), res_id);
+        self.keys.insert(res_id, key);
+    }
+
+    pub(crate) fn remove_resource(&mut self, res_id: VaccelId) {
+        if let Some(key) = self.keys.remove(&res_id) {
+            // The key may have been taken over by a resource created
+            // concurrently from the same blobs
+            if self.resources.get(&key) == Some(&res_id) {
+                self.resources.remove(&key);
+            }
+        }
+    }
+
+    pub(crate) fn clear_resources(&mut self) {
+        self.resources.clear();
+        self.keys.clear();
+    }
+}
+
+impl AgentService {
+    pub(crate) fn set_cache_config(&self, config: &CacheConfig) {
+        self.cache
+            .lock()
+            .unwrap()
+            .set_max_bytes(config.max_bytes.unwrap_or(0) as usize);
+    }
+
+    pub(crate) fn do_lookup_blobs(
+        &self,
+        ctx: &RequestContext,
+        req: LookupBlobsRequest,
+    ) -> Result<LookupBlobsResponse> {
+        let cache = self.cache.lock().unwrap();
+        let mut missing = Vec::new();
+        for digest in req.sha256 {
+            if !cache.contains(&ctx.client, &to_digest(&digest)?) {
+                missing.push(digest);
+            }
+        }
+
+        let mut resp = LookupBlobsResponse::new();
+        resp.missing = missing;
+
+        Ok(resp)
+    }
+
+    /// Fills in the data of blobs sent only by digest from the blobs the
+    /// client has uploaded before.
+    ///
+    /// Returns the digest of each blob, the digest and data of the uploaded
+    /// blobs to be cached with `cache_blobs` once the resource is registered,
+    /// if caching is enabled, and the digests of the blobs that are not
+    /// cached.
+    pub(crate) fn resolve_blobs(
+        &self,
+        scope: &Scope,
+        blobs: &mut [ProtoBlob],
+    ) -> Result<ResolvedBlobs> {
+        let caching = self.cache.lock().unwrap().is_enabled();
+        let mut resolved = ResolvedBlobs::default();
+        for blob in blobs.iter_mut() {
+            let digest = if blob.data.is_empty() && !blob.sha256.is_empty() {
+                let digest = to_digest(&blob.sha256)?;
+                match self.cache.lock().unwrap().get(scope, &digest) {
+                    Some(data) => blob.data = data.as_ref().clone(),
+                    None => resolved.missing.push(blob.sha256.clone()),
+                }
+                digest
+            } else {
+                let digest: Digest = Sha256::digest(&blob.data).into();
+                if !blob.sha256.is_empty() && blob.sha256 != digest {
+                    return Err(AgentServiceError::InvalidArgument(format!(
+                        "SHA-256 digest mismatch for blob `{}`",
+                        blob.name
+                    )));
+                }
+                if caching {
+                    resolved.uploads.push((digest, blob.data.clone()));
+                }
+                digest
+            };
+            resolved.digests.push(digest);
+        }
+
+        Ok(resolved)
+    }
+
+    /// Caches the data of blobs uploaded by a client.
+    pub(crate) fn cache_blobs(&self, scope: &Scope, uploads: Uploads) {
+        let mut cache = self.cache.lock().unwrap();
+        for (digest, data) in uploads {
+            cache.insert(scope, digest, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(name: &str) -> Scope {
+        Some(name.to_string())
+    }
+
+    fn digest(data: &[u8]) -> Digest {
+        Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn caching_is_disabled_by_default() {
+        let mut cache = ResourceCache::new();
+        let data = vec![1u8; 16];
+        let d = digest(&data);
+        let key = (None, 1, vec![("model".to_string(), d)]);
+
+        cache.insert(&None, d, data);
+        cache.add_resource(key.clone(), VaccelId::try_from(1i64).unwrap());
+        assert!(!cache.contains(&None, &d));
+        assert_eq!(cache.resource(&key), None);
+    }
+
+    #[test]
+    fn blobs_are_only_visible_to_their_owners() {
+        let mut cache = ResourceCache::new();
+        cache.set_max_bytes(1024);
+        let (alice, bob) = (scope("alice"), scope("bob"));
+        let data = vec![1u8; 16];
+        let d = digest(&data);
+
+        cache.insert(&alice, d, data.clone());
+        assert!(cache.contains(&alice, &d));
+        assert!(!cache.contains(&bob, &d));
+        assert!(!cache.contains(&None, &d));
+        assert!(cache.get(&bob, &d).is_none());
+
+        // Uploading the data proves possession, without storing it again
+        cache.insert(&bob, d, data.clone());
+        assert_eq!(cache.get(&bob, &d).unwrap().as_slice(), data.as_slice());
+        assert_eq!(cache.size, data.len());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_blobs() {
+        let mut cache = ResourceCache::new();
+        cache.set_max_bytes(32);
+        let blobs: Vec<Vec<u8>> = (0..3).map(|i| vec![i; 16]).collect();
+        let digests: Vec<Digest> = blobs.iter().map(|b| digest(b)).collect();
+
+        cache.insert(&None, digests[0], blobs[0].clone());
+        cache.insert(&None, digests[1], blobs[1].clone());
+        cache.get(&None, &digests[0]).unwrap();
+        cache.insert(&None, digests[2], blobs[2].clone());
+
+        assert!(cache.contains(&None, &digests[0]));
+        assert!(!cache.contains(&None, &digests[1]));
+        assert!(cache.contains(&None, &digests[2]));
+        assert_eq!(cache.size, 32);
+        assert_eq!(cache.lru.len(), 2);
+
+        // Blobs larger than the cache are not cached
+        let large = vec![0u8; 64];
+        cache.insert(&None, digest(&large), large.clone());
+        assert!(!cache.contains(&None, &digest(&large)));
+        assert!(cache.contains(&None, &digests[2]));
+
+        cache.set_max_bytes(16);
+        assert!(!cache.contains(&None, &digests[0]));
+        assert!(cache.contains(&None, &digests[2]));
+    }
+
+    #[test]
+    fn resources_are_indexed_per_client() {
+        let mut cache = ResourceCache::new();
+        cache.set_max_bytes(1024);
+        let blobs = vec![("model".to_string(), digest(b"model"))];
+        let key = |s: &str| (scope(s), 1, blobs.clone());
+        let res_id = VaccelId::try_from(1i64).unwrap();
+
+        cache.add_resource(key("alice"), res_id);
+        assert_eq!(cache.resource(&key("alice")), Some(res_id));
+        assert_eq!(cache.resource(&key("bob")), None);
+
+        cache.remove_resource(res_id);
+        assert_eq!(cache.resource(&key("alice")), None);
+    }
+}
@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "async")]
+use crate::asynchronous::SERVICE;
+#[cfg(not(feature = "async"))]
+use crate::sync::SERVICE;
+use crate::{
+    agent_service::{AgentService, Result},
+    config::CaptureConfig,
+    connection::ConnectionId,
+};
+use log::warn;
+use protobuf::{
+    reflect::{ReflectValueBox, ReflectValueRef},
+    Message, MessageDyn, MessageFull,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::{fs::OpenOptionsExt, io::RawFd},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use vaccel_rpc_proto::capture::Record;
+
+/// The fields holding session ids in requests and responses.
+pub(crate) const SESSION_ID_FIELDS: &[&str] = &["session_id"];
+/// The fields holding resource ids in requests and responses.
+pub(crate) const RESOURCE_ID_FIELDS: &[&str] = &["resource_id", "model_id"];
+
+pub(crate) fn id_field(msg: &dyn MessageDyn, name: &str) -> Option<i64> {
+    let field = msg.descriptor_dyn().field_by_name(name)?;
+    if !field.is_singular() {
+        return None;
+    }
+    match field.get_singular_field_or_default(msg) {
+        ReflectValueRef::I64(id) if id != 0 => Some(id),
+        _ => None,
+    }
+}
+
+pub(crate) fn set_id_field(msg: &mut dyn MessageDyn, name: &str, id: i64) {
+    if let Some(field) = msg.descriptor_dyn().field_by_name(name) {
+        field.set_singular_field(msg, ReflectValueBox::I64(id));
+    }
+}
+
+/// Returns a copy of a message with only its session and resource id
+/// fields set.
+fn id_fields(msg: &dyn MessageDyn) -> Box<dyn MessageDyn> {
+    let mut ids = msg.descriptor_dyn().new_instance();
+    for name in SESSION_ID_FIELDS.iter().chain(RESOURCE_ID_FIELDS) {
+        if let Some(id) = id_field(msg, name) {
+            set_id_field(&mut *ids, name, id);
+        }
+    }
+    ids
+}
+
+#[derive(Debug)]
+struct CaptureFile {
+    file: File,
+    size: u64,
+    full: bool,
+}
+
+/// A file recording the requests served by the agent, and optionally their
+/// responses, as length-delimited `capture.Record` messages. The ids of the
+/// responses are recorded regardless, as replaying depends on them.
+///
+/// Recording stops once the file reaches its size limit.
+#[derive(Debug)]
+pub(crate) struct Capture {
+    path: PathBuf,
+    responses: bool,
+    max_bytes: u64,
+    file: Mutex<CaptureFile>,
+}
+
+impl Capture {
+    pub(crate) const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+    pub(crate) fn open(config: &CaptureConfig) -> io::Result<Option<Self>> {
+        let path = match &config.path {
+            Some(p) => p.clone(),
+            None => return Ok(None),
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Some(Capture {
+            path,
+            responses: config.responses.unwrap_or(true),
+            max_bytes: config.max_bytes.unwrap_or(Self::DEFAULT_MAX_BYTES),
+            file: Mutex::new(CaptureFile {
+                file,
+                size,
+                full: false,
+            }),
+        }))
+    }
+
+    fn write(&self, record: &Record) -> protobuf::Result<()> {
+        let bytes = record.write_length_delimited_to_bytes()?;
+
+        let mut current = self.file.lock().unwrap();
+        if self.max_bytes > 0 && current.size + bytes.len() as u64 > self.max_bytes {
+            if !current.full {
+                warn!(
+                    "Capture file {} reached {} bytes; not recording further requests",
+                    self.path.display(),
+                    self.max_bytes
+                );
+                current.full = true;
+            }
+            return Ok(());
+        }
+        current.file.write_all(&bytes)?;
+        current.size += bytes.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl AgentService {
+    pub(crate) fn set_capture(&self, capture: Option<Capture>) {
+        *self.capture.write().unwrap() = capture;
+    }
+
+    /// Returns the serialized request, if requests are captured.
+    pub(crate) fn capture_request(&self, req: &dyn MessageDyn) -> Option<Vec<u8>> {
+        self.capture.read().unwrap().as_ref()?;
+
+        match req.write_to_bytes_dyn() {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                warn!("Could not serialize captured request: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Records a served request, serialized by `capture_request()`, to the
+    /// capture file.
+    pub(crate) fn capture<Resp: MessageFull>(
+        &self,
+        fd: RawFd,
+        rpc: &str,
+        request: Vec<u8>,
+        ret: &Result<Resp>,
+        duration: Duration,
+    ) {
+        let capture = self.capture.read().unwrap();
+        let capture = match capture.as_ref() {
+            Some(c) => c,
+            None => return,
+        };
+
+        let mut record = Record {
+            timestamp_us: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            service: SERVICE.to_string(),
+            method: rpc.to_string(),
+            peer: ConnectionId::from_fd(fd)
+                .map(|c| c.peer())
+                .unwrap_or_default(),
+            request,
+            duration_us: duration.as_micros() as u64,
+            ..Default::default()
+        };
+        match ret {
+            Ok(resp) if capture.responses => match resp.write_to_bytes() {
+                Ok(bytes) => record.response = bytes,
+                Err(e) => warn!("Could not serialize captured response: {}", e),
+            },
+            Ok(resp) => match id_fields(resp).write_to_bytes_dyn() {
+                Ok(bytes) => record.response_ids = bytes,
+                Err(e) => warn!("Could not serialize captured response ids: {}", e),
+            },
+            Err(e) => {
+                record.code = e.code() as i32;
+                record.message = e.to_string();
+            }
+        }
+
+        if let Err(e) = capture.write(&record) {
+            warn!("Could not write capture record: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vaccel_rpc_proto::resource::RegisterResponse;
+
+    #[test]
+    fn id_fields_keep_only_the_ids() {
+        let resp = RegisterResponse {
+            resource_id: 5,
+            missing: vec![vec![1; 32]],
+            ..Default::default()
+        };
+
+        let ids = id_fields(&resp);
+        assert_eq!(id_field(&*ids, "resource_id"), Some(5));
+        let expected = RegisterResponse {
+            resource_id: 5,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids.write_to_bytes_dyn().unwrap(),
+            expected.write_to_bytes().unwrap()
+        );
+    }
+}
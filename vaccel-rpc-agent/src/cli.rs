@@ -1,25 +1,302 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::Error;
-use clap::Parser;
-use std::str::FromStr;
+use crate::{Config, Error, Limits};
+use clap::{
+    builder::{NonEmptyStringValueParser, RangedU64ValueParser},
+    Args, Parser, Subcommand,
+};
+use serde::Deserialize;
+use std::{path::PathBuf, str::FromStr};
 
 #[derive(Debug, Default, Parser)]
 #[command(name = "vAccel RPC Agent")]
 #[command(about = "A vAccel RPC agent that can respond to acceleration requests")]
 pub struct Cli {
+    #[arg(short = 'c')]
+    #[arg(long = "config")]
+    #[arg(env = "VACCEL_RPC_AGENT_CONFIG")]
+    #[arg(help = "Path to a TOML configuration file")]
+    pub config: Option<PathBuf>,
+
     #[arg(short = 'a')]
     #[arg(long = "server-address")]
-    #[arg(help = "The server address in the format '<socket-type>://<host>:<port>'")]
-    #[arg(default_value = "tcp://127.0.0.1:65500")]
-    pub server_address: String,
+    #[arg(env = "VACCEL_RPC_AGENT_SERVER_ADDRESS")]
+    #[arg(value_delimiter = ',')]
+    #[arg(value_parser = NonEmptyStringValueParser::new())]
+    #[arg(
+        help = "The server address in the format '<socket-type>://<host>:<port>'. Can be repeated or comma-separated to listen on multiple addresses [default: tcp://127.0.0.1:65500]"
+    )]
+    pub server_address: Vec<String>,
+
+    #[arg(long = "admin-address")]
+    #[arg(env = "VACCEL_RPC_AGENT_ADMIN_ADDRESS")]
+    #[arg(value_parser = NonEmptyStringValueParser::new())]
+    #[arg(
+        help = "The admin server address, either 'unix://<path>' or 'vsock://<cid>:<port>'. The admin server is disabled if not set"
+    )]
+    pub admin_address: Option<String>,
+
+    #[arg(long = "drain-timeout")]
+    #[arg(env = "VACCEL_RPC_AGENT_DRAIN_TIMEOUT")]
+    #[arg(
+        help = "Seconds to wait for in-flight requests to complete on shutdown before releasing all sessions [default: 30]"
+    )]
+    pub drain_timeout: Option<u64>,
+
+    #[arg(long = "blocking-threads")]
+    #[arg(env = "VACCEL_RPC_AGENT_BLOCKING_THREADS")]
+    #[arg(value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    #[arg(
+        help = "Maximum number of threads running vAccel calls in the async agent. Ignored by the sync agent, which serves each connection on its own thread [default: available parallelism]"
+    )]
+    pub blocking_threads: Option<usize>,
 
     #[arg(long = "vaccel-config")]
+    #[arg(env = "VACCEL_RPC_AGENT_VACCEL_CONFIG")]
     #[arg(help = "Configuration options passed to vAccel in the format 'opt1=value1,opt2=value2'")]
     pub vaccel_config: Option<VaccelConfig>,
+
+    #[arg(long = "log-level")]
+    #[arg(env = "VACCEL_RPC_AGENT_LOG_LEVEL")]
+    #[arg(help = "The log filter in the 'env_logger' format [default: info]")]
+    pub log_level: Option<String>,
+
+    #[arg(long = "max-sessions")]
+    #[arg(env = "VACCEL_RPC_AGENT_MAX_SESSIONS")]
+    #[arg(help = "Maximum number of sessions per client connection")]
+    pub max_sessions: Option<usize>,
+
+    #[arg(long = "max-resources")]
+    #[arg(env = "VACCEL_RPC_AGENT_MAX_RESOURCES")]
+    #[arg(help = "Maximum number of registered resources per client connection")]
+    pub max_resources: Option<usize>,
+
+    #[arg(long = "max-blob-bytes")]
+    #[arg(env = "VACCEL_RPC_AGENT_MAX_BLOB_BYTES")]
+    #[arg(help = "Maximum total size in bytes of blobs uploaded per client connection")]
+    pub max_blob_bytes: Option<usize>,
+
+    #[arg(long = "max-in-flight")]
+    #[arg(env = "VACCEL_RPC_AGENT_MAX_IN_FLIGHT")]
+    #[arg(help = "Maximum number of concurrent requests per client connection")]
+    pub max_in_flight: Option<usize>,
+
+    #[arg(long = "metrics-address")]
+    #[arg(env = "VACCEL_RPC_AGENT_METRICS_ADDRESS")]
+    #[arg(value_parser = NonEmptyStringValueParser::new())]
+    #[arg(
+        help = "The '<host>:<port>' address to serve Prometheus metrics on, e.g. '127.0.0.1:9100'. Metrics are not served if not set"
+    )]
+    pub metrics_address: Option<String>,
+
+    #[arg(long = "tls-cert")]
+    #[arg(env = "VACCEL_RPC_AGENT_TLS_CERT")]
+    #[arg(help = "Path to the PEM-encoded certificate chain used for tls:// addresses")]
+    pub tls_cert: Option<PathBuf>,
+
+    #[arg(long = "tls-key")]
+    #[arg(env = "VACCEL_RPC_AGENT_TLS_KEY")]
+    #[arg(help = "Path to the PEM-encoded private key used for tls:// addresses")]
+    pub tls_key: Option<PathBuf>,
+
+    #[arg(long = "tls-ca")]
+    #[arg(env = "VACCEL_RPC_AGENT_TLS_CA")]
+    #[arg(
+        help = "Path to the PEM-encoded CA certificates used to verify clients. Enables mutual TLS"
+    )]
+    pub tls_ca: Option<PathBuf>,
+
+    #[arg(long = "auth-token")]
+    #[arg(env = "VACCEL_RPC_AGENT_AUTH_TOKENS")]
+    #[arg(value_delimiter = ',')]
+    #[arg(
+        help = "A client token in the format 'name=token'. Can be repeated or comma-separated. Clients must present one of the tokens if any is set. Prefer --auth-token-file, as arguments are visible to other users"
+    )]
+    pub auth_token: Vec<String>,
+
+    #[arg(long = "auth-token-file")]
+    #[arg(env = "VACCEL_RPC_AGENT_AUTH_TOKEN_FILE")]
+    #[arg(
+        help = "Path of a file with a client token per line in the format 'name=token'. Empty lines and lines starting with '#' are ignored"
+    )]
+    pub auth_token_file: Option<PathBuf>,
+
+    #[arg(long = "audit-log")]
+    #[arg(env = "VACCEL_RPC_AGENT_AUDIT_LOG")]
+    #[arg(help = "Path of a JSON-lines log recording every request served by the agent")]
+    pub audit_log: Option<PathBuf>,
+
+    #[arg(long = "audit-log-max-bytes")]
+    #[arg(env = "VACCEL_RPC_AGENT_AUDIT_LOG_MAX_BYTES")]
+    #[arg(
+        help = "Size in bytes after which the audit log is rotated, 0 to disable rotation [default: 104857600]"
+    )]
+    pub audit_log_max_bytes: Option<u64>,
+
+    #[arg(long = "audit-log-max-files")]
+    #[arg(env = "VACCEL_RPC_AGENT_AUDIT_LOG_MAX_FILES")]
+    #[arg(help = "Number of rotated audit logs to keep [default: 5]")]
+    pub audit_log_max_files: Option<usize>,
+
+    #[arg(long = "capture")]
+    #[arg(env = "VACCEL_RPC_AGENT_CAPTURE")]
+    #[arg(
+        help = "Path of a file recording every request and response served by the agent, for replay with the 'capture replay' command"
+    )]
+    pub capture: Option<PathBuf>,
+
+    #[arg(long = "capture-requests-only")]
+    #[arg(env = "VACCEL_RPC_AGENT_CAPTURE_REQUESTS_ONLY")]
+    #[arg(help = "Record only the requests, and the ids of the responses, to the capture file")]
+    pub capture_requests_only: bool,
+
+    #[arg(long = "capture-max-bytes")]
+    #[arg(env = "VACCEL_RPC_AGENT_CAPTURE_MAX_BYTES")]
+    #[arg(
+        help = "Size in bytes after which no more requests are recorded, 0 to disable the limit [default: 1073741824]"
+    )]
+    pub capture_max_bytes: Option<u64>,
+
+    #[arg(long = "cache-max-bytes")]
+    #[arg(env = "VACCEL_RPC_AGENT_CACHE_MAX_BYTES")]
+    #[arg(
+        help = "Maximum total size in bytes of uploaded blob data cached by content. Caching is disabled if not set or 0"
+    )]
+    pub cache_max_bytes: Option<u64>,
+
+    #[arg(long = "storage-dir")]
+    #[arg(env = "VACCEL_RPC_AGENT_STORAGE_DIR")]
+    #[arg(
+        help = "Directory for the blob files of uploaded resources and for persistent resources"
+    )]
+    pub storage_dir: Option<PathBuf>,
+
+    #[arg(long = "storage-max-bytes")]
+    #[arg(env = "VACCEL_RPC_AGENT_STORAGE_MAX_BYTES")]
+    #[arg(help = "Maximum total size in bytes of the files in the storage directory")]
+    pub storage_max_bytes: Option<u64>,
+
+    #[arg(long = "persistent-idle-timeout")]
+    #[arg(env = "VACCEL_RPC_AGENT_PERSISTENT_IDLE_TIMEOUT")]
+    #[arg(
+        help = "Seconds after which persistent resources not registered with any session are removed [default: never]"
+    )]
+    pub persistent_idle_timeout: Option<u64>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Inspect and manage a running agent through its admin server")]
+    Ctl(CtlArgs),
+
+    #[command(about = "Inspect and replay capture files recorded by an agent")]
+    Capture(CaptureArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct CtlArgs {
+    #[arg(short = 'a')]
+    #[arg(long = "admin-address")]
+    #[arg(env = "VACCEL_RPC_AGENT_ADMIN_ADDRESS")]
+    #[arg(help = "The admin server address in the format '<socket-type>://<host>:<port>'")]
+    pub admin_address: String,
+
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CtlCommand {
+    #[command(about = "List the sessions held by the agent")]
+    ListSessions,
+
+    #[command(about = "List the resources held by the agent")]
+    ListResources,
+
+    #[command(about = "Destroy a session, releasing its resources")]
+    DestroySession {
+        #[arg(help = "The ID of the session to destroy")]
+        session_id: i64,
+    },
+
+    #[command(about = "Export a session with its resources to an archive file")]
+    ExportSession {
+        #[arg(help = "The ID of the session to export")]
+        session_id: i64,
+        #[arg(help = "The path of the archive to write")]
+        archive: PathBuf,
+    },
+
+    #[command(about = "Import a session exported by another agent")]
+    ImportSession {
+        #[arg(help = "The path of the archive to import")]
+        archive: PathBuf,
+    },
+
+    #[command(about = "Remove a persistent resource and its files")]
+    RemoveResource {
+        #[arg(help = "The name of the persistent resource")]
+        name: String,
+        #[arg(long = "client")]
+        #[arg(help = "The token name of the client the resource belongs to, if any")]
+        client: Option<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct CaptureArgs {
+    #[command(subcommand)]
+    pub command: CaptureCommand,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Subcommand)]
+pub enum CaptureCommand {
+    #[command(about = "Print the records of a capture file as JSON lines")]
+    Dump {
+        #[arg(help = "The path of the capture file")]
+        capture: PathBuf,
+    },
+
+    #[command(about = "Re-issue the requests of a capture file and compare the responses")]
+    Replay {
+        #[arg(short = 'a')]
+        #[arg(long = "server-address")]
+        #[arg(default_value = Config::DEFAULT_SERVER_ADDRESS)]
+        #[arg(help = "The address of the agent in the format '<socket-type>://<host>:<port>'")]
+        server_address: String,
+
+        #[arg(long = "auth-token")]
+        #[arg(env = "VACCEL_RPC_TOKEN")]
+        #[arg(help = "The client token to present to the agent")]
+        auth_token: Option<String>,
+
+        #[arg(long = "auth-token-file")]
+        #[arg(env = "VACCEL_RPC_TOKEN_FILE")]
+        #[arg(conflicts_with = "auth_token")]
+        #[arg(help = "Path of a file holding the client token to present to the agent")]
+        auth_token_file: Option<PathBuf>,
+
+        #[arg(help = "The path of the capture file")]
+        capture: PathBuf,
+    },
+}
+
+impl Cli {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_sessions: self.max_sessions,
+            max_resources: self.max_resources,
+            max_blob_bytes: self.max_blob_bytes,
+            max_in_flight: self.max_in_flight,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct VaccelConfig {
     pub plugins: Option<String>,
     pub log_level: Option<u8>,
@@ -28,6 +305,19 @@ pub struct VaccelConfig {
     pub version_ignore: Option<bool>,
 }
 
+impl VaccelConfig {
+    /// Merges two configs, with the values set in `other` taking precedence.
+    pub(crate) fn merge(self, other: VaccelConfig) -> Self {
+        VaccelConfig {
+            plugins: other.plugins.or(self.plugins),
+            log_level: other.log_level.or(self.log_level),
+            log_file: other.log_file.or(self.log_file),
+            profiling_enabled: other.profiling_enabled.or(self.profiling_enabled),
+            version_ignore: other.version_ignore.or(self.version_ignore),
+        }
+    }
+}
+
 impl FromStr for VaccelConfig {
     type Err = Error;
 
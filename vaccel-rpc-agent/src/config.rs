@@ -0,0 +1,662 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent::check_admin_address,
+    cli::{Cli, VaccelConfig},
+    Error, Limits, Result,
+};
+use serde::{de::Error as _, Deserialize, Deserializer};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+/// The agent configuration.
+///
+/// It is read from a TOML file and can be overridden by CLI arguments and
+/// `VACCEL_RPC_AGENT_*` environment variables.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub vaccel: Option<VaccelConfig>,
+    pub logging: LoggingConfig,
+    pub limits: Limits,
+    pub metrics: MetricsConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub audit: AuditConfig,
+    pub capture: CaptureConfig,
+    pub cache: CacheConfig,
+    pub storage: StorageConfig,
+    #[serde(deserialize_with = "models")]
+    pub models: Vec<ModelConfig>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The server address in the format '<socket-type>://<host>:<port>'
+    #[serde(deserialize_with = "some_non_empty")]
+    pub address: Option<String>,
+    /// Additional server addresses to listen on
+    #[serde(deserialize_with = "all_non_empty")]
+    pub addresses: Vec<String>,
+    /// The admin server address in the format '<socket-type>://<host>:<port>'
+    #[serde(deserialize_with = "some_non_empty")]
+    pub admin_address: Option<String>,
+    /// Seconds to wait for in-flight requests to complete on shutdown
+    pub drain_timeout: Option<u64>,
+    /// Maximum number of threads running vAccel calls in the async agent.
+    /// Ignored by the sync agent
+    #[serde(deserialize_with = "some_positive")]
+    pub blocking_threads: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// The log filter in the `env_logger` format, e.g. 'info' or
+    /// 'vaccel_rpc_agent=debug'
+    pub level: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// The '<host>:<port>' address of the HTTP server exposing Prometheus
+    /// metrics
+    #[serde(deserialize_with = "some_non_empty")]
+    pub address: Option<String>,
+}
+
+/// TLS settings for `tls://` server addresses.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Path to the PEM-encoded server certificate chain
+    pub cert: Option<PathBuf>,
+    /// Path to the PEM-encoded server private key
+    pub key: Option<PathBuf>,
+    /// Path to the PEM-encoded CA certificates used to verify clients. Client
+    /// certificates are required (mutual TLS) if set
+    pub ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// The accepted client tokens, keyed by name. Authentication is disabled
+    /// if empty and no token file is set
+    #[serde(deserialize_with = "tokens")]
+    pub tokens: HashMap<String, String>,
+    /// Path of a file with a client token per line in the format
+    /// 'name=token'. This is the preferred way to set tokens, as the file
+    /// can be kept readable only by the agent
+    pub token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Path of the JSON-lines audit log. No audit log is written if not set
+    pub path: Option<PathBuf>,
+    /// Size in bytes after which the audit log is rotated. 0 disables rotation
+    pub max_bytes: Option<u64>,
+    /// Number of rotated audit logs to keep
+    pub max_files: Option<usize>,
+}
+
+/// Recording of the served requests for later replay.
+///
+/// Client-streaming requests are recorded once fully received, as the
+/// equivalent unary request.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Path of the capture file. No requests are recorded if not set
+    pub path: Option<PathBuf>,
+    /// Whether responses are recorded along with the requests. The session
+    /// and resource ids of responses are always recorded
+    pub responses: Option<bool>,
+    /// Size in bytes after which no more requests are recorded. 0 disables
+    /// the limit
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Maximum total size in bytes of cached blob data. Caching is disabled
+    /// if not set or 0
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Directory holding the blob files of uploaded resources and the
+    /// persistent resources. Blob files go to the vAccel default directory
+    /// if not set
+    pub dir: Option<PathBuf>,
+    /// Maximum total size in bytes of the files in the storage directory
+    pub max_bytes: Option<u64>,
+    /// Seconds after which persistent resources not registered with any
+    /// session are removed. Persistent resources are kept until removed
+    /// through the admin server if not set
+    pub persistent_idle_timeout: Option<u64>,
+}
+
+/// A model to be loaded when the agent starts.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    /// The name clients register the model by
+    #[serde(deserialize_with = "non_empty")]
+    pub name: String,
+    #[serde(deserialize_with = "paths")]
+    pub paths: Vec<String>,
+    /// Dynamic batching of the runs of the model. Runs are not batched if
+    /// not set
+    pub batching: Option<BatchingConfig>,
+}
+
+/// Dynamic batching of the Torch and TensorFlow Lite runs of a model.
+///
+/// Runs with the same input signature are concatenated along the first
+/// dimension of their inputs and run at once.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchingConfig {
+    /// Time in milliseconds the first run of a batch waits for more runs
+    pub window_ms: u64,
+    /// Maximum size of the first dimension of the batched inputs
+    #[serde(deserialize_with = "positive")]
+    pub max_batch_size: u64,
+}
+
+// Values that can be checked on their own are validated while deserializing,
+// so that errors point to their line in the config file.
+
+fn non_empty<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<String, D::Error> {
+    let value = String::deserialize(d)?;
+    match value.is_empty() {
+        true => Err(D::Error::custom("value cannot be empty")),
+        false => Ok(value),
+    }
+}
+
+fn some_non_empty<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    non_empty(d).map(Some)
+}
+
+fn all_non_empty<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<String>, D::Error> {
+    let values = Vec::<String>::deserialize(d)?;
+    match values.iter().any(|v| v.is_empty()) {
+        true => Err(D::Error::custom("values cannot be empty")),
+        false => Ok(values),
+    }
+}
+
+fn paths<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<String>, D::Error> {
+    let paths = all_non_empty(d)?;
+    match paths.is_empty() {
+        true => Err(D::Error::custom("no paths provided")),
+        false => Ok(paths),
+    }
+}
+
+fn positive<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<u64, D::Error> {
+    match u64::deserialize(d)? {
+        0 => Err(D::Error::custom("value must be at least 1")),
+        value => Ok(value),
+    }
+}
+
+fn some_positive<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<usize>, D::Error> {
+    match usize::deserialize(d)? {
+        0 => Err(D::Error::custom("value must be at least 1")),
+        value => Ok(Some(value)),
+    }
+}
+
+fn tokens<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<HashMap<String, String>, D::Error> {
+    let tokens = HashMap::<String, String>::deserialize(d)?;
+    check_tokens(&tokens).map_err(D::Error::custom)?;
+
+    Ok(tokens)
+}
+
+fn models<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<ModelConfig>, D::Error> {
+    let models = Vec::<ModelConfig>::deserialize(d)?;
+    let mut names = HashSet::new();
+    if let Some(model) = models.iter().find(|m| !names.insert(m.name.as_str())) {
+        return Err(D::Error::custom(format!(
+            "duplicate model `{}`",
+            model.name
+        )));
+    }
+
+    Ok(models)
+}
+
+/// Parses a client token in the format 'name=token'.
+fn parse_token(token: &str) -> std::result::Result<(String, String), String> {
+    token
+        .split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| {
+            // Do not echo the token value back
+            "Invalid auth token; expected the format 'name=token'".to_string()
+        })
+}
+
+/// Checks that token names and values are set and that values are unique.
+fn check_tokens(tokens: &HashMap<String, String>) -> std::result::Result<(), String> {
+    let mut values = HashSet::new();
+    for (name, token) in tokens {
+        if name.is_empty() {
+            return Err("Auth token name cannot be empty".to_string());
+        }
+        if token.is_empty() {
+            return Err(format!("Auth token `{}` cannot be empty", name));
+        }
+        if !values.insert(token.as_str()) {
+            return Err(format!("Auth token `{}` is not unique", name));
+        }
+    }
+
+    Ok(())
+}
+
+impl Config {
+    pub const DEFAULT_SERVER_ADDRESS: &'static str = "tcp://127.0.0.1:65500";
+    pub const DEFAULT_LOG_LEVEL: &'static str = "info";
+    pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const DEFAULT_BLOCKING_THREADS: usize = 4;
+
+    /// Reads the configuration from a TOML file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Could not read {}: {}", path.display(), e)))?;
+
+        Self::from_toml(&contents)
+            .map_err(|e| Error::Config(format!("Invalid file {}: {}", path.display(), e)))
+    }
+
+    fn from_toml(contents: &str) -> std::result::Result<Self, String> {
+        let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Creates the configuration from the CLI arguments.
+    ///
+    /// If a config file is given it is read first and any values set by the
+    /// arguments override the ones from the file.
+    pub fn from_cli(cli: &Cli) -> Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        if !cli.server_address.is_empty() {
+            config.server.address = None;
+            config.server.addresses = cli.server_address.clone();
+        }
+
+        if let Some(address) = &cli.admin_address {
+            config.server.admin_address = Some(address.clone());
+        }
+
+        if let Some(timeout) = cli.drain_timeout {
+            config.server.drain_timeout = Some(timeout);
+        }
+
+        if let Some(threads) = cli.blocking_threads {
+            config.server.blocking_threads = Some(threads);
+        }
+
+        if let Some(vaccel_config) = &cli.vaccel_config {
+            config.vaccel = Some(match config.vaccel.take() {
+                Some(c) => c.merge(vaccel_config.clone()),
+                None => vaccel_config.clone(),
+            });
+        }
+
+        if let Some(level) = &cli.log_level {
+            config.logging.level = Some(level.clone());
+        }
+
+        config.limits = config.limits.merge(cli.limits());
+
+        if let Some(address) = &cli.metrics_address {
+            config.metrics.address = Some(address.clone());
+        }
+
+        if let Some(cert) = &cli.tls_cert {
+            config.tls.cert = Some(cert.clone());
+        }
+
+        if let Some(key) = &cli.tls_key {
+            config.tls.key = Some(key.clone());
+        }
+
+        if let Some(ca) = &cli.tls_ca {
+            config.tls.ca = Some(ca.clone());
+        }
+
+        if let Some(path) = &cli.audit_log {
+            config.audit.path = Some(path.clone());
+        }
+
+        if let Some(max_bytes) = cli.audit_log_max_bytes {
+            config.audit.max_bytes = Some(max_bytes);
+        }
+
+        if let Some(max_files) = cli.audit_log_max_files {
+            config.audit.max_files = Some(max_files);
+        }
+
+        if let Some(path) = &cli.capture {
+            config.capture.path = Some(path.clone());
+        }
+
+        if cli.capture_requests_only {
+            config.capture.responses = Some(false);
+        }
+
+        if let Some(max_bytes) = cli.capture_max_bytes {
+            config.capture.max_bytes = Some(max_bytes);
+        }
+
+        if let Some(max_bytes) = cli.cache_max_bytes {
+            config.cache.max_bytes = Some(max_bytes);
+        }
+
+        if let Some(dir) = &cli.storage_dir {
+            config.storage.dir = Some(dir.clone());
+        }
+
+        if let Some(max_bytes) = cli.storage_max_bytes {
+            config.storage.max_bytes = Some(max_bytes);
+        }
+
+        if let Some(timeout) = cli.persistent_idle_timeout {
+            config.storage.persistent_idle_timeout = Some(timeout);
+        }
+
+        if let Some(path) = &cli.auth_token_file {
+            config.auth.token_file = Some(path.clone());
+        }
+
+        if let Some(path) = &config.auth.token_file {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| Error::Config(format!("Could not read {}: {}", path.display(), e)))?;
+            for (i, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (name, value) = parse_token(line).map_err(|e| {
+                    Error::Config(format!(
+                        "Invalid file {}: line {}: {}",
+                        path.display(),
+                        i + 1,
+                        e
+                    ))
+                })?;
+                config.auth.tokens.insert(name, value);
+            }
+        }
+
+        for token in &cli.auth_token {
+            let (name, value) = parse_token(token).map_err(Error::Config)?;
+            config.auth.tokens.insert(name, value);
+        }
+
+        config.validate().map_err(Error::Config)?;
+
+        Ok(config)
+    }
+
+    /// Returns all the addresses the server should listen on.
+    pub fn server_addresses(&self) -> Vec<&str> {
+        let addresses: Vec<&str> = self
+            .server
+            .address
+            .iter()
+            .chain(self.server.addresses.iter())
+            .map(|a| a.as_str())
+            .collect();
+
+        if addresses.is_empty() {
+            vec![Self::DEFAULT_SERVER_ADDRESS]
+        } else {
+            addresses
+        }
+    }
+
+    /// Returns the first address the server should listen on.
+    #[deprecated(note = "use `server_addresses()`")]
+    pub fn server_address(&self) -> &str {
+        self.server_addresses()[0]
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.server
+            .drain_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(Self::DEFAULT_DRAIN_TIMEOUT)
+    }
+
+    /// Returns the size of the blocking pool of the async agent, which
+    /// defaults to the available parallelism.
+    pub fn blocking_threads(&self) -> usize {
+        self.server.blocking_threads.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(Self::DEFAULT_BLOCKING_THREADS)
+        })
+    }
+
+    pub fn log_level(&self) -> &str {
+        self.logging
+            .level
+            .as_deref()
+            .unwrap_or(Self::DEFAULT_LOG_LEVEL)
+    }
+
+    /// Checks the constraints between values, which may come from both the
+    /// config file and the CLI arguments.
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return Err("Both a TLS certificate and a key must be provided".to_string());
+        }
+
+        if let Some(address) = &self.server.admin_address {
+            check_admin_address(address).map_err(|e| e.to_string())?;
+        }
+
+        check_tokens(&self.auth.tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::{env, process};
+
+    fn parse_err(contents: &str) -> String {
+        Config::from_toml(contents).unwrap_err()
+    }
+
+    #[test]
+    fn parses_config_file() {
+        let config = Config::from_toml(
+            r#"
+            [server]
+            addresses = ["unix:///tmp/agent.sock", "tcp://0.0.0.0:65500"]
+            blocking_threads = 2
+
+            [limits]
+            max_sessions = 4
+
+            [auth.tokens]
+            alice = "secret"
+
+            [[models]]
+            name = "resnet"
+            paths = ["/models/resnet.pt"]
+            batching = { window_ms = 5, max_batch_size = 8 }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.server_addresses(),
+            ["unix:///tmp/agent.sock", "tcp://0.0.0.0:65500"]
+        );
+        assert_eq!(config.blocking_threads(), 2);
+        assert_eq!(config.limits.max_sessions, Some(4));
+        assert_eq!(config.auth.tokens["alice"], "secret");
+        assert_eq!(config.models[0].name, "resnet");
+        assert_eq!(
+            config.models[0].batching.as_ref().unwrap().max_batch_size,
+            8
+        );
+        assert_eq!(config.drain_timeout(), Config::DEFAULT_DRAIN_TIMEOUT);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn server_address_is_the_first_address() {
+        assert_eq!(
+            Config::default().server_address(),
+            Config::DEFAULT_SERVER_ADDRESS
+        );
+
+        let config = Config::from_toml("[server]\naddresses = [\"unix:///a\", \"unix:///b\"]\n");
+        assert_eq!(config.unwrap().server_address(), "unix:///a");
+    }
+
+    #[test]
+    fn rejects_invalid_values_with_their_line() {
+        let err =
+            parse_err("[server]\naddress = \"tcp://127.0.0.1:65500\"\nadmin_address = \"\"\n");
+        assert!(err.contains("line 3"), "{}", err);
+        assert!(err.contains("value cannot be empty"), "{}", err);
+
+        let err = parse_err("[server]\nadmin_address = \"tcp://127.0.0.1:65501\"\n");
+        assert!(err.contains("must be a unix or vsock address"), "{}", err);
+
+        let err = parse_err("[server]\n\nblocking_threads = 0\n");
+        assert!(err.contains("line 3"), "{}", err);
+        assert!(err.contains("at least 1"), "{}", err);
+
+        let err = parse_err(
+            "[[models]]\nname = \"m\"\npaths = [\"/m\"]\nbatching = { window_ms = 1, max_batch_size = 0 }\n",
+        );
+        assert!(err.contains("line 4"), "{}", err);
+
+        let err = parse_err("[[models]]\nname = \"m\"\npaths = []\n");
+        assert!(err.contains("line 3"), "{}", err);
+        assert!(err.contains("no paths provided"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let err = parse_err("[server]\nport = 1\n");
+        assert!(err.contains("unknown field `port`"), "{}", err);
+
+        let err = parse_err(
+            "[[models]]\nname = \"m\"\npaths = [\"/a\"]\n[[models]]\nname = \"m\"\npaths = [\"/b\"]\n",
+        );
+        assert!(err.contains("duplicate model `m`"), "{}", err);
+
+        let err = parse_err("[auth.tokens]\na = \"t\"\nb = \"t\"\n");
+        assert!(err.contains("is not unique"), "{}", err);
+
+        let err = parse_err("[tls]\ncert = \"/cert.pem\"\n");
+        assert!(err.contains("a key must be provided"), "{}", err);
+    }
+
+    #[test]
+    fn cli_arguments_override_the_config_file() {
+        let path = env::temp_dir().join(format!("vaccel-rpc-agent-{}.toml", process::id()));
+        fs::write(
+            &path,
+            "[server]\naddress = \"tcp://127.0.0.1:1\"\ndrain_timeout = 5\n\
+             [limits]\nmax_sessions = 1\nmax_resources = 2\n\
+             [auth.tokens]\nalice = \"a\"\n",
+        )
+        .unwrap();
+
+        let cli = Cli::parse_from([
+            "vaccel-rpc-agent",
+            "--config",
+            path.to_str().unwrap(),
+            "--server-address",
+            "unix:///tmp/a.sock,unix:///tmp/b.sock",
+            "--max-resources",
+            "3",
+            "--auth-token",
+            "bob=b",
+        ]);
+        let config = Config::from_cli(&cli);
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(
+            config.server_addresses(),
+            ["unix:///tmp/a.sock", "unix:///tmp/b.sock"]
+        );
+        assert_eq!(config.drain_timeout(), Duration::from_secs(5));
+        assert_eq!(config.limits.max_sessions, Some(1));
+        assert_eq!(config.limits.max_resources, Some(3));
+        assert_eq!(config.auth.tokens.len(), 2);
+
+        // Tokens given as arguments must be unique too
+        let cli = Cli::parse_from(["vaccel-rpc-agent", "--auth-token", "a=t,b=t"]);
+        assert!(Config::from_cli(&cli).is_err());
+    }
+
+    #[test]
+    fn reads_the_token_file() {
+        let path = env::temp_dir().join(format!("vaccel-rpc-agent-{}.tokens", process::id()));
+        fs::write(&path, "# clients\nalice=a\n\n  bob=b  \n").unwrap();
+        let cli = Cli::parse_from([
+            "vaccel-rpc-agent",
+            "--auth-token-file",
+            path.to_str().unwrap(),
+            "--auth-token",
+            "carol=c",
+        ]);
+        let config = Config::from_cli(&cli);
+
+        fs::write(&path, "alice=a\nbob\n").unwrap();
+        let err = Config::from_cli(&cli).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.auth.tokens.len(), 3);
+        assert_eq!(config.auth.tokens["bob"], "b");
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn cli_rejects_invalid_values() {
+        assert!(Cli::try_parse_from(["vaccel-rpc-agent", "--blocking-threads", "0"]).is_err());
+        assert!(Cli::try_parse_from(["vaccel-rpc-agent", "--admin-address", ""]).is_err());
+    }
+}
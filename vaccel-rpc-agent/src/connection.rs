@@ -0,0 +1,236 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{tls, AgentService};
+use log::debug;
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr},
+    os::unix::{ffi::OsStrExt, io::RawFd},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+#[cfg(not(feature = "async"))]
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+};
+use vaccel::VaccelId;
+
+/// Identifies a client connection.
+///
+/// The socket inode is kept alongside the fd so that a connection is not
+/// confused with a later one that got the same fd number.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub(crate) struct ConnectionId {
+    fd: RawFd,
+    ino: u64,
+}
+
+impl ConnectionId {
+    /// Creates a new `ConnectionId` from the fd of a connected socket.
+    pub(crate) fn from_fd(fd: RawFd) -> io::Result<Self> {
+        Ok(Self {
+            fd,
+            ino: socket_inode(fd)?,
+        })
+    }
+
+    /// Returns `true` if the connection is still open.
+    pub(crate) fn is_alive(&self) -> bool {
+        match socket_inode(self.fd) {
+            Ok(ino) if ino == self.ino => (),
+            _ => return false,
+        }
+
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLRDHUP,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut pfd, 1, 0) } {
+            n if n > 0 => {
+                pfd.revents & (libc::POLLRDHUP | libc::POLLHUP | libc::POLLERR | libc::POLLNVAL)
+                    == 0
+            }
+            _ => true,
+        }
+    }
+}
+
+impl ConnectionId {
+    /// Returns the address of the connection peer.
+    pub(crate) fn peer(&self) -> String {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if unsafe {
+            libc::getpeername(
+                self.fd,
+                &mut addr as *mut _ as *mut libc::sockaddr,
+                &mut len,
+            )
+        } < 0
+        {
+            return self.to_string();
+        }
+
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { *(&addr as *const _ as *const libc::sockaddr_in) };
+                format!(
+                    "tcp://{}:{}",
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port)
+                )
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { *(&addr as *const _ as *const libc::sockaddr_in6) };
+                format!(
+                    "tcp://[{}]:{}",
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port)
+                )
+            }
+            libc::AF_VSOCK => {
+                let addr = unsafe { *(&addr as *const _ as *const libc::sockaddr_vm) };
+                format!("vsock://{}:{}", addr.svm_cid, addr.svm_port)
+            }
+            libc::AF_UNIX => {
+                let addr = unsafe { &*(&addr as *const _ as *const libc::sockaddr_un) };
+                // Connections forwarded by a TLS listener report the TCP peer
+                if let Some(peer) = socket_path(addr, len).and_then(tls::forwarded_peer) {
+                    return format!("tls://{}", peer);
+                }
+                match peer_credentials(self.fd) {
+                    Ok(cred) => {
+                        format!("unix (pid:{} uid:{} gid:{})", cred.pid, cred.uid, cred.gid)
+                    }
+                    Err(_) => format!("unix ({})", self),
+                }
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "fd:{}", self.fd)
+    }
+}
+
+fn socket_inode(fd: RawFd) -> io::Result<u64> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.st_ino as u64)
+}
+
+/// Returns the path a unix socket address is bound to, if any.
+fn socket_path(addr: &libc::sockaddr_un, len: libc::socklen_t) -> Option<&Path> {
+    let len = (len as usize).checked_sub(mem::size_of::<libc::sa_family_t>())?;
+    let path = &addr.sun_path[..len.min(addr.sun_path.len())];
+    // Unnamed and abstract sockets have no path
+    if path.first().copied().unwrap_or(0) == 0 {
+        return None;
+    }
+    let end = path.iter().position(|c| *c == 0).unwrap_or(path.len());
+    let bytes = unsafe { &*(&path[..end] as *const [libc::c_char] as *const [u8]) };
+    Some(Path::new(OsStr::from_bytes(bytes)))
+}
+
+fn peer_credentials(fd: RawFd) -> io::Result<libc::ucred> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred)
+}
+
+/// The agent state owned by a client connection.
+#[derive(Debug, Default)]
+pub(crate) struct Connection {
+    pub(crate) sessions: HashSet<VaccelId>,
+    pub(crate) resources: usize,
+    pub(crate) blob_bytes: usize,
+    pub(crate) in_flight: usize,
+    pub(crate) uploads: usize,
+    pub(crate) pending_sessions: usize,
+}
+
+/// Periodically releases the state of closed client connections and of
+/// imported sessions left unclaimed, and removes the persistent resources
+/// left unused for longer than their idle timeout.
+pub(crate) struct ConnectionReaper {
+    #[cfg(not(feature = "async"))]
+    stop_tx: mpsc::Sender<()>,
+    #[cfg(not(feature = "async"))]
+    handle: thread::JoinHandle<()>,
+    #[cfg(feature = "async")]
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl ConnectionReaper {
+    pub(crate) const INTERVAL: Duration = Duration::from_secs(1);
+
+    #[cfg(not(feature = "async"))]
+    pub(crate) fn start(service: Arc<AgentService>) -> Self {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(Self::INTERVAL) {
+                service.reap_connections();
+                service.expire_persistent();
+                service.expire_imported();
+            }
+        });
+
+        debug!("Started connection reaper");
+        Self { stop_tx, handle }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn start(service: Arc<AgentService>) -> Self {
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Self::INTERVAL);
+            loop {
+                interval.tick().await;
+                // Releasing a session waits for its in-flight requests
+                let _ = service
+                    .blocking(|s| {
+                        s.reap_connections();
+                        s.expire_persistent();
+                        s.expire_imported();
+                        Ok(())
+                    })
+                    .await;
+            }
+        });
+
+        debug!("Started connection reaper");
+        Self { handle }
+    }
+
+    #[cfg(not(feature = "async"))]
+    pub(crate) fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn stop(self) {
+        self.handle.abort();
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent::resolve_uri,
+    cli::{CtlArgs, CtlCommand},
+    Error, Result,
+};
+use std::fs;
+use ttrpc::{context::Context, Client};
+use vaccel_rpc_proto::{
+    admin::{
+        DestroySessionRequest, ExportSessionRequest, ImportSessionRequest, RemovePersistentRequest,
+    },
+    empty::Empty,
+    sync::admin_service_ttrpc::AdminServiceClient,
+};
+
+/// Runs a `ctl` command against the admin server of an agent.
+pub fn run(args: &CtlArgs) -> Result<()> {
+    let address = resolve_uri(&args.admin_address)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::InvalidArgument("Invalid admin address".into()))?;
+    let client = AdminServiceClient::new(Client::connect(&address)?);
+
+    match &args.command {
+        CtlCommand::ListSessions => {
+            let resp = client.list_sessions(Context::default(), &Empty::new())?;
+
+            println!(
+                "{:<10} {:<10} {:<12} {:<32} RESOURCES",
+                "ID", "FLAGS", "CREATED", "PEER"
+            );
+            for sess in resp.sessions {
+                if sess.busy {
+                    println!("{:<10} (busy)", sess.id);
+                    continue;
+                }
+                let resources = sess
+                    .resource_ids
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                println!(
+                    "{:<10} {:<#10x} {:<12} {:<32} {}",
+                    sess.id, sess.flags, sess.created_at, sess.peer, resources
+                );
+            }
+        }
+        CtlCommand::ListResources => {
+            let resp = client.list_resources(Context::default(), &Empty::new())?;
+
+            println!(
+                "{:<10} {:<8} {:<10} {:<24} BLOBS",
+                "ID", "TYPE", "REFCOUNT", "NAME"
+            );
+            for res in resp.resources {
+                let blobs = res
+                    .blobs
+                    .iter()
+                    .map(|b| format!("{} ({} bytes)", b.name, b.size))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let name = match (res.name.as_str(), res.client.as_str()) {
+                    ("", _) => "-".to_string(),
+                    (name, "") => name.to_string(),
+                    (name, client) => format!("{}/{}", client, name),
+                };
+                println!(
+                    "{:<10} {:<8} {:<10} {:<24} {}",
+                    res.id,
+                    format!("{:?}", res.resource_type.enum_value_or_default()),
+                    res.refcount,
+                    name,
+                    blobs
+                );
+            }
+        }
+        CtlCommand::DestroySession { session_id } => {
+            let req = DestroySessionRequest {
+                session_id: *session_id,
+                ..Default::default()
+            };
+            client.force_destroy_session(Context::default(), &req)?;
+
+            println!("Destroyed session {}", session_id);
+        }
+        CtlCommand::ExportSession {
+            session_id,
+            archive,
+        } => {
+            let req = ExportSessionRequest {
+                session_id: *session_id,
+                ..Default::default()
+            };
+            let resp = client.export_session(Context::default(), &req)?;
+            fs::write(archive, &resp.archive)?;
+
+            println!("Exported session {} to {}", session_id, archive.display());
+        }
+        CtlCommand::ImportSession { archive } => {
+            let req = ImportSessionRequest {
+                archive: fs::read(archive)?,
+                ..Default::default()
+            };
+            let resp = client.import_session(Context::default(), &req)?;
+
+            println!(
+                "Imported session {} of agent `{}` as session {}",
+                resp.session.old_id, resp.source, resp.session.new_id
+            );
+            println!("{:<10} NEW ID", "OLD ID");
+            for res in resp.resources {
+                println!("{:<10} {}", res.old_id, res.new_id);
+            }
+        }
+        CtlCommand::RemoveResource {
+            name,
+            client: owner,
+        } => {
+            let req = RemovePersistentRequest {
+                name: name.clone(),
+                client: owner.clone().unwrap_or_default(),
+                ..Default::default()
+            };
+            client.remove_persistent_resource(Context::default(), &req)?;
+
+            println!("Removed persistent resource `{}`", name);
+        }
+    }
+
+    Ok(())
+}
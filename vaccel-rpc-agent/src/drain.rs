@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::agent_service::AgentService;
+use log::{info, warn};
+use std::sync::atomic::Ordering;
+use vaccel::VaccelId;
+
+impl AgentService {
+    /// Rejects any new requests from now on.
+    pub(crate) fn begin_drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests currently served across all
+    /// connections.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.connections.iter().map(|c| c.in_flight).sum()
+    }
+
+    /// Destroys all sessions, unloading their models and unregistering their
+    /// resources, and then destroys any remaining resources.
+    pub(crate) fn release_all(&self) {
+        let sessions: Vec<VaccelId> = self.sessions.iter().map(|s| *s.key()).collect();
+        if !sessions.is_empty() {
+            warn!("Forcing release of {} open session(s)", sessions.len());
+        }
+        for sess_id in sessions {
+            if let Err(e) = self.destroy_session(sess_id) {
+                warn!("Could not release session {}: {}", sess_id, e);
+            }
+        }
+
+        self.preloaded.clear();
+        self.batching.clear();
+        self.persistent.clear();
+        self.resource_clients.clear();
+        self.cache.lock().unwrap().clear_resources();
+        let resources: Vec<VaccelId> = self.resources.iter().map(|r| *r.key()).collect();
+        for res_id in resources {
+            info!("Destroying resource {}", res_id);
+            self.resources.remove(&res_id);
+            self.remove_blob_dir(res_id);
+        }
+
+        self.uploads.clear();
+        self.sync_state.clear();
+        self.connections.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent_service::AgentServiceError,
+        testing::{bootstrap, request_context},
+    };
+    use protobuf::EnumOrUnknown;
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+    use vaccel_rpc_proto::{
+        resource::{Blob, BlobType, RegisterRequest, ResourceType},
+        session::CreateRequest,
+        tflite,
+    };
+
+    #[test]
+    fn drain_rejects_new_requests_and_releases_state() {
+        bootstrap();
+
+        let service = AgentService::new();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+
+        let sess = service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap()
+            .session_id;
+        let resource = service
+            .do_register_resource(
+                &ctx,
+                RegisterRequest {
+                    blobs: vec![Blob {
+                        type_: EnumOrUnknown::new(BlobType::BUFFER),
+                        name: "model".to_string(),
+                        data: vec![0u8; 64],
+                        size: 64,
+                        ..Default::default()
+                    }],
+                    resource_type: EnumOrUnknown::new(ResourceType::MODEL),
+                    session_id: sess,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .resource_id;
+        service
+            .do_tflite_model_load(
+                &ctx,
+                tflite::ModelLoadRequest {
+                    session_id: sess,
+                    model_id: resource,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let in_flight = service.begin_request(&ctx).unwrap();
+        service.begin_drain();
+        assert!(service.is_draining());
+        assert!(matches!(
+            service.begin_request(&ctx),
+            Err(AgentServiceError::Unavailable(_))
+        ));
+
+        // Requests already being served are still accounted
+        assert_eq!(service.in_flight(), 1);
+        drop(in_flight);
+        assert_eq!(service.in_flight(), 0);
+
+        service.release_all();
+        assert!(service.sessions.is_empty());
+        assert!(service.resources.is_empty());
+        assert!(service.connections.is_empty());
+        assert!(vaccel::ffi::vaccel_stub_model_unloaded(sess, resource));
+    }
+}
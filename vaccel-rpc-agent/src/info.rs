@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::agent_service::{AgentService, Result};
+use log::{info, warn};
+#[cfg(feature = "async")]
+use vaccel_rpc_proto::asynchronous::agent;
+#[cfg(not(feature = "async"))]
+use vaccel_rpc_proto::sync::agent;
+use vaccel_rpc_proto::{
+    info::{Request, Response},
+    PROTOCOL_VERSION,
+};
+
+/// RPCs of the service not implemented on this target.
+#[cfg(target_pointer_width = "64")]
+const UNSUPPORTED_RPCS: &[&str] = &[];
+#[cfg(not(target_pointer_width = "64"))]
+const UNSUPPORTED_RPCS: &[&str] = &[
+    "TensorflowModelLoad",
+    "TensorflowModelUnload",
+    "TensorflowModelRun",
+];
+
+/// Returns the RPCs served by the agent, as defined by the service
+/// descriptor.
+pub(crate) fn rpcs() -> Vec<String> {
+    agent::file_descriptor()
+        .proto()
+        .service
+        .iter()
+        .flat_map(|s| s.method.iter())
+        .map(|m| m.name().to_string())
+        .filter(|m| !UNSUPPORTED_RPCS.contains(&m.as_str()))
+        .collect()
+}
+
+impl AgentService {
+    pub const VERSION: &'static str = match option_env!("VACCEL_RPC_VERSION") {
+        Some(v) => v,
+        None => env!("CARGO_PKG_VERSION"),
+    };
+
+    pub(crate) fn do_get_agent_info(&self, req: Request) -> Result<Response> {
+        info!(
+            "Agent info requested by client with protocol version {}",
+            req.protocol_version
+        );
+        if req.protocol_version != PROTOCOL_VERSION {
+            warn!(
+                "Client protocol version {} does not match agent protocol version {}",
+                req.protocol_version, PROTOCOL_VERSION
+            );
+        }
+
+        let mut resp = Response::new();
+        resp.agent_version = Self::VERSION.to_string();
+        resp.protocol_version = PROTOCOL_VERSION;
+        resp.vaccel_version = vaccel::VERSION.to_string();
+        resp.rpcs = rpcs();
+        resp.plugins = vaccel::plugins();
+        resp.instance_id = self.instance_id.clone();
+        resp.loaded_vaccel_version_guess = vaccel::guess_loaded_version().unwrap_or_default();
+
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advertised_rpcs_match_the_service() {
+        let rpcs = rpcs();
+        for rpc in ["CreateSession", "RegisterResource", "GetAgentInfo"] {
+            assert!(rpcs.iter().any(|r| r == rpc), "{} is not advertised", rpc);
+        }
+        #[cfg(feature = "async")]
+        assert!(rpcs.iter().any(|r| r == "GenopStream"));
+        #[cfg(not(feature = "async"))]
+        assert!(!rpcs.iter().any(|r| r == "GenopStream"));
+    }
+}
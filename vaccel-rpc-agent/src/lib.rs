@@ -3,19 +3,42 @@
 use agent_service::AgentService;
 use thiserror::Error as ThisError;
 
+mod admin;
 pub mod agent;
 mod agent_service;
 #[cfg(feature = "async")]
 mod asynchronous;
+mod audit;
+mod auth;
+mod batch;
+mod cache;
+mod capture;
 pub mod cli;
+pub mod config;
+mod connection;
+pub mod ctl;
+mod drain;
+mod info;
+mod limits;
+mod metrics;
+mod migration;
 mod ops;
+mod reload;
+pub mod replay;
 mod resource;
 mod session;
+mod storage;
 #[cfg(not(feature = "async"))]
 mod sync;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod upload;
 
-pub use agent::Agent;
+pub use agent::{Agent, Reloaded};
 pub use cli::Cli;
+pub use config::Config;
+pub use limits::Limits;
 
 #[derive(ThisError, Debug)]
 pub enum Error {
@@ -28,9 +51,18 @@ pub enum Error {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("Protobuf error: {0}")]
+    Protobuf(#[from] protobuf::Error),
+
     #[error("Invalid argument: {0}")]
     InvalidArgument(String),
 
+    #[error("Config error: {0}")]
+    Config(String),
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
     #[error("Agent not running")]
     NotRunning,
 
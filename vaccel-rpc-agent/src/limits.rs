@@ -0,0 +1,333 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent_service::{AgentService, AgentServiceError, RequestContext, Result},
+    connection::ConnectionId,
+};
+use serde::Deserialize;
+use vaccel::VaccelId;
+
+/// Per-connection limits enforced by the agent.
+///
+/// A `None` value means that the respective quantity is not limited.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum number of sessions per connection
+    pub max_sessions: Option<usize>,
+    /// Maximum number of resources registered per connection
+    pub max_resources: Option<usize>,
+    /// Maximum total size, in bytes, of blobs uploaded per connection
+    pub max_blob_bytes: Option<usize>,
+    /// Maximum number of concurrently served requests per connection
+    pub max_in_flight: Option<usize>,
+}
+
+impl Limits {
+    /// Merges two sets of limits, with the values set in `other` taking
+    /// precedence.
+    pub(crate) fn merge(self, other: Limits) -> Self {
+        Limits {
+            max_sessions: other.max_sessions.or(self.max_sessions),
+            max_resources: other.max_resources.or(self.max_resources),
+            max_blob_bytes: other.max_blob_bytes.or(self.max_blob_bytes),
+            max_in_flight: other.max_in_flight.or(self.max_in_flight),
+        }
+    }
+}
+
+fn check(limit: Option<usize>, used: usize, requested: usize, what: &str) -> Result<()> {
+    match limit {
+        Some(max) if used.saturating_add(requested) > max => Err(
+            AgentServiceError::ResourceExhausted(format!("{} limit of {} reached", what, max)),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Accounts a request as in flight until dropped.
+pub(crate) struct InFlight<'a> {
+    service: &'a AgentService,
+    connection: ConnectionId,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(mut conn) = self.service.connections.get_mut(&self.connection) {
+            conn.in_flight = conn.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+/// A session reserved on a connection by `reserve_session()`, released if
+/// dropped before the session is created.
+pub(crate) struct SessionSlot<'a> {
+    service: &'a AgentService,
+    connection: ConnectionId,
+}
+
+impl SessionSlot<'_> {
+    /// Accounts the created session `sess_id` in place of the reservation.
+    pub(crate) fn fill(self, sess_id: VaccelId) {
+        if let Some(mut conn) = self.service.connections.get_mut(&self.connection) {
+            conn.sessions.insert(sess_id);
+        }
+    }
+}
+
+impl Drop for SessionSlot<'_> {
+    fn drop(&mut self) {
+        if let Some(mut conn) = self.service.connections.get_mut(&self.connection) {
+            conn.pending_sessions = conn.pending_sessions.saturating_sub(1);
+        }
+    }
+}
+
+impl AgentService {
+    pub(crate) fn limits(&self) -> Limits {
+        self.limits.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_limits(&self, limits: Limits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Accounts a request to its connection, failing if the connection has
+    /// reached the in-flight requests limit.
+    pub(crate) fn begin_request(&self, ctx: &RequestContext) -> Result<InFlight<'_>> {
+        if self.is_draining() {
+            return Err(AgentServiceError::Unavailable(
+                "Agent is shutting down".to_string(),
+            ));
+        }
+
+        let limits = self.limits();
+        let mut conn = self.connections.entry(ctx.connection).or_default();
+        check(
+            limits.max_in_flight,
+            conn.in_flight,
+            1,
+            "In-flight requests",
+        )?;
+        conn.in_flight += 1;
+
+        Ok(InFlight {
+            service: self,
+            connection: ctx.connection,
+        })
+    }
+
+    /// Reserves a session of a connection, failing if the connection has
+    /// reached the sessions limit, so that the session is only created
+    /// within the limit.
+    pub(crate) fn reserve_session(&self, conn_id: ConnectionId) -> Result<SessionSlot<'_>> {
+        let limits = self.limits();
+        let mut conn = self.connections.entry(conn_id).or_default();
+        check(
+            limits.max_sessions,
+            conn.sessions.len() + conn.pending_sessions,
+            1,
+            "Sessions",
+        )?;
+        conn.pending_sessions += 1;
+
+        Ok(SessionSlot {
+            service: self,
+            connection: conn_id,
+        })
+    }
+
+    /// Accounts a resource registration, and any blob bytes uploaded with it,
+    /// to a connection, failing if the connection has reached the resources
+    /// or the blob bytes limit.
+    pub(crate) fn charge_resource(&self, conn_id: ConnectionId, blob_bytes: usize) -> Result<()> {
+        let limits = self.limits();
+        let mut conn = self.connections.entry(conn_id).or_default();
+        check(limits.max_resources, conn.resources, 1, "Resources")?;
+        check(
+            limits.max_blob_bytes,
+            conn.blob_bytes,
+            blob_bytes,
+            "Blob bytes",
+        )?;
+        conn.resources += 1;
+        conn.blob_bytes += blob_bytes;
+
+        Ok(())
+    }
+
+    /// Accounts a session imported from another agent, and the resources
+    /// and blob bytes already registered with it, to the connection claiming
+    /// it, failing if the connection has reached the sessions limit.
+    pub(crate) fn charge_claimed_session(
+        &self,
+        conn_id: ConnectionId,
+        sess_id: VaccelId,
+        resources: usize,
+        blob_bytes: usize,
+    ) -> Result<()> {
+        self.reserve_session(conn_id)?.fill(sess_id);
+        if let Some(mut conn) = self.connections.get_mut(&conn_id) {
+            conn.resources += resources;
+            conn.blob_bytes += blob_bytes;
+        }
+
+        Ok(())
+    }
+
+    /// Accounts blob bytes received by an upload to a connection, failing
+    /// if the connection has reached the blob bytes limit.
+    pub(crate) fn charge_upload(&self, conn_id: ConnectionId, blob_bytes: usize) -> Result<()> {
+        let limits = self.limits();
+        let mut conn = self.connections.entry(conn_id).or_default();
+        check(
+            limits.max_blob_bytes,
+            conn.blob_bytes,
+            blob_bytes,
+            "Blob bytes",
+        )?;
+        conn.blob_bytes += blob_bytes;
+
+        Ok(())
+    }
+
+    /// Reverts the accounting of `charge_resource()`.
+    pub(crate) fn refund_resource(&self, conn_id: ConnectionId, blob_bytes: usize) {
+        if let Some(mut conn) = self.connections.get_mut(&conn_id) {
+            conn.resources = conn.resources.saturating_sub(1);
+            conn.blob_bytes = conn.blob_bytes.saturating_sub(blob_bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bootstrap, request_context};
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+    use vaccel_rpc_proto::session::CreateRequest;
+
+    fn connection(stream: &UnixStream) -> ConnectionId {
+        ConnectionId::from_fd(stream.as_raw_fd()).unwrap()
+    }
+
+    fn assert_exhausted<T: std::fmt::Debug>(ret: Result<T>) {
+        assert_eq!(
+            ret.unwrap_err().code(),
+            ttrpc::Code::RESOURCE_EXHAUSTED,
+            "expected a RESOURCE_EXHAUSTED error"
+        );
+    }
+
+    #[test]
+    fn merge_prefers_set_values_of_other() {
+        let base = Limits {
+            max_sessions: Some(1),
+            max_resources: Some(2),
+            ..Default::default()
+        };
+        let merged = base.merge(Limits {
+            max_resources: Some(3),
+            max_in_flight: Some(4),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            merged,
+            Limits {
+                max_sessions: Some(1),
+                max_resources: Some(3),
+                max_blob_bytes: None,
+                max_in_flight: Some(4),
+            }
+        );
+    }
+
+    #[test]
+    fn sessions_limit() {
+        bootstrap();
+
+        let service = AgentService::new();
+        service.set_limits(Limits {
+            max_sessions: Some(1),
+            ..Default::default()
+        });
+        let (_client, server) = UnixStream::pair().unwrap();
+        let (_other_client, other_server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+
+        service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap();
+        assert_exhausted(service.do_create_session(&ctx, CreateRequest::new()));
+        assert_eq!(service.sessions.len(), 1);
+
+        // Sessions being created count against the limit
+        let (_third_client, third_server) = UnixStream::pair().unwrap();
+        let third = request_context(third_server.as_raw_fd());
+        let slot = service.reserve_session(third.connection).unwrap();
+        assert_exhausted(service.do_create_session(&third, CreateRequest::new()));
+        drop(slot);
+        service
+            .do_create_session(&third, CreateRequest::new())
+            .unwrap();
+
+        // The limit applies per connection
+        let other = request_context(other_server.as_raw_fd());
+        service
+            .do_create_session(&other, CreateRequest::new())
+            .unwrap();
+    }
+
+    #[test]
+    fn resources_and_blob_bytes_limits() {
+        let service = AgentService::new();
+        service.set_limits(Limits {
+            max_resources: Some(2),
+            max_blob_bytes: Some(100),
+            ..Default::default()
+        });
+        let (_client, server) = UnixStream::pair().unwrap();
+        let conn = connection(&server);
+
+        service.charge_resource(conn, 60).unwrap();
+        assert_exhausted(service.charge_resource(conn, 60));
+        // A failed charge is not accounted
+        assert_eq!(service.connections.get(&conn).unwrap().resources, 1);
+        assert_eq!(service.connections.get(&conn).unwrap().blob_bytes, 60);
+
+        service.charge_resource(conn, 40).unwrap();
+        assert_exhausted(service.charge_resource(conn, 0));
+
+        service.refund_resource(conn, 40);
+        service.charge_resource(conn, 0).unwrap();
+    }
+
+    #[test]
+    fn in_flight_limit() {
+        let service = AgentService::new();
+        service.set_limits(Limits {
+            max_in_flight: Some(1),
+            ..Default::default()
+        });
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+
+        let in_flight = service.begin_request(&ctx).unwrap();
+        assert_exhausted(service.begin_request(&ctx).map(|_| ()));
+
+        drop(in_flight);
+        service.begin_request(&ctx).unwrap();
+    }
+
+    #[test]
+    fn unset_limits_are_not_enforced() {
+        let service = AgentService::new();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let conn = connection(&server);
+
+        for _ in 0..100 {
+            service.charge_resource(conn, usize::MAX / 100).unwrap();
+        }
+    }
+}
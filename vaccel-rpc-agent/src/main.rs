@@ -2,12 +2,17 @@
 
 use clap::Parser;
 #[cfg(not(feature = "async"))]
-use std::sync::mpsc::{self};
-#[cfg(not(feature = "async"))]
-use std::thread;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::process;
 #[cfg(feature = "async")]
 use tokio::signal::unix::{signal, SignalKind};
-use vaccel_rpc_agent::{Agent as VaccelRpcAgent, Cli};
+use vaccel_rpc_agent::{
+    cli::{CaptureArgs, Command, CtlArgs},
+    ctl, replay, Agent as VaccelRpcAgent, Cli, Config, Reloaded,
+};
 //#[cfg(feature = "async")]
 //use log::levelfilter;
 //#[cfg(feature = "async")]
@@ -15,46 +20,102 @@ use vaccel_rpc_agent::{Agent as VaccelRpcAgent, Cli};
 
 use env_logger::Env;
 #[allow(unused_imports)]
-use log::{debug, info};
-
-#[cfg(not(feature = "async"))]
-fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+use log::{debug, error, info, warn};
 
+fn load_config() -> Config {
     let cli = Cli::parse();
+    match &cli.command {
+        Some(Command::Ctl(args)) => run_ctl(args),
+        Some(Command::Capture(args)) => run_capture(args),
+        None => (),
+    }
+
+    Config::from_cli(&cli).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    })
+}
+
+/// Re-reads the agent configuration and applies it to `agent`.
+fn reload(agent: &mut VaccelRpcAgent, config: &mut Config) {
+    info!("Reloading configuration");
+    let reloaded = Config::from_cli(&Cli::parse()).and_then(|c| {
+        let reloaded = agent.reload(&c)?;
+        Ok((c, reloaded))
+    });
+    match reloaded {
+        Ok((c, Reloaded::All)) => {
+            *config = c;
+            info!("Configuration reloaded");
+        }
+        Ok((c, Reloaded::VaccelDeferred(reason))) => {
+            *config = c;
+            warn!(
+                "Configuration reloaded except for vAccel and the preloaded models, deferred to a reload with no active sessions: {}",
+                reason
+            );
+        }
+        Err(e) => error!("Could not reload configuration: {}", e),
+    }
+}
+
+fn run_ctl(args: &CtlArgs) -> ! {
+    match ctl::run(args) {
+        Ok(()) => process::exit(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_capture(args: &CaptureArgs) -> ! {
+    match replay::run(args) {
+        Ok(()) => process::exit(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
 
-    let mut agent = VaccelRpcAgent::new(&cli.server_address);
-    if let Some(vaccel_config) = cli.vaccel_config {
-        agent
-            .set_vaccel_config(vaccel_config.try_into().unwrap())
-            .unwrap();
+#[cfg(not(feature = "async"))]
+fn main() {
+    let mut config = load_config();
+    env_logger::Builder::from_env(Env::default().default_filter_or(config.log_level())).init();
+    if config.server.blocking_threads.is_some() {
+        warn!("Ignoring the number of blocking threads, which only applies to the async agent");
     }
 
+    let mut agent = VaccelRpcAgent::from_config(&config).unwrap();
+
     agent.start().unwrap();
 
     info!("vAccel RPC agent started");
 
-    // Hold the main thread until receiving SIGINT, SIGTERM or SIGHUP
-    let (tx, rx) = mpsc::channel();
-    thread::spawn(move || {
-        ctrlc::set_handler(move || {
-            tx.send(()).unwrap();
-        })
-        .expect("Error setting Ctrl-C handler");
-        info!(
-            "Listening on '{}', press Ctrl+C to exit",
-            &cli.server_address
-        );
-    });
+    // Hold the main thread until receiving SIGINT or SIGTERM, reloading the
+    // configuration on SIGHUP
+    let mut signals =
+        Signals::new([SIGINT, SIGTERM, SIGHUP]).expect("Error setting signal handlers");
+    info!(
+        "Listening on '{}', press Ctrl+C to exit",
+        config.server_addresses().join("', '")
+    );
+    for sig in signals.forever() {
+        if sig != SIGHUP {
+            break;
+        }
+        reload(&mut agent, &mut config);
+    }
 
-    rx.recv().unwrap();
-    agent.shutdown().unwrap();
+    info!("Shutting down");
+    agent.drain(config.drain_timeout()).unwrap();
 }
 
 #[cfg(feature = "async")]
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+fn main() {
+    let config = load_config();
+    env_logger::Builder::from_env(Env::default().default_filter_or(config.log_level())).init();
     /*
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::TRACE)
@@ -62,33 +123,42 @@ async fn main() {
         .init();
     */
 
-    let cli = Cli::parse();
+    // vAccel calls run on the blocking pool, so that they do not stall the
+    // runtime workers
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .max_blocking_threads(config.blocking_threads())
+        .build()
+        .expect("Error creating the tokio runtime")
+        .block_on(run(config));
+}
 
-    let mut agent = VaccelRpcAgent::new(&cli.server_address);
-    if let Some(vaccel_config) = cli.vaccel_config {
-        agent
-            .set_vaccel_config(vaccel_config.try_into().unwrap())
-            .unwrap();
-    }
+#[cfg(feature = "async")]
+async fn run(mut config: Config) {
+    let mut agent = VaccelRpcAgent::from_config(&config).unwrap();
 
     agent.start().await.unwrap();
 
     info!(
-        "vAccel async ttrpc server started. Address: {}",
-        &cli.server_address
+        "vAccel async ttrpc server started. Addresses: {}",
+        config.server_addresses().join(", ")
     );
 
     let mut sigint = signal(SignalKind::interrupt()).unwrap();
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
     let mut sighup = signal(SignalKind::hangup()).unwrap();
 
-    // Hold the main thread until receiving SIGINT, SIGTERM or SIGHUP
+    // Hold the main thread until receiving SIGINT or SIGTERM, reloading the
+    // configuration on SIGHUP
     info!("vAccel RPC agent is running, press Ctrl+C to exit");
-    tokio::select! {
-        _ = sigint.recv() => {}
-        _ = sigterm.recv() => {}
-        _ = sighup.recv() => {}
-    };
+    loop {
+        tokio::select! {
+            _ = sigint.recv() => break,
+            _ = sigterm.recv() => break,
+            _ = sighup.recv() => reload(&mut agent, &mut config),
+        };
+    }
 
-    agent.shutdown().await.unwrap();
+    info!("Shutting down");
+    agent.drain(config.drain_timeout()).await.unwrap();
 }
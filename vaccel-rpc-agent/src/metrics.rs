@@ -0,0 +1,342 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{info::rpcs, AgentService};
+use log::{debug, warn};
+use protobuf::Enum;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Upper bounds, in microseconds, of the request latency histogram buckets.
+const LATENCY_BUCKETS_US: [u64; 10] = [
+    500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000, 10_000_000,
+];
+
+/// Number of ttrpc status codes.
+const NR_CODES: usize = 17;
+
+#[derive(Debug, Default)]
+struct RpcMetrics {
+    requests: AtomicU64,
+    errors: [AtomicU64; NR_CODES],
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_US.len()],
+    latency_sum_us: AtomicU64,
+}
+
+/// Always-on request counters of the agent.
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    rpcs: HashMap<String, RpcMetrics>,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            rpcs: rpcs()
+                .into_iter()
+                .map(|rpc| (rpc, RpcMetrics::default()))
+                .collect(),
+            received_bytes: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// Records a served request.
+    pub(crate) fn observe(
+        &self,
+        rpc: &str,
+        latency: Duration,
+        received: u64,
+        sent: u64,
+        error: Option<ttrpc::Code>,
+    ) {
+        self.received_bytes.fetch_add(received, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(sent, Ordering::Relaxed);
+
+        let m = match self.rpcs.get(rpc) {
+            Some(m) => m,
+            None => {
+                debug!("No metrics for unknown RPC {}", rpc);
+                return;
+            }
+        };
+
+        m.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(code) = error {
+            if let Some(c) = m.errors.get(code.value() as usize) {
+                c.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let latency_us = latency.as_micros() as u64;
+        m.latency_sum_us.fetch_add(latency_us, Ordering::Relaxed);
+        if let Some(i) = LATENCY_BUCKETS_US.iter().position(|&b| latency_us <= b) {
+            m.latency_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl AgentService {
+    /// Renders the agent metrics in the Prometheus text format.
+    pub(crate) fn render_metrics(&self) -> String {
+        let m = &self.metrics;
+        let mut rpcs: Vec<(&String, &RpcMetrics)> = m.rpcs.iter().collect();
+        rpcs.sort_by_key(|(rpc, _)| *rpc);
+
+        let mut out = String::new();
+
+        out.push_str("# HELP vaccel_rpc_agent_requests_total Requests served per RPC.\n");
+        out.push_str("# TYPE vaccel_rpc_agent_requests_total counter\n");
+        for (rpc, r) in &rpcs {
+            let _ = writeln!(
+                out,
+                "vaccel_rpc_agent_requests_total{{rpc=\"{}\"}} {}",
+                rpc,
+                r.requests.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP vaccel_rpc_agent_errors_total Failed requests per RPC and code.\n");
+        out.push_str("# TYPE vaccel_rpc_agent_errors_total counter\n");
+        for (rpc, r) in &rpcs {
+            for (i, c) in r.errors.iter().enumerate() {
+                let count = c.load(Ordering::Relaxed);
+                if count == 0 {
+                    continue;
+                }
+                let code = ttrpc::Code::from_i32(i as i32).unwrap_or_default();
+                let _ = writeln!(
+                    out,
+                    "vaccel_rpc_agent_errors_total{{rpc=\"{}\",code=\"{:?}\"}} {}",
+                    rpc, code, count
+                );
+            }
+        }
+
+        out.push_str("# HELP vaccel_rpc_agent_request_duration_seconds Request latency per RPC.\n");
+        out.push_str("# TYPE vaccel_rpc_agent_request_duration_seconds histogram\n");
+        for (rpc, r) in &rpcs {
+            let mut cumulative = 0;
+            for (i, bound) in LATENCY_BUCKETS_US.iter().enumerate() {
+                cumulative += r.latency_buckets[i].load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "vaccel_rpc_agent_request_duration_seconds_bucket{{rpc=\"{}\",le=\"{}\"}} {}",
+                    rpc,
+                    *bound as f64 / 1e6,
+                    cumulative
+                );
+            }
+            let count = r.requests.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "vaccel_rpc_agent_request_duration_seconds_bucket{{rpc=\"{}\",le=\"+Inf\"}} {}",
+                rpc, count
+            );
+            let _ = writeln!(
+                out,
+                "vaccel_rpc_agent_request_duration_seconds_sum{{rpc=\"{}\"}} {}",
+                rpc,
+                r.latency_sum_us.load(Ordering::Relaxed) as f64 / 1e6
+            );
+            let _ = writeln!(
+                out,
+                "vaccel_rpc_agent_request_duration_seconds_count{{rpc=\"{}\"}} {}",
+                rpc, count
+            );
+        }
+
+        out.push_str("# HELP vaccel_rpc_agent_sessions Active sessions.\n");
+        out.push_str("# TYPE vaccel_rpc_agent_sessions gauge\n");
+        let _ = writeln!(out, "vaccel_rpc_agent_sessions {}", self.sessions.len());
+
+        out.push_str("# HELP vaccel_rpc_agent_resources Active resources.\n");
+        out.push_str("# TYPE vaccel_rpc_agent_resources gauge\n");
+        let _ = writeln!(out, "vaccel_rpc_agent_resources {}", self.resources.len());
+
+        out.push_str("# HELP vaccel_rpc_agent_received_bytes_total Request payload bytes.\n");
+        out.push_str("# TYPE vaccel_rpc_agent_received_bytes_total counter\n");
+        let _ = writeln!(
+            out,
+            "vaccel_rpc_agent_received_bytes_total {}",
+            m.received_bytes.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP vaccel_rpc_agent_sent_bytes_total Response payload bytes.\n");
+        out.push_str("# TYPE vaccel_rpc_agent_sent_bytes_total counter\n");
+        let _ = writeln!(
+            out,
+            "vaccel_rpc_agent_sent_bytes_total {}",
+            m.sent_bytes.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+/// A minimal HTTP server exposing the agent metrics at `/metrics`.
+pub(crate) struct MetricsServer {
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub(crate) fn start(address: &str, service: Arc<AgentService>) -> io::Result<Self> {
+        let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Invalid metrics address")
+        })?;
+        if !address.ip().is_loopback() {
+            warn!("Metrics are exposed on non-loopback address {}", address);
+        }
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop_flag.load(Ordering::Relaxed) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = handle_request(stream, &service) {
+                            debug!("Could not serve metrics request: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Could not accept metrics connection: {}", e),
+                }
+            }
+        });
+
+        debug!("Serving metrics on http://{}/metrics", address);
+        Ok(Self {
+            address,
+            stop,
+            handle,
+        })
+    }
+
+    pub(crate) fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake up the listener thread
+        let _ = TcpStream::connect(self.address);
+        let _ = self.handle.join();
+    }
+}
+
+fn handle_request(mut stream: TcpStream, service: &AgentService) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // Read the whole request head, which may arrive in more than one
+    // segment, so that closing the connection does not reset it
+    let mut reader = BufReader::new((&stream).take(8192));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", service.render_metrics()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn renders_observed_requests() {
+        let service = AgentService::new();
+        let metrics = &service.metrics;
+        metrics.observe("CreateSession", Duration::from_millis(2), 10, 20, None);
+        metrics.observe(
+            "CreateSession",
+            Duration::from_secs(3),
+            5,
+            0,
+            Some(ttrpc::Code::NOT_FOUND),
+        );
+        // Only the bytes of unknown RPCs are accounted
+        metrics.observe("Unknown", Duration::from_secs(1), 1, 2, None);
+
+        let out = service.render_metrics();
+        let lines: Vec<&str> = out.lines().collect();
+        for expected in [
+            r#"vaccel_rpc_agent_requests_total{rpc="CreateSession"} 2"#,
+            r#"vaccel_rpc_agent_requests_total{rpc="DestroySession"} 0"#,
+            r#"vaccel_rpc_agent_errors_total{rpc="CreateSession",code="NOT_FOUND"} 1"#,
+            r#"vaccel_rpc_agent_request_duration_seconds_bucket{rpc="CreateSession",le="0.001"} 0"#,
+            r#"vaccel_rpc_agent_request_duration_seconds_bucket{rpc="CreateSession",le="0.005"} 1"#,
+            r#"vaccel_rpc_agent_request_duration_seconds_bucket{rpc="CreateSession",le="5"} 2"#,
+            r#"vaccel_rpc_agent_request_duration_seconds_bucket{rpc="CreateSession",le="+Inf"} 2"#,
+            r#"vaccel_rpc_agent_request_duration_seconds_sum{rpc="CreateSession"} 3.002"#,
+            r#"vaccel_rpc_agent_request_duration_seconds_count{rpc="CreateSession"} 2"#,
+            "vaccel_rpc_agent_sessions 0",
+            "vaccel_rpc_agent_received_bytes_total 16",
+            "vaccel_rpc_agent_sent_bytes_total 22",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "missing `{}` in:\n{}",
+                expected,
+                out
+            );
+        }
+        assert!(!out.contains("Unknown"));
+        // No error series are rendered for codes that did not occur
+        assert_eq!(out.matches("vaccel_rpc_agent_errors_total{").count(), 1);
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let server = MetricsServer::start("127.0.0.1:0", Arc::new(AgentService::new())).unwrap();
+
+        let response = get(server.address, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\n\r\n# HELP vaccel_rpc_agent_requests_total"));
+
+        let response = get(server.address, "/other");
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+
+        server.stop();
+    }
+}
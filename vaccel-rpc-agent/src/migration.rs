@@ -0,0 +1,525 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent_service::{AgentService, AgentServiceError, RequestContext, Result},
+    cache::Scope,
+    connection::ConnectionId,
+    session::{AgentSession, ModelType},
+};
+use log::{info, warn};
+use protobuf::{EnumOrUnknown, Message, MessageField};
+use sha2::{Digest as _, Sha256};
+use std::{
+    fs,
+    os::unix::io::RawFd,
+    process,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use vaccel::{Blob, Session, VaccelId};
+use vaccel_rpc_proto::{
+    admin::{
+        ArchivedResource, ExportSessionRequest, ExportSessionResponse, ImportSessionRequest,
+        ImportSessionResponse, ModelType as ProtoModelType, SessionArchive,
+    },
+    resource::{Blob as ProtoBlob, RegisterRequest},
+    session::{IdMapping, ResolveIdsRequest, ResolveIdsResponse},
+};
+
+/// The version of the session archive format.
+const ARCHIVE_VERSION: u32 = 1;
+
+/// The time an imported session waits to be claimed before it is released.
+pub(crate) const CLAIM_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Identifies an imported session by the instance id of the agent it was
+/// exported from and its id on that agent, as ids of different agents may
+/// collide.
+pub(crate) type ImportKey = (String, i64);
+
+/// Returns a random id for the agent instance.
+pub(crate) fn instance_id() -> String {
+    let mut buf = [0u8; 16];
+    let n = unsafe { libc::getrandom(buf.as_mut_ptr().cast(), buf.len(), 0) };
+    if n != buf.len() as isize {
+        // Unique enough for telling agents apart
+        let seed = format!("{}:{:?}", process::id(), SystemTime::now());
+        buf.copy_from_slice(&Sha256::digest(seed)[..16]);
+    }
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A session imported from another agent, until claimed by a client
+/// connection.
+#[derive(Debug)]
+pub(crate) struct ImportedSession {
+    pub(crate) session: VaccelId,
+    /// The token name of the client the session was exported for
+    client: Scope,
+    imported_at: Instant,
+    resources: Vec<IdMapping>,
+}
+
+fn id_mapping(old_id: i64, new_id: i64) -> IdMapping {
+    IdMapping {
+        old_id,
+        new_id,
+        ..Default::default()
+    }
+}
+
+/// Converts a blob of a resource, reading its file if the data is not in
+/// memory.
+fn archive_blob(blob: &Blob, res_id: VaccelId) -> Result<ProtoBlob> {
+    let data = match (blob.data(), blob.path()?) {
+        (Some(d), _) => d.to_vec(),
+        (None, _) if blob.size() == 0 => Vec::new(),
+        (None, Some(path)) => fs::read(&path)
+            .map_err(|e| AgentServiceError::Internal(format!("Could not read {}: {}", path, e)))?,
+        (None, None) => {
+            return Err(AgentServiceError::Internal(format!(
+                "Blob of resource {} has no data",
+                res_id
+            )))
+        }
+    };
+
+    let mut proto_blob = ProtoBlob::try_from(blob)?;
+    proto_blob.data = data;
+    Ok(proto_blob)
+}
+
+impl From<ModelType> for ProtoModelType {
+    fn from(model_type: ModelType) -> Self {
+        match model_type {
+            ModelType::Tensorflow => ProtoModelType::TENSORFLOW,
+            ModelType::TensorflowLite => ProtoModelType::TENSORFLOW_LITE,
+            ModelType::Torch => ProtoModelType::TORCH,
+        }
+    }
+}
+
+impl AgentService {
+    /// Exports the state of a session, i.e. its flags and its resources
+    /// with their blobs and loaded models, as a portable archive.
+    pub(crate) fn do_export_session(
+        &self,
+        req: ExportSessionRequest,
+    ) -> Result<ExportSessionResponse> {
+        let sess_id = req.session_id.try_into()?;
+        let sess = self.session(sess_id)?;
+        let sess = sess.lock().unwrap();
+
+        let mut archive = SessionArchive::new();
+        archive.version = ARCHIVE_VERSION;
+        archive.session_id = sess_id.into();
+        archive.flags = sess.flags();
+        archive.client = sess.client.clone().unwrap_or_default();
+        archive.source = self.instance_id.clone();
+
+        let mut resources: Vec<VaccelId> = sess.resources.iter().copied().collect();
+        resources.sort_by_key(|&id| i64::from(id));
+        for res_id in resources {
+            let res = self.resource(res_id, "resource")?;
+            let mut res = res.lock().unwrap();
+
+            let blobs = res
+                .blobs()?
+                .iter()
+                .map(|blob| archive_blob(blob, res_id))
+                .collect::<Result<Vec<ProtoBlob>>>()?;
+
+            archive.resources.push(ArchivedResource {
+                id: res_id.into(),
+                resource_type: EnumOrUnknown::from_i32(u32::from(res.type_()) as i32),
+                blobs,
+                name: self
+                    .persistent
+                    .get(&res_id)
+                    .map(|p| p.name.clone())
+                    .unwrap_or_default(),
+                preloaded: self
+                    .preloaded
+                    .get(&res_id)
+                    .map(|n| n.clone())
+                    .unwrap_or_default(),
+                loaded_model: sess
+                    .models
+                    .get(&res_id)
+                    .map(|&m| ProtoModelType::from(m))
+                    .unwrap_or(ProtoModelType::NONE)
+                    .into(),
+                ..Default::default()
+            });
+        }
+
+        let mut resp = ExportSessionResponse::new();
+        resp.archive = archive
+            .write_to_bytes()
+            .map_err(|e| AgentServiceError::Internal(format!("Could not write archive: {}", e)))?;
+
+        info!(
+            "Exported session {} with {} resource(s)",
+            sess_id,
+            archive.resources.len()
+        );
+        Ok(resp)
+    }
+
+    /// Recreates a session exported by another agent.
+    ///
+    /// The session is held for the admin connection of `fd` until a client
+    /// with the token name of the exporting client claims it by the instance
+    /// id of the exporting agent and its old id with `do_resolve_ids()`, or released if it is not claimed within
+    /// `CLAIM_TIMEOUT`. Persistent resources are persisted for that client.
+    pub(crate) fn do_import_session(
+        &self,
+        fd: RawFd,
+        req: ImportSessionRequest,
+    ) -> Result<ImportSessionResponse> {
+        let archive = SessionArchive::parse_from_bytes(&req.archive)
+            .map_err(|e| AgentServiceError::InvalidArgument(format!("Invalid archive: {}", e)))?;
+        if archive.version != ARCHIVE_VERSION {
+            return Err(AgentServiceError::InvalidArgument(format!(
+                "Unsupported archive version {}",
+                archive.version
+            )));
+        }
+        let key = (archive.source.clone(), archive.session_id);
+        if self.imported.contains_key(&key) {
+            return Err(AgentServiceError::InvalidArgument(format!(
+                "Session {} of agent `{}` is already imported",
+                archive.session_id, archive.source
+            )));
+        }
+
+        let owner = ConnectionId::from_fd(fd).map_err(|e| {
+            AgentServiceError::Internal(format!("Could not identify connection: {}", e))
+        })?;
+
+        let client = Some(archive.client).filter(|c| !c.is_empty());
+
+        let _vaccel = self.vaccel_guard();
+        let sess = Session::with_flags(archive.flags)?;
+        let sess_id = sess.id().ok_or(AgentServiceError::Internal(
+            "Invalid session ID".to_string(),
+        ))?;
+        let entry = Arc::new(Mutex::new(AgentSession::new(sess, owner, client.clone())));
+        let e = self.sessions.insert(sess_id, entry.clone());
+        assert!(e.is_none());
+
+        let mut sess = entry.lock().unwrap();
+        let mut resources = Vec::new();
+        for res in archive.resources {
+            let old_id = res.id;
+            match self.import_resource(res, &client, sess_id, &mut sess) {
+                Ok(res_id) => resources.push(id_mapping(old_id, res_id.into())),
+                Err(e) => {
+                    drop(sess);
+                    let _ = self.release_session(sess_id);
+                    return Err(e);
+                }
+            }
+        }
+        drop(sess);
+
+        info!(
+            "Imported session {} of agent `{}` as session {} with {} resource(s)",
+            archive.session_id,
+            archive.source,
+            sess_id,
+            resources.len()
+        );
+
+        let mut resp = ImportSessionResponse::new();
+        resp.session = MessageField::some(id_mapping(archive.session_id, sess_id.into()));
+        resp.resources = resources.clone();
+        resp.source = archive.source;
+        self.imported.insert(
+            key,
+            ImportedSession {
+                session: sess_id,
+                client,
+                imported_at: Instant::now(),
+                resources,
+            },
+        );
+
+        Ok(resp)
+    }
+
+    /// Registers an archived resource with an imported session, loading it
+    /// as a model if it was loaded in the exported session.
+    fn import_resource(
+        &self,
+        res: ArchivedResource,
+        client: &Scope,
+        sess_id: VaccelId,
+        sess: &mut AgentSession,
+    ) -> Result<VaccelId> {
+        let preloaded = self
+            .preloaded
+            .iter()
+            .find(|p| !res.preloaded.is_empty() && *p.value() == res.preloaded)
+            .map(|p| *p.key());
+
+        let res_id = match preloaded {
+            Some(res_id) => self.register_existing(res_id, sess_id.into(), sess)?,
+            None => {
+                let blob_bytes: usize = res.blobs.iter().map(|b| b.data.len()).sum();
+                let mut req = RegisterRequest::new();
+                req.resource_type = res.resource_type;
+                req.blobs = res.blobs;
+                req.name = res.name;
+                req.session_id = sess_id.into();
+
+                let res_id = self.register_resource(req, client, None, None, sess)?;
+                if blob_bytes > 0 {
+                    sess.blob_bytes.insert(res_id, blob_bytes);
+                }
+                res_id
+            }
+        };
+
+        let model_type = match res.loaded_model.enum_value_or_default() {
+            ProtoModelType::NONE => return Ok(res_id),
+            ProtoModelType::TENSORFLOW => ModelType::Tensorflow,
+            ProtoModelType::TENSORFLOW_LITE => ModelType::TensorflowLite,
+            ProtoModelType::TORCH => ModelType::Torch,
+        };
+        let entry = self.resource(res_id, "resource")?;
+        let mut vaccel_res = entry.lock().unwrap();
+        info!(
+            "session:{} Loading {:?} model {}",
+            sess_id, model_type, res_id
+        );
+        match model_type {
+            ModelType::Tensorflow => sess.tf_model_load(&mut vaccel_res).map(|_| ()),
+            ModelType::TensorflowLite => sess.tflite_model_load(&mut vaccel_res),
+            ModelType::Torch => sess.torch_model_load(&mut vaccel_res),
+        }?;
+        sess.models.insert(res_id, model_type);
+
+        Ok(res_id)
+    }
+
+    /// Hands the sessions imported from the requested source agent with the
+    /// requested old ids over to the request connection, returning the new
+    /// ids of the sessions and of their resources.
+    ///
+    /// Sessions exported for a client with another token name are left for
+    /// that client to claim.
+    pub(crate) fn do_resolve_ids(
+        &self,
+        ctx: &RequestContext,
+        req: ResolveIdsRequest,
+    ) -> Result<ResolveIdsResponse> {
+        let mut resp = ResolveIdsResponse::new();
+        for old_id in req.session_ids {
+            let key = (req.source.clone(), old_id);
+            let (key, imported) = match self.imported.remove_if(&key, |_, i| i.client == ctx.client)
+            {
+                Some(i) => i,
+                None => continue,
+            };
+
+            let entry = self.session(imported.session)?;
+            let mut sess = entry.lock().unwrap();
+            let blob_bytes = sess.blob_bytes.values().sum();
+            if let Err(e) = self.charge_claimed_session(
+                ctx.connection,
+                imported.session,
+                sess.resources.len(),
+                blob_bytes,
+            ) {
+                drop(sess);
+                self.imported.insert(key, imported);
+                return Err(e);
+            }
+            sess.owner = ctx.connection;
+            sess.peer = ctx.connection.peer();
+
+            info!(
+                "Connection {} claimed session {} imported from agent `{}` as session {}",
+                ctx.connection, old_id, req.source, imported.session
+            );
+            resp.sessions
+                .push(id_mapping(old_id, imported.session.into()));
+            resp.resources.extend(imported.resources);
+        }
+
+        Ok(resp)
+    }
+
+    /// Releases the imported sessions that have not been claimed within
+    /// `CLAIM_TIMEOUT`.
+    pub(crate) fn expire_imported(&self) {
+        let expired: Vec<(ImportKey, VaccelId)> = self
+            .imported
+            .iter()
+            .filter(|i| i.imported_at.elapsed() > CLAIM_TIMEOUT)
+            .map(|i| (i.key().clone(), i.session))
+            .collect();
+        for (key, sess_id) in expired {
+            // Claimed in the meantime
+            if self
+                .imported
+                .remove_if(&key, |_, i| i.session == sess_id)
+                .is_none()
+            {
+                continue;
+            }
+            match self.release_session(sess_id) {
+                Ok(()) => info!(
+                    "Session {} imported from agent `{}` was not claimed, released session {}",
+                    key.1, key.0, sess_id
+                ),
+                Err(e) => warn!("Could not release imported session {}: {}", sess_id, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bootstrap, request_context};
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+    use vaccel_rpc_proto::session::CreateRequest;
+
+    /// Exports a new session of `client`, returning its id and archive.
+    fn export(service: &AgentService, client: &str) -> (i64, SessionArchive) {
+        let (_peer, conn) = UnixStream::pair().unwrap();
+        let ctx = RequestContext {
+            client: Some(client.to_string()),
+            ..request_context(conn.as_raw_fd())
+        };
+        let sess_id = service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap()
+            .session_id;
+
+        let resp = service
+            .do_export_session(ExportSessionRequest {
+                session_id: sess_id,
+                ..Default::default()
+            })
+            .unwrap();
+        service
+            .destroy_session(sess_id.try_into().unwrap())
+            .unwrap();
+
+        (
+            sess_id,
+            SessionArchive::parse_from_bytes(&resp.archive).unwrap(),
+        )
+    }
+
+    fn import(service: &AgentService, admin: RawFd, archive: SessionArchive) -> ImportKey {
+        let resp = service
+            .do_import_session(
+                admin,
+                ImportSessionRequest {
+                    archive: archive.write_to_bytes().unwrap(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        (resp.source, resp.session.old_id)
+    }
+
+    /// Exports a new session of `client` and imports it back, returning the
+    /// key of the imported session.
+    fn export_and_import(service: &AgentService, admin: RawFd, client: &str) -> ImportKey {
+        let (sess_id, archive) = export(service, client);
+        let key = import(service, admin, archive);
+        assert_eq!(key, (service.instance_id.clone(), sess_id));
+
+        key
+    }
+
+    fn resolve(service: &AgentService, fd: RawFd, client: &str, key: &ImportKey) -> usize {
+        let ctx = RequestContext {
+            client: Some(client.to_string()),
+            ..request_context(fd)
+        };
+        service
+            .do_resolve_ids(
+                &ctx,
+                ResolveIdsRequest {
+                    session_ids: vec![key.1],
+                    source: key.0.clone(),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .sessions
+            .len()
+    }
+
+    #[test]
+    fn imported_sessions_are_claimed_by_the_exporting_client() {
+        bootstrap();
+        let service = AgentService::new();
+        let (_admin, admin) = UnixStream::pair().unwrap();
+        let (_client, conn) = UnixStream::pair().unwrap();
+
+        let key = export_and_import(&service, admin.as_raw_fd(), "alice");
+
+        assert_eq!(resolve(&service, conn.as_raw_fd(), "mallory", &key), 0);
+        assert!(service.imported.contains_key(&key));
+
+        assert_eq!(resolve(&service, conn.as_raw_fd(), "alice", &key), 1);
+        assert!(!service.imported.contains_key(&key));
+    }
+
+    #[test]
+    fn sessions_of_different_agents_are_imported_apart() {
+        bootstrap();
+        let service = AgentService::new();
+        let (_admin, admin) = UnixStream::pair().unwrap();
+        let (_client, conn) = UnixStream::pair().unwrap();
+
+        let (_, archive) = export(&service, "alice");
+        let first = import(
+            &service,
+            admin.as_raw_fd(),
+            SessionArchive {
+                source: "first".to_string(),
+                ..archive.clone()
+            },
+        );
+        let second = import(
+            &service,
+            admin.as_raw_fd(),
+            SessionArchive {
+                source: "second".to_string(),
+                ..archive
+            },
+        );
+        assert_eq!(first.1, second.1);
+
+        assert_eq!(resolve(&service, conn.as_raw_fd(), "alice", &second), 1);
+        assert!(service.imported.contains_key(&first));
+        assert!(!service.imported.contains_key(&second));
+    }
+
+    #[test]
+    fn unclaimed_imported_sessions_expire() {
+        bootstrap();
+        let service = AgentService::new();
+        let (_admin, admin) = UnixStream::pair().unwrap();
+
+        let key = export_and_import(&service, admin.as_raw_fd(), "alice");
+        let sess_id = service.imported.get(&key).unwrap().session;
+
+        service.expire_imported();
+        assert!(service.sessions.contains_key(&sess_id));
+
+        service.imported.get_mut(&key).unwrap().imported_at -= CLAIM_TIMEOUT;
+        service.expire_imported();
+        assert!(!service.imported.contains_key(&key));
+        assert!(!service.sessions.contains_key(&sess_id));
+    }
+}
@@ -1,20 +1,15 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::agent_service::{AgentService, AgentServiceError, Result};
+use crate::agent_service::{AgentService, AgentServiceError, RequestContext, Result};
 use log::info;
 use vaccel::{profiling::SessionProfiler, Arg};
 use vaccel_rpc_proto::genop::{Arg as ProtoArg, Request, Response};
 
 impl AgentService {
-    pub(crate) fn do_genop(&self, req: Request) -> Result<Response> {
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+    pub(crate) fn do_genop(&self, ctx: &RequestContext, req: Request) -> Result<Response> {
+        let _writes = self.writing_blobs();
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
+        let mut sess = sess.lock().unwrap();
         let sess_id = sess.id().ok_or(AgentServiceError::Internal(
             "Invalid session ID".to_string(),
         ))?;
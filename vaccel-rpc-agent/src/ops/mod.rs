@@ -6,3 +6,20 @@ pub mod image;
 pub mod tf;
 pub mod tflite;
 pub mod torch;
+
+use crate::agent_service::{AgentServiceError, Result};
+
+/// The maximum number of warm-up runs of a model load.
+pub(crate) const MAX_WARMUP_RUNS: u32 = 100;
+
+/// Fails if a model load asks for more than `MAX_WARMUP_RUNS` warm-up runs.
+pub(crate) fn check_warmup_runs(warmup_runs: u32) -> Result<()> {
+    if warmup_runs > MAX_WARMUP_RUNS {
+        return Err(AgentServiceError::InvalidArgument(format!(
+            "{} warm-up runs requested, at most {} are allowed",
+            warmup_runs, MAX_WARMUP_RUNS
+        )));
+    }
+
+    Ok(())
+}
@@ -1,8 +1,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::agent_service::{AgentService, AgentServiceError, Result};
+use crate::{
+    agent_service::{AgentService, RequestContext, Result},
+    session::ModelType,
+};
 use log::info;
-use vaccel::ops::tf::{Buffer, DynTensor, Node};
+use vaccel::{
+    ops::tf::{Buffer, DynTensor, Node},
+    VaccelId,
+};
 use vaccel_rpc_proto::tf::{
     ModelLoadRequest, ModelLoadResponse, ModelRunRequest, ModelRunResponse, ModelUnloadRequest,
     ModelUnloadResponse,
@@ -11,28 +17,19 @@ use vaccel_rpc_proto::tf::{
 impl AgentService {
     pub(crate) fn do_tensorflow_model_load(
         &self,
+        ctx: &RequestContext,
         req: ModelLoadRequest,
     ) -> Result<ModelLoadResponse> {
-        let mut res = self
-            .resources
-            .get_mut(&req.model_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown TensorFlow model {}", &req.model_id).to_string(),
-                )
-            })?;
-
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+        let res = self.client_resource(ctx, req.model_id.try_into()?, "TensorFlow model")?;
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
+
+        let mut sess = sess.lock().unwrap();
+        let mut res = res.lock().unwrap();
 
         info!("session:{} TensorFlow model load", &req.session_id);
         let status = sess.tf_model_load(&mut res)?;
+        sess.models
+            .insert(VaccelId::try_from(req.model_id)?, ModelType::Tensorflow);
 
         let mut resp = ModelLoadResponse::new();
         // FIXME: Either remove this or properly return graph_def
@@ -44,28 +41,18 @@ impl AgentService {
 
     pub(crate) fn do_tensorflow_model_unload(
         &self,
+        ctx: &RequestContext,
         req: ModelUnloadRequest,
     ) -> Result<ModelUnloadResponse> {
-        let mut res = self
-            .resources
-            .get_mut(&req.model_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown TensorFlow model {}", &req.model_id).to_string(),
-                )
-            })?;
-
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+        let res = self.client_resource(ctx, req.model_id.try_into()?, "TensorFlow model")?;
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
+
+        let mut sess = sess.lock().unwrap();
+        let mut res = res.lock().unwrap();
 
         info!("session:{} TensorFlow model unload", &req.session_id);
         let status = sess.tf_model_unload(&mut res)?;
+        sess.models.remove(&VaccelId::try_from(req.model_id)?);
 
         let mut resp = ModelUnloadResponse::new();
         resp.status = Some(status.try_into()?).into();
@@ -73,24 +60,14 @@ impl AgentService {
         Ok(resp)
     }
 
-    pub(crate) fn do_tensorflow_model_run(&self, req: ModelRunRequest) -> Result<ModelRunResponse> {
-        let mut res = self
-            .resources
-            .get_mut(&req.model_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown TensorFlow model {}", &req.model_id).to_string(),
-                )
-            })?;
-
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+    pub(crate) fn do_tensorflow_model_run(
+        &self,
+        ctx: &RequestContext,
+        req: ModelRunRequest,
+    ) -> Result<ModelRunResponse> {
+        let _writes = self.writing_blobs();
+        let res = self.client_resource(ctx, req.model_id.try_into()?, "TensorFlow model")?;
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
 
         let run_options = req.run_options.map(Buffer::new).transpose()?;
 
@@ -111,6 +88,9 @@ impl AgentService {
             .map(|e| e.try_into())
             .collect::<vaccel::Result<Vec<DynTensor>>>()?;
 
+        let mut sess = sess.lock().unwrap();
+        let mut res = res.lock().unwrap();
+
         info!("session:{} TensorFlow model run", &req.session_id);
         let (out_tensors, status) = sess.tf_model_run(
             &mut res,
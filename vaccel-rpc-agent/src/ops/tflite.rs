@@ -1,89 +1,147 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::agent_service::{AgentService, AgentServiceError, Result};
-use log::info;
-use std::num::TryFromIntError;
-use vaccel::ops::tf::lite::DynTensor;
+use crate::{
+    agent_service::{AgentService, AgentServiceError, RequestContext, Result},
+    ops::check_warmup_runs,
+    session::{AgentSession, ModelType},
+};
+use log::{info, warn};
+use std::{num::TryFromIntError, time::Duration};
+use vaccel::{
+    ops::{
+        tf::lite::{DataType, DynTensor},
+        warmup,
+    },
+    profiling::SessionProfiler,
+    Resource, VaccelId,
+};
 use vaccel_rpc_proto::{
     empty::Empty,
-    tflite::{ModelLoadRequest, ModelRunRequest, ModelRunResponse, ModelUnloadRequest},
+    tflite::{
+        ModelLoadRequest, ModelLoadResponse, ModelRunRequest, ModelRunResponse, ModelUnloadRequest,
+        Tensor,
+    },
+    vaccel::Status as ProtoStatus,
 };
 
 impl AgentService {
-    pub(crate) fn do_tflite_model_load(&self, req: ModelLoadRequest) -> Result<Empty> {
-        let mut res = self
-            .resources
-            .get_mut(&req.model_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown TensorFlow Lite model {}", &req.model_id).to_string(),
-                )
-            })?;
-
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+    pub(crate) fn do_tflite_model_load(
+        &self,
+        ctx: &RequestContext,
+        req: ModelLoadRequest,
+    ) -> Result<ModelLoadResponse> {
+        check_warmup_runs(req.warmup_runs)?;
+        let model_id = VaccelId::try_from(req.model_id)?;
+        let res = self.client_resource(ctx, model_id, "TensorFlow Lite model")?;
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
+
+        let mut sess = sess.lock().unwrap();
+        let mut res = res.lock().unwrap();
 
         info!("session:{} TensorFlow Lite model load", &req.session_id);
         sess.tflite_model_load(&mut res)?;
+        sess.models.insert(model_id, ModelType::TensorflowLite);
+
+        let mut resp = ModelLoadResponse::new();
+        if req.warmup_runs == 0 {
+            return Ok(resp);
+        }
+
+        let session_id = req.session_id;
+        match self.tflite_model_warmup(ctx, &mut sess, &mut res, req) {
+            Ok(warmup_time) => {
+                resp.warmup_time_ns = warmup_time.as_nanos().min(u64::MAX as u128) as u64;
+                Ok(resp)
+            }
+            Err(e) => {
+                info!("session:{} TensorFlow Lite model unload", session_id);
+                if let Err(e) = sess.tflite_model_unload(&mut res) {
+                    warn!(
+                        "session:{} Could not unload model {}: {}",
+                        session_id, model_id, e
+                    );
+                }
+                sess.models.remove(&model_id);
+                Err(e)
+            }
+        }
+    }
 
-        Ok(Empty::new())
+    /// Runs the warm-up inferences of a model load, returning the time they
+    /// took.
+    fn tflite_model_warmup(
+        &self,
+        ctx: &RequestContext,
+        sess: &mut AgentSession,
+        res: &mut Resource,
+        req: ModelLoadRequest,
+    ) -> Result<Duration> {
+        let sess_id = sess.id().ok_or(AgentServiceError::Internal(
+            "Invalid session ID".to_string(),
+        ))?;
+
+        let in_tensors = self.profile_fn(sess_id, "tflite_model_load > warmup_tensors", || {
+            req.warmup_tensors
+                .into_iter()
+                .map(|t| match t.data.is_empty() {
+                    true => DynTensor::new(&t.dims, DataType::from(t.type_.value() as u32)),
+                    false => t.try_into(),
+                })
+                .collect::<vaccel::Result<Vec<DynTensor>>>()
+        })?;
+
+        let nr_out_tensors = usize::try_from(req.nr_out_tensors.max(1)).map_err(|e| {
+            AgentServiceError::Internal(format!(
+                "Could not convert `nr_out_tensors` to `usize`: {}",
+                e
+            ))
+        })?;
+
+        info!(
+            "session:{} TensorFlow Lite model warm-up ({} runs)",
+            &req.session_id, req.warmup_runs
+        );
+        self.profile_fn(sess_id, "tflite_model_load > warmup", || {
+            warmup(
+                req.warmup_runs as usize,
+                || {
+                    sess.tflite_model_run(res, &in_tensors, nr_out_tensors)
+                        .map_err(AgentServiceError::from)
+                },
+                |_| ctx.check_deadline(),
+            )
+        })
     }
 
-    pub(crate) fn do_tflite_model_unload(&self, req: ModelUnloadRequest) -> Result<Empty> {
-        let mut res = self
-            .resources
-            .get_mut(&req.model_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown TensorFlow Lite model {}", &req.model_id).to_string(),
-                )
-            })?;
+    pub(crate) fn do_tflite_model_unload(
+        &self,
+        ctx: &RequestContext,
+        req: ModelUnloadRequest,
+    ) -> Result<Empty> {
+        let res = self.client_resource(ctx, req.model_id.try_into()?, "TensorFlow Lite model")?;
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
 
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+        let mut sess = sess.lock().unwrap();
+        let mut res = res.lock().unwrap();
 
         info!("session:{} TensorFlow Lite model unload", &req.session_id);
         sess.tflite_model_unload(&mut res)?;
+        sess.models.remove(&VaccelId::try_from(req.model_id)?);
 
         Ok(Empty::new())
     }
 
-    pub(crate) fn do_tflite_model_run(&self, req: ModelRunRequest) -> Result<ModelRunResponse> {
-        let mut res = self
-            .resources
-            .get_mut(&req.model_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown TensorFlow Lite model {}", &req.model_id).to_string(),
-                )
-            })?;
-
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+    pub(crate) fn do_tflite_model_run(
+        &self,
+        ctx: &RequestContext,
+        req: ModelRunRequest,
+    ) -> Result<ModelRunResponse> {
+        let _writes = self.writing_blobs();
+        let model_id = req.model_id.try_into()?;
+        let res = self.client_resource(ctx, model_id, "TensorFlow Lite model")?;
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
 
-        let in_tensors = req
-            .in_tensors
-            .into_iter()
-            .map(|e| e.try_into())
-            .collect::<vaccel::Result<Vec<DynTensor>>>()?;
+        let batching = self.batching(&sess, model_id, &req.in_tensors, req.nr_out_tensors, None)?;
 
         let nr_out_tensors = req
             .nr_out_tensors
@@ -94,13 +152,91 @@ impl AgentService {
                 )
             })?;
 
-        info!("session:{} TensorFlow Lite model run", &req.session_id);
-        let (out_tensors, status) = sess.tflite_model_run(&mut res, &in_tensors, nr_out_tensors)?;
+        let run = |in_tensors: Vec<Tensor>| -> Result<(Vec<Tensor>, ProtoStatus)> {
+            let in_tensors = in_tensors
+                .into_iter()
+                .map(|e| e.try_into())
+                .collect::<vaccel::Result<Vec<DynTensor>>>()?;
+
+            let mut sess = sess.lock().unwrap();
+            let mut res = res.lock().unwrap();
+
+            info!("session:{} TensorFlow Lite model run", &req.session_id);
+            let (out_tensors, status) =
+                sess.tflite_model_run(&mut res, &in_tensors, nr_out_tensors)?;
+            Ok((
+                out_tensors.into_iter().map(Into::into).collect(),
+                status.into(),
+            ))
+        };
+
+        let (out_tensors, status) = match batching {
+            Some((key, config)) => {
+                self.tflite_batches
+                    .run(key, &config, req.in_tensors, ctx.deadline, run)?
+            }
+            None => run(req.in_tensors)?,
+        };
 
         let mut resp = ModelRunResponse::new();
-        resp.out_tensors = out_tensors.into_iter().map(Into::into).collect();
-        resp.status = Some(status.into()).into();
+        resp.out_tensors = out_tensors;
+        resp.status = Some(status).into();
 
         Ok(resp)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bootstrap, create_session, register, request_context};
+    use protobuf::EnumOrUnknown;
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+    use vaccel_rpc_proto::tflite;
+
+    #[test]
+    fn failed_warmup_unloads_the_model() {
+        bootstrap();
+
+        let service = AgentService::new();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+        let sess = create_session(&service, &ctx);
+        let resource = register(&service, &ctx, sess, 0);
+        let load = |warmup_runs, type_| {
+            service.do_tflite_model_load(
+                &ctx,
+                tflite::ModelLoadRequest {
+                    session_id: sess,
+                    model_id: resource,
+                    warmup_runs,
+                    warmup_tensors: vec![tflite::Tensor {
+                        dims: vec![1],
+                        type_: EnumOrUnknown::new(type_),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                },
+            )
+        };
+        let loaded = || {
+            let sess = service.session(sess.try_into().unwrap()).unwrap();
+            let loaded = !sess.lock().unwrap().models.is_empty();
+            loaded
+        };
+
+        assert!(matches!(
+            load(crate::ops::MAX_WARMUP_RUNS + 1, tflite::DataType::FLOAT32),
+            Err(AgentServiceError::InvalidArgument(_))
+        ));
+        assert!(!loaded());
+
+        // Strings have no tensor type to warm up with
+        assert!(load(1, tflite::DataType::STRING).is_err());
+        assert!(!loaded());
+        assert!(vaccel::ffi::vaccel_stub_model_unloaded(sess, resource));
+
+        load(2, tflite::DataType::FLOAT32).unwrap();
+        assert!(loaded());
+    }
+}
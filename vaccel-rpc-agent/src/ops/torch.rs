@@ -1,66 +1,121 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::agent_service::{AgentService, AgentServiceError, Result};
+use crate::{
+    agent_service::{AgentService, AgentServiceError, RequestContext, Result},
+    ops::check_warmup_runs,
+    session::{AgentSession, ModelType},
+};
 use log::info;
-use std::num::TryFromIntError;
-use vaccel::ops::torch::{Buffer, DynTensor};
-use vaccel_rpc_proto::{
-    empty::Empty,
-    torch::{ModelLoadRequest, ModelRunRequest, ModelRunResponse},
+use std::{num::TryFromIntError, time::Duration};
+use vaccel::{
+    ops::{
+        torch::{Buffer, DataType, DynTensor},
+        warmup,
+    },
+    profiling::SessionProfiler,
+    Resource, VaccelId,
+};
+use vaccel_rpc_proto::torch::{
+    ModelLoadRequest, ModelLoadResponse, ModelRunRequest, ModelRunResponse, Tensor,
 };
 
 impl AgentService {
-    pub(crate) fn do_torch_model_load(&self, req: ModelLoadRequest) -> Result<Empty> {
-        let mut res = self
-            .resources
-            .get_mut(&req.model_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown PyTorch model {}", &req.model_id).to_string(),
-                )
-            })?;
+    pub(crate) fn do_torch_model_load(
+        &self,
+        ctx: &RequestContext,
+        req: ModelLoadRequest,
+    ) -> Result<ModelLoadResponse> {
+        check_warmup_runs(req.warmup_runs)?;
+        let model_id = VaccelId::try_from(req.model_id)?;
+        let res = self.client_resource(ctx, model_id, "PyTorch model")?;
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
 
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+        let mut sess = sess.lock().unwrap();
+        let mut res = res.lock().unwrap();
 
         info!("session:{} PyTorch model load", &req.session_id);
         sess.torch_model_load(&mut res)?;
+        sess.models.insert(model_id, ModelType::Torch);
+
+        let mut resp = ModelLoadResponse::new();
+        if req.warmup_runs == 0 {
+            return Ok(resp);
+        }
 
-        Ok(Empty::new())
+        // vAccel cannot unload PyTorch models, so the model stays loaded, and
+        // tracked by the session, if its warm-up fails
+        let warmup_time = self.torch_model_warmup(ctx, &mut sess, &mut res, req)?;
+        resp.warmup_time_ns = warmup_time.as_nanos().min(u64::MAX as u128) as u64;
+        Ok(resp)
     }
 
-    pub(crate) fn do_torch_model_run(&self, req: ModelRunRequest) -> Result<ModelRunResponse> {
-        let mut res = self
-            .resources
-            .get_mut(&req.model_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown Py model {}", &req.model_id).to_string(),
-                )
-            })?;
+    /// Runs the warm-up inferences of a model load, returning the time they
+    /// took.
+    fn torch_model_warmup(
+        &self,
+        ctx: &RequestContext,
+        sess: &mut AgentSession,
+        res: &mut Resource,
+        req: ModelLoadRequest,
+    ) -> Result<Duration> {
+        let sess_id = sess.id().ok_or(AgentServiceError::Internal(
+            "Invalid session ID".to_string(),
+        ))?;
 
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+        let in_tensors = self.profile_fn(sess_id, "torch_model_load > warmup_tensors", || {
+            req.warmup_tensors
+                .into_iter()
+                .map(|t| match t.data.is_empty() {
+                    true => DynTensor::new(&t.dims, DataType::from(t.type_.value() as u32)),
+                    false => t.try_into(),
+                })
+                .collect::<vaccel::Result<Vec<DynTensor>>>()
+        })?;
 
         let run_options = req.run_options.map(Buffer::new).transpose()?;
 
-        let in_tensors = req
-            .in_tensors
-            .into_iter()
-            .map(|e| e.try_into())
-            .collect::<vaccel::Result<Vec<DynTensor>>>()?;
+        let nr_out_tensors = usize::try_from(req.nr_out_tensors.max(1)).map_err(|e| {
+            AgentServiceError::Internal(format!(
+                "Could not convert `nr_out_tensors` to `usize`: {}",
+                e
+            ))
+        })?;
+
+        info!(
+            "session:{} PyTorch model warm-up ({} runs)",
+            &req.session_id, req.warmup_runs
+        );
+        self.profile_fn(sess_id, "torch_model_load > warmup", || {
+            warmup(
+                req.warmup_runs as usize,
+                || {
+                    sess.torch_model_run(res, run_options.as_ref(), &in_tensors, nr_out_tensors)
+                        .map_err(AgentServiceError::from)
+                },
+                |_| ctx.check_deadline(),
+            )
+        })
+    }
+
+    pub(crate) fn do_torch_model_run(
+        &self,
+        ctx: &RequestContext,
+        req: ModelRunRequest,
+    ) -> Result<ModelRunResponse> {
+        let _writes = self.writing_blobs();
+        let model_id = req.model_id.try_into()?;
+        let res = self.client_resource(ctx, model_id, "PyTorch model")?;
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
+
+        let batching = self.batching(
+            &sess,
+            model_id,
+            &req.in_tensors,
+            req.nr_out_tensors,
+            req.run_options.as_ref(),
+        )?;
+
+        let run_options = req.run_options.map(Buffer::new).transpose()?;
 
         let nr_out_tensors = req
             .nr_out_tensors
@@ -71,12 +126,30 @@ impl AgentService {
                 )
             })?;
 
-        info!("session:{} PyTorch model run", &req.session_id);
-        let out_tensors =
-            sess.torch_model_run(&mut res, run_options.as_ref(), &in_tensors, nr_out_tensors)?;
+        let run = |in_tensors: Vec<Tensor>| -> Result<(Vec<Tensor>, ())> {
+            let in_tensors = in_tensors
+                .into_iter()
+                .map(|e| e.try_into())
+                .collect::<vaccel::Result<Vec<DynTensor>>>()?;
+
+            let mut sess = sess.lock().unwrap();
+            let mut res = res.lock().unwrap();
+
+            info!("session:{} PyTorch model run", &req.session_id);
+            let out_tensors =
+                sess.torch_model_run(&mut res, run_options.as_ref(), &in_tensors, nr_out_tensors)?;
+            Ok((out_tensors.into_iter().map(Into::into).collect(), ()))
+        };
 
         let mut resp = ModelRunResponse::new();
-        resp.out_tensors = out_tensors.into_iter().map(Into::into).collect();
+        resp.out_tensors = match batching {
+            Some((key, config)) => {
+                self.torch_batches
+                    .run(key, &config, req.in_tensors, ctx.deadline, run)?
+                    .0
+            }
+            None => run(req.in_tensors)?.0,
+        };
 
         Ok(resp)
     }
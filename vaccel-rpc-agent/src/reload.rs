@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent_service::{AgentService, AgentServiceError, Result},
+    config::ModelConfig,
+};
+use log::info;
+use std::sync::{RwLockReadGuard, RwLockWriteGuard};
+use vaccel::{Config as VaccelConfig, VaccelId};
+
+impl AgentService {
+    /// Keeps vAccel from being re-bootstrapped while the guard is held.
+    ///
+    /// Held while creating a session, so that no session is created halfway
+    /// through a reload.
+    pub(crate) fn vaccel_guard(&self) -> RwLockReadGuard<'_, ()> {
+        self.vaccel_lock.read().unwrap()
+    }
+
+    fn vaccel_exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.vaccel_lock.write().unwrap()
+    }
+
+    /// Destroys the resources of preloaded models.
+    fn release_preloaded(&self) {
+        let preloaded: Vec<VaccelId> = self.preloaded.iter().map(|p| *p.key()).collect();
+        for res_id in preloaded {
+            if let Some((_, name)) = self.preloaded.remove(&res_id) {
+                info!("Releasing preloaded model `{}` (resource {})", name, res_id);
+                self.resources.remove(&res_id);
+                self.sync_state.remove(&res_id);
+                self.batching.remove(&res_id);
+            }
+        }
+    }
+
+    /// Re-bootstraps vAccel with `config`, preloading `models` and loading
+    /// the persistent resources again.
+    ///
+    /// Fails without touching vAccel if any session is active. Session
+    /// creation is blocked until the reload completes.
+    pub(crate) fn reload_vaccel(
+        &self,
+        config: Option<&mut VaccelConfig>,
+        models: &[ModelConfig],
+    ) -> Result<()> {
+        let _guard = self.vaccel_exclusive();
+
+        let active = self.sessions.len();
+        if active > 0 {
+            return Err(AgentServiceError::Unavailable(format!(
+                "{} session(s) still active",
+                active
+            )));
+        }
+
+        self.release_preloaded();
+        self.release_persistent();
+        vaccel::cleanup()?;
+        match config {
+            Some(c) => vaccel::bootstrap_with_config(c),
+            None => vaccel::bootstrap(),
+        }?;
+        info!("Re-bootstrapped vAccel");
+
+        for model in models {
+            self.preload_model(model)?;
+        }
+        self.load_persistent()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bootstrap, request_context};
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+    use vaccel_rpc_proto::session::{CreateRequest, DestroyRequest};
+
+    #[test]
+    fn reload_is_refused_while_sessions_are_active() {
+        bootstrap();
+
+        let service = AgentService::new();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+        let sess = service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap()
+            .session_id;
+
+        let err = service.reload_vaccel(None, &[]).unwrap_err();
+        assert!(
+            matches!(&err, AgentServiceError::Unavailable(m) if m.contains("1 session(s)")),
+            "{}",
+            err
+        );
+        assert!(vaccel::is_initialized());
+        assert_eq!(service.sessions.len(), 1);
+
+        service
+            .do_destroy_session(
+                &ctx,
+                DestroyRequest {
+                    session_id: sess,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent::resolve_uri,
+    capture::{id_field, set_id_field, RESOURCE_ID_FIELDS, SESSION_ID_FIELDS},
+    cli::{CaptureArgs, CaptureCommand},
+    Error, Result,
+};
+use protobuf::{
+    reflect::{MessageDescriptor, MethodDescriptor},
+    CodedInputStream, Enum, MessageDyn,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::Path,
+};
+use ttrpc::{
+    proto::{Code, KeyValue, Request},
+    Client,
+};
+use vaccel_rpc_proto::{capture::Record, TOKEN_METADATA_KEY};
+
+/// Runs a `capture` command.
+pub fn run(args: &CaptureArgs) -> Result<()> {
+    match &args.command {
+        CaptureCommand::Dump { capture } => dump(capture),
+        CaptureCommand::Replay {
+            server_address,
+            auth_token,
+            auth_token_file,
+            capture,
+        } => {
+            let token = match auth_token_file {
+                Some(path) => Some(
+                    fs::read_to_string(path)
+                        .map_err(|e| {
+                            Error::Other(format!("Could not read {}: {}", path.display(), e))
+                        })?
+                        .trim()
+                        .to_string(),
+                ),
+                None => auth_token.clone(),
+            };
+            replay(server_address, token.as_deref(), capture)
+        }
+    }
+}
+
+/// Calls `f` with each record of a capture file.
+fn for_each_record(path: &Path, mut f: impl FnMut(Record) -> Result<()>) -> Result<()> {
+    let mut file = File::open(path)?;
+    let mut input = CodedInputStream::new(&mut file);
+    while !input.eof()? {
+        f(input.read_message()?)?;
+    }
+    Ok(())
+}
+
+/// Returns the descriptor of an RPC of the sync or async agent service.
+fn method_descriptor(service: &str, method: &str) -> Option<MethodDescriptor> {
+    [
+        vaccel_rpc_proto::sync::agent::file_descriptor(),
+        vaccel_rpc_proto::asynchronous::agent::file_descriptor(),
+    ]
+    .into_iter()
+    .flat_map(|file| {
+        file.services()
+            .filter(move |s| format!("{}.{}", file.proto().package(), s.proto().name()) == service)
+    })
+    .flat_map(|s| s.methods().collect::<Vec<_>>())
+    .find(|m| m.proto().name() == method)
+}
+
+/// Converts a serialized message to JSON, falling back to the raw bytes if
+/// it cannot be parsed.
+fn to_json(desc: Option<MessageDescriptor>, bytes: &[u8]) -> Value {
+    let json = desc
+        .and_then(|d| d.parse_from_bytes(bytes).ok())
+        .and_then(|m| protobuf_json_mapping::print_to_string(&*m).ok())
+        .and_then(|s| serde_json::from_str(&s).ok());
+    json.unwrap_or_else(|| json!({ "bytes": bytes.len() }))
+}
+
+/// Prints the records of a capture file as JSON lines.
+fn dump(path: &Path) -> Result<()> {
+    for_each_record(path, |record| {
+        let method = method_descriptor(&record.service, &record.method);
+        let mut line = json!({
+            "timestamp_us": record.timestamp_us,
+            "service": record.service,
+            "method": record.method,
+            "peer": record.peer,
+            "code": format!("{:?}", Code::from_i32(record.code).unwrap_or_default()),
+            "duration_us": record.duration_us,
+            "request": to_json(method.as_ref().map(|m| m.input_type()), &record.request),
+        });
+        if !record.message.is_empty() {
+            line["message"] = json!(record.message);
+        }
+        if !record.response.is_empty() {
+            line["response"] = to_json(method.map(|m| m.output_type()), &record.response);
+        } else if !record.response_ids.is_empty() {
+            line["response_ids"] = to_json(method.map(|m| m.output_type()), &record.response_ids);
+        }
+
+        println!("{}", line);
+        Ok(())
+    })
+}
+
+/// The ids of the replayed sessions and resources, by their recorded ids.
+#[derive(Debug, Default)]
+struct IdMap {
+    sessions: HashMap<i64, i64>,
+    resources: HashMap<i64, i64>,
+}
+
+impl IdMap {
+    fn tables(&mut self) -> [(&[&str], &mut HashMap<i64, i64>); 2] {
+        [
+            (SESSION_ID_FIELDS, &mut self.sessions),
+            (RESOURCE_ID_FIELDS, &mut self.resources),
+        ]
+    }
+
+    /// Replaces the recorded ids of a request with the replayed ones.
+    fn map_request(&mut self, req: &mut dyn MessageDyn) {
+        for (names, ids) in self.tables() {
+            for name in names {
+                if let Some(new_id) = id_field(req, name).and_then(|id| ids.get(&id)) {
+                    set_id_field(req, name, *new_id);
+                }
+            }
+        }
+    }
+
+    /// Learns the replayed ids from a replayed response and replaces them
+    /// with the recorded ones, so that the responses can be compared.
+    fn unmap_response(&mut self, recorded: &dyn MessageDyn, replayed: &mut dyn MessageDyn) {
+        for (names, ids) in self.tables() {
+            for name in names {
+                if let (Some(old_id), Some(new_id)) =
+                    (id_field(recorded, name), id_field(replayed, name))
+                {
+                    ids.insert(old_id, new_id);
+                    set_id_field(replayed, name, old_id);
+                }
+            }
+        }
+    }
+}
+
+/// Re-issues the requests of a capture file against an agent, in order and
+/// over a single connection, and reports any responses that differ from
+/// the recorded ones. Only the status codes are compared for requests
+/// recorded without their responses.
+fn replay(server_address: &str, token: Option<&str>, path: &Path) -> Result<()> {
+    let address = resolve_uri(server_address)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::InvalidArgument("Invalid server address".into()))?;
+    let client = Client::connect(&address)?;
+
+    let mut ids = IdMap::default();
+    let (mut total, mut differing, mut skipped) = (0, 0, 0);
+    for_each_record(path, |record| {
+        total += 1;
+        let method = match method_descriptor(&record.service, &record.method) {
+            Some(m) => m,
+            None => {
+                println!(
+                    "#{} {}: unknown method of service {}; skipping",
+                    total, record.method, record.service
+                );
+                skipped += 1;
+                return Ok(());
+            }
+        };
+
+        let mut req = method.input_type().parse_from_bytes(&record.request)?;
+        ids.map_request(&mut *req);
+
+        let mut ttrpc_req = Request::new();
+        ttrpc_req.service = record.service.clone();
+        ttrpc_req.method = record.method.clone();
+        ttrpc_req.payload = req.write_to_bytes_dyn()?;
+        if let Some(token) = token {
+            let mut kv = KeyValue::new();
+            kv.key = TOKEN_METADATA_KEY.to_string();
+            kv.value = token.to_string();
+            ttrpc_req.metadata.push(kv);
+        }
+
+        let (code, message, payload) = match client.request(ttrpc_req) {
+            Ok(resp) => (Code::OK as i32, String::new(), resp.payload),
+            Err(ttrpc::Error::RpcStatus(s)) => (s.code() as i32, s.message().to_string(), vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut differs = code != record.code;
+        let mut replayed = Value::Null;
+        if code == Code::OK as i32 && !record.response.is_empty() {
+            let recorded = method.output_type().parse_from_bytes(&record.response)?;
+            let mut resp = method.output_type().parse_from_bytes(&payload)?;
+            ids.unmap_response(&*recorded, &mut *resp);
+            differs |= !method.output_type().eq(&*recorded, &*resp);
+            replayed = to_json(Some(method.output_type()), &resp.write_to_bytes_dyn()?);
+        } else if code == Code::OK as i32 && !record.response_ids.is_empty() {
+            let recorded = method
+                .output_type()
+                .parse_from_bytes(&record.response_ids)?;
+            let mut resp = method.output_type().parse_from_bytes(&payload)?;
+            ids.unmap_response(&*recorded, &mut *resp);
+        }
+
+        if differs {
+            differing += 1;
+            let code_name = |c| format!("{:?}", Code::from_i32(c).unwrap_or_default());
+            println!("#{} {}: response differs", total, record.method);
+            println!(
+                "- {} {} {}",
+                code_name(record.code),
+                record.message,
+                to_json(Some(method.output_type()), &record.response)
+            );
+            println!("+ {} {} {}", code_name(code), message, replayed);
+        }
+        Ok(())
+    })?;
+
+    println!(
+        "Replayed {} requests: {} differing, {} skipped",
+        total - skipped,
+        differing,
+        skipped
+    );
+    match differing {
+        0 => Ok(()),
+        n => Err(Error::Other(format!("{} responses differ", n))),
+    }
+}
@@ -1,7 +1,15 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::agent_service::{AgentService, AgentServiceError, Result};
+use crate::{
+    agent_service::{is_current, AgentService, AgentServiceError, RequestContext, Result, Shared},
+    cache::{Digest, ResourceKey, Scope},
+    config::ModelConfig,
+    session::AgentSession,
+    storage::to_vaccel_blob,
+};
 use log::info;
+use sha2::{Digest as _, Sha256};
+use std::sync::{atomic::Ordering, Arc, Mutex};
 use vaccel::{Blob, Resource, ResourceType, VaccelId};
 use vaccel_rpc_proto::{
     empty::Empty,
@@ -12,31 +20,172 @@ use vaccel_rpc_proto::{
 };
 
 impl AgentService {
-    pub(crate) fn do_register_resource(&self, req: RegisterRequest) -> Result<RegisterResponse> {
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+    /// Returns the entry of a resource, named `kind` in errors.
+    pub(crate) fn resource(&self, res_id: VaccelId, kind: &str) -> Result<Shared<Resource>> {
+        self.resources
+            .get(&res_id)
+            .map(|res| res.clone())
+            .ok_or_else(|| AgentServiceError::NotFound(format!("Unknown {} {}", kind, res_id)))
+    }
 
+    /// Returns the entry of a resource the client of `ctx` can use, named
+    /// `kind` in errors.
+    ///
+    /// Resources of other clients are reported as unknown.
+    pub(crate) fn client_resource(
+        &self,
+        ctx: &RequestContext,
+        res_id: VaccelId,
+        kind: &str,
+    ) -> Result<Shared<Resource>> {
+        let client = match self.persistent.get(&res_id) {
+            Some(p) => Some(p.client.clone()),
+            None => self.resource_clients.get(&res_id).map(|c| c.clone()),
+        };
+        match client {
+            Some(client) if client != ctx.client => Err(AgentServiceError::NotFound(format!(
+                "Unknown {} {}",
+                kind, res_id
+            ))),
+            _ => self.resource(res_id, kind),
+        }
+    }
+
+    pub(crate) fn do_register_resource(
+        &self,
+        ctx: &RequestContext,
+        mut req: RegisterRequest,
+    ) -> Result<RegisterResponse> {
         let proto_res_id = VaccelId::from_ffi(req.resource_id)?;
+        if let Some(res_id) = proto_res_id {
+            self.client_resource(ctx, res_id, "resource")?;
+        }
+        // Blobs sent by digest are resolved before anything is accounted,
+        // while uploaded blobs are only cached once the resource is
+        // registered
+        let (key, uploads): (Option<ResourceKey>, _) = match proto_res_id {
+            None if !req.blobs.is_empty() => {
+                let resolved = self.resolve_blobs(&ctx.client, &mut req.blobs)?;
+                if !resolved.missing.is_empty() {
+                    // Clients send the request again with the blob data
+                    let mut resp = RegisterResponse::new();
+                    resp.missing = resolved.missing;
+                    return Ok(resp);
+                }
+                let blobs = req.blobs.iter().map(|b| b.name.clone());
+                let key = (
+                    ctx.client.clone(),
+                    req.resource_type.value() as u32,
+                    blobs.zip(resolved.digests).collect(),
+                );
+                (Some(key), resolved.uploads)
+            }
+            _ => (None, Vec::new()),
+        };
+
+        let sess_id = req.session_id.try_into()?;
+        let entry = self.client_session(ctx, sess_id)?;
+        let mut sess = entry.lock().unwrap();
+        // The session may have been destroyed while waiting for it
+        if !is_current(&self.sessions, &sess_id, &entry) {
+            return Err(AgentServiceError::NotFound(format!(
+                "Unknown session {}",
+                sess_id
+            )));
+        }
+
+        let blob_bytes = match proto_res_id {
+            None => req.blobs.iter().map(|b| b.data.len()).sum(),
+            Some(_) => 0,
+        };
+
+        let owner = sess.owner;
+        self.charge_resource(owner, blob_bytes)?;
+
+        let res_id = match self.register_resource(req, &ctx.client, proto_res_id, key, &mut sess) {
+            Ok(res_id) => res_id,
+            Err(e) => {
+                self.refund_resource(owner, blob_bytes);
+                return Err(e);
+            }
+        };
+
+        if blob_bytes > 0 {
+            sess.blob_bytes.insert(res_id, blob_bytes);
+        }
+        self.cache_blobs(&ctx.client, uploads);
+
         let mut resp = RegisterResponse::new();
+        resp.resource_id = res_id.into();
+
+        Ok(resp)
+    }
+
+    /// Registers a resource with a session, creating it if `proto_res_id`
+    /// is not set. Named resources are looked up, or persisted, among the
+    /// persistent resources of `client`.
+    pub(crate) fn register_resource(
+        &self,
+        req: RegisterRequest,
+        client: &Scope,
+        proto_res_id: Option<VaccelId>,
+        key: Option<ResourceKey>,
+        sess: &mut AgentSession,
+    ) -> Result<VaccelId> {
         if proto_res_id.is_none() {
+            if !req.name.is_empty() {
+                let res_id = match req.blobs.is_empty() && req.paths.is_empty() {
+                    true => self
+                        .preloaded_model(&req.name)
+                        .or_else(|| self.persistent_resource(client, &req.name))
+                        .ok_or_else(|| {
+                            AgentServiceError::NotFound(format!("Unknown resource `{}`", req.name))
+                        })?,
+                    false if req.blobs.is_empty() => {
+                        return Err(AgentServiceError::InvalidArgument(
+                            "Only resources created from blobs can be persisted".to_string(),
+                        ))
+                    }
+                    false => self.persist_resource(
+                        client,
+                        &req.name,
+                        req.resource_type.value() as u32,
+                        &req.blobs,
+                    )?,
+                };
+                return self.register_existing(res_id, req.session_id, sess);
+            }
+
+            // Reuse a live resource created from the same blobs
+            let cached = key
+                .as_ref()
+                .and_then(|k| self.cache.lock().unwrap().resource(k));
+            if let Some(res_id) = cached {
+                match self.register_existing(res_id, req.session_id, sess) {
+                    Ok(res_id) => {
+                        info!("Reused resource {} with the same blobs", res_id);
+                        return Ok(res_id);
+                    }
+                    // The resource was destroyed in the meantime
+                    Err(AgentServiceError::NotFound(_)) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+
             // If we got resource id == 0 we need to create a resource before registering
             info!("Creating new resource");
             let res_type = ResourceType::from(req.resource_type.value() as u32);
-            let mut res = match req.blobs.is_empty() {
+            let mut blob_dir = None;
+            let ret = match req.blobs.is_empty() {
                 false => {
-                    let blobs = req
-                        .blobs
+                    let bytes = req.blobs.iter().map(|b| b.data.len() as u64).sum();
+                    blob_dir = self.create_blob_dir(bytes)?.map(|d| (d, bytes));
+                    let dir = blob_dir.as_ref().map(|(d, _)| d.as_path());
+                    req.blobs
                         .into_iter()
-                        .map(|f| Ok(f.try_into()?))
-                        .collect::<Result<Vec<Blob>>>()?;
-
-                    Resource::from_blobs(blobs, res_type)?
+                        .map(|b| to_vaccel_blob(b, dir))
+                        .collect::<Result<Vec<Blob>>>()
+                        .and_then(|blobs| Ok(Resource::from_blobs(blobs, res_type)?))
                 }
                 true => {
                     if req.paths.is_empty() {
@@ -45,128 +194,379 @@ impl AgentService {
                         ));
                     }
 
-                    Resource::new(&req.paths, res_type)?
+                    Ok(Resource::new(&req.paths, res_type)?)
+                }
+            };
+            let ret = ret.and_then(|res| match res.id() {
+                Some(res_id) => Ok((res, res_id)),
+                None => Err(AgentServiceError::Internal(
+                    "Invalid resource ID".to_string(),
+                )),
+            });
+            let (mut res, res_id) = match ret {
+                Ok(r) => r,
+                Err(e) => {
+                    if let Some((dir, bytes)) = blob_dir {
+                        self.discard_blob_dir(&dir, bytes);
+                    }
+                    return Err(e);
                 }
             };
-
-            let res_id = res.id().ok_or(AgentServiceError::Internal(
-                "Invalid resource ID".to_string(),
-            ))?;
 
             info!(
                 "Registering resource {} with session {}",
                 res_id, req.session_id
             );
-            res.register(&mut sess)?;
-
-            resp.resource_id = res_id.into();
+            if let Err(e) = res.register(sess) {
+                if let Some((dir, bytes)) = blob_dir {
+                    self.discard_blob_dir(&dir, bytes);
+                }
+                return Err(e.into());
+            }
+            if let Some(blob_dir) = blob_dir {
+                self.blob_dirs.insert(res_id, blob_dir);
+            }
+            sess.resources.insert(res_id);
 
-            let e = self.resources.insert(res_id, Box::new(res));
+            let e = self.resources.insert(res_id, Arc::new(Mutex::new(res)));
             assert!(e.is_none());
+            self.resource_clients.insert(res_id, client.clone());
+            if let Some(key) = key {
+                self.cache.lock().unwrap().add_resource(key, res_id);
+            }
+
+            Ok(res_id)
         } else {
             // If we got resource id > 0 simply register the resource
-            let mut res = self
-                .resources
-                .get_mut(&proto_res_id.unwrap())
-                .ok_or_else(|| {
-                    AgentServiceError::NotFound(
-                        format!("Unknown resource {}", &proto_res_id.unwrap()).to_string(),
-                    )
-                })?;
-
-            let res_id = res.id().ok_or(AgentServiceError::Internal(
-                "Invalid resource ID".to_string(),
-            ))?;
-
-            info!(
-                "Registering resource {} with session {}",
-                res_id, req.session_id
-            );
-            res.register(&mut sess)?;
+            self.register_existing(proto_res_id.unwrap(), req.session_id, sess)
+        }
+    }
 
-            resp.resource_id = res_id.into();
+    pub(crate) fn register_existing(
+        &self,
+        res_id: VaccelId,
+        session_id: i64,
+        sess: &mut AgentSession,
+    ) -> Result<VaccelId> {
+        let entry = self.resource(res_id, "resource")?;
+        let mut res = entry.lock().unwrap();
+        // The resource may have been destroyed while waiting for it
+        if !is_current(&self.resources, &res_id, &entry) {
+            return Err(AgentServiceError::NotFound(format!(
+                "Unknown resource {}",
+                res_id
+            )));
         }
 
-        Ok(resp)
+        info!(
+            "Registering resource {} with session {}",
+            res_id, session_id
+        );
+        res.register(sess)?;
+        sess.resources.insert(res_id);
+        self.touch_persistent(res_id);
+
+        Ok(res_id)
     }
 
-    pub(crate) fn do_unregister_resource(&self, req: UnregisterRequest) -> Result<Empty> {
-        let mut res = self
-            .resources
-            .get_mut(&req.resource_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown resource {}", &req.resource_id).to_string(),
-                )
-            })?;
-
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+    pub(crate) fn do_unregister_resource(
+        &self,
+        ctx: &RequestContext,
+        req: UnregisterRequest,
+    ) -> Result<Empty> {
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
+        let mut sess = sess.lock().unwrap();
 
-        let res_id = res.id().ok_or(AgentServiceError::Internal(
-            "Invalid resource ID".to_string(),
+        self.unregister_resource(req.resource_id.try_into()?, &mut sess)?;
+
+        Ok(Empty::new())
+    }
+
+    /// Unregisters a resource from a session and destroys the resource if it
+    /// is not registered with other sessions.
+    ///
+    /// The resource is removed while still locked, so that requests waiting
+    /// for it can tell it was destroyed.
+    pub(crate) fn unregister_resource(
+        &self,
+        res_id: VaccelId,
+        sess: &mut AgentSession,
+    ) -> Result<()> {
+        let res = self.resource(res_id, "resource")?;
+        let mut res = res.lock().unwrap();
+
+        let sess_id = sess.id().ok_or(AgentServiceError::Internal(
+            "Invalid session ID".to_string(),
         ))?;
 
-        info!(
-            "Unregistering resource {} from session {}",
-            res_id, req.session_id
-        );
-        res.unregister(&mut sess)?;
+        info!("Unregistering resource {} from session {}", res_id, sess_id);
+        res.unregister(sess)?;
+        if sess.resources.remove(&res_id) {
+            let blob_bytes = sess.blob_bytes.remove(&res_id).unwrap_or(0);
+            self.refund_resource(sess.owner, blob_bytes);
+        }
+        sess.models.remove(&res_id);
+        self.touch_persistent(res_id);
 
-        // If resource in registered to other sessions do not destroy
+        // If resource in registered to other sessions, preloaded or persistent
+        // do not destroy
         let refcount = res.refcount()?;
-        if refcount > 0 {
-            return Ok(Empty::new());
+        if refcount > 0
+            || self.preloaded.contains_key(&res_id)
+            || self.persistent.contains_key(&res_id)
+        {
+            return Ok(());
         }
 
         info!("Destroying resource {}", res_id);
-        drop(res);
-
         self.resources.remove(&res_id).ok_or_else(|| {
             AgentServiceError::NotFound(format!("Unknown resource {}", &res_id).to_string())
         })?;
+        self.resource_clients.remove(&res_id);
+        self.cache.lock().unwrap().remove_resource(res_id);
+        self.remove_blob_dir(res_id);
+        self.sync_state.remove(&res_id);
 
-        Ok(Empty::new())
+        Ok(())
     }
 
-    pub(crate) fn do_sync_resource(&self, req: SyncRequest) -> Result<SyncResponse> {
-        let mut res = self
-            .resources
-            .get_mut(&req.resource_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown resource {}", &req.resource_id).to_string(),
-                )
-            })?;
+    /// Creates a resource for a model of the agent configuration.
+    ///
+    /// Preloaded resources are kept until the agent exits.
+    pub(crate) fn preload_model(&self, model: &ModelConfig) -> Result<VaccelId> {
+        let res = Resource::new(&model.paths, ResourceType::Model)?;
+        let res_id = res.id().ok_or(AgentServiceError::Internal(
+            "Invalid resource ID".to_string(),
+        ))?;
+
+        info!("Preloaded model `{}` as resource {}", model.name, res_id);
+        let e = self.resources.insert(res_id, Arc::new(Mutex::new(res)));
+        assert!(e.is_none());
+        self.preloaded.insert(res_id, model.name.clone());
+        if let Some(batching) = &model.batching {
+            self.batching.insert(res_id, batching.clone());
+        }
 
-        info!("Synchronizing resource {}", &req.resource_id);
+        Ok(res_id)
+    }
 
-        let proto_blobs = res
-            .blobs()?
+    pub(crate) fn preloaded_model(&self, name: &str) -> Option<VaccelId> {
+        self.preloaded
             .iter()
-            .map(|blob| {
-                let data = blob
-                    .data()
-                    .map(|d| d.to_vec())
-                    .ok_or(AgentServiceError::Internal("Blob has no data".to_string()))?;
-
-                Ok(ProtoBlob {
-                    data,
-                    size: blob.size() as u32,
-                    ..Default::default()
-                })
-            })
-            .collect::<Result<Vec<ProtoBlob>>>()?;
+            .find(|p| p.value() == name)
+            .map(|p| *p.key())
+    }
+
+    /// Returns the blobs of a resource changed after `req.since_generation`,
+    /// or all of them if it is not set.
+    ///
+    /// Changes are detected by comparing the digest of each blob with the
+    /// one seen by the previous sync. Blobs are only hashed again if an
+    /// operation has completed since then.
+    pub(crate) fn do_sync_resource(
+        &self,
+        ctx: &RequestContext,
+        req: SyncRequest,
+    ) -> Result<SyncResponse> {
+        let res_id = req.resource_id.try_into()?;
+        let res = self.client_resource(ctx, res_id, "resource")?;
+        let mut res = res.lock().unwrap();
+
+        info!(
+            "Synchronizing resource {} since generation {}",
+            &req.resource_id, req.since_generation
+        );
+
+        // Read before hashing, so that writes of operations completing in the
+        // meantime are detected by the next sync
+        let writes = self.blob_writes.load(Ordering::Acquire);
+        let blobs = res.blobs()?;
+        let previous = self
+            .sync_state
+            .get(&res_id)
+            .map(|s| s.clone())
+            .unwrap_or_default();
+        let state = match previous.writes == writes && previous.blobs.len() == blobs.len() {
+            true => previous,
+            false => {
+                let mut state = SyncState {
+                    writes,
+                    blobs: Vec::with_capacity(blobs.len()),
+                };
+                for (i, blob) in blobs.iter().enumerate() {
+                    let data = match blob.data() {
+                        Some(d) => d,
+                        None if blob.size() == 0 => &[],
+                        None => {
+                            return Err(AgentServiceError::Internal("Blob has no data".to_string()))
+                        }
+                    };
+
+                    let digest: Digest = Sha256::digest(data).into();
+                    let generation = match previous.blobs.get(i) {
+                        Some(&(d, generation)) if d == digest => generation,
+                        _ => self.next_generation.fetch_add(1, Ordering::Relaxed) + 1,
+                    };
+                    state.blobs.push((digest, generation));
+                }
+                self.sync_state.insert(res_id, state.clone());
+                state
+            }
+        };
 
         let mut resp = SyncResponse::new();
-        resp.blobs = proto_blobs;
+        for (i, (blob, &(_, generation))) in blobs.iter().zip(&state.blobs).enumerate() {
+            resp.generation = resp.generation.max(generation);
+            if generation > req.since_generation {
+                resp.blobs.push(ProtoBlob::try_from(blob)?);
+                resp.indices.push(i as u32);
+            }
+        }
 
         Ok(resp)
     }
+
+    /// Returns a guard to hold while running an operation, recording that
+    /// blobs may have been written once the operation completes.
+    pub(crate) fn writing_blobs(&self) -> BlobWrites<'_> {
+        BlobWrites(self)
+    }
+}
+
+/// The blobs of a resource as of its last sync.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SyncState {
+    /// The value of the blob writes counter when the blobs were hashed
+    writes: u64,
+    /// The digest of each blob and the generation it last changed at
+    blobs: Vec<(Digest, u64)>,
+}
+
+/// Bumps the blob writes counter of the agent when dropped.
+pub(crate) struct BlobWrites<'a>(&'a AgentService);
+
+impl Drop for BlobWrites<'_> {
+    fn drop(&mut self) {
+        self.0.blob_writes.fetch_add(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bootstrap, request_context};
+    use protobuf::EnumOrUnknown;
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+    use vaccel_rpc_proto::{
+        resource::{BlobType, ResourceType as ProtoResourceType},
+        session::CreateRequest,
+    };
+
+    /// Overwrites the data of the first blob of a resource, as a plugin
+    /// would.
+    fn write_blob(service: &AgentService, res_id: VaccelId, value: u8) {
+        let res = service.resource(res_id, "resource").unwrap();
+        let mut res = res.lock().unwrap();
+        let blob = &res.blobs().unwrap()[0];
+        let data = blob.data().unwrap();
+        // SAFETY: the resource owns the blob data and is locked
+        unsafe { std::ptr::write_bytes(data.as_ptr() as *mut u8, value, data.len()) };
+    }
+
+    fn sync(
+        service: &AgentService,
+        ctx: &RequestContext,
+        res_id: VaccelId,
+        since: u64,
+    ) -> SyncResponse {
+        service
+            .do_sync_resource(
+                ctx,
+                SyncRequest {
+                    resource_id: res_id.into(),
+                    since_generation: since,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn sync_rehashes_blobs_after_operations_only() {
+        bootstrap();
+
+        let service = AgentService::new();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+        let sess = service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap()
+            .session_id;
+        let res_id = service
+            .do_register_resource(
+                &ctx,
+                RegisterRequest {
+                    blobs: vec![ProtoBlob {
+                        type_: EnumOrUnknown::new(BlobType::BUFFER),
+                        name: "out".to_string(),
+                        data: vec![0u8; 16],
+                        size: 16,
+                        ..Default::default()
+                    }],
+                    resource_type: EnumOrUnknown::new(ProtoResourceType::DATA),
+                    session_id: sess,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .resource_id;
+        let res_id = VaccelId::try_from(res_id).unwrap();
+
+        let first = sync(&service, &ctx, res_id, 0);
+        assert_eq!(first.indices, vec![0]);
+        assert!(sync(&service, &ctx, res_id, first.generation)
+            .blobs
+            .is_empty());
+
+        // Writes outside operations are not looked for
+        write_blob(&service, res_id, 1);
+        assert!(sync(&service, &ctx, res_id, first.generation)
+            .blobs
+            .is_empty());
+
+        drop(service.writing_blobs());
+        let second = sync(&service, &ctx, res_id, first.generation);
+        assert_eq!(second.indices, vec![0]);
+        assert_eq!(second.blobs[0].data, vec![1u8; 16]);
+        assert!(second.generation > first.generation);
+
+        // An operation that leaves the blobs unchanged keeps the generation
+        drop(service.writing_blobs());
+        assert_eq!(
+            sync(&service, &ctx, res_id, 0).generation,
+            second.generation
+        );
+    }
+
+    #[test]
+    fn sync_state_follows_the_blob_count() {
+        bootstrap();
+
+        let service = AgentService::new();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+        let res = Resource::new(["/tmp/sync-state"], ResourceType::Data).unwrap();
+        let res_id = res.id().unwrap();
+        service.resources.insert(res_id, Arc::new(Mutex::new(res)));
+        service.sync_state.insert(
+            res_id,
+            SyncState {
+                writes: 0,
+                blobs: vec![([0; 32], 1); 3],
+            },
+        );
+
+        sync(&service, &ctx, res_id, 0);
+        assert_eq!(service.sync_state.get(&res_id).unwrap().blobs.len(), 1);
+    }
 }
@@ -1,15 +1,109 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::agent_service::{AgentService, AgentServiceError, Result};
-use log::info;
-use vaccel::Session;
+use crate::{
+    agent_service::{AgentService, AgentServiceError, RequestContext, Result, Shared},
+    connection::ConnectionId,
+};
+use log::{info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use vaccel::{Session, VaccelId};
 use vaccel_rpc_proto::{
     empty::Empty,
     session::{CreateRequest, CreateResponse, DestroyRequest, UpdateRequest},
 };
 
+/// The model types that can be loaded in a session.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum ModelType {
+    Tensorflow,
+    TensorflowLite,
+    Torch,
+}
+
+/// A vAccel `Session` held by the agent on behalf of a client connection.
+#[derive(Debug)]
+pub(crate) struct AgentSession {
+    inner: Box<Session>,
+    pub(crate) owner: ConnectionId,
+    pub(crate) peer: String,
+    /// The token name of the owning client, if authentication is enabled
+    pub(crate) client: Option<String>,
+    pub(crate) created_at: SystemTime,
+    pub(crate) resources: HashSet<VaccelId>,
+    pub(crate) models: HashMap<VaccelId, ModelType>,
+    pub(crate) blob_bytes: HashMap<VaccelId, usize>,
+}
+
+impl AgentSession {
+    pub(crate) fn new(inner: Session, owner: ConnectionId, client: Option<String>) -> Self {
+        AgentSession {
+            inner: Box::new(inner),
+            owner,
+            peer: owner.peer(),
+            client,
+            created_at: SystemTime::now(),
+            resources: HashSet::new(),
+            models: HashMap::new(),
+            blob_bytes: HashMap::new(),
+        }
+    }
+}
+
+impl Deref for AgentSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for AgentSession {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
 impl AgentService {
-    pub(crate) fn do_create_session(&self, req: CreateRequest) -> Result<CreateResponse> {
+    /// Returns the entry of a session.
+    pub(crate) fn session(&self, sess_id: VaccelId) -> Result<Shared<AgentSession>> {
+        self.sessions
+            .get(&sess_id)
+            .map(|sess| sess.clone())
+            .ok_or_else(|| AgentServiceError::NotFound(format!("Unknown session {}", sess_id)))
+    }
+
+    /// Returns the entry of a session of the client of `ctx`.
+    ///
+    /// Sessions of other clients are reported as unknown, so that clients
+    /// cannot tell which sessions exist.
+    pub(crate) fn client_session(
+        &self,
+        ctx: &RequestContext,
+        sess_id: VaccelId,
+    ) -> Result<Shared<AgentSession>> {
+        let sess = self.session(sess_id)?;
+        if sess.lock().unwrap().client != ctx.client {
+            return Err(AgentServiceError::NotFound(format!(
+                "Unknown session {}",
+                sess_id
+            )));
+        }
+        Ok(sess)
+    }
+
+    pub(crate) fn do_create_session(
+        &self,
+        ctx: &RequestContext,
+        req: CreateRequest,
+    ) -> Result<CreateResponse> {
+        let slot = self.reserve_session(ctx.connection)?;
+
+        let _vaccel = self.vaccel_guard();
         let sess = Session::with_flags(req.flags)?;
         let sess_id = sess.id().ok_or(AgentServiceError::Internal(
             "Invalid session ID".to_string(),
@@ -18,46 +112,114 @@ impl AgentService {
         let mut resp = CreateResponse::new();
         resp.session_id = sess_id.into();
 
-        let e = self.sessions.insert(sess_id, Box::new(sess));
+        let e = self.sessions.insert(
+            sess_id,
+            Arc::new(Mutex::new(AgentSession::new(
+                sess,
+                ctx.connection,
+                ctx.client.clone(),
+            ))),
+        );
         assert!(e.is_none());
+        slot.fill(sess_id);
 
-        info!("Created session {}", resp.session_id);
+        info!(
+            "Created session {} for connection {}",
+            resp.session_id, ctx.connection
+        );
         Ok(resp)
     }
 
-    pub(crate) fn do_update_session(&self, req: UpdateRequest) -> Result<Empty> {
-        let mut sess = self
-            .sessions
-            .get_mut(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
+    pub(crate) fn do_update_session(
+        &self,
+        ctx: &RequestContext,
+        req: UpdateRequest,
+    ) -> Result<Empty> {
+        let sess = self.client_session(ctx, req.session_id.try_into()?)?;
 
         info!("Updating hint {} for session {}", req.flags, req.session_id);
 
-        sess.update(req.flags);
+        sess.lock().unwrap().update(req.flags);
         Ok(Empty::new())
     }
 
-    pub(crate) fn do_destroy_session(&self, req: DestroyRequest) -> Result<Empty> {
-        let (_, sess) = self
-            .sessions
-            .remove(&req.session_id.try_into()?)
-            .ok_or_else(|| {
-                AgentServiceError::NotFound(
-                    format!("Unknown session {}", &req.session_id).to_string(),
-                )
-            })?;
-
-        self.profiler_manager
-            .remove(sess.id().ok_or(AgentServiceError::Internal(
-                "Invalid session ID".to_string(),
-            ))?);
-        drop(sess);
+    pub(crate) fn do_destroy_session(
+        &self,
+        ctx: &RequestContext,
+        req: DestroyRequest,
+    ) -> Result<Empty> {
+        let sess_id = req.session_id.try_into()?;
+        self.client_session(ctx, sess_id)?;
+        self.destroy_session(sess_id)?;
 
         info!("Destroyed session {}", req.session_id);
         Ok(Empty::new())
     }
+
+    /// Detaches a session from its owning connection and releases it.
+    pub(crate) fn destroy_session(&self, sess_id: VaccelId) -> Result<()> {
+        let owner = self.session(sess_id)?.lock().unwrap().owner;
+
+        if let Some(mut conn) = self.connections.get_mut(&owner) {
+            conn.sessions.remove(&sess_id);
+        }
+
+        self.release_session(sess_id)
+    }
+
+    /// Removes a session, unloading its models and unregistering its
+    /// resources before destroying it.
+    ///
+    /// Resources that are no longer registered with any session are destroyed
+    /// as well.
+    ///
+    /// Requests already holding the session finish before it is released.
+    pub(crate) fn release_session(&self, sess_id: VaccelId) -> Result<()> {
+        let (_, sess) = self.sessions.remove(&sess_id).ok_or_else(|| {
+            AgentServiceError::NotFound(format!("Unknown session {}", &sess_id).to_string())
+        })?;
+        let mut sess = sess.lock().unwrap();
+
+        let models: Vec<(VaccelId, ModelType)> = sess.models.drain().collect();
+        for (res_id, model_type) in models {
+            let res = match self.resources.get(&res_id).map(|r| r.clone()) {
+                Some(res) => res,
+                None => continue,
+            };
+            let mut res = res.lock().unwrap();
+
+            info!(
+                "session:{} Unloading {:?} model {}",
+                sess_id, model_type, res_id
+            );
+            let ret = match model_type {
+                ModelType::Tensorflow => sess.tf_model_unload(&mut res).map(|_| ()),
+                ModelType::TensorflowLite => sess.tflite_model_unload(&mut res),
+                // PyTorch models are unloaded when the resource is unregistered
+                ModelType::Torch => Ok(()),
+            };
+            if let Err(e) = ret {
+                warn!(
+                    "session:{} Could not unload model {}: {}",
+                    sess_id, res_id, e
+                );
+            }
+        }
+
+        let resources: Vec<VaccelId> = sess.resources.iter().copied().collect();
+        for res_id in resources {
+            if let Err(e) = self.unregister_resource(res_id, &mut sess) {
+                warn!(
+                    "session:{} Could not unregister resource {}: {}",
+                    sess_id, res_id, e
+                );
+            }
+        }
+
+        self.profiler_manager.remove(sess_id);
+        self.imported.retain(|_, i| i.session != sess_id);
+        drop(sess);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,779 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent_service::{AgentService, AgentServiceError, Result},
+    cache::Scope,
+    config::StorageConfig,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use vaccel::{Blob, BlobType, Resource, ResourceType, VaccelId};
+use vaccel_rpc_proto::resource::Blob as ProtoBlob;
+
+const BLOBS_DIR: &str = "blobs";
+const PERSISTENT_DIR: &str = "persistent";
+/// Holds the persistent resources created without authentication
+const SHARED_DIR: &str = "shared";
+/// Holds a directory of persistent resources for each authenticated client
+const CLIENTS_DIR: &str = "clients";
+const MANIFEST: &str = "resource.json";
+
+/// The description of a persistent resource, stored next to its blob files.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    resource_type: u32,
+    blobs: Vec<String>,
+}
+
+/// A resource kept across agent restarts, named by the client that created
+/// it.
+#[derive(Debug)]
+pub(crate) struct Persistent {
+    pub(crate) client: Scope,
+    pub(crate) name: String,
+    /// When the resource was last registered with or unregistered from a
+    /// session
+    pub(crate) last_used: Instant,
+}
+
+/// A persistent resource found in the storage directory, with the paths of
+/// its blob files.
+#[derive(Debug)]
+struct Persisted {
+    client: Scope,
+    name: String,
+    resource_type: u32,
+    paths: Vec<String>,
+}
+
+/// Returns the total size of the files under `path`.
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += match meta.is_dir() {
+            true => disk_usage(&entry.path())?,
+            false => meta.len(),
+        };
+    }
+    Ok(size)
+}
+
+/// Checks that `name` can be used as a file name in the storage directory.
+fn validate_name(name: &str, what: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name == MANIFEST {
+        return Err(AgentServiceError::InvalidArgument(format!(
+            "Invalid {} name `{}`",
+            what, name
+        )));
+    }
+    Ok(())
+}
+
+fn storage_error(e: io::Error, what: String) -> AgentServiceError {
+    AgentServiceError::Internal(format!("Could not {}: {}", what, e))
+}
+
+/// Converts an uploaded blob, writing its file under `dir` if set.
+pub(crate) fn to_vaccel_blob(blob: ProtoBlob, dir: Option<&Path>) -> Result<Blob> {
+    let dir = dir
+        .map(|d| {
+            d.to_str().ok_or_else(|| {
+                AgentServiceError::Internal(format!("Invalid storage path {}", d.display()))
+            })
+        })
+        .transpose()?;
+
+    let mut vaccel_blob = Blob::from_buf(blob.data, &blob.name, dir, false)?;
+    vaccel_blob.set_type(BlobType::from(blob.type_.value() as u32));
+    Ok(vaccel_blob)
+}
+
+/// Returns the directories holding the persistent resources of each client.
+fn scope_dirs(persistent: &Path) -> io::Result<Vec<(Scope, PathBuf)>> {
+    let mut dirs = vec![(None, persistent.join(SHARED_DIR))];
+    for entry in fs::read_dir(persistent.join(CLIENTS_DIR))? {
+        let entry = entry?;
+        let client = entry.file_name().to_string_lossy().into_owned();
+        dirs.push((Some(client), entry.path()));
+    }
+    Ok(dirs)
+}
+
+/// The agent storage directory.
+///
+/// It holds the blob files of uploaded resources, which are removed along
+/// with their resource, and the persistent resources, which are kept across
+/// agent restarts until removed through the admin server or, if an idle
+/// timeout is set, once left unused for longer. All files count towards an
+/// optional quota.
+#[derive(Debug)]
+pub(crate) struct Storage {
+    dir: PathBuf,
+    max_bytes: Option<u64>,
+    idle_timeout: Option<Duration>,
+    used: Mutex<u64>,
+    next_id: AtomicU64,
+    /// Serializes the creation of persistent resources
+    persist_lock: Mutex<()>,
+}
+
+impl Storage {
+    /// Opens the storage directory, removing any blob files left over by
+    /// previous runs.
+    pub(crate) fn open(config: &StorageConfig) -> io::Result<Option<Self>> {
+        let dir = match &config.dir {
+            Some(d) => d.clone(),
+            None => return Ok(None),
+        };
+
+        let blobs = dir.join(BLOBS_DIR);
+        if blobs.exists() {
+            let orphaned = disk_usage(&blobs)?;
+            if orphaned > 0 {
+                info!("Removing {} bytes of orphaned blob files", orphaned);
+            }
+            fs::remove_dir_all(&blobs)?;
+        }
+        fs::create_dir_all(&blobs)?;
+
+        // Resources still being persisted when the agent stopped are
+        // incomplete
+        let persistent = dir.join(PERSISTENT_DIR);
+        fs::create_dir_all(persistent.join(SHARED_DIR))?;
+        fs::create_dir_all(persistent.join(CLIENTS_DIR))?;
+        for (_, scope_dir) in scope_dirs(&persistent)? {
+            for entry in fs::read_dir(&scope_dir)? {
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    warn!("Removing incomplete resource {}", entry.path().display());
+                    fs::remove_dir_all(entry.path())?;
+                }
+            }
+        }
+
+        let used = disk_usage(&dir)?;
+        Ok(Some(Storage {
+            dir,
+            max_bytes: config.max_bytes,
+            idle_timeout: config.persistent_idle_timeout.map(Duration::from_secs),
+            used: Mutex::new(used),
+            next_id: AtomicU64::new(0),
+            persist_lock: Mutex::new(()),
+        }))
+    }
+
+    /// Accounts `bytes` to the storage, failing if the quota is exceeded.
+    fn reserve(&self, bytes: u64) -> Result<()> {
+        let mut used = self.used.lock().unwrap();
+        if let Some(max) = self.max_bytes {
+            if used.saturating_add(bytes) > max {
+                return Err(AgentServiceError::ResourceExhausted(format!(
+                    "Storage quota of {} bytes reached",
+                    max
+                )));
+            }
+        }
+        *used += bytes;
+        Ok(())
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut used = self.used.lock().unwrap();
+        *used = used.saturating_sub(bytes);
+    }
+
+    /// Creates the directory for the blob files of an uploaded resource,
+    /// reserving `bytes` for them.
+    pub(crate) fn create_blob_dir(&self, bytes: u64) -> Result<PathBuf> {
+        self.reserve(bytes)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let dir = self.dir.join(BLOBS_DIR).join(id.to_string());
+        fs::create_dir(&dir).map_err(|e| {
+            self.release(bytes);
+            storage_error(e, format!("create {}", dir.display()))
+        })?;
+        Ok(dir)
+    }
+
+    /// Removes the blob files of an uploaded resource.
+    pub(crate) fn remove_blob_dir(&self, dir: &Path, bytes: u64) {
+        if let Err(e) = fs::remove_dir_all(dir) {
+            warn!("Could not remove {}: {}", dir.display(), e);
+        }
+        self.release(bytes);
+    }
+
+    fn blob_paths(dir: &Path, manifest: &Manifest) -> Vec<String> {
+        manifest
+            .blobs
+            .iter()
+            .map(|b| dir.join(b).to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// Returns the directory of the persistent resources of `client`.
+    fn scope_dir(&self, client: &Scope) -> PathBuf {
+        let persistent = self.dir.join(PERSISTENT_DIR);
+        match client {
+            Some(client) => persistent.join(CLIENTS_DIR).join(client),
+            None => persistent.join(SHARED_DIR),
+        }
+    }
+
+    /// Writes the blobs of a persistent resource and returns the paths of
+    /// their files.
+    fn persist(
+        &self,
+        client: &Scope,
+        name: &str,
+        res_type: u32,
+        blobs: &[ProtoBlob],
+    ) -> Result<Vec<String>> {
+        for blob in blobs {
+            validate_name(&blob.name, "blob")?;
+        }
+        let scope_dir = self.scope_dir(client);
+        fs::create_dir_all(&scope_dir)
+            .map_err(|e| storage_error(e, format!("create {}", scope_dir.display())))?;
+
+        let bytes = blobs.iter().map(|b| b.data.len() as u64).sum();
+        self.reserve(bytes)?;
+
+        let manifest = Manifest {
+            resource_type: res_type,
+            blobs: blobs.iter().map(|b| b.name.clone()).collect(),
+        };
+        // Write to a hidden directory first, so that an interrupted write is
+        // removed on the next start
+        let tmp = scope_dir.join(format!(".{}", name));
+        let dir = scope_dir.join(name);
+        let ret = (|| -> io::Result<()> {
+            fs::create_dir_all(&tmp)?;
+            for blob in blobs {
+                fs::write(tmp.join(&blob.name), &blob.data)?;
+            }
+            fs::write(tmp.join(MANIFEST), serde_json::to_vec(&manifest)?)?;
+            fs::rename(&tmp, &dir)
+        })();
+        if let Err(e) = ret {
+            let _ = fs::remove_dir_all(&tmp);
+            self.release(bytes);
+            return Err(storage_error(e, format!("persist resource `{}`", name)));
+        }
+
+        Ok(Self::blob_paths(&dir, &manifest))
+    }
+
+    /// Returns whether the persistent resource `name` of `client` has the
+    /// same type and blobs.
+    fn matches(
+        &self,
+        client: &Scope,
+        name: &str,
+        res_type: u32,
+        blobs: &[ProtoBlob],
+    ) -> io::Result<bool> {
+        let dir = self.scope_dir(client).join(name);
+        let manifest: Manifest = serde_json::from_slice(&fs::read(dir.join(MANIFEST))?)?;
+        if manifest.resource_type != res_type
+            || !manifest.blobs.iter().eq(blobs.iter().map(|b| &b.name))
+        {
+            return Ok(false);
+        }
+        for blob in blobs {
+            if fs::read(dir.join(&blob.name))? != blob.data {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Removes the files of the persistent resource `name` of `client`.
+    fn remove(&self, client: &Scope, name: &str) -> io::Result<()> {
+        let dir = self.scope_dir(client).join(name);
+        let bytes = disk_usage(&dir)?;
+        fs::remove_dir_all(&dir)?;
+        self.release(bytes);
+        Ok(())
+    }
+
+    /// Returns the persistent resources found in the storage directory.
+    fn persisted(&self) -> io::Result<Vec<Persisted>> {
+        let mut resources = Vec::new();
+        for (client, scope_dir) in scope_dirs(&self.dir.join(PERSISTENT_DIR))? {
+            for entry in fs::read_dir(scope_dir)? {
+                let dir = entry?.path();
+                let name = dir
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let manifest: Manifest = match fs::read(dir.join(MANIFEST))
+                    .map_err(|e| e.to_string())
+                    .and_then(|m| serde_json::from_slice(&m).map_err(|e| e.to_string()))
+                {
+                    Ok(m) => m,
+                    Err(e) => {
+                        warn!("Skipping persistent resource `{}`: {}", name, e);
+                        continue;
+                    }
+                };
+                let paths = Self::blob_paths(&dir, &manifest);
+                resources.push(Persisted {
+                    client: client.clone(),
+                    name,
+                    resource_type: manifest.resource_type,
+                    paths,
+                });
+            }
+        }
+        Ok(resources)
+    }
+}
+
+impl AgentService {
+    pub(crate) fn set_storage(&self, storage: Option<Storage>) {
+        *self.storage.write().unwrap() = storage;
+    }
+
+    /// Creates a directory for the blob files of an uploaded resource, if a
+    /// storage directory is set.
+    pub(crate) fn create_blob_dir(&self, bytes: u64) -> Result<Option<PathBuf>> {
+        self.storage
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|s| s.create_blob_dir(bytes))
+            .transpose()
+    }
+
+    /// Removes a directory created by `create_blob_dir`.
+    pub(crate) fn discard_blob_dir(&self, dir: &Path, bytes: u64) {
+        if let Some(storage) = self.storage.read().unwrap().as_ref() {
+            storage.remove_blob_dir(dir, bytes);
+        }
+    }
+
+    /// Removes the blob files of an uploaded resource, if any.
+    pub(crate) fn remove_blob_dir(&self, res_id: VaccelId) {
+        if let Some((_, (dir, bytes))) = self.blob_dirs.remove(&res_id) {
+            self.discard_blob_dir(&dir, bytes);
+        }
+    }
+
+    pub(crate) fn persistent_resource(&self, client: &Scope, name: &str) -> Option<VaccelId> {
+        self.persistent
+            .iter()
+            .find(|p| p.client == *client && p.name == name)
+            .map(|p| *p.key())
+    }
+
+    /// Records that a persistent resource was registered with or
+    /// unregistered from a session.
+    pub(crate) fn touch_persistent(&self, res_id: VaccelId) {
+        if let Some(mut p) = self.persistent.get_mut(&res_id) {
+            p.last_used = Instant::now();
+        }
+    }
+
+    fn add_persistent(
+        &self,
+        client: &Scope,
+        name: &str,
+        res_type: u32,
+        paths: &[String],
+    ) -> Result<VaccelId> {
+        let res = Resource::new(paths, ResourceType::from(res_type))?;
+        let res_id = res.id().ok_or(AgentServiceError::Internal(
+            "Invalid resource ID".to_string(),
+        ))?;
+
+        let e = self.resources.insert(res_id, Arc::new(Mutex::new(res)));
+        assert!(e.is_none());
+        self.persistent.insert(
+            res_id,
+            Persistent {
+                client: client.clone(),
+                name: name.to_string(),
+                last_used: Instant::now(),
+            },
+        );
+
+        Ok(res_id)
+    }
+
+    /// Stores the blobs of a resource on disk and creates the resource,
+    /// keeping it across agent restarts. Names are scoped per client.
+    ///
+    /// Returns the existing resource if one with the same type and blobs is
+    /// already persisted as `name`.
+    pub(crate) fn persist_resource(
+        &self,
+        client: &Scope,
+        name: &str,
+        res_type: u32,
+        blobs: &[ProtoBlob],
+    ) -> Result<VaccelId> {
+        validate_name(name, "resource")?;
+        if let Some(client) = client {
+            validate_name(client, "client")?;
+        }
+        if self.preloaded_model(name).is_some() {
+            return Err(AgentServiceError::InvalidArgument(format!(
+                "Name `{}` is used by a preloaded model",
+                name
+            )));
+        }
+        let storage = self.storage.read().unwrap();
+        let storage = storage.as_ref().ok_or_else(|| {
+            AgentServiceError::InvalidArgument(
+                "Persistent resources require a storage directory".to_string(),
+            )
+        })?;
+
+        let _guard = storage.persist_lock.lock().unwrap();
+        if let Some(res_id) = self.persistent_resource(client, name) {
+            let same = storage
+                .matches(client, name, res_type, blobs)
+                .map_err(|e| storage_error(e, format!("read resource `{}`", name)))?;
+            return match same {
+                true => Ok(res_id),
+                false => Err(AgentServiceError::AlreadyExists(format!(
+                    "A different resource is persisted as `{}`",
+                    name
+                ))),
+            };
+        }
+
+        let paths = storage.persist(client, name, res_type, blobs)?;
+        let res_id = self.add_persistent(client, name, res_type, &paths)?;
+        info!("Persisted resource `{}` as resource {}", name, res_id);
+
+        Ok(res_id)
+    }
+
+    /// Destroys the persistent resource `name` of `client` and removes its
+    /// files. Resources still registered with sessions are kept.
+    pub(crate) fn remove_persistent(&self, client: &Scope, name: &str) -> Result<()> {
+        let storage = self.storage.read().unwrap();
+        let storage = storage.as_ref().ok_or_else(|| {
+            AgentServiceError::NotFound(format!("Unknown persistent resource `{}`", name))
+        })?;
+
+        let _guard = storage.persist_lock.lock().unwrap();
+        let res_id = self.persistent_resource(client, name).ok_or_else(|| {
+            AgentServiceError::NotFound(format!("Unknown persistent resource `{}`", name))
+        })?;
+
+        // Keep the resource locked while it is removed, so that sessions
+        // waiting to register it can tell it was destroyed
+        let res = self.resource(res_id, "resource")?;
+        let res = res.lock().unwrap();
+        let refcount = res.refcount()?;
+        if refcount > 0 {
+            return Err(AgentServiceError::FailedPrecondition(format!(
+                "Resource `{}` is registered with {} session(s)",
+                name, refcount
+            )));
+        }
+
+        self.persistent.remove(&res_id);
+        self.resources.remove(&res_id);
+        self.sync_state.remove(&res_id);
+        storage
+            .remove(client, name)
+            .map_err(|e| storage_error(e, format!("remove resource `{}`", name)))?;
+        info!("Removed persistent resource `{}`", name);
+
+        Ok(())
+    }
+
+    /// Removes the persistent resources that have not been registered with
+    /// any session for longer than the idle timeout of the storage.
+    pub(crate) fn expire_persistent(&self) {
+        let idle_timeout = match self.storage.read().unwrap().as_ref() {
+            Some(Storage {
+                idle_timeout: Some(t),
+                ..
+            }) => *t,
+            _ => return,
+        };
+
+        let idle: Vec<(Scope, String)> = self
+            .persistent
+            .iter()
+            .filter(|p| p.last_used.elapsed() > idle_timeout)
+            .map(|p| (p.client.clone(), p.name.clone()))
+            .collect();
+        for (client, name) in idle {
+            match self.remove_persistent(&client, &name) {
+                Ok(()) => info!("Persistent resource `{}` expired", name),
+                // Still in use, or removed in the meantime
+                Err(AgentServiceError::FailedPrecondition(_))
+                | Err(AgentServiceError::NotFound(_)) => (),
+                Err(e) => warn!("Could not expire persistent resource `{}`: {}", name, e),
+            }
+        }
+    }
+
+    /// Creates the persistent resources found in the storage directory.
+    pub(crate) fn load_persistent(&self) -> Result<()> {
+        let storage = self.storage.read().unwrap();
+        let storage = match storage.as_ref() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        let persisted = storage
+            .persisted()
+            .map_err(|e| storage_error(e, "read persistent resources".to_string()))?;
+        for p in persisted {
+            let res_id = self.add_persistent(&p.client, &p.name, p.resource_type, &p.paths)?;
+            info!(
+                "Loaded persistent resource `{}` as resource {}",
+                p.name, res_id
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Destroys the persistent resources, keeping their files.
+    pub(crate) fn release_persistent(&self) {
+        let persistent: Vec<VaccelId> = self.persistent.iter().map(|p| *p.key()).collect();
+        for res_id in persistent {
+            if self.persistent.remove(&res_id).is_some() {
+                self.resources.remove(&res_id);
+                self.sync_state.remove(&res_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bootstrap, request_context};
+    use protobuf::EnumOrUnknown;
+    use std::{
+        env,
+        os::unix::{io::AsRawFd, net::UnixStream},
+        process,
+    };
+    use vaccel_rpc_proto::{
+        resource::{
+            BlobType as ProtoBlobType, RegisterRequest, ResourceType as ProtoResourceType,
+            UnregisterRequest,
+        },
+        session::CreateRequest,
+    };
+
+    const MODEL: u32 = 1;
+
+    /// Returns a service with a new storage directory named after `test`.
+    fn service_with_storage(test: &str, idle_timeout: Option<u64>) -> (AgentService, PathBuf) {
+        bootstrap();
+
+        let dir = env::temp_dir().join(format!(
+            "vaccel-rpc-agent-storage-{}-{}",
+            test,
+            process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let storage = Storage::open(&StorageConfig {
+            dir: Some(dir.clone()),
+            max_bytes: None,
+            persistent_idle_timeout: idle_timeout,
+        })
+        .unwrap();
+
+        let service = AgentService::new();
+        service.set_storage(storage);
+        (service, dir)
+    }
+
+    fn blobs(data: u8) -> Vec<ProtoBlob> {
+        vec![ProtoBlob {
+            type_: EnumOrUnknown::new(ProtoBlobType::BUFFER),
+            name: "model.pt".to_string(),
+            data: vec![data; 16],
+            size: 16,
+            ..Default::default()
+        }]
+    }
+
+    fn used(service: &AgentService) -> u64 {
+        *service
+            .storage
+            .read()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .used
+            .lock()
+            .unwrap()
+    }
+
+    #[test]
+    fn persisting_a_name_again_compares_the_content() {
+        let (service, dir) = service_with_storage("content", None);
+
+        let res_id = service
+            .persist_resource(&None, "model", MODEL, &blobs(1))
+            .unwrap();
+        assert_eq!(
+            service
+                .persist_resource(&None, "model", MODEL, &blobs(1))
+                .unwrap(),
+            res_id
+        );
+        assert_eq!(
+            service
+                .persist_resource(&None, "model", MODEL, &blobs(2))
+                .unwrap_err()
+                .code(),
+            ttrpc::Code::ALREADY_EXISTS
+        );
+        assert_eq!(
+            service
+                .persist_resource(&None, "model", MODEL + 1, &blobs(1))
+                .unwrap_err()
+                .code(),
+            ttrpc::Code::ALREADY_EXISTS
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn names_are_scoped_per_client() {
+        let (service, dir) = service_with_storage("scoped", None);
+        let alice = Some("alice".to_string());
+        let bob = Some("bob".to_string());
+
+        let alice_id = service
+            .persist_resource(&alice, "model", MODEL, &blobs(1))
+            .unwrap();
+        let bob_id = service
+            .persist_resource(&bob, "model", MODEL, &blobs(2))
+            .unwrap();
+        assert_ne!(alice_id, bob_id);
+        assert_eq!(service.persistent_resource(&alice, "model"), Some(alice_id));
+        assert_eq!(service.persistent_resource(&bob, "model"), Some(bob_id));
+        assert_eq!(service.persistent_resource(&None, "model"), None);
+
+        // Client names are used as directory names
+        assert_eq!(
+            service
+                .persist_resource(&Some("../x".to_string()), "model", MODEL, &blobs(1))
+                .unwrap_err()
+                .code(),
+            ttrpc::Code::INVALID_ARGUMENT
+        );
+
+        // The scopes survive a reload
+        service.release_persistent();
+        service.load_persistent().unwrap();
+        assert!(service.persistent_resource(&alice, "model").is_some());
+        assert!(service.persistent_resource(&bob, "model").is_some());
+        assert_eq!(service.persistent.len(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn removes_persistent_resources_not_in_use() {
+        let (service, dir) = service_with_storage("remove", None);
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+
+        let sess = service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap()
+            .session_id;
+        let res_id = service
+            .do_register_resource(
+                &ctx,
+                RegisterRequest {
+                    name: "model".to_string(),
+                    blobs: blobs(1),
+                    resource_type: EnumOrUnknown::new(ProtoResourceType::MODEL),
+                    session_id: sess,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .resource_id;
+        assert!(used(&service) > 0);
+
+        assert_eq!(
+            service
+                .remove_persistent(&None, "model")
+                .unwrap_err()
+                .code(),
+            ttrpc::Code::FAILED_PRECONDITION
+        );
+
+        service
+            .do_unregister_resource(
+                &ctx,
+                UnregisterRequest {
+                    resource_id: res_id,
+                    session_id: sess,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        service.remove_persistent(&None, "model").unwrap();
+        assert!(service.persistent.is_empty());
+        assert!(service.resources.is_empty());
+        assert!(!dir
+            .join(PERSISTENT_DIR)
+            .join(SHARED_DIR)
+            .join("model")
+            .exists());
+        assert_eq!(used(&service), 0);
+
+        assert_eq!(
+            service
+                .remove_persistent(&None, "model")
+                .unwrap_err()
+                .code(),
+            ttrpc::Code::NOT_FOUND
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expires_idle_persistent_resources() {
+        let (service, dir) = service_with_storage("expire", Some(60));
+        let backdate = |res_id: VaccelId| {
+            service.persistent.get_mut(&res_id).unwrap().last_used -= Duration::from_secs(120);
+        };
+
+        let idle = service
+            .persist_resource(&None, "idle", MODEL, &blobs(1))
+            .unwrap();
+        let recent = service
+            .persist_resource(&None, "recent", MODEL, &blobs(2))
+            .unwrap();
+        backdate(idle);
+
+        service.expire_persistent();
+        assert_eq!(service.persistent_resource(&None, "idle"), None);
+        assert_eq!(service.persistent_resource(&None, "recent"), Some(recent));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{agent_service::IntoTtrpcResult, AgentService};
+use vaccel_rpc_proto::{
+    admin::{
+        DestroySessionRequest, ExportSessionRequest, ExportSessionResponse, ImportSessionRequest,
+        ImportSessionResponse, ListResourcesResponse, ListSessionsResponse,
+        RemovePersistentRequest,
+    },
+    empty::Empty,
+    sync::admin_service_ttrpc,
+};
+
+impl admin_service_ttrpc::AdminService for AgentService {
+    fn list_sessions(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: Empty,
+    ) -> ttrpc::Result<ListSessionsResponse> {
+        self.serve_admin(ctx.fd, "ListSessions", req, |_| self.do_list_sessions())
+            .into_ttrpc()
+    }
+
+    fn list_resources(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: Empty,
+    ) -> ttrpc::Result<ListResourcesResponse> {
+        self.serve_admin(ctx.fd, "ListResources", req, |_| self.do_list_resources())
+            .into_ttrpc()
+    }
+
+    fn force_destroy_session(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: DestroySessionRequest,
+    ) -> ttrpc::Result<Empty> {
+        self.serve_admin(ctx.fd, "ForceDestroySession", req, |req| {
+            self.do_force_destroy_session(req)
+        })
+        .into_ttrpc()
+    }
+
+    fn export_session(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: ExportSessionRequest,
+    ) -> ttrpc::Result<ExportSessionResponse> {
+        self.serve_admin(ctx.fd, "ExportSession", req, |req| {
+            self.do_export_session(req)
+        })
+        .into_ttrpc()
+    }
+
+    fn import_session(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: ImportSessionRequest,
+    ) -> ttrpc::Result<ImportSessionResponse> {
+        self.serve_admin(ctx.fd, "ImportSession", req, |req| {
+            self.do_import_session(ctx.fd, req)
+        })
+        .into_ttrpc()
+    }
+
+    fn remove_persistent_resource(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: RemovePersistentRequest,
+    ) -> ttrpc::Result<Empty> {
+        self.serve_admin(ctx.fd, "RemovePersistentResource", req, |req| {
+            self.do_remove_persistent_resource(req)
+        })
+        .into_ttrpc()
+    }
+}
@@ -1,6 +1,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{agent_service::IntoTtrpcResult, AgentService};
+use crate::{
+    agent_service::{IntoTtrpcResult, RequestMeta},
+    AgentService,
+};
 #[allow(unused_imports)]
 use vaccel_rpc_proto::tf::{
     ModelLoadRequest as TFModelLoadRequest, ModelLoadResponse as TFModelLoadResponse,
@@ -11,157 +14,271 @@ use vaccel_rpc_proto::{
     empty::Empty,
     genop::{Request as GenopRequest, Response as GenopResponse},
     image::{Request as ImageRequest, Response as ImageResponse},
+    info::{Request as InfoRequest, Response as InfoResponse},
     profiling::{Request as ProfilingRequest, Response as ProfilingResponse},
-    resource::{RegisterRequest, RegisterResponse, SyncRequest, SyncResponse, UnregisterRequest},
-    session::{CreateRequest, CreateResponse, DestroyRequest, UpdateRequest},
+    resource::{
+        LookupBlobsRequest, LookupBlobsResponse, RegisterChunk, RegisterChunkResponse,
+        RegisterRequest, RegisterResponse, SyncRequest, SyncResponse, UnregisterRequest,
+    },
+    session::{
+        CreateRequest, CreateResponse, DestroyRequest, ResolveIdsRequest, ResolveIdsResponse,
+        UpdateRequest,
+    },
     sync::agent_ttrpc,
     tflite::{
-        ModelLoadRequest as TFLiteModelLoadRequest, ModelRunRequest as TFLiteModelRunRequest,
-        ModelRunResponse as TFLiteModelRunResponse, ModelUnloadRequest as TFLiteModelUnloadRequest,
+        ModelLoadRequest as TFLiteModelLoadRequest, ModelLoadResponse as TFLiteModelLoadResponse,
+        ModelRunRequest as TFLiteModelRunRequest, ModelRunResponse as TFLiteModelRunResponse,
+        ModelUnloadRequest as TFLiteModelUnloadRequest,
     },
     torch::{
-        ModelLoadRequest as TorchModelLoadRequest, ModelRunRequest as TorchModelRunRequest,
-        ModelRunResponse as TorchModelRunResponse,
+        ModelLoadRequest as TorchModelLoadRequest, ModelLoadResponse as TorchModelLoadResponse,
+        ModelRunRequest as TorchModelRunRequest, ModelRunResponse as TorchModelRunResponse,
     },
 };
 
+/// The full name of the agent ttrpc service.
+pub(crate) const SERVICE: &str = "vaccel.sync.agent.AgentService";
+
+impl<'a> From<&'a ::ttrpc::sync::TtrpcContext> for RequestMeta<'a> {
+    fn from(ctx: &'a ::ttrpc::sync::TtrpcContext) -> Self {
+        RequestMeta {
+            fd: ctx.fd,
+            metadata: &ctx.metadata,
+            timeout_nano: ctx.timeout_nano,
+        }
+    }
+}
+
 impl agent_ttrpc::AgentService for AgentService {
     fn create_session(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: CreateRequest,
     ) -> ttrpc::Result<CreateResponse> {
-        self.do_create_session(req).into_ttrpc()
+        self.serve(ctx, "CreateSession", req, |ctx, req| {
+            self.do_create_session(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn update_session(
         &self,
-        _ctx: &::ttrpc::TtrpcContext,
+        ctx: &::ttrpc::TtrpcContext,
         req: UpdateRequest,
     ) -> ttrpc::Result<Empty> {
-        self.do_update_session(req).into_ttrpc()
+        self.serve(ctx, "UpdateSession", req, |ctx, req| {
+            self.do_update_session(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn destroy_session(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: DestroyRequest,
     ) -> ttrpc::Result<Empty> {
-        self.do_destroy_session(req).into_ttrpc()
+        self.serve(ctx, "DestroySession", req, |ctx, req| {
+            self.do_destroy_session(ctx, req)
+        })
+        .into_ttrpc()
+    }
+
+    fn resolve_ids(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: ResolveIdsRequest,
+    ) -> ttrpc::Result<ResolveIdsResponse> {
+        self.serve(ctx, "ResolveIds", req, |ctx, req| {
+            self.do_resolve_ids(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn register_resource(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: RegisterRequest,
     ) -> ttrpc::Result<RegisterResponse> {
-        self.do_register_resource(req).into_ttrpc()
+        self.serve(ctx, "RegisterResource", req, |ctx, req| {
+            self.do_register_resource(ctx, req)
+        })
+        .into_ttrpc()
+    }
+
+    fn register_resource_chunk(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: RegisterChunk,
+    ) -> ttrpc::Result<RegisterChunkResponse> {
+        self.serve(ctx, "RegisterResourceChunk", req, |ctx, req| {
+            self.do_register_chunk(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn unregister_resource(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: UnregisterRequest,
     ) -> ttrpc::Result<Empty> {
-        self.do_unregister_resource(req).into_ttrpc()
+        self.serve(ctx, "UnregisterResource", req, |ctx, req| {
+            self.do_unregister_resource(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn sync_resource(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: SyncRequest,
     ) -> ttrpc::Result<SyncResponse> {
-        self.do_sync_resource(req).into_ttrpc()
+        self.serve(ctx, "SyncResource", req, |ctx, req| {
+            self.do_sync_resource(ctx, req)
+        })
+        .into_ttrpc()
+    }
+
+    fn lookup_blobs(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: LookupBlobsRequest,
+    ) -> ttrpc::Result<LookupBlobsResponse> {
+        self.serve(ctx, "LookupBlobs", req, |ctx, req| {
+            self.do_lookup_blobs(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn genop(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: GenopRequest,
     ) -> ttrpc::Result<GenopResponse> {
-        self.do_genop(req).into_ttrpc()
+        self.serve(ctx, "Genop", req, |ctx, req| self.do_genop(ctx, req))
+            .into_ttrpc()
     }
 
     fn get_profiler(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: ProfilingRequest,
     ) -> ttrpc::Result<ProfilingResponse> {
-        self.do_get_profiler(req).into_ttrpc()
+        self.serve(ctx, "GetProfiler", req, |ctx, req| {
+            self.do_get_profiler(ctx, req)
+        })
+        .into_ttrpc()
+    }
+
+    fn get_agent_info(
+        &self,
+        ctx: &::ttrpc::sync::TtrpcContext,
+        req: InfoRequest,
+    ) -> ttrpc::Result<InfoResponse> {
+        self.serve(ctx, "GetAgentInfo", req, |_, req| {
+            self.do_get_agent_info(req)
+        })
+        .into_ttrpc()
     }
 
     fn image_classification(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: ImageRequest,
     ) -> ttrpc::Result<ImageResponse> {
-        self.do_image_classification(req).into_ttrpc()
+        self.serve(ctx, "ImageClassification", req, |ctx, req| {
+            self.do_image_classification(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     #[cfg(target_pointer_width = "64")]
     fn tensorflow_model_load(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: TFModelLoadRequest,
     ) -> ttrpc::Result<TFModelLoadResponse> {
-        self.do_tensorflow_model_load(req).into_ttrpc()
+        self.serve(ctx, "TensorflowModelLoad", req, |ctx, req| {
+            self.do_tensorflow_model_load(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     #[cfg(target_pointer_width = "64")]
     fn tensorflow_model_unload(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: TFModelUnloadRequest,
     ) -> ttrpc::Result<TFModelUnloadResponse> {
-        self.do_tensorflow_model_unload(req).into_ttrpc()
+        self.serve(ctx, "TensorflowModelUnload", req, |ctx, req| {
+            self.do_tensorflow_model_unload(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     #[cfg(target_pointer_width = "64")]
     fn tensorflow_model_run(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: TFModelRunRequest,
     ) -> ttrpc::Result<TFModelRunResponse> {
-        self.do_tensorflow_model_run(req).into_ttrpc()
+        self.serve(ctx, "TensorflowModelRun", req, |ctx, req| {
+            self.do_tensorflow_model_run(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn tensorflow_lite_model_load(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: TFLiteModelLoadRequest,
-    ) -> ttrpc::Result<Empty> {
-        self.do_tflite_model_load(req).into_ttrpc()
+    ) -> ttrpc::Result<TFLiteModelLoadResponse> {
+        self.serve(ctx, "TensorflowLiteModelLoad", req, |ctx, req| {
+            self.do_tflite_model_load(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn tensorflow_lite_model_unload(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: TFLiteModelUnloadRequest,
     ) -> ttrpc::Result<Empty> {
-        self.do_tflite_model_unload(req).into_ttrpc()
+        self.serve(ctx, "TensorflowLiteModelUnload", req, |ctx, req| {
+            self.do_tflite_model_unload(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn tensorflow_lite_model_run(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: TFLiteModelRunRequest,
     ) -> ttrpc::Result<TFLiteModelRunResponse> {
-        self.do_tflite_model_run(req).into_ttrpc()
+        self.serve(ctx, "TensorflowLiteModelRun", req, |ctx, req| {
+            self.do_tflite_model_run(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn torch_model_load(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: TorchModelLoadRequest,
-    ) -> ttrpc::Result<Empty> {
-        self.do_torch_model_load(req).into_ttrpc()
+    ) -> ttrpc::Result<TorchModelLoadResponse> {
+        self.serve(ctx, "TorchModelLoad", req, |ctx, req| {
+            self.do_torch_model_load(ctx, req)
+        })
+        .into_ttrpc()
     }
 
     fn torch_model_run(
         &self,
-        _ctx: &::ttrpc::sync::TtrpcContext,
+        ctx: &::ttrpc::sync::TtrpcContext,
         req: TorchModelRunRequest,
     ) -> ttrpc::Result<TorchModelRunResponse> {
-        self.do_torch_model_run(req).into_ttrpc()
+        self.serve(ctx, "TorchModelRun", req, |ctx, req| {
+            self.do_torch_model_run(ctx, req)
+        })
+        .into_ttrpc()
     }
 }
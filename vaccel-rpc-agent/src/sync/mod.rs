@@ -1,3 +1,6 @@
 // SPDX-License-Identifier: Apache-2.0
 
+mod admin_service;
 mod agent_service;
+
+pub(crate) use agent_service::SERVICE;
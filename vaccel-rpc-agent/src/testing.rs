@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers shared by the unit tests.
+
+use crate::agent_service::{AgentService, RequestContext, RequestMeta, Result};
+use protobuf::EnumOrUnknown;
+use std::{collections::HashMap, os::unix::io::RawFd, sync::Once};
+use vaccel_rpc_proto::{
+    resource::{Blob as ProtoBlob, BlobType, RegisterRequest, ResourceType, UnregisterRequest},
+    session::{CreateRequest, DestroyRequest},
+    torch,
+};
+
+/// Bootstraps vAccel with the noop plugin, once per test binary.
+pub(crate) fn bootstrap() {
+    static BOOTSTRAP: Once = Once::new();
+    BOOTSTRAP.call_once(|| {
+        if !vaccel::is_initialized() {
+            let mut config =
+                vaccel::Config::new(Some("libvaccel-noop.so"), 1, None, false, false).unwrap();
+            vaccel::bootstrap_with_config(&mut config).unwrap();
+        }
+    });
+}
+
+/// Returns the context of an unauthenticated request, without a deadline,
+/// received on the connection of `fd`.
+pub(crate) fn request_context(fd: RawFd) -> RequestContext {
+    let metadata = HashMap::new();
+    let meta = RequestMeta {
+        fd,
+        metadata: &metadata,
+        timeout_nano: 0,
+    };
+    RequestContext::new(&meta, None).unwrap()
+}
+
+pub(crate) fn create_session(service: &AgentService, ctx: &RequestContext) -> i64 {
+    service
+        .do_create_session(ctx, CreateRequest::new())
+        .unwrap()
+        .session_id
+}
+
+pub(crate) fn destroy_session(service: &AgentService, ctx: &RequestContext, session_id: i64) {
+    service
+        .do_destroy_session(
+            ctx,
+            DestroyRequest {
+                session_id,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+}
+
+pub(crate) fn register(
+    service: &AgentService,
+    ctx: &RequestContext,
+    session_id: i64,
+    resource_id: i64,
+) -> i64 {
+    let blob = (resource_id == 0).then(|| vec![0u8; 64]);
+    register_blob(service, ctx, session_id, resource_id, blob)
+}
+
+/// Registers a resource, creating it from `data` if `resource_id` is 0.
+/// Resources with the same data are shared.
+pub(crate) fn register_blob(
+    service: &AgentService,
+    ctx: &RequestContext,
+    session_id: i64,
+    resource_id: i64,
+    data: Option<Vec<u8>>,
+) -> i64 {
+    let blobs = match data {
+        Some(data) => vec![ProtoBlob {
+            type_: EnumOrUnknown::new(BlobType::BUFFER),
+            name: "blob".to_string(),
+            size: data.len() as u32,
+            data,
+            ..Default::default()
+        }],
+        None => Vec::new(),
+    };
+    service
+        .do_register_resource(
+            ctx,
+            RegisterRequest {
+                blobs,
+                resource_type: EnumOrUnknown::new(ResourceType::MODEL),
+                resource_id,
+                session_id,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .resource_id
+}
+
+pub(crate) fn unregister(
+    service: &AgentService,
+    ctx: &RequestContext,
+    session_id: i64,
+    resource_id: i64,
+) {
+    service
+        .do_unregister_resource(
+            ctx,
+            UnregisterRequest {
+                resource_id,
+                session_id,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+}
+
+pub(crate) fn torch_run(
+    service: &AgentService,
+    ctx: &RequestContext,
+    session_id: i64,
+    model_id: i64,
+) -> Result<torch::ModelRunResponse> {
+    service.do_torch_model_run(
+        ctx,
+        torch::ModelRunRequest {
+            session_id,
+            model_id,
+            in_tensors: vec![torch::Tensor {
+                data: vec![0u8; 16],
+                dims: vec![4],
+                type_: EnumOrUnknown::new(torch::DataType::FLOAT),
+                ..Default::default()
+            }],
+            nr_out_tensors: 1,
+            ..Default::default()
+        },
+    )
+}
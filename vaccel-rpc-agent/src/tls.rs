@@ -0,0 +1,467 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{config::TlsConfig, Error, Result};
+use log::{debug, warn};
+use rustls::{
+    server::WebPkiClientVerifier, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, DirBuilder},
+    io, mem,
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::DirBuilderExt,
+        io::{FromRawFd, OwnedFd},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use vaccel_rpc_proto::tls::{bridge, load_certs, load_key};
+
+/// The maximum time a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum number of TLS connections served at once per listener.
+const MAX_CONNECTIONS: usize = 1024;
+
+/// The TCP peers of the forwarded connections, keyed by the path of the
+/// unix socket that forwards each.
+static FORWARDED_PEERS: Mutex<BTreeMap<PathBuf, SocketAddr>> = Mutex::new(BTreeMap::new());
+
+fn tls_error(e: io::Error) -> Error {
+    Error::Tls(e.to_string())
+}
+
+/// Builds the rustls server configuration, requiring client certificates
+/// signed by the configured CA if one is set.
+pub(crate) fn server_config(tls: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let (cert, key) = match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => (
+            load_certs(cert).map_err(tls_error)?,
+            load_key(key).map_err(tls_error)?,
+        ),
+        _ => {
+            return Err(Error::Tls(
+                "A certificate and a key are required for tls:// addresses".to_string(),
+            ))
+        }
+    };
+
+    let builder = ServerConfig::builder();
+    let builder = match &tls.ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for c in load_certs(ca).map_err(tls_error)? {
+                roots
+                    .add(c)
+                    .map_err(|e| Error::Tls(format!("Invalid CA certificate: {}", e)))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| Error::Tls(e.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(cert, key)
+        .map_err(|e| Error::Tls(e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+/// A private directory holding the unix sockets the TLS listeners forward
+/// to.
+#[derive(Debug)]
+pub(crate) struct SocketDir(PathBuf);
+
+impl SocketDir {
+    pub(crate) fn new() -> Result<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "vaccel-rpc-agent-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        DirBuilder::new().mode(0o700).create(&path)?;
+        Ok(Self(path))
+    }
+
+    /// Returns a new socket path in the directory.
+    pub(crate) fn socket(&self, index: usize) -> PathBuf {
+        self.0.join(format!("tls-{}.sock", index))
+    }
+}
+
+impl Drop for SocketDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Returns the TCP peer of a forwarded TLS connection, given the path of the
+/// unix socket the agent sees as its peer.
+pub(crate) fn forwarded_peer(path: &Path) -> Option<SocketAddr> {
+    FORWARDED_PEERS.lock().unwrap().get(path).copied()
+}
+
+/// Records the TCP peer of a forwarded connection for as long as it is
+/// alive.
+struct ForwardedPeer(PathBuf);
+
+impl ForwardedPeer {
+    fn new(path: PathBuf, peer: SocketAddr) -> Self {
+        FORWARDED_PEERS.lock().unwrap().insert(path.clone(), peer);
+        Self(path)
+    }
+}
+
+impl Drop for ForwardedPeer {
+    fn drop(&mut self) {
+        FORWARDED_PEERS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Counts a connection towards `MAX_CONNECTIONS` while alive.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn acquire(count: &Arc<AtomicUsize>) -> Option<Self> {
+        count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(count.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Accepts TLS connections and forwards them to a unix socket.
+///
+/// ttrpc only supports plain sockets, so a `tls://` address is served by a
+/// listener that terminates TLS in front of a ttrpc server bound on a private
+/// unix socket. Each connection is forwarded from its own unix socket path,
+/// so that the agent can map it back to the TCP peer.
+pub(crate) struct TlsListener {
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl TlsListener {
+    pub(crate) fn start(
+        address: SocketAddr,
+        backend: PathBuf,
+        config: Arc<ServerConfig>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let handle = thread::spawn(move || {
+            static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+            let connections = Arc::new(AtomicUsize::new(0));
+
+            for stream in listener.incoming() {
+                if stop_flag.load(Ordering::Relaxed) {
+                    break;
+                }
+                let tcp = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("Could not accept TLS connection: {}", e);
+                        continue;
+                    }
+                };
+                let peer = match tcp.peer_addr() {
+                    Ok(p) => p,
+                    Err(e) => {
+                        debug!("Could not get TLS connection peer: {}", e);
+                        continue;
+                    }
+                };
+                let Some(slot) = ConnectionSlot::acquire(&connections) else {
+                    warn!(
+                        "Rejecting TLS connection from {}: too many connections",
+                        peer
+                    );
+                    continue;
+                };
+
+                let local = backend.with_file_name(format!(
+                    "peer-{}.sock",
+                    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+                ));
+                let backend = backend.clone();
+                let config = config.clone();
+                thread::spawn(move || {
+                    let _slot = slot;
+                    debug!("New TLS connection from {}", peer);
+                    if let Err(e) = serve(tcp, peer, &local, &backend, config) {
+                        debug!("TLS connection from {} closed: {}", peer, e);
+                    }
+                });
+            }
+        });
+
+        debug!("Listening for TLS connections on {}", address);
+        Ok(Self {
+            address,
+            stop,
+            handle,
+        })
+    }
+
+    pub(crate) fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake up the listener thread
+        let _ = TcpStream::connect(self.address);
+        let _ = self.handle.join();
+    }
+}
+
+fn serve(
+    mut tcp: TcpStream,
+    peer: SocketAddr,
+    local: &Path,
+    backend: &Path,
+    config: Arc<ServerConfig>,
+) -> io::Result<()> {
+    let mut tls = ServerConnection::new(config).map_err(io::Error::other)?;
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    while tls.is_handshaking() {
+        let timeout = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|t| !t.is_zero())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?;
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+        tls.complete_io(&mut tcp)?;
+    }
+    tcp.set_read_timeout(None)?;
+    tcp.set_write_timeout(None)?;
+
+    let _peer = ForwardedPeer::new(local.to_path_buf(), peer);
+    let plain = connect_from(local, backend)?;
+    bridge(Connection::Server(tls), tcp, plain)
+}
+
+fn sockaddr_un(path: &Path) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Socket path {} is too long", path.display()),
+        ));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+
+    let len = mem::size_of::<libc::sa_family_t>() + bytes.len() + 1;
+    Ok((addr, len as libc::socklen_t))
+}
+
+/// Connects to the unix socket `backend` from a socket bound to `local`, so
+/// that the accepted connection has `local` as its peer address.
+fn connect_from(local: &Path, backend: &Path) -> io::Result<UnixStream> {
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let (addr, len) = sockaddr_un(local)?;
+    if unsafe { libc::bind(fd, &addr as *const _ as *const libc::sockaddr, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The bound address is kept by the socket, the file is not needed
+    let _ = fs::remove_file(local);
+
+    let (addr, len) = sockaddr_un(backend)?;
+    if unsafe { libc::connect(fd, &addr as *const _ as *const libc::sockaddr, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(UnixStream::from(socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionId;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, StreamOwned};
+    use std::{
+        io::{Read, Write},
+        os::unix::{io::AsRawFd, net::UnixListener},
+    };
+
+    struct Pki {
+        dir: SocketDir,
+        ca: CertifiedKey,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key_pair = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key_pair).unwrap();
+            Self {
+                dir: SocketDir::new().unwrap(),
+                ca: CertifiedKey { cert, key_pair },
+            }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.0.join(name)
+        }
+
+        /// Writes the CA certificate, returning its path.
+        fn ca(&self) -> PathBuf {
+            let path = self.path("ca.pem");
+            fs::write(&path, self.ca.cert.pem()).unwrap();
+            path
+        }
+
+        /// Issues a certificate for `name`, returning the certificate and key
+        /// paths.
+        fn issue(&self, name: &str) -> (PathBuf, PathBuf) {
+            let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            let key_pair = KeyPair::generate().unwrap();
+            let cert = params
+                .signed_by(&key_pair, &self.ca.cert, &self.ca.key_pair)
+                .unwrap();
+
+            let (cert_path, key_path) = (
+                self.path(&format!("{}.pem", name)),
+                self.path(&format!("{}.key", name)),
+            );
+            fs::write(&cert_path, cert.pem()).unwrap();
+            fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+            (cert_path, key_path)
+        }
+
+        fn start(&self, client_ca: bool) -> (TlsListener, UnixListener) {
+            let (cert, key) = self.issue("localhost");
+            let config = server_config(&TlsConfig {
+                cert: Some(cert),
+                key: Some(key),
+                ca: client_ca.then(|| self.ca()),
+            })
+            .unwrap();
+
+            let socket = self.dir.socket(0);
+            let backend = UnixListener::bind(&socket).unwrap();
+            let listener =
+                TlsListener::start("127.0.0.1:0".parse().unwrap(), socket, config).unwrap();
+            (listener, backend)
+        }
+
+        fn connect(
+            &self,
+            listener: &TlsListener,
+            client_cert: Option<&str>,
+        ) -> StreamOwned<ClientConnection, TcpStream> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.cert.der().clone()).unwrap();
+            let builder = ClientConfig::builder().with_root_certificates(roots);
+            let config = match client_cert {
+                Some(name) => {
+                    let (cert, key) = self.issue(name);
+                    builder
+                        .with_client_auth_cert(load_certs(&cert).unwrap(), load_key(&key).unwrap())
+                        .unwrap()
+                }
+                None => builder.with_no_client_auth(),
+            };
+
+            let tls =
+                ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap())
+                    .unwrap();
+            let tcp = TcpStream::connect(listener.address).unwrap();
+            StreamOwned::new(tls, tcp)
+        }
+    }
+
+    /// Sends a message through the listener and echoes it back from the
+    /// backend, returning the peer the agent sees.
+    fn round_trip(
+        client: &mut StreamOwned<ClientConnection, TcpStream>,
+        backend: &UnixListener,
+    ) -> String {
+        client.write_all(b"ping").unwrap();
+        client.flush().unwrap();
+
+        let (mut conn, _) = backend.accept().unwrap();
+        let peer = ConnectionId::from_fd(conn.as_raw_fd()).unwrap().peer();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        conn.write_all(b"pong").unwrap();
+
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+        peer
+    }
+
+    #[test]
+    fn forwards_tls_connections_with_their_peer() {
+        let pki = Pki::new();
+        let (listener, backend) = pki.start(false);
+
+        let mut client = pki.connect(&listener, None);
+        let peer = round_trip(&mut client, &backend);
+        assert_eq!(
+            peer,
+            format!("tls://{}", client.get_ref().local_addr().unwrap())
+        );
+
+        drop(client);
+        listener.stop();
+    }
+
+    #[test]
+    fn mutual_tls_requires_a_client_certificate() {
+        let pki = Pki::new();
+        let (listener, backend) = pki.start(true);
+
+        let mut client = pki.connect(&listener, None);
+        let _ = client.write_all(b"ping");
+        let mut buf = [0u8; 4];
+        assert!(client.read_exact(&mut buf).is_err());
+
+        backend.set_nonblocking(true).unwrap();
+        assert_eq!(
+            backend.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        backend.set_nonblocking(false).unwrap();
+
+        let mut client = pki.connect(&listener, Some("client"));
+        round_trip(&mut client, &backend);
+
+        drop(client);
+        listener.stop();
+    }
+}
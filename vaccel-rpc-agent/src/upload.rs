@@ -0,0 +1,399 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent_service::{AgentService, AgentServiceError, RequestContext, Result},
+    connection::ConnectionId,
+};
+use log::info;
+use std::{mem, sync::atomic::Ordering};
+use vaccel_rpc_proto::resource::{
+    Blob as ProtoBlob, RegisterChunk, RegisterChunkResponse, RegisterRequest,
+};
+
+/// Maximum number of concurrent uploads per connection.
+pub(crate) const MAX_UPLOADS: usize = 4;
+
+/// A resource being uploaded in chunks.
+///
+/// The data received is charged to the blob bytes of the connection while
+/// the upload is in progress.
+#[derive(Debug)]
+pub(crate) struct Upload {
+    req: RegisterRequest,
+    /// The number of data bytes expected for the blob currently uploaded,
+    /// zero if it is sent by digest
+    blob_size: u64,
+    bytes: usize,
+    committed: bool,
+}
+
+impl Upload {
+    /// Creates an upload from its first chunk.
+    pub(crate) fn new(first: &RegisterChunk) -> Self {
+        let mut req = RegisterRequest::new();
+        req.session_id = first.session_id;
+        req.resource_type = first.resource_type;
+        req.name = first.name.clone();
+
+        Upload {
+            req,
+            blob_size: 0,
+            bytes: 0,
+            committed: false,
+        }
+    }
+
+    fn is_blob_complete(&self) -> bool {
+        self.req
+            .blobs
+            .last()
+            .is_none_or(|b| b.data.len() as u64 == self.blob_size)
+    }
+
+    /// Returns the number of data bytes received.
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Adds the data of `chunk` to the upload, after accounting it with
+    /// `charge`.
+    pub(crate) fn add(
+        &mut self,
+        mut chunk: RegisterChunk,
+        charge: impl FnOnce(usize) -> Result<()>,
+    ) -> Result<()> {
+        if self.committed {
+            return Err(AgentServiceError::InvalidArgument(
+                "Chunk received after commit".to_string(),
+            ));
+        }
+
+        let index = chunk.blob_index as usize;
+        if index == self.req.blobs.len() {
+            if !self.is_blob_complete() {
+                return Err(AgentServiceError::InvalidArgument(format!(
+                    "Blob {} is incomplete",
+                    index - 1
+                )));
+            }
+            let size = u32::try_from(chunk.blob_size).map_err(|_| {
+                AgentServiceError::InvalidArgument(format!("Blob {} is too large", index))
+            })?;
+            // The data of a blob sent by digest is filled in from the cache
+            // when the resource is registered
+            let by_digest = chunk.data.is_empty() && !chunk.blob_sha256.is_empty();
+            self.req.blobs.push(ProtoBlob {
+                type_: chunk.blob_type,
+                name: chunk.blob_name.clone(),
+                size,
+                sha256: mem::take(&mut chunk.blob_sha256),
+                ..Default::default()
+            });
+            self.blob_size = match by_digest {
+                true => 0,
+                false => chunk.blob_size,
+            };
+        } else if index + 1 != self.req.blobs.len() {
+            return Err(AgentServiceError::InvalidArgument(format!(
+                "Unexpected chunk of blob {}",
+                index
+            )));
+        }
+
+        let blob = self.req.blobs.last_mut().unwrap();
+        if chunk.offset != blob.data.len() as u64 {
+            return Err(AgentServiceError::InvalidArgument(format!(
+                "Unexpected offset {} of blob {}; expected {}",
+                chunk.offset,
+                index,
+                blob.data.len()
+            )));
+        }
+        if chunk.offset + chunk.data.len() as u64 > self.blob_size {
+            return Err(AgentServiceError::InvalidArgument(format!(
+                "Data of blob {} exceeds its size of {} bytes",
+                index, self.blob_size
+            )));
+        }
+
+        charge(chunk.data.len())?;
+        self.bytes += chunk.data.len();
+        blob.data.append(&mut chunk.data);
+        self.committed = chunk.commit;
+
+        Ok(())
+    }
+
+    /// Returns the request registering the uploaded resource.
+    pub(crate) fn finish(self) -> Result<RegisterRequest> {
+        if !self.committed {
+            return Err(AgentServiceError::InvalidArgument(
+                "Upload was not committed".to_string(),
+            ));
+        }
+        if self.req.blobs.is_empty() || !self.is_blob_complete() {
+            return Err(AgentServiceError::InvalidArgument(
+                "Upload is incomplete".to_string(),
+            ));
+        }
+
+        Ok(self.req)
+    }
+}
+
+impl AgentService {
+    /// Accounts an upload to a connection, failing if the connection has
+    /// reached the concurrent uploads limit.
+    pub(crate) fn begin_upload(&self, conn_id: ConnectionId) -> Result<()> {
+        let mut conn = self.connections.entry(conn_id).or_default();
+        if conn.uploads >= MAX_UPLOADS {
+            return Err(AgentServiceError::ResourceExhausted(format!(
+                "Concurrent uploads limit of {} reached",
+                MAX_UPLOADS
+            )));
+        }
+        conn.uploads += 1;
+
+        Ok(())
+    }
+
+    /// Reverts the accounting of `begin_upload()` and of the `bytes`
+    /// received. The bytes are charged again if the resource is registered.
+    pub(crate) fn end_upload(&self, conn_id: ConnectionId, bytes: usize) {
+        if let Some(mut conn) = self.connections.get_mut(&conn_id) {
+            conn.uploads = conn.uploads.saturating_sub(1);
+            conn.blob_bytes = conn.blob_bytes.saturating_sub(bytes);
+        }
+    }
+
+    /// Adds a chunk to an upload of the request connection, starting a new
+    /// upload if the chunk has no upload id. The resource is registered once
+    /// the upload is committed.
+    pub(crate) fn do_register_chunk(
+        &self,
+        ctx: &RequestContext,
+        chunk: RegisterChunk,
+    ) -> Result<RegisterChunkResponse> {
+        let upload_id = match chunk.upload_id {
+            0 => {
+                self.begin_upload(ctx.connection)?;
+                let id = self.next_upload_id.fetch_add(1, Ordering::Relaxed) + 1;
+                self.uploads
+                    .insert(id, (ctx.connection, Upload::new(&chunk)));
+                info!("Started upload {}", id);
+                id
+            }
+            id => id,
+        };
+
+        let commit = chunk.commit;
+        {
+            let mut upload = self
+                .uploads
+                .get_mut(&upload_id)
+                .filter(|u| u.0 == ctx.connection)
+                .ok_or_else(|| {
+                    AgentServiceError::NotFound(format!("Unknown upload {}", upload_id))
+                })?;
+            let ret = upload
+                .1
+                .add(chunk, |bytes| self.charge_upload(ctx.connection, bytes));
+            if let Err(e) = ret {
+                drop(upload);
+                if let Some((_, (_, upload))) = self.uploads.remove(&upload_id) {
+                    self.end_upload(ctx.connection, upload.bytes());
+                }
+                return Err(e);
+            }
+        }
+
+        let mut resp = RegisterChunkResponse::new();
+        resp.upload_id = upload_id;
+        if commit {
+            let (_, (_, upload)) = self.uploads.remove(&upload_id).ok_or_else(|| {
+                AgentServiceError::NotFound(format!("Unknown upload {}", upload_id))
+            })?;
+            self.end_upload(ctx.connection, upload.bytes());
+            let registered = self.do_register_resource(ctx, upload.finish()?)?;
+            resp.resource_id = registered.resource_id;
+            resp.missing = registered.missing;
+        }
+
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::CacheConfig,
+        limits::Limits,
+        testing::{bootstrap, request_context},
+    };
+    use protobuf::EnumOrUnknown;
+    use sha2::{Digest as _, Sha256};
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+    use vaccel_rpc_proto::{
+        resource::{BlobType, ResourceType},
+        session::CreateRequest,
+    };
+
+    fn chunk(session_id: i64, upload_id: i64, data: Vec<u8>, commit: bool) -> RegisterChunk {
+        RegisterChunk {
+            session_id,
+            resource_type: EnumOrUnknown::new(ResourceType::MODEL),
+            blob_name: "model".to_string(),
+            blob_type: EnumOrUnknown::new(BlobType::BUFFER),
+            blob_size: 64,
+            data,
+            commit,
+            upload_id,
+            ..Default::default()
+        }
+    }
+
+    fn by_digest(session_id: i64, data: &[u8]) -> RegisterChunk {
+        RegisterChunk {
+            blob_sha256: Sha256::digest(data).to_vec(),
+            ..chunk(session_id, 0, Vec::new(), true)
+        }
+    }
+
+    #[test]
+    fn uploads_are_charged_to_the_connection() {
+        bootstrap();
+
+        let service = AgentService::new();
+        service.set_limits(Limits {
+            max_blob_bytes: Some(90),
+            ..Default::default()
+        });
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+        let sess = service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap()
+            .session_id;
+        let conn = |f: fn(&crate::connection::Connection) -> usize| {
+            f(&service.connections.get(&ctx.connection).unwrap())
+        };
+
+        let first = service
+            .do_register_chunk(&ctx, chunk(sess, 0, vec![1u8; 32], false))
+            .unwrap()
+            .upload_id;
+        assert_eq!(conn(|c| c.blob_bytes), 32);
+
+        // The data of both uploads exceeds the limit
+        assert_eq!(
+            service
+                .do_register_chunk(&ctx, chunk(sess, 0, vec![2u8; 64], false))
+                .unwrap_err()
+                .code(),
+            ttrpc::Code::RESOURCE_EXHAUSTED
+        );
+        assert_eq!(service.uploads.len(), 1);
+        assert_eq!((conn(|c| c.blob_bytes), conn(|c| c.uploads)), (32, 1));
+
+        // The bytes are charged to the resource once registered
+        let resp = service
+            .do_register_chunk(
+                &ctx,
+                RegisterChunk {
+                    offset: 32,
+                    ..chunk(sess, first, vec![1u8; 32], true)
+                },
+            )
+            .unwrap();
+        assert_ne!(resp.resource_id, 0);
+        assert!(service.uploads.is_empty());
+        assert_eq!((conn(|c| c.blob_bytes), conn(|c| c.uploads)), (64, 0));
+    }
+
+    #[test]
+    fn concurrent_uploads_are_capped() {
+        bootstrap();
+
+        let service = AgentService::new();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+
+        for _ in 0..MAX_UPLOADS {
+            service
+                .do_register_chunk(&ctx, chunk(1, 0, vec![0u8; 16], false))
+                .unwrap();
+        }
+        assert_eq!(
+            service
+                .do_register_chunk(&ctx, chunk(1, 0, vec![0u8; 16], false))
+                .unwrap_err()
+                .code(),
+            ttrpc::Code::RESOURCE_EXHAUSTED
+        );
+
+        // Uploads of other connections are not affected
+        let (_other_client, other_server) = UnixStream::pair().unwrap();
+        let other = request_context(other_server.as_raw_fd());
+        service
+            .do_register_chunk(&other, chunk(1, 0, vec![0u8; 16], false))
+            .unwrap();
+    }
+
+    #[test]
+    fn cached_blobs_are_uploaded_by_digest() {
+        bootstrap();
+
+        let service = AgentService::new();
+        service.set_cache_config(&CacheConfig {
+            max_bytes: Some(1024),
+        });
+        let (_client, server) = UnixStream::pair().unwrap();
+        let ctx = request_context(server.as_raw_fd());
+        let sess = service
+            .do_create_session(&ctx, CreateRequest::new())
+            .unwrap()
+            .session_id;
+
+        // Blobs of resources that fail to register are not cached
+        let data = vec![7u8; 64];
+        service
+            .do_register_chunk(&ctx, chunk(1 << 40, 0, data.clone(), true))
+            .unwrap_err();
+        let resp = service
+            .do_register_chunk(&ctx, by_digest(sess, &data))
+            .unwrap();
+        assert_eq!(resp.resource_id, 0);
+        assert_eq!(resp.missing, vec![Sha256::digest(&data).to_vec()]);
+
+        let uploaded = service
+            .do_register_chunk(&ctx, chunk(sess, 0, data.clone(), true))
+            .unwrap()
+            .resource_id;
+        let resp = service
+            .do_register_chunk(&ctx, by_digest(sess, &data))
+            .unwrap();
+        assert_eq!(resp.resource_id, uploaded);
+        assert!(resp.missing.is_empty());
+    }
+
+    #[test]
+    fn blobs_sent_by_digest_take_no_data() {
+        let first = by_digest(1, &[0u8; 64]);
+        let mut upload = Upload::new(&first);
+        upload
+            .add(
+                RegisterChunk {
+                    commit: false,
+                    ..first
+                },
+                |_| Ok(()),
+            )
+            .unwrap();
+
+        let err = upload
+            .add(chunk(1, 1, vec![0u8; 64], true), |_| Ok(()))
+            .unwrap_err();
+        assert_eq!(err.code(), ttrpc::Code::INVALID_ARGUMENT);
+    }
+}
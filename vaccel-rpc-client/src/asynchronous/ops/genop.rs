@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::client::VaccelRpcClient;
-use crate::Result;
+use crate::{timeout::Deadline, Result};
 use protobuf::Message;
 use ttrpc::asynchronous::ClientStreamSender;
 use vaccel::{profiling::SessionProfiler, VaccelId};
@@ -18,9 +18,10 @@ impl VaccelRpcClient {
         is_read: bool,
     ) {
         let sess_vaccel_id = VaccelId::try_from(sess_id).unwrap();
+        let agent_sess_id = self.ids.session(sess_id);
 
         let mut req = Request {
-            session_id: sess_vaccel_id.into(),
+            session_id: agent_sess_id,
             ..Default::default()
         };
 
@@ -63,12 +64,12 @@ impl VaccelRpcClient {
                                 };
                                 match is_read {
                                     true => Request {
-                                        session_id: sess_id,
+                                        session_id: agent_sess_id,
                                         read_args: vec![arg],
                                         ..Default::default()
                                     },
                                     false => Request {
-                                        session_id: sess_id,
+                                        session_id: agent_sess_id,
                                         write_args: vec![arg],
                                         ..Default::default()
                                     },
@@ -85,7 +86,7 @@ impl VaccelRpcClient {
                     .await;
                 }
                 req = Request {
-                    session_id: sess_id,
+                    session_id: agent_sess_id,
                     ..Default::default()
                 };
             }
@@ -111,7 +112,8 @@ impl VaccelRpcClient {
         read_args: Vec<Arg>,
         write_args: Vec<Arg>,
     ) -> Result<Vec<Arg>> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.with_token(self.context("genop_stream"));
+        let deadline = Deadline::of(&ctx);
         let sess_vaccel_id = VaccelId::try_from(sess_id)?;
 
         self.start_profiling(sess_vaccel_id, "genop > client > ttrpc_client.genop");
@@ -140,6 +142,7 @@ impl VaccelRpcClient {
                 )
                 .await
             })
+            .map_err(|e| deadline.error(e))
             .inspect_err(|_| {
                 self.stop_profiling(sess_vaccel_id, "genop > client > ttrpc_client.genop");
             })?;
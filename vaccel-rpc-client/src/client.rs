@@ -4,14 +4,17 @@
 use crate::asynchronous::client::VaccelRpcClient;
 #[cfg(not(feature = "async"))]
 use crate::sync::client::VaccelRpcClient;
-use crate::{Error, Result};
+use crate::{tls, Error, IntoFfiResult, Result};
 use env_logger::Env;
-use log::error;
-use std::{env, net::ToSocketAddrs};
+use log::{error, warn};
+use std::{env, ffi::c_int, fs, net::ToSocketAddrs};
 #[cfg(feature = "async")]
 use ttrpc::asynchronous::Client as TtrpcClient;
+use ttrpc::context::Context;
 #[cfg(not(feature = "async"))]
 use ttrpc::Client as TtrpcClient;
+use vaccel::ffi;
+use vaccel_rpc_proto::TOKEN_METADATA_KEY;
 
 impl VaccelRpcClient {
     pub fn get_env_address() -> String {
@@ -24,6 +27,30 @@ impl VaccelRpcClient {
         }
     }
 
+    /// Returns the client token from `VACCEL_RPC_TOKEN`, or else from the
+    /// file `VACCEL_RPC_TOKEN_FILE` points to.
+    pub fn get_env_token() -> Option<String> {
+        env::var("VACCEL_RPC_TOKEN")
+            .ok()
+            .or_else(|| {
+                let path = env::var("VACCEL_RPC_TOKEN_FILE").ok()?;
+                fs::read_to_string(&path)
+                    .inspect_err(|e| warn!("Could not read token file {}: {}", path, e))
+                    .ok()
+                    .map(|t| t.trim().to_string())
+            })
+            .filter(|t| !t.is_empty())
+    }
+
+    /// Attaches the client authentication token, if any, to a request
+    /// context.
+    pub(crate) fn with_token(&self, mut ctx: Context) -> Context {
+        if let Some(token) = &self.token {
+            ctx.add(TOKEN_METADATA_KEY.to_string(), token.clone());
+        }
+        ctx
+    }
+
     pub(crate) fn resolve_uri(uri: &str) -> Result<String> {
         let parts: Vec<&str> = uri.split("://").collect();
         if parts.len() != 2 {
@@ -33,7 +60,7 @@ impl VaccelRpcClient {
         let scheme = parts[0].to_lowercase();
         match scheme.as_str() {
             "vsock" | "unix" => Ok(uri.to_string()),
-            "tcp" => {
+            "tcp" | "tls" => {
                 let address = parts[1].to_lowercase();
                 let mut resolved = address.to_socket_addrs()?;
                 let resolved_address = match resolved.next() {
@@ -57,9 +84,20 @@ impl VaccelRpcClient {
         }
 
         let resolved_uri = Self::resolve_uri(server_address)?;
+        if let Some(address) = resolved_uri.strip_prefix("tls://") {
+            let fd = tls::connect(address, Self::uri_host(server_address))?;
+            return Ok(TtrpcClient::new(fd));
+        }
 
         Ok(TtrpcClient::connect(&resolved_uri)?)
     }
+
+    /// Returns the host part of a '<socket-type>://<host>:<port>' uri.
+    fn uri_host(uri: &str) -> &str {
+        let address = uri.split_once("://").map_or(uri, |(_, a)| a);
+        let host = address.rsplit_once(':').map_or(address, |(h, _)| h);
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
 }
 
 #[no_mangle]
@@ -85,3 +123,20 @@ pub unsafe extern "C" fn vaccel_rpc_client_destroy(client: *mut VaccelRpcClient)
         unsafe { drop(Box::from_raw(client)) };
     }
 }
+
+/// Reconnects a client to the agent and resolves the ids of its open
+/// sessions, e.g. after the VM was migrated to another host.
+///
+/// # Safety
+///
+/// `client_ptr` must be a valid pointer to an object obtained by
+/// `create_client()`.
+#[no_mangle]
+pub unsafe extern "C" fn vaccel_rpc_client_reconnect(client_ptr: *mut VaccelRpcClient) -> c_int {
+    let client = match unsafe { client_ptr.as_mut() } {
+        Some(client) => client,
+        None => return ffi::VACCEL_EINVAL as c_int,
+    };
+
+    client.reconnect().into_ffi()
+}
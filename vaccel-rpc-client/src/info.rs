@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "async")]
+use crate::asynchronous::client::VaccelRpcClient;
+#[cfg(not(feature = "async"))]
+use crate::sync::client::VaccelRpcClient;
+use crate::{Error, Result};
+use log::{debug, warn};
+#[cfg(feature = "async")]
+use vaccel_rpc_proto::asynchronous::agent_ttrpc::AgentServiceClient;
+#[cfg(not(feature = "async"))]
+use vaccel_rpc_proto::sync::agent_ttrpc::AgentServiceClient;
+use vaccel_rpc_proto::{
+    info::{Request, Response},
+    PROTOCOL_VERSION,
+};
+
+impl VaccelRpcClient {
+    pub fn agent_info(&self) -> Result<Response> {
+        let ctx = self.context("get_agent_info");
+        let req = Request {
+            protocol_version: PROTOCOL_VERSION,
+            ..Default::default()
+        };
+
+        self.execute(AgentServiceClient::get_agent_info, ctx, &req)
+    }
+
+    /// Checks that the agent implements a compatible protocol version,
+    /// returning the instance id of the agent, if reported.
+    pub(crate) fn check_agent_compat(&self) -> Result<String> {
+        match self.agent_info() {
+            Ok(info) if info.protocol_version == PROTOCOL_VERSION => {
+                debug!(
+                    "Connected to agent {} (vAccel {}, protocol version {})",
+                    info.agent_version, info.vaccel_version, info.protocol_version
+                );
+                Ok(info.instance_id)
+            }
+            Ok(info) => Err(Error::IncompatibleAgent(format!(
+                "Agent {} implements protocol version {} but client implements version {}",
+                info.agent_version, info.protocol_version, PROTOCOL_VERSION
+            ))),
+            Err(Error::Ttrpc(ttrpc::Error::RpcStatus(s)))
+                if s.code() == ttrpc::Code::UNIMPLEMENTED || s.code() == ttrpc::Code::NOT_FOUND =>
+            {
+                warn!("Agent does not report its info; skipping compatibility check");
+                Ok(String::new())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
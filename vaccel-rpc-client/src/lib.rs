@@ -16,10 +16,15 @@ pub mod sync;
 #[cfg(feature = "async")]
 pub use asynchronous as r#async;
 pub mod client;
+pub mod info;
+pub mod migration;
 pub mod ops;
 pub mod profiling;
 pub mod resource;
 pub mod session;
+pub mod timeout;
+mod tls;
+mod upload;
 
 extern crate ttrpc;
 
@@ -41,6 +46,21 @@ pub enum Error {
     #[error("Host vAccel error: {0}")]
     HostVaccel(vaccel::Error),
 
+    #[error("Host resources exhausted: {0}")]
+    ResourceExhausted(String),
+
+    #[error("Deadline exceeded: {0}")]
+    DeadlineExceeded(String),
+
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+
+    #[error("Incompatible agent: {0}")]
+    IncompatibleAgent(String),
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
     #[error("Invalid argument: {0}")]
     InvalidArgument(String),
 
@@ -54,6 +74,13 @@ pub enum Error {
     Unknown,
 }
 
+/// vAccel error codes are errno values but there is no vAccel code for
+/// timeouts, so use `ETIMEDOUT` directly.
+const VACCEL_ETIMEDOUT: u32 = libc::ETIMEDOUT as u32;
+
+/// Returned when data did not fit in the buffers provided by the caller.
+const VACCEL_ENOBUFS: u32 = libc::ENOBUFS as u32;
+
 impl Error {
     pub fn to_ffi(&self) -> u32 {
         match self {
@@ -63,6 +90,8 @@ impl Error {
                 _ => ffi::VACCEL_EBACKEND,
             },
             Error::Ttrpc(_) => ffi::VACCEL_EIO,
+            Error::ResourceExhausted(_) => ffi::VACCEL_ENOMEM,
+            Error::DeadlineExceeded(_) => VACCEL_ETIMEDOUT,
             _ => ffi::VACCEL_EBACKEND,
         }
     }
@@ -71,6 +100,18 @@ impl Error {
 impl From<ttrpc::Error> for Error {
     fn from(err: ttrpc::Error) -> Self {
         if let ttrpc::Error::RpcStatus(ref rpc_status) = err {
+            if rpc_status.code() == ttrpc::Code::RESOURCE_EXHAUSTED {
+                return Error::ResourceExhausted(rpc_status.message().to_string());
+            }
+
+            if rpc_status.code() == ttrpc::Code::UNAUTHENTICATED {
+                return Error::Unauthenticated(rpc_status.message().to_string());
+            }
+
+            if rpc_status.code() == ttrpc::Code::DEADLINE_EXCEEDED {
+                return Error::DeadlineExceeded(rpc_status.message().to_string());
+            }
+
             let details = rpc_status.details();
             if !details.is_empty() {
                 if let Ok(proto_error) = ProtoError::parse_from_bytes(details[0].value()) {
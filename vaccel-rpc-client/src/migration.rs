@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "async")]
+use crate::asynchronous::client::VaccelRpcClient;
+#[cfg(not(feature = "async"))]
+use crate::sync::client::VaccelRpcClient;
+use crate::Result;
+use log::{debug, info};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+#[cfg(feature = "async")]
+use vaccel_rpc_proto::asynchronous::agent_ttrpc::AgentServiceClient;
+use vaccel_rpc_proto::session::{IdMapping, ResolveIdsRequest};
+#[cfg(not(feature = "async"))]
+use vaccel_rpc_proto::sync::agent_ttrpc::AgentServiceClient;
+
+/// Translates between the ids known to the guest and the ids of the agent.
+///
+/// Ids map to themselves unless remapped after a migration.
+#[derive(Debug, Default)]
+struct IdTable {
+    to_agent: HashMap<i64, i64>,
+    to_guest: HashMap<i64, i64>,
+}
+
+impl IdTable {
+    fn agent(&self, guest: i64) -> i64 {
+        self.to_agent.get(&guest).copied().unwrap_or(guest)
+    }
+
+    /// Returns the guest id of an agent id, allocating a new one if the
+    /// agent id is already used by the guest for another object.
+    fn guest(&mut self, agent: i64) -> i64 {
+        if let Some(&guest) = self.to_guest.get(&agent) {
+            return guest;
+        }
+        if !self.to_agent.contains_key(&agent) {
+            return agent;
+        }
+
+        let guest = self
+            .to_agent
+            .keys()
+            .chain(self.to_guest.keys())
+            .chain([&agent])
+            .max()
+            .map_or(1, |&id| id + 1);
+        self.insert(guest, agent);
+        guest
+    }
+
+    fn insert(&mut self, guest: i64, agent: i64) {
+        if guest != agent {
+            self.to_agent.insert(guest, agent);
+            self.to_guest.insert(agent, guest);
+        }
+    }
+
+    fn remove(&mut self, guest: i64) {
+        if let Some(agent) = self.to_agent.remove(&guest) {
+            self.to_guest.remove(&agent);
+        }
+    }
+
+    /// Points the guest ids of the `old_id`s of the mappings to the
+    /// respective `new_id`s.
+    fn remap(&mut self, mappings: &[IdMapping]) {
+        // Look up all guest ids first, as new ids may equal old ones
+        let guests: Vec<i64> = mappings
+            .iter()
+            .map(|m| self.to_guest.get(&m.old_id).copied().unwrap_or(m.old_id))
+            .collect();
+        for &guest in &guests {
+            self.remove(guest);
+        }
+        for (guest, mapping) in guests.into_iter().zip(mappings) {
+            self.insert(guest, mapping.new_id);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Ids {
+    sessions: IdTable,
+    resources: IdTable,
+    open_sessions: HashSet<i64>,
+    /// The instance id of the agent the sessions were created on.
+    agent: String,
+}
+
+/// The session and resource id mappings of a client.
+#[derive(Debug, Default)]
+pub struct IdMap(Mutex<Ids>);
+
+impl IdMap {
+    /// Returns the agent id of a guest session id.
+    pub fn session(&self, guest_id: i64) -> i64 {
+        self.0.lock().unwrap().sessions.agent(guest_id)
+    }
+
+    /// Returns the agent id of a guest resource id.
+    pub fn resource(&self, guest_id: i64) -> i64 {
+        self.0.lock().unwrap().resources.agent(guest_id)
+    }
+
+    /// Tracks a session created by the agent and returns its guest id.
+    pub fn add_session(&self, agent_id: i64) -> i64 {
+        let mut ids = self.0.lock().unwrap();
+        let guest_id = ids.sessions.guest(agent_id);
+        ids.open_sessions.insert(guest_id);
+        guest_id
+    }
+
+    pub fn remove_session(&self, guest_id: i64) {
+        let mut ids = self.0.lock().unwrap();
+        ids.sessions.remove(guest_id);
+        ids.open_sessions.remove(&guest_id);
+    }
+
+    /// Returns the guest id of a resource registered by the agent.
+    pub fn add_resource(&self, agent_id: i64) -> i64 {
+        self.0.lock().unwrap().resources.guest(agent_id)
+    }
+
+    /// Returns the agent ids of the open sessions.
+    fn open_sessions(&self) -> Vec<i64> {
+        let ids = self.0.lock().unwrap();
+        ids.open_sessions
+            .iter()
+            .map(|&id| ids.sessions.agent(id))
+            .collect()
+    }
+
+    /// Returns the instance id of the agent the sessions were created on.
+    pub fn agent(&self) -> String {
+        self.0.lock().unwrap().agent.clone()
+    }
+
+    pub(crate) fn set_agent(&self, instance_id: String) {
+        self.0.lock().unwrap().agent = instance_id;
+    }
+
+    fn remap(&self, sessions: &[IdMapping], resources: &[IdMapping]) {
+        let mut ids = self.0.lock().unwrap();
+        ids.sessions.remap(sessions);
+        ids.resources.remap(resources);
+    }
+}
+
+impl VaccelRpcClient {
+    /// Claims the open sessions on the agent, if they were imported from
+    /// another agent, and applies the returned id mappings.
+    pub(crate) fn resolve_ids(&self) -> Result<()> {
+        let session_ids = self.ids.open_sessions();
+        if session_ids.is_empty() {
+            return Ok(());
+        }
+
+        let ctx = self.context("resolve_ids");
+        let req = ResolveIdsRequest {
+            session_ids,
+            source: self.ids.agent(),
+            ..Default::default()
+        };
+        let resp = self.execute(AgentServiceClient::resolve_ids, ctx, &req)?;
+
+        for mapping in &resp.sessions {
+            info!(
+                "Session {} resolved to session {}",
+                mapping.old_id, mapping.new_id
+            );
+        }
+        debug!("Resolved {} resource ids", resp.resources.len());
+        self.ids.remap(&resp.sessions, &resp.resources);
+
+        Ok(())
+    }
+}
@@ -21,10 +21,10 @@ impl VaccelRpcClient {
         read_args: Vec<ProtoArg>,
         write_args: Vec<ProtoArg>,
     ) -> Result<Vec<ProtoArg>> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("genop");
         let sess_vaccel_id = VaccelId::try_from(sess_id)?;
         let req = self.profile_fn(sess_vaccel_id, "genop > client > req create", || Request {
-            session_id: sess_vaccel_id.into(),
+            session_id: self.ids.session(sess_vaccel_id.into()),
             read_args,
             write_args,
             ..Default::default()
@@ -19,9 +19,9 @@ use vaccel_rpc_proto::sync::agent_ttrpc::AgentServiceClient;
 
 impl VaccelRpcClient {
     pub fn image_classify(&self, sess_id: i64, img: Vec<u8>) -> Result<Vec<u8>> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("image_classification");
         let req = Request {
-            session_id: sess_id,
+            session_id: self.ids.session(sess_id),
             image: img,
             ..Default::default()
         };
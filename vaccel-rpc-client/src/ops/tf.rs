@@ -23,10 +23,10 @@ use vaccel_rpc_proto::{
 
 impl VaccelRpcClient {
     pub fn tf_model_load(&self, model_id: i64, session_id: i64) -> Result<(Vec<u8>, Status)> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("tensorflow_model_load");
         let req = ModelLoadRequest {
-            session_id,
-            model_id,
+            session_id: self.ids.session(session_id),
+            model_id: self.ids.resource(model_id),
             ..Default::default()
         };
 
@@ -38,10 +38,10 @@ impl VaccelRpcClient {
     }
 
     pub fn tf_model_unload(&self, model_id: i64, session_id: i64) -> Result<Status> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("tensorflow_model_unload");
         let req = ModelUnloadRequest {
-            session_id,
-            model_id,
+            session_id: self.ids.session(session_id),
+            model_id: self.ids.resource(model_id),
             ..Default::default()
         };
 
@@ -59,10 +59,10 @@ impl VaccelRpcClient {
         in_tensors: Vec<Tensor>,
         out_nodes: Vec<ProtoNode>,
     ) -> Result<(Vec<*mut ffi::vaccel_tf_tensor>, Status)> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("tensorflow_model_run");
         let req = ModelRunRequest {
-            model_id,
-            session_id,
+            model_id: self.ids.resource(model_id),
+            session_id: self.ids.session(session_id),
             run_options,
             in_nodes,
             in_tensors,
@@ -6,7 +6,7 @@ use crate::asynchronous::client::VaccelRpcClient;
 use crate::sync::client::VaccelRpcClient;
 use crate::{Error, IntoFfiResult, Result};
 use log::error;
-use std::ffi::c_int;
+use std::{ffi::c_int, time::Duration};
 use vaccel::{
     c_pointer_to_mut_slice, c_pointer_to_slice, ffi,
     ops::tf::lite::{DataType, DynTensor, Status},
@@ -23,23 +23,43 @@ use vaccel_rpc_proto::{
 
 impl VaccelRpcClient {
     pub fn tflite_model_load(&self, model_id: i64, session_id: i64) -> Result<()> {
-        let ctx = ttrpc::context::Context::default();
+        self.tflite_model_load_with_warmup(model_id, session_id, vec![], 1, 0)?;
+
+        Ok(())
+    }
+
+    /// Loads the model and runs `warmup_runs` inferences on `warmup_tensors`
+    /// with it. Tensors without data are run as zero tensors of their dims.
+    ///
+    /// Returns the time the warm-up took on the agent.
+    pub fn tflite_model_load_with_warmup(
+        &self,
+        model_id: i64,
+        session_id: i64,
+        warmup_tensors: Vec<Tensor>,
+        nr_out_tensors: u64,
+        warmup_runs: u32,
+    ) -> Result<Duration> {
+        let ctx = self.context("tensorflow_lite_model_load");
         let req = ModelLoadRequest {
-            session_id,
-            model_id,
+            session_id: self.ids.session(session_id),
+            model_id: self.ids.resource(model_id),
+            warmup_runs,
+            warmup_tensors,
+            nr_out_tensors,
             ..Default::default()
         };
 
-        self.execute(AgentServiceClient::tensorflow_lite_model_load, ctx, &req)?;
+        let resp = self.execute(AgentServiceClient::tensorflow_lite_model_load, ctx, &req)?;
 
-        Ok(())
+        Ok(Duration::from_nanos(resp.warmup_time_ns))
     }
 
     pub fn tflite_model_unload(&self, model_id: i64, session_id: i64) -> Result<()> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("tensorflow_lite_model_unload");
         let req = ModelUnloadRequest {
-            session_id,
-            model_id,
+            session_id: self.ids.session(session_id),
+            model_id: self.ids.resource(model_id),
             ..Default::default()
         };
 
@@ -55,10 +75,10 @@ impl VaccelRpcClient {
         in_tensors: Vec<Tensor>,
         nr_out_tensors: u64,
     ) -> Result<(Vec<*mut ffi::vaccel_tflite_tensor>, Status)> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("tensorflow_lite_model_run");
         let req = ModelRunRequest {
-            model_id,
-            session_id,
+            model_id: self.ids.resource(model_id),
+            session_id: self.ids.session(session_id),
             in_tensors,
             nr_out_tensors,
             ..Default::default()
@@ -6,7 +6,7 @@ use crate::asynchronous::client::VaccelRpcClient;
 use crate::sync::client::VaccelRpcClient;
 use crate::{Error, Result};
 use log::error;
-use std::ffi::c_int;
+use std::{ffi::c_int, time::Duration};
 use vaccel::{
     c_pointer_to_mut_slice, c_pointer_to_slice, ffi,
     ops::torch::{DataType, DynTensor},
@@ -20,16 +20,38 @@ use vaccel_rpc_proto::torch::{ModelLoadRequest, ModelRunRequest, Tensor};
 
 impl VaccelRpcClient {
     pub fn torch_model_load(&self, session_id: i64, model_id: i64) -> Result<()> {
-        let ctx = ttrpc::context::Context::default();
+        self.torch_model_load_with_warmup(session_id, model_id, None, vec![], 1, 0)?;
+
+        Ok(())
+    }
+
+    /// Loads the model and runs `warmup_runs` inferences on `warmup_tensors`
+    /// with it. Tensors without data are run as zero tensors of their dims.
+    ///
+    /// Returns the time the warm-up took on the agent.
+    pub fn torch_model_load_with_warmup(
+        &self,
+        session_id: i64,
+        model_id: i64,
+        run_options: Option<Vec<u8>>,
+        warmup_tensors: Vec<Tensor>,
+        nr_out_tensors: u64,
+        warmup_runs: u32,
+    ) -> Result<Duration> {
+        let ctx = self.context("torch_model_load");
         let req = ModelLoadRequest {
-            session_id,
-            model_id,
+            session_id: self.ids.session(session_id),
+            model_id: self.ids.resource(model_id),
+            warmup_runs,
+            warmup_tensors,
+            run_options,
+            nr_out_tensors,
             ..Default::default()
         };
 
-        self.execute(AgentServiceClient::torch_model_load, ctx, &req)?;
+        let resp = self.execute(AgentServiceClient::torch_model_load, ctx, &req)?;
 
-        Ok(())
+        Ok(Duration::from_nanos(resp.warmup_time_ns))
     }
 
     pub fn torch_model_run(
@@ -40,10 +62,10 @@ impl VaccelRpcClient {
         in_tensors: Vec<Tensor>,
         nr_out_tensors: u64,
     ) -> Result<Vec<*mut ffi::vaccel_torch_tensor>> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("torch_model_run");
         let req = ModelRunRequest {
-            session_id,
-            model_id,
+            session_id: self.ids.session(session_id),
+            model_id: self.ids.resource(model_id),
             run_options,
             in_tensors,
             nr_out_tensors,
@@ -28,10 +28,10 @@ impl VaccelRpcClient {
     pub const TIMERS_PREFIX: &'static str = "vaccel-rpc-client";
 
     pub fn get_profiler(&mut self, sess_id: i64) -> Result<Profiler> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("get_profiler");
 
         let req = Request {
-            session_id: sess_id,
+            session_id: self.ids.session(sess_id),
             ..Default::default()
         };
 
@@ -4,20 +4,67 @@
 use crate::asynchronous::client::VaccelRpcClient;
 #[cfg(not(feature = "async"))]
 use crate::sync::client::VaccelRpcClient;
-use crate::{Error, IntoFfiResult, Result};
-use log::error;
-use protobuf::Enum;
-use std::ffi::{c_char, c_int, CStr};
-use vaccel::{c_pointer_to_slice, ffi, profiling::SessionProfiler, Blob, Handle, VaccelId};
+use crate::{upload::MAX_REQ_LEN, Error, IntoFfiResult, Result, VACCEL_ENOBUFS};
+use log::{debug, error};
+use protobuf::{Enum, Message};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    ffi::{c_char, c_int, CStr},
+    mem,
+};
+use vaccel::{
+    c_pointer_to_mut_slice, c_pointer_to_slice, ffi, profiling::SessionProfiler, Blob, Handle,
+    VaccelId,
+};
 #[cfg(feature = "async")]
 use vaccel_rpc_proto::asynchronous::agent_ttrpc::AgentServiceClient;
 use vaccel_rpc_proto::resource::{
-    Blob as ProtoBlob, RegisterRequest, ResourceType, SyncRequest, UnregisterRequest,
+    Blob as ProtoBlob, LookupBlobsRequest, RegisterRequest, RegisterResponse, ResourceType,
+    SyncRequest, SyncResponse, UnregisterRequest,
 };
 #[cfg(not(feature = "async"))]
 use vaccel_rpc_proto::sync::agent_ttrpc::AgentServiceClient;
 
 impl VaccelRpcClient {
+    /// Sets the digest of each non-empty blob and drops the data of the blobs
+    /// the agent has already cached, so that they are not uploaded again.
+    ///
+    /// Returns the dropped data of each blob by index.
+    fn strip_cached_blobs(&self, blobs: &mut [ProtoBlob]) -> Vec<(usize, Vec<u8>)> {
+        for blob in blobs.iter_mut().filter(|b| !b.data.is_empty()) {
+            blob.sha256 = Sha256::digest(&blob.data).to_vec();
+        }
+
+        let mut req = LookupBlobsRequest::new();
+        req.sha256 = blobs
+            .iter()
+            .filter(|b| !b.sha256.is_empty())
+            .map(|b| b.sha256.clone())
+            .collect();
+        if req.sha256.is_empty() {
+            return Vec::new();
+        }
+
+        let ctx = self.context("lookup_blobs");
+        let missing: HashSet<Vec<u8>> =
+            match self.execute(AgentServiceClient::lookup_blobs, ctx, &req) {
+                Ok(resp) => resp.missing.into_iter().collect(),
+                Err(e) => {
+                    // Older agents do not cache blobs
+                    debug!("Could not look up cached blobs: {}", e);
+                    return Vec::new();
+                }
+            };
+
+        blobs
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, b)| !b.sha256.is_empty() && !missing.contains(&b.sha256))
+            .map(|(i, b)| (i, mem::take(&mut b.data)))
+            .collect()
+    }
+
     pub fn resource_register(
         &self,
         paths: Vec<String>,
@@ -26,26 +73,63 @@ impl VaccelRpcClient {
         res_id: i64,
         sess_id: i64,
     ) -> Result<i64> {
-        let ctx = ttrpc::context::Context::default();
         let mut req = RegisterRequest::new();
         req.paths = paths;
         req.blobs = blobs;
         req.resource_type = ResourceType::from_i32(res_type)
             .ok_or(Error::InvalidArgument("Invalid resource type".to_string()))?
             .into();
-        req.resource_id = res_id;
-        req.session_id = sess_id;
+        req.resource_id = self.ids.resource(res_id);
+        req.session_id = self.ids.session(sess_id);
 
+        let stripped = self.strip_cached_blobs(&mut req.blobs);
+        let mut resp = self.register_blobs(&req)?;
+        if !resp.missing.is_empty() {
+            // The agent may have evicted blobs since the lookup
+            debug!(
+                "Retrying resource registration with all blob data: {} blob(s) not cached",
+                resp.missing.len()
+            );
+            for (i, data) in stripped {
+                req.blobs[i].data = data;
+            }
+            resp = self.register_blobs(&req)?;
+        }
+
+        let res_id: i64 = VaccelId::try_from(resp.resource_id)?.into();
+        Ok(self.ids.add_resource(res_id))
+    }
+
+    /// Sends a request creating a resource, in chunks if it is too large for
+    /// a single request.
+    fn register_blobs(&self, req: &RegisterRequest) -> Result<RegisterResponse> {
+        if req.compute_size() > MAX_REQ_LEN {
+            return self.resource_register_chunked(req);
+        }
+
+        let ctx = self.context("register_resource");
+        self.execute(AgentServiceClient::register_resource, ctx, req)
+    }
+
+    /// Registers a resource the agent holds under `name`: a model preloaded
+    /// by the agent configuration or a persistent resource.
+    pub fn resource_register_by_name(&self, name: &str, sess_id: i64) -> Result<i64> {
+        let mut req = RegisterRequest::new();
+        req.name = name.to_string();
+        req.session_id = self.ids.session(sess_id);
+
+        let ctx = self.context("register_resource");
         let resp = self.execute(AgentServiceClient::register_resource, ctx, &req)?;
 
-        Ok(VaccelId::try_from(resp.resource_id)?.into())
+        let res_id: i64 = VaccelId::try_from(resp.resource_id)?.into();
+        Ok(self.ids.add_resource(res_id))
     }
 
     pub fn resource_unregister(&self, res_id: i64, sess_id: i64) -> Result<()> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("unregister_resource");
         let mut req = UnregisterRequest::new();
-        req.resource_id = res_id;
-        req.session_id = sess_id;
+        req.resource_id = self.ids.resource(res_id);
+        req.session_id = self.ids.session(sess_id);
 
         self.execute(AgentServiceClient::unregister_resource, ctx, &req)?;
 
@@ -53,13 +137,25 @@ impl VaccelRpcClient {
     }
 
     pub fn resource_sync(&self, res_id: i64) -> Result<Vec<ProtoBlob>> {
-        let ctx = ttrpc::context::Context::default();
+        Ok(self.resource_sync_since(res_id, 0)?.blobs)
+    }
+
+    /// Returns the blobs of a resource changed after `since_generation`, or
+    /// all of them if it is zero, along with their indices and the current
+    /// generation.
+    pub fn resource_sync_since(&self, res_id: i64, since_generation: u64) -> Result<SyncResponse> {
+        let ctx = self.context("sync_resource");
         let mut req = SyncRequest::new();
-        req.resource_id = res_id;
+        req.resource_id = self.ids.resource(res_id);
+        req.since_generation = since_generation;
 
-        let resp = self.execute(AgentServiceClient::sync_resource, ctx, &req)?;
+        let mut resp = self.execute(AgentServiceClient::sync_resource, ctx, &req)?;
+        // Older agents return all the blobs without indices
+        if resp.indices.is_empty() {
+            resp.indices = (0..resp.blobs.len() as u32).collect();
+        }
 
-        Ok(resp.blobs)
+        Ok(resp)
     }
 }
 
@@ -252,10 +348,107 @@ pub unsafe extern "C" fn vaccel_rpc_client_resource_sync(
 
     for (i, blob) in blobs.iter().enumerate() {
         let data = blob.data.as_ptr();
-        let size = blob.size as usize;
+        let size = (blob.size as usize).min(blob.data.len());
         let dest = ptrs_slice[i];
         std::ptr::copy_nonoverlapping(data, dest, size);
     }
 
     ffi::VACCEL_OK as c_int
 }
+
+/// Copies the blobs of a resource into caller buffers of the given
+/// capacities.
+///
+/// `generation` holds the generation of the previous sync, or zero, and is
+/// updated to the current one. Only blobs changed after it are copied, with
+/// `changed` (if not null) set for them. `sizes` (if not null) receives the
+/// full size of each copied blob. Returns `ENOBUFS` if any blob was truncated
+/// to the capacity of its buffer.
+///
+/// # Safety
+///
+/// `client_ptr` must be a valid pointer to an object obtained by
+/// `create_client()`.
+/// `data_ptrs` and `capacities` must point to `nr_elems` elements, with
+/// each data pointer valid for writes of its capacity. `sizes` and `changed`
+/// must be null or point to `nr_elems` elements. `generation` must be a
+/// valid pointer.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn vaccel_rpc_client_resource_sync_bounded(
+    client_ptr: *mut VaccelRpcClient,
+    res_id: ffi::vaccel_id_t,
+    data_ptrs: *mut *mut u8,
+    capacities: *const usize,
+    sizes: *mut usize,
+    changed: *mut bool,
+    nr_elems: usize,
+    generation: *mut u64,
+) -> c_int {
+    let client = match unsafe { client_ptr.as_mut() } {
+        Some(client) => client,
+        None => return ffi::VACCEL_EINVAL as c_int,
+    };
+
+    let (ptrs_slice, caps_slice) = match (
+        c_pointer_to_slice(data_ptrs, nr_elems),
+        c_pointer_to_slice(capacities, nr_elems),
+    ) {
+        (Some(ptrs), Some(caps)) => (ptrs, caps),
+        _ => return ffi::VACCEL_EINVAL as c_int,
+    };
+    let mut sizes_slice = c_pointer_to_mut_slice(sizes, nr_elems);
+    let mut changed_slice = c_pointer_to_mut_slice(changed, nr_elems);
+    let generation = match unsafe { generation.as_mut() } {
+        Some(g) => g,
+        None => return ffi::VACCEL_EINVAL as c_int,
+    };
+
+    let res_vaccel_id = match VaccelId::try_from(res_id) {
+        Ok(id) => id,
+        Err(e) => {
+            let err = Error::from(e);
+            error!("{}", err);
+            return err.to_ffi() as c_int;
+        }
+    };
+
+    let resp = match client.resource_sync_since(res_vaccel_id.into(), *generation) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("{}", e);
+            return e.to_ffi() as c_int;
+        }
+    };
+
+    if let Some(i) = resp.indices.iter().find(|&&i| i as usize >= nr_elems) {
+        error!(
+            "Unexpected blob index {}; resource has {} blobs",
+            i, nr_elems
+        );
+        return ffi::VACCEL_EBACKEND as c_int;
+    }
+
+    if let Some(changed) = changed_slice.as_deref_mut() {
+        changed.fill(false);
+    }
+    let mut truncated = false;
+    for (&i, blob) in resp.indices.iter().zip(resp.blobs.iter()) {
+        let i = i as usize;
+        let size = blob.data.len().min(caps_slice[i]);
+        truncated |= size < blob.data.len();
+        std::ptr::copy_nonoverlapping(blob.data.as_ptr(), ptrs_slice[i], size);
+        if let Some(sizes) = sizes_slice.as_deref_mut() {
+            sizes[i] = blob.data.len();
+        }
+        if let Some(changed) = changed_slice.as_deref_mut() {
+            changed[i] = true;
+        }
+    }
+    *generation = resp.generation;
+
+    match truncated {
+        true => VACCEL_ENOBUFS as c_int,
+        false => ffi::VACCEL_OK as c_int,
+    }
+}
@@ -17,7 +17,7 @@ use vaccel_rpc_proto::sync::agent_ttrpc::AgentServiceClient;
 
 impl VaccelRpcClient {
     pub fn session_init(&self, flags: u32) -> Result<i64> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("create_session");
         let req = CreateRequest {
             flags,
             ..Default::default()
@@ -25,13 +25,15 @@ impl VaccelRpcClient {
 
         let resp = self.execute(AgentServiceClient::create_session, ctx, &req)?;
 
-        Ok(VaccelId::try_from(resp.session_id)?.into())
+        let sess_id: i64 = VaccelId::try_from(resp.session_id)?.into();
+
+        Ok(self.ids.add_session(sess_id))
     }
 
     pub fn session_update(&self, sess_id: i64, flags: u32) -> Result<()> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("update_session");
         let req = UpdateRequest {
-            session_id: sess_id,
+            session_id: self.ids.session(sess_id),
             flags,
             ..Default::default()
         };
@@ -42,13 +44,14 @@ impl VaccelRpcClient {
     }
 
     pub fn session_release(&self, sess_id: i64) -> Result<()> {
-        let ctx = ttrpc::context::Context::default();
+        let ctx = self.context("destroy_session");
         let req = DestroyRequest {
-            session_id: sess_id,
+            session_id: self.ids.session(sess_id),
             ..Default::default()
         };
 
         self.execute(AgentServiceClient::destroy_session, ctx, &req)?;
+        self.ids.remove_session(sess_id);
 
         Ok(())
     }
@@ -1,6 +1,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::Result;
+use crate::{
+    migration::IdMap,
+    timeout::{Deadline, Timeouts},
+    Result,
+};
 use log::debug;
 use ttrpc::context::Context;
 use vaccel::profiling::ProfilerManager;
@@ -10,25 +14,53 @@ use vaccel_rpc_proto::sync::agent_ttrpc::AgentServiceClient;
 pub struct VaccelRpcClient {
     pub ttrpc_client: AgentServiceClient,
     pub profiler_manager: ProfilerManager,
+    pub token: Option<String>,
+    pub timeouts: Timeouts,
+    pub ids: IdMap,
 }
 
 impl VaccelRpcClient {
     pub fn new() -> Result<Self> {
+        Self::with_address(&Self::get_env_address())
+    }
+
+    /// Creates a new client connected to the agent at `server_address`.
+    pub fn with_address(server_address: &str) -> Result<Self> {
         debug!("Client is sync");
 
-        let server_address = Self::get_env_address();
-        let ttrpc_client = Self::create_ttrpc_client(&server_address)?;
+        let ttrpc_client = Self::create_ttrpc_client(server_address)?;
 
-        Ok(VaccelRpcClient {
+        let client = VaccelRpcClient {
             ttrpc_client: AgentServiceClient::new(ttrpc_client),
             profiler_manager: ProfilerManager::new(Self::TIMERS_PREFIX),
-        })
+            token: Self::get_env_token(),
+            timeouts: Timeouts::from_env(),
+            ids: IdMap::default(),
+        };
+        let instance_id = client.check_agent_compat()?;
+        client.ids.set_agent(instance_id);
+
+        Ok(client)
+    }
+
+    /// Reconnects to the agent, e.g. after the VM was migrated to another
+    /// host, and resolves the ids of the open sessions.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let ttrpc_client = Self::create_ttrpc_client(&Self::get_env_address())?;
+        self.ttrpc_client = AgentServiceClient::new(ttrpc_client);
+        let instance_id = self.check_agent_compat()?;
+
+        // The sessions are claimed from the previous agent
+        self.resolve_ids()?;
+        self.ids.set_agent(instance_id);
+        Ok(())
     }
 
-    pub fn execute<'a, 'b, F, A, R>(&'a self, func: F, ctx: Context, req: &'b A) -> R
+    pub fn execute<'a, 'b, F, A, T>(&'a self, func: F, ctx: Context, req: &'b A) -> Result<T>
     where
-        F: Fn(&'a AgentServiceClient, Context, &'b A) -> R,
+        F: Fn(&'a AgentServiceClient, Context, &'b A) -> ttrpc::Result<T>,
     {
-        func(&self.ttrpc_client, ctx, req)
+        let deadline = Deadline::of(&ctx);
+        func(&self.ttrpc_client, self.with_token(ctx), req).map_err(|e| deadline.error(e))
     }
 }
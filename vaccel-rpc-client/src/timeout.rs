@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "async")]
+use crate::asynchronous::client::VaccelRpcClient;
+#[cfg(not(feature = "async"))]
+use crate::sync::client::VaccelRpcClient;
+use crate::Error;
+use log::warn;
+use std::{
+    collections::HashMap,
+    env,
+    time::{Duration, Instant},
+};
+use ttrpc::context::{self, Context};
+
+const TIMEOUT_ENV: &str = "VACCEL_RPC_TIMEOUT_MS";
+
+/// Per-operation request timeouts.
+///
+/// Operations are named after the agent client methods, e.g. `genop` or
+/// `torch_model_run`. A zero timeout means no timeout.
+#[derive(Debug, Default, Clone)]
+pub struct Timeouts {
+    default: Option<Duration>,
+    ops: HashMap<String, Option<Duration>>,
+}
+
+fn parse_timeout(var: &str, value: &str) -> Option<Option<Duration>> {
+    match value.parse::<u64>() {
+        Ok(0) => Some(None),
+        Ok(ms) => Some(Some(Duration::from_millis(ms))),
+        Err(_) => {
+            warn!("Ignoring invalid {} value `{}`", var, value);
+            None
+        }
+    }
+}
+
+impl Timeouts {
+    /// Reads the default timeout from `VACCEL_RPC_TIMEOUT_MS` and per-op
+    /// overrides from `VACCEL_RPC_TIMEOUT_MS_<OP>`, e.g.
+    /// `VACCEL_RPC_TIMEOUT_MS_TORCH_MODEL_RUN`.
+    pub fn from_env() -> Self {
+        let mut timeouts = Timeouts::default();
+        let prefix = format!("{}_", TIMEOUT_ENV);
+        for (var, value) in env::vars() {
+            if var == TIMEOUT_ENV {
+                if let Some(t) = parse_timeout(&var, &value) {
+                    timeouts.default = t;
+                }
+            } else if let Some(op) = var.strip_prefix(&prefix) {
+                if let Some(t) = parse_timeout(&var, &value) {
+                    timeouts.ops.insert(op.to_lowercase(), t);
+                }
+            }
+        }
+        timeouts
+    }
+
+    pub fn get(&self, op: &str) -> Option<Duration> {
+        match self.ops.get(op) {
+            Some(t) => *t,
+            None => self.default,
+        }
+    }
+
+    pub fn set_default(&mut self, timeout: Option<Duration>) {
+        self.default = timeout;
+    }
+
+    pub fn set(&mut self, op: &str, timeout: Option<Duration>) {
+        self.ops.insert(op.to_string(), timeout);
+    }
+}
+
+/// The client-side deadline of a request.
+///
+/// The ttrpc client does not report its own request timeouts with a
+/// dedicated error, so a transport error is taken to be a timeout if the
+/// deadline has passed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub(crate) fn of(ctx: &Context) -> Self {
+        Self(
+            (ctx.timeout_nano > 0)
+                .then(|| Instant::now() + Duration::from_nanos(ctx.timeout_nano as u64)),
+        )
+    }
+
+    /// Converts the error of a request sent with this deadline.
+    pub(crate) fn error(&self, err: ttrpc::Error) -> Error {
+        match (&err, self.0) {
+            (ttrpc::Error::RpcStatus(_), _) => err.into(),
+            (_, Some(deadline)) if Instant::now() >= deadline => {
+                Error::DeadlineExceeded(err.to_string())
+            }
+            _ => err.into(),
+        }
+    }
+}
+
+impl VaccelRpcClient {
+    /// Creates the request context of an operation, with the deadline set
+    /// from its configured timeout.
+    pub(crate) fn context(&self, op: &str) -> Context {
+        match self.timeouts.get(op) {
+            Some(t) => context::with_timeout(t.as_nanos().min(i64::MAX as u128) as i64),
+            None => Context::default(),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{Error, Result};
+use log::debug;
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, Connection, RootCertStore};
+use std::{
+    env, io,
+    net::TcpStream,
+    os::unix::{
+        io::{IntoRawFd, RawFd},
+        net::UnixStream,
+    },
+    path::Path,
+    sync::Arc,
+    thread,
+};
+use vaccel_rpc_proto::tls::{bridge, load_certs, load_key};
+
+fn tls_error(e: io::Error) -> Error {
+    Error::Tls(e.to_string())
+}
+
+/// Builds the rustls client configuration from the `VACCEL_RPC_TLS_CA`,
+/// `VACCEL_RPC_TLS_CERT` and `VACCEL_RPC_TLS_KEY` env vars.
+fn client_config() -> Result<Arc<ClientConfig>> {
+    let ca = env::var("VACCEL_RPC_TLS_CA").map_err(|_| {
+        Error::Tls("VACCEL_RPC_TLS_CA must be set for tls:// addresses".to_string())
+    })?;
+    let mut roots = RootCertStore::empty();
+    for c in load_certs(Path::new(&ca)).map_err(tls_error)? {
+        roots
+            .add(c)
+            .map_err(|e| Error::Tls(format!("Invalid CA certificate: {}", e)))?;
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = match (
+        env::var("VACCEL_RPC_TLS_CERT"),
+        env::var("VACCEL_RPC_TLS_KEY"),
+    ) {
+        (Ok(cert), Ok(key)) => builder
+            .with_client_auth_cert(
+                load_certs(Path::new(&cert)).map_err(tls_error)?,
+                load_key(Path::new(&key)).map_err(tls_error)?,
+            )
+            .map_err(|e| Error::Tls(e.to_string()))?,
+        (Err(_), Err(_)) => builder.with_no_client_auth(),
+        _ => {
+            return Err(Error::Tls(
+                "Both VACCEL_RPC_TLS_CERT and VACCEL_RPC_TLS_KEY must be set".to_string(),
+            ))
+        }
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Opens a TLS connection to `address`, verifying the agent certificate
+/// against `host`.
+///
+/// Returns a unix socket fd that carries the decrypted stream, to be used as
+/// the ttrpc client socket.
+pub(crate) fn connect(address: &str, host: &str) -> Result<RawFd> {
+    let host = env::var("VACCEL_RPC_TLS_SERVER_NAME").unwrap_or_else(|_| host.to_string());
+    let server_name = ServerName::try_from(host.clone())
+        .map_err(|e| Error::Tls(format!("Invalid server name {}: {}", host, e)))?;
+    let mut tls = ClientConnection::new(client_config()?, server_name)
+        .map_err(|e| Error::Tls(e.to_string()))?;
+
+    let mut tcp = TcpStream::connect(address)?;
+    while tls.is_handshaking() {
+        tls.complete_io(&mut tcp)
+            .map_err(|e| Error::Tls(format!("Handshake with {} failed: {}", address, e)))?;
+    }
+    debug!("TLS connection to {} established", address);
+
+    let (plain, client) = UnixStream::pair()?;
+    thread::spawn(move || {
+        if let Err(e) = bridge(Connection::Client(tls), tcp, plain) {
+            debug!("TLS connection closed: {}", e);
+        }
+    });
+
+    Ok(client.into_raw_fd())
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "async")]
+use crate::asynchronous::client::VaccelRpcClient;
+#[cfg(not(feature = "async"))]
+use crate::sync::client::VaccelRpcClient;
+#[cfg(feature = "async")]
+use crate::timeout::Deadline;
+#[cfg(not(feature = "async"))]
+use crate::Error;
+use crate::Result;
+use vaccel_rpc_proto::resource::{RegisterChunk, RegisterRequest, RegisterResponse};
+#[cfg(not(feature = "async"))]
+use vaccel_rpc_proto::sync::agent_ttrpc::AgentServiceClient;
+
+/// Requests larger than this exceed the ttrpc message size limit.
+pub(crate) const MAX_REQ_LEN: u64 = 4 * 1024 * 1024;
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Splits the blobs of `req` into chunks, marking the last chunk as the
+/// commit. Blobs without data are sent by digest in a single chunk.
+fn chunks(req: &RegisterRequest) -> impl Iterator<Item = RegisterChunk> + '_ {
+    let parts = |len: usize| len.div_ceil(CHUNK_SIZE).max(1);
+    let total: usize = req.blobs.iter().map(|b| parts(b.data.len())).sum();
+
+    req.blobs
+        .iter()
+        .enumerate()
+        .flat_map(move |(index, blob)| {
+            (0..parts(blob.data.len())).map(move |part| {
+                let offset = part * CHUNK_SIZE;
+                let end = (offset + CHUNK_SIZE).min(blob.data.len());
+                let blob_size = match blob.data.is_empty() {
+                    true => blob.size as u64,
+                    false => blob.data.len() as u64,
+                };
+                RegisterChunk {
+                    session_id: req.session_id,
+                    resource_type: req.resource_type,
+                    name: req.name.clone(),
+                    blob_index: index as u32,
+                    blob_name: blob.name.clone(),
+                    blob_type: blob.type_,
+                    blob_size,
+                    blob_sha256: match part {
+                        0 => blob.sha256.clone(),
+                        _ => Vec::new(),
+                    },
+                    offset: offset as u64,
+                    data: blob.data[offset..end].to_vec(),
+                    ..Default::default()
+                }
+            })
+        })
+        .enumerate()
+        .map(move |(no, mut chunk)| {
+            chunk.commit = no + 1 == total;
+            chunk
+        })
+}
+
+impl VaccelRpcClient {
+    /// Registers a new resource uploading its blobs in chunks.
+    ///
+    /// Sync ttrpc clients cannot send streams, so each chunk is sent in its
+    /// own request.
+    #[cfg(not(feature = "async"))]
+    pub(crate) fn resource_register_chunked(
+        &self,
+        req: &RegisterRequest,
+    ) -> Result<RegisterResponse> {
+        let mut upload_id = 0;
+        for mut chunk in chunks(req) {
+            chunk.upload_id = upload_id;
+            let ctx = self.context("register_resource");
+            let resp = self.execute(AgentServiceClient::register_resource_chunk, ctx, &chunk)?;
+            if chunk.commit {
+                let mut registered = RegisterResponse::new();
+                registered.resource_id = resp.resource_id;
+                registered.missing = resp.missing;
+                return Ok(registered);
+            }
+            upload_id = resp.upload_id;
+        }
+
+        Err(Error::InvalidArgument("No blobs to upload".to_string()))
+    }
+
+    /// Registers a new resource streaming its blobs in chunks.
+    #[cfg(feature = "async")]
+    pub(crate) fn resource_register_chunked(
+        &self,
+        req: &RegisterRequest,
+    ) -> Result<RegisterResponse> {
+        let ctx = self.with_token(self.context("register_resource"));
+        let deadline = Deadline::of(&ctx);
+        let resp = self
+            .runtime
+            .block_on(async {
+                let mut stream = self.ttrpc_client.register_resource_stream(ctx).await?;
+                for chunk in chunks(req) {
+                    stream.send(&chunk).await?;
+                }
+                stream.close_and_recv().await
+            })
+            .map_err(|e| deadline.error(e))?;
+
+        Ok(resp)
+    }
+}
@@ -0,0 +1,329 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![cfg(feature = "stub")]
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+#[cfg(feature = "async")]
+use tokio::runtime::Runtime;
+use vaccel::{
+    ffi,
+    ops::{
+        tf::{self, lite as tflite},
+        torch, Tensor as _,
+    },
+    Handle,
+};
+use vaccel_rpc_agent::Agent;
+#[cfg(feature = "async")]
+use vaccel_rpc_client::asynchronous::client::VaccelRpcClient;
+#[cfg(not(feature = "async"))]
+use vaccel_rpc_client::sync::client::VaccelRpcClient;
+use vaccel_rpc_client::Error;
+use vaccel_rpc_proto::{
+    genop::{Arg, ArgType},
+    resource::{Blob, BlobType, ResourceType},
+    tf::Node,
+    torch::{DataType as TorchDataType, Tensor as TorchTensor},
+};
+
+/// An agent serving on a temporary unix socket.
+struct TestAgent {
+    agent: Agent,
+    address: String,
+    socket: PathBuf,
+    #[cfg(feature = "async")]
+    runtime: Runtime,
+}
+
+impl TestAgent {
+    fn start() -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let socket = env::temp_dir().join(format!(
+            "vaccel-rpc-e2e-{}-{}.sock",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let address = format!("unix://{}", socket.display());
+
+        let mut agent = Agent::new(&address);
+        let config = vaccel::Config::new(None, 1, None, true, false).unwrap();
+        agent.set_vaccel_config(config).unwrap();
+
+        #[cfg(not(feature = "async"))]
+        agent.start().unwrap();
+        // The client runs its own runtime, so the agent needs a separate one
+        #[cfg(feature = "async")]
+        let runtime = Runtime::new().unwrap();
+        #[cfg(feature = "async")]
+        runtime.block_on(agent.start()).unwrap();
+
+        Self {
+            agent,
+            address,
+            socket,
+            #[cfg(feature = "async")]
+            runtime,
+        }
+    }
+
+    fn client(&self) -> VaccelRpcClient {
+        VaccelRpcClient::with_address(&self.address).unwrap()
+    }
+}
+
+impl Drop for TestAgent {
+    fn drop(&mut self) {
+        #[cfg(not(feature = "async"))]
+        let _ = self.agent.shutdown();
+        #[cfg(feature = "async")]
+        let _ = self.runtime.block_on(self.agent.shutdown());
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+/// Registers a model resource from an in-memory blob.
+fn register_model(client: &VaccelRpcClient, sess_id: i64) -> i64 {
+    let blob = Blob {
+        type_: BlobType::BUFFER.into(),
+        name: "model.bin".to_string(),
+        data: vec![0u8; 16],
+        size: 16,
+        ..Default::default()
+    };
+    client
+        .resource_register(vec![], vec![blob], ResourceType::MODEL as i32, 0, sess_id)
+        .unwrap()
+}
+
+fn raw_arg(buf: &[u8]) -> Arg {
+    Arg {
+        buf: buf.to_vec(),
+        size: buf.len() as u32,
+        arg_type: ArgType::RAW.into(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn session_lifecycle() {
+    let agent = TestAgent::start();
+    let client = agent.client();
+
+    let sess_id = client.session_init(0).unwrap();
+    assert!(sess_id > 0);
+    client.session_update(sess_id, 1).unwrap();
+    client.session_release(sess_id).unwrap();
+
+    assert!(client.session_release(sess_id).is_err());
+}
+
+#[test]
+fn resource_register_from_path_and_blobs() {
+    let agent = TestAgent::start();
+    let client = agent.client();
+    let sess_id = client.session_init(0).unwrap();
+
+    let path = env::temp_dir().join(format!("vaccel-rpc-e2e-{}.bin", process::id()));
+    fs::write(&path, b"resource data").unwrap();
+    let path_res_id = client
+        .resource_register(
+            vec![path.display().to_string()],
+            vec![],
+            ResourceType::DATA as i32,
+            0,
+            sess_id,
+        )
+        .unwrap();
+    let blob_res_id = register_model(&client, sess_id);
+    assert_ne!(path_res_id, blob_res_id);
+
+    client.resource_unregister(path_res_id, sess_id).unwrap();
+    client.resource_unregister(blob_res_id, sess_id).unwrap();
+    assert!(client.resource_unregister(blob_res_id, sess_id).is_err());
+
+    client.session_release(sess_id).unwrap();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn genop_echoes_read_args() {
+    let agent = TestAgent::start();
+    let mut client = agent.client();
+    let sess_id = client.session_init(0).unwrap();
+
+    let write_args = client
+        .genop(sess_id, vec![raw_arg(b"hello")], vec![raw_arg(&[0; 5])])
+        .unwrap();
+    assert_eq!(write_args.len(), 1);
+    assert_eq!(write_args[0].buf, b"hello");
+
+    client.session_release(sess_id).unwrap();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn genop_stream_echoes_read_args() {
+    let agent = TestAgent::start();
+    let mut client = agent.client();
+    let sess_id = client.session_init(0).unwrap();
+
+    let write_args = client
+        .genop_stream(sess_id, vec![raw_arg(b"hello")], vec![raw_arg(&[0; 5])])
+        .unwrap();
+    assert_eq!(write_args.len(), 1);
+    assert_eq!(write_args[0].buf, b"hello");
+
+    client.session_release(sess_id).unwrap();
+}
+
+#[test]
+fn tflite_model_lifecycle() {
+    let agent = TestAgent::start();
+    let client = agent.client();
+    let sess_id = client.session_init(0).unwrap();
+    let model_id = register_model(&client, sess_id);
+
+    client.tflite_model_load(model_id, sess_id).unwrap();
+    let input = tflite::Tensor::<f32>::from_data(&[1, 2], vec![1.0, 2.0]).unwrap();
+    let (out, status) = client
+        .tflite_model_run(model_id, sess_id, vec![input.into()], 1)
+        .unwrap();
+    assert_eq!(status.0, 0);
+    assert_eq!(out.len(), 1);
+    let out = unsafe { tflite::DynTensor::from_ptr_owned(out[0]) }.unwrap();
+    assert_eq!(out.dims().unwrap(), &[1, 2]);
+    assert_eq!(out.as_slice::<f32>().unwrap().unwrap(), &[1.0, 2.0]);
+    client.tflite_model_unload(model_id, sess_id).unwrap();
+
+    client.session_release(sess_id).unwrap();
+}
+
+#[test]
+fn torch_model_lifecycle() {
+    let agent = TestAgent::start();
+    let client = agent.client();
+    let sess_id = client.session_init(0).unwrap();
+    let model_id = register_model(&client, sess_id);
+
+    client.torch_model_load(sess_id, model_id).unwrap();
+    let input = torch::Tensor::<f32>::from_data(&[2, 1], vec![3.0, 4.0]).unwrap();
+    let out = client
+        .torch_model_run(sess_id, model_id, None, vec![input.into()], 1)
+        .unwrap();
+    assert_eq!(out.len(), 1);
+    let out = unsafe { torch::DynTensor::from_ptr_owned(out[0]) }.unwrap();
+    assert_eq!(out.dims().unwrap(), &[2, 1]);
+    assert_eq!(out.as_slice::<f32>().unwrap().unwrap(), &[3.0, 4.0]);
+
+    client.session_release(sess_id).unwrap();
+}
+
+#[test]
+fn model_load_warmup() {
+    let agent = TestAgent::start();
+    let mut client = agent.client();
+    let sess_id = client.session_init(0).unwrap();
+    let model_id = register_model(&client, sess_id);
+
+    // A tensor without data is warmed up with zeros
+    let shape = TorchTensor {
+        dims: vec![1, 4],
+        type_: TorchDataType::FLOAT.into(),
+        ..Default::default()
+    };
+    client
+        .torch_model_load_with_warmup(sess_id, model_id, None, vec![shape], 1, 3)
+        .unwrap();
+    let profiler = client.get_profiler(sess_id).unwrap();
+    assert!(profiler
+        .get_by_full_name("[vaccel-rpc-agent] torch_model_load > warmup")
+        .is_some());
+
+    let input = tflite::Tensor::<f32>::from_data(&[1, 2], vec![1.0, 2.0]).unwrap();
+    client
+        .tflite_model_load_with_warmup(model_id, sess_id, vec![input.into()], 1, 2)
+        .unwrap();
+    // Warm-up failures fail the load
+    let input = tflite::Tensor::<f32>::from_data(&[1], vec![1.0]).unwrap();
+    assert!(client
+        .tflite_model_load_with_warmup(model_id, sess_id, vec![input.into()], 2, 1)
+        .is_err());
+
+    client.session_release(sess_id).unwrap();
+}
+
+#[test]
+fn tf_model_lifecycle() {
+    let agent = TestAgent::start();
+    let client = agent.client();
+    let sess_id = client.session_init(0).unwrap();
+    let model_id = register_model(&client, sess_id);
+
+    let (_, status) = client.tf_model_load(model_id, sess_id).unwrap();
+    assert!(status.is_ok());
+    let node = |name: &str| Node {
+        name: name.to_string(),
+        id: 0,
+        ..Default::default()
+    };
+    let input = tf::Tensor::<i32>::from_data(&[3], vec![5, 6, 7]).unwrap();
+    let (out, status) = client
+        .tf_model_run(
+            model_id,
+            sess_id,
+            None,
+            vec![node("input")],
+            vec![input.into()],
+            vec![node("output")],
+        )
+        .unwrap();
+    assert!(status.is_ok());
+    assert_eq!(out.len(), 1);
+    let out = unsafe { tf::DynTensor::from_ptr_owned(out[0]) }.unwrap();
+    assert_eq!(out.dims().unwrap(), &[3]);
+    assert_eq!(out.as_slice::<i32>().unwrap().unwrap(), &[5, 6, 7]);
+    client.tf_model_unload(model_id, sess_id).unwrap();
+
+    client.session_release(sess_id).unwrap();
+}
+
+#[test]
+fn profiler_has_agent_regions() {
+    let agent = TestAgent::start();
+    let mut client = agent.client();
+    let sess_id = client.session_init(0).unwrap();
+
+    client
+        .genop(sess_id, vec![raw_arg(b"hi")], vec![raw_arg(&[0; 2])])
+        .unwrap();
+    let profiler = client.get_profiler(sess_id).unwrap();
+    assert!(profiler
+        .get_by_full_name("[vaccel-rpc-agent] genop > sess.genop")
+        .is_some());
+
+    client.session_release(sess_id).unwrap();
+}
+
+#[test]
+fn host_errors_propagate() {
+    let agent = TestAgent::start();
+    let client = agent.client();
+    let sess_id = client.session_init(0).unwrap();
+    let model_id = register_model(&client, sess_id);
+
+    // The stub backend rejects more outputs than inputs
+    let input = tflite::Tensor::<f32>::from_data(&[1], vec![1.0]).unwrap();
+    let err = client
+        .tflite_model_run(model_id, sess_id, vec![input.into()], 2)
+        .unwrap_err();
+    assert!(matches!(err, Error::HostVaccel(_)));
+    assert_eq!(err.to_ffi(), ffi::VACCEL_EINVAL);
+
+    client.session_release(sess_id).unwrap();
+}
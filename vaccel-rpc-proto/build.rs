@@ -90,9 +90,14 @@ fn main() {
             .unwrap_or_else(|_| panic!("Error stripping file {:?}", proto))
             .with_extension("");
 
+        // Every proto in 'protos/async' defines a service, so add as well the
+        // ttrpc file
         libf_async
             .write_all(format!("pub mod {};\n", mod_name.to_str().unwrap()).as_bytes())
             .expect("Could not write mod in crate's async mod file");
+        libf_async
+            .write_all(format!("pub mod {}_ttrpc;\n", mod_name.to_str().unwrap()).as_bytes())
+            .expect("Could not write mod in crate's async mod file");
     }
 
     for proto in protos_sync.iter() {
@@ -105,19 +110,16 @@ fn main() {
             .unwrap_or_else(|_| panic!("Error stripping file {:?}", proto))
             .with_extension("");
 
+        // Every proto in 'protos/sync' defines a service, so add as well the
+        // ttrpc file
         libf_sync
             .write_all(format!("pub mod {};\n", mod_name.to_str().unwrap()).as_bytes())
             .expect("Could not write mod in crate's sync mod file");
+        libf_sync
+            .write_all(format!("pub mod {}_ttrpc;\n", mod_name.to_str().unwrap()).as_bytes())
+            .expect("Could not write mod in crate's sync mod file");
     }
 
-    // Add as well the ttrpc file
-    libf_async
-        .write_all(b"pub mod agent_ttrpc;\n")
-        .expect("Could not write mod in crate's async/mod.rs file");
-    libf_sync
-        .write_all(b"pub mod agent_ttrpc;\n")
-        .expect("Could not write mod in crate's sync/mod.rs file");
-
     let protobuf_customized = ProtobufCustomize::default().gen_mod_rs(false);
     Codegen::new()
         .out_dir(out_dir.clone())
@@ -2,4 +2,11 @@
 
 include!(concat!(env!("OUT_DIR"), "/lib.rs"));
 
+/// The version of the agent RPC protocol.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The ttrpc metadata key carrying the client authentication token.
+pub const TOKEN_METADATA_KEY: &str = "vaccel-rpc-token";
+
 pub mod extensions;
+pub mod tls;
@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! TLS helpers shared by the agent and the client.
+//!
+//! ttrpc only supports plain sockets, so both sides terminate TLS in a
+//! thread that bridges the encrypted TCP stream to a unix socket.
+
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    Connection,
+};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::TcpStream,
+    os::unix::{io::AsRawFd, net::UnixStream},
+    path::Path,
+};
+
+const BUF_SIZE: usize = 16 * 1024;
+
+fn open(path: &Path) -> io::Result<File> {
+    File::open(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Could not open {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Loads the PEM certificates in `path`.
+pub fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(open(path)?))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid certificates in {}: {}", path.display(), e),
+            )
+        })?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+/// Loads the first PEM private key in `path`.
+pub fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(open(path)?))
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid private key in {}: {}", path.display(), e),
+            )
+        })?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No private key found in {}", path.display()),
+            )
+        })
+}
+
+fn flush_tls(tls: &mut Connection, tcp: &mut TcpStream) -> io::Result<()> {
+    while tls.wants_write() {
+        tls.write_tls(tcp)?;
+    }
+    Ok(())
+}
+
+/// Copies data between a TLS stream and a plain stream until either side is
+/// closed.
+pub fn bridge(mut tls: Connection, mut tcp: TcpStream, mut plain: UnixStream) -> io::Result<()> {
+    let mut buf = vec![0u8; BUF_SIZE];
+    loop {
+        flush_tls(&mut tls, &mut tcp)?;
+
+        let mut fds = [
+            libc::pollfd {
+                fd: tcp.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: plain.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        if fds[0].revents != 0 {
+            if tls.read_tls(&mut tcp)? == 0 {
+                return Ok(());
+            }
+            if let Err(e) = tls.process_new_packets() {
+                // Send any pending alert before failing
+                let _ = flush_tls(&mut tls, &mut tcp);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            loop {
+                match tls.reader().read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => plain.write_all(&buf[..n])?,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        if fds[1].revents != 0 {
+            let n = plain.read(&mut buf)?;
+            if n == 0 {
+                tls.send_close_notify();
+                return flush_tls(&mut tls, &mut tcp);
+            }
+            tls.writer().write_all(&buf[..n])?;
+        }
+    }
+}